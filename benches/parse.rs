@@ -0,0 +1,23 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use mona_spy::data_provider::wiki::promotional_codes::PromotionalCodes;
+use mona_spy::data_provider::wiki::{parse_wikitext, WikiResource};
+
+const TITLE: &str = "Promotional_Codes";
+const WIKI_TEXT: &str =
+  include_str!("../src/data_provider/wiki/fixtures/promotional_codes.wikitext");
+
+fn parse(c: &mut Criterion) {
+  let mut group = c.benchmark_group("promotional_codes");
+  group.bench_function("uncached", |b| {
+    b.iter(|| PromotionalCodes::from_wikitext(black_box(WIKI_TEXT)))
+  });
+  // The first call fills the parse cache, every later one is a lookup
+  parse_wikitext::<PromotionalCodes>(TITLE, WIKI_TEXT).unwrap();
+  group.bench_function("cached", |b| {
+    b.iter(|| parse_wikitext::<PromotionalCodes>(black_box(TITLE), black_box(WIKI_TEXT)).unwrap())
+  });
+  group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS locks (
+  name TEXT PRIMARY KEY,
+  token TEXT NOT NULL,
+  expires_at TIMESTAMPTZ NOT NULL
+);
@@ -1,25 +1,26 @@
-use reqwest;
-use std::thread;
+use actix_rt::time::delay_for;
+use log::{info, warn};
 use std::time::Duration;
 
-#[allow(dead_code)] // Now the API is called from a free cron-job
-pub async fn call_every_hour(addr: String, paths: Vec<&'static str>) {
+// Refreshes the resources by calling the API itself, for deployments without an
+// external cron-job
+pub async fn call_every(addr: String, paths: Vec<&'static str>, interval: Duration) {
   let paths: Vec<_> = paths
     .into_iter()
     .map(|path| "http://".to_owned() + addr.as_str() + path)
     .collect();
 
-  thread::sleep(Duration::from_secs(5));
+  delay_for(Duration::from_secs(5)).await;
   loop {
+    info!("Starting Scheduled Call");
+    let client = reqwest::Client::new();
     for path in &paths {
-      println!("Starting Hourly Call");
-      let client = reqwest::Client::new();
       let resp = client.get(path).send().await;
       match resp {
         Ok(_) => {}
-        Err(err) => println!("Error during hourly call: {:?}", err),
+        Err(err) => warn!("Error during scheduled call: {:?}", err),
       }
     }
-    thread::sleep(Duration::from_secs(3600));
+    delay_for(interval).await;
   }
 }
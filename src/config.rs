@@ -0,0 +1,160 @@
+use crate::data_provider::persist::BackendKind;
+use crate::data_provider::wiki::{DEFAULT_WIKI_HOST, DEFAULT_WIKI_LANG};
+use derive_more::{Display, Error};
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_PERSIST_DIR: &str = "data";
+const DEFAULT_REQUESTS_PER_SECOND: f64 = 2.0;
+const DEFAULT_BURST: f64 = 4.0;
+const DEFAULT_WEBHOOK_TIMEOUT: u64 = 10;
+const DEFAULT_NOTIFY_MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Display, Error)]
+pub enum ConfigError {
+  #[display(fmt = "{} is invalid: {}", _0, _1)]
+  Invalid(#[error(not(source))] String, String),
+}
+
+// Everything the service can be tuned with, read once at startup so a bad value
+// stops the server instead of silently falling back to a default. Main builds the
+// persist backend, the wiki context and the notifiers out of it.
+#[derive(Debug, Clone)]
+pub struct Config {
+  pub port: u16,
+  pub wiki_host: String,
+  pub wiki_lang: String,
+  pub poll_interval: Option<Duration>, // None when the API is called by an external cron-job
+  pub webhook_urls: Vec<reqwest::Url>,
+  pub webhook_timeout: Duration,
+  pub notify_coalesce: Option<Duration>, // None sends every change on its own
+  pub notify_max_attempts: u32,          // Tries of a delivery before it goes to the outbox
+  // Events a minute each notifier, ex: "discord", lets through, see ThrottledNotifier
+  pub max_per_minute: HashMap<String, u32>,
+  pub persist_backend: BackendKind,
+  pub persist_dir: PathBuf,
+  pub persist_cache: bool,
+  pub requests_per_second: f64,
+  pub burst: f64,
+  pub admin_token: Option<String>, // None leaves the admin endpoints out
+}
+
+fn var(name: &'static str) -> Option<String> {
+  env::var(name)
+    .ok()
+    .map(|x| x.trim().to_owned())
+    .filter(|x| !x.is_empty())
+}
+
+fn parse<T: FromStr>(name: &'static str, default: T) -> Result<T, ConfigError>
+where
+  T::Err: ToString,
+{
+  match var(name) {
+    None => Ok(default),
+    Some(value) => value.parse().map_err(|err: T::Err| {
+      ConfigError::Invalid(name.to_owned(), format!("{:?}, {}", value, err.to_string()))
+    }),
+  }
+}
+
+fn positive(name: &'static str, default: f64) -> Result<f64, ConfigError> {
+  match parse(name, default)? {
+    x if x > 0.0 && x.is_finite() => Ok(x),
+    x => Err(ConfigError::Invalid(
+      name.to_owned(),
+      format!("{} must be positive", x),
+    )),
+  }
+}
+
+// Every <NAME>_MAX_PER_MINUTE, keyed by the lowercase notifier name. 0 is no limit.
+fn max_per_minute() -> Result<HashMap<String, u32>, ConfigError> {
+  let mut limits = HashMap::new();
+  for (name, value) in env::vars() {
+    let notifier = match name.strip_suffix("_MAX_PER_MINUTE") {
+      Some(notifier) if !value.trim().is_empty() => notifier.to_lowercase(),
+      _ => continue,
+    };
+    match value.trim().parse::<u32>() {
+      Ok(0) => {}
+      Ok(limit) => {
+        limits.insert(notifier, limit);
+      }
+      Err(err) => return Err(ConfigError::Invalid(name, format!("{:?}, {}", value, err))),
+    }
+  }
+  Ok(limits)
+}
+
+impl Config {
+  // PORT, WIKI_HOST, WIKI_LANG, POLL_INTERVAL (seconds), WEBHOOK_URLS,
+  // WEBHOOK_TIMEOUT (seconds), NOTIFY_COALESCE_SECONDS, NOTIFY_MAX_ATTEMPTS,
+  // <NAME>_MAX_PER_MINUTE,
+  // PERSIST_BACKEND, PERSIST_DIR, PERSIST_CACHE, WIKI_REQUESTS_PER_SECOND,
+  // WIKI_BURST and ADMIN_TOKEN
+  pub fn from_env() -> Result<Config, ConfigError> {
+    let wiki_host = var("WIKI_HOST").unwrap_or_else(|| DEFAULT_WIKI_HOST.to_owned());
+    if wiki_host.contains("://") || wiki_host.contains('/') {
+      return Err(ConfigError::Invalid(
+        "WIKI_HOST".to_owned(),
+        format!(
+          "{:?} must be a bare host, ex: {}",
+          wiki_host, DEFAULT_WIKI_HOST
+        ),
+      ));
+    }
+
+    let poll_interval = match parse::<u64>("POLL_INTERVAL", 0)? {
+      0 => None,
+      secs => Some(Duration::from_secs(secs)),
+    };
+    let notify_coalesce = match parse::<u64>("NOTIFY_COALESCE_SECONDS", 0)? {
+      0 => None,
+      secs => Some(Duration::from_secs(secs)),
+    };
+    let notify_max_attempts = match parse("NOTIFY_MAX_ATTEMPTS", DEFAULT_NOTIFY_MAX_ATTEMPTS)? {
+      0 => {
+        return Err(ConfigError::Invalid(
+          "NOTIFY_MAX_ATTEMPTS".to_owned(),
+          "0 must be positive".to_owned(),
+        ))
+      }
+      attempts => attempts,
+    };
+
+    let webhook_urls = var("WEBHOOK_URLS")
+      .unwrap_or_default()
+      .split(',')
+      .map(str::trim)
+      .filter(|x| !x.is_empty())
+      .map(|url| {
+        reqwest::Url::parse(url).map_err(|err| {
+          ConfigError::Invalid("WEBHOOK_URLS".to_owned(), format!("{:?}, {}", url, err))
+        })
+      })
+      .collect::<Result<_, _>>()?;
+
+    Ok(Config {
+      port: parse("PORT", DEFAULT_PORT)?,
+      wiki_host,
+      wiki_lang: var("WIKI_LANG").unwrap_or_else(|| DEFAULT_WIKI_LANG.to_owned()),
+      poll_interval,
+      webhook_urls,
+      webhook_timeout: Duration::from_secs(parse("WEBHOOK_TIMEOUT", DEFAULT_WEBHOOK_TIMEOUT)?),
+      notify_coalesce,
+      notify_max_attempts,
+      max_per_minute: max_per_minute()?,
+      persist_backend: parse("PERSIST_BACKEND", BackendKind::Redis)?,
+      persist_dir: var("PERSIST_DIR").map_or(DEFAULT_PERSIST_DIR.into(), PathBuf::from),
+      persist_cache: parse("PERSIST_CACHE", false)?,
+      requests_per_second: positive("WIKI_REQUESTS_PER_SECOND", DEFAULT_REQUESTS_PER_SECOND)?,
+      burst: positive("WIKI_BURST", DEFAULT_BURST)?,
+      admin_token: var("ADMIN_TOKEN"),
+    })
+  }
+}
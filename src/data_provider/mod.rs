@@ -1,3 +1,6 @@
-mod persist;
+pub mod notifier;
+pub mod persist;
 pub mod subscription;
 pub mod wiki;
+
+pub use wiki::{get_or_update, Freshness};
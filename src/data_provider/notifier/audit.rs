@@ -0,0 +1,127 @@
+use super::super::persist;
+use super::{ChangeEvent, NotifyError};
+use async_std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use log::error;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+const AUDIT_KEY: &str = "notifier_audit";
+const DEFAULT_AUDIT_LENGTH: usize = 500;
+
+// Serializes the read-modify-write of the log within this process
+static WRITE: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+  Delivered,
+  Failed,
+}
+
+// One attempt of a notifier to deliver a change, the retries are recorded as well
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditRecord {
+  pub at: DateTime<Utc>,
+  pub notifier: String,
+  pub title: String,
+  pub summary: String,
+  pub outcome: Outcome,
+  pub error: Option<String>,
+  // 1 for the first attempt, outbox retries keep counting
+  pub attempt: u32,
+}
+
+// NOTIFY_AUDIT_LENGTH bounds the records kept, the oldest are dropped first
+fn audit_length() -> usize {
+  env::var("NOTIFY_AUDIT_LENGTH")
+    .ok()
+    .and_then(|x| x.parse().ok())
+    .unwrap_or(DEFAULT_AUDIT_LENGTH)
+}
+
+// Never fails the delivery, a record that isn't saved is only logged
+pub async fn record(
+  notifier: &str,
+  event: &ChangeEvent,
+  result: &Result<(), NotifyError>,
+  attempt: u32,
+) {
+  let record = AuditRecord {
+    at: Utc::now(),
+    notifier: notifier.to_owned(),
+    title: event.title.to_owned(),
+    summary: event.summary.to_owned(),
+    outcome: match result {
+      Ok(()) => Outcome::Delivered,
+      Err(_) => Outcome::Failed,
+    },
+    error: result.as_ref().err().map(|err| err.to_string()),
+    attempt,
+  };
+
+  let _guard = WRITE.lock().await;
+  let mut records: Vec<AuditRecord> = persist::get_by_key(AUDIT_KEY).await.unwrap_or_default();
+  records.push(record);
+  let excess = records.len().saturating_sub(audit_length());
+  records.drain(..excess);
+  if let Err(err) = persist::set_by_key(AUDIT_KEY, &records).await {
+    error!("Failed to save the notification audit log: {:?}", err);
+  }
+}
+
+// The newest first
+pub async fn records(notifier: Option<&str>, outcome: Option<Outcome>) -> Vec<AuditRecord> {
+  let records: Vec<AuditRecord> = persist::get_by_key(AUDIT_KEY).await.unwrap_or_default();
+  records
+    .into_iter()
+    .rev()
+    .filter(|x| notifier.map_or(true, |notifier| x.notifier == notifier))
+    .filter(|x| outcome.map_or(true, |outcome| x.outcome == outcome))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{test_event, Notifier, RecordingNotifier, Reply, RetryingNotifier};
+  use super::*;
+  use std::sync::Arc;
+
+  #[actix_rt::test]
+  async fn records_every_attempt() {
+    let delivered = "test/audit/delivered";
+    let failed = "test/audit/failed";
+    let before = Utc::now();
+    let succeeding = RetryingNotifier::new(
+      delivered,
+      1,
+      Arc::new(RecordingNotifier::new(Reply::Succeed)),
+    );
+    let failing = RetryingNotifier::new(failed, 1, Arc::new(RecordingNotifier::new(Reply::Fail)));
+    succeeding.notify(&test_event("Delivered")).await.unwrap();
+    assert!(failing.notify(&test_event("Failed")).await.is_err());
+
+    let success = records(Some(delivered), None).await;
+    assert_eq!(success.len(), 1);
+    assert_eq!(success[0].notifier, delivered);
+    assert_eq!(success[0].title, "Delivered");
+    assert_eq!(success[0].summary, "1 added");
+    assert_eq!(success[0].outcome, Outcome::Delivered);
+    assert_eq!(success[0].error, None);
+    assert_eq!(success[0].attempt, 1);
+    assert!(success[0].at >= before);
+
+    let failure = records(Some(failed), Some(Outcome::Failed)).await;
+    assert_eq!(failure.len(), 1);
+    assert_eq!(failure[0].title, "Failed");
+    assert_eq!(
+      failure[0].error.as_deref(),
+      Some("Failed to notify: told to fail")
+    );
+    assert_eq!(failure[0].attempt, 1);
+    assert!(records(Some(failed), Some(Outcome::Delivered))
+      .await
+      .is_empty());
+  }
+}
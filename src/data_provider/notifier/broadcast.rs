@@ -0,0 +1,125 @@
+use super::{ChangeEvent, Notifier, NotifyError};
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::debug;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Events kept for subscribers coming back with a Last-Event-ID
+const REPLAY_LENGTH: usize = 20;
+// A subscriber this far behind is dropped instead of buffering without bounds
+const SUBSCRIBER_BUFFER: usize = 64;
+const KEEP_ALIVE_INTERVAL: u64 = 15;
+
+#[derive(Debug, Clone)]
+pub enum Broadcast {
+  Event { id: u64, event: Arc<ChangeEvent> },
+  KeepAlive,
+}
+
+impl Broadcast {
+  // A Server-Sent Events frame, keep-alives are comments the clients ignore
+  pub fn to_sse(&self) -> String {
+    match self {
+      Broadcast::Event { id, event } => format!(
+        "id: {}\nevent: change\ndata: {}\n\n",
+        id,
+        serde_json::to_string(event.as_ref()).unwrap_or_default()
+      ),
+      Broadcast::KeepAlive => ": keep-alive\n\n".to_owned(),
+    }
+  }
+}
+
+#[derive(Default)]
+struct State {
+  next_id: u64,
+  recent: VecDeque<(u64, Arc<ChangeEvent>)>,
+  subscribers: Vec<Sender<Broadcast>>,
+}
+
+// Hands every change to the live subscribers, ex: the /events and /ws streams. Ids start
+// at the startup time in milliseconds, so a Last-Event-ID from before a restart
+// is always older than the events held here.
+pub struct EventBroadcaster {
+  state: Mutex<State>,
+}
+
+impl EventBroadcaster {
+  pub fn new() -> EventBroadcaster {
+    EventBroadcaster {
+      state: Mutex::new(State {
+        next_id: Utc::now().timestamp_millis() as u64,
+        ..State::default()
+      }),
+    }
+  }
+
+  // Only the events that come next
+  pub fn subscribe_live(&self) -> Receiver<Broadcast> {
+    let (sender, receiver) = channel::bounded(SUBSCRIBER_BUFFER);
+    self.state.lock().unwrap().subscribers.push(sender);
+    receiver
+  }
+
+  // The events after last_event_id that are still held are replayed first, for
+  // a client that reconnects
+  pub fn subscribe_after(&self, last_event_id: u64) -> Receiver<Broadcast> {
+    let (sender, receiver) = channel::bounded(SUBSCRIBER_BUFFER);
+    let mut state = self.state.lock().unwrap();
+    let replay = state.recent.iter().filter(|(id, _)| *id > last_event_id);
+    for (id, event) in replay {
+      let _ = sender.try_send(Broadcast::Event {
+        id: *id,
+        event: event.clone(),
+      });
+    }
+    state.subscribers.push(sender);
+    receiver
+  }
+
+  // Subscribers that went away or fell behind are dropped
+  fn send(state: &mut State, message: Broadcast) {
+    state
+      .subscribers
+      .retain(|subscriber| match subscriber.try_send(message.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+          debug!("Dropping a subscriber that fell behind");
+          false
+        }
+        Err(TrySendError::Closed(_)) => false,
+      });
+  }
+
+  // Also what notices the closed connections when nothing changes
+  pub async fn keep_alive_loop(self: Arc<Self>) {
+    loop {
+      actix_rt::time::delay_for(Duration::from_secs(KEEP_ALIVE_INTERVAL)).await;
+      EventBroadcaster::send(&mut self.state.lock().unwrap(), Broadcast::KeepAlive);
+    }
+  }
+}
+
+#[async_trait]
+impl Notifier for EventBroadcaster {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let mut state = self.state.lock().unwrap();
+    let id = state.next_id;
+    state.next_id += 1;
+
+    let event = Arc::new(event.to_owned());
+    state.recent.push_back((id, event.clone()));
+    if state.recent.len() > REPLAY_LENGTH {
+      state.recent.pop_front();
+    }
+    EventBroadcaster::send(&mut state, Broadcast::Event { id, event });
+    Ok(())
+  }
+
+  fn delivers(&self) -> bool {
+    false
+  }
+}
@@ -0,0 +1,123 @@
+use super::{notify_all, ChangeEvent, Notifier, Notifiers, NotifyError};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+type Pending = Arc<Mutex<HashMap<String, ChangeEvent>>>;
+
+// Holds the events of a resource for a window and merges the ones that come in
+// meanwhile, so an editing storm on the wiki sends a single notification
+pub struct CoalescingNotifier {
+  notifiers: Notifiers,
+  window: Duration,
+  pending: Pending,
+}
+
+impl CoalescingNotifier {
+  pub fn new(notifiers: Notifiers, window: Duration) -> CoalescingNotifier {
+    CoalescingNotifier {
+      notifiers,
+      window,
+      pending: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+}
+
+// Entries listed in both are kept once, anything else takes the later value
+fn merge_values(earlier: &mut Value, later: Value) {
+  match (earlier, later) {
+    (Value::Object(earlier), Value::Object(later)) => {
+      for (key, value) in later {
+        match earlier.get_mut(&key) {
+          Some(earlier) => merge_values(earlier, value),
+          None => {
+            earlier.insert(key, value);
+          }
+        }
+      }
+    }
+    (Value::Array(earlier), Value::Array(later)) => {
+      for value in later {
+        if !earlier.contains(&value) {
+          earlier.push(value);
+        }
+      }
+    }
+    (_, Value::Null) => {}
+    (earlier, later) => *earlier = later,
+  }
+}
+
+// Summaries are concatenated, the entries of both diffs are kept once
+pub fn merge(earlier: &mut ChangeEvent, later: ChangeEvent) {
+  earlier.summary = format!("{}\n{}", earlier.summary, later.summary);
+  earlier.added_count += later.added_count;
+  earlier.removed_count += later.removed_count;
+  earlier.modified_count += later.modified_count;
+  merge_values(&mut earlier.added, later.added);
+  merge_values(&mut earlier.removed, later.removed);
+  merge_values(&mut earlier.modified, later.modified);
+  merge_values(&mut earlier.previous, later.previous);
+  earlier.fetched_at = later.fetched_at;
+  earlier.revision = later.revision;
+}
+
+async fn flush_resource(notifiers: &Notifiers, pending: &Pending, title: &str) {
+  let event = pending.lock().unwrap().remove(title);
+  if let Some(event) = event {
+    notify_all(notifiers, &event).await;
+  }
+}
+
+#[async_trait]
+impl Notifier for CoalescingNotifier {
+  // The first event of a resource opens its window, the merged event is sent
+  // when the window closes
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let opened = {
+      let mut pending = self.pending.lock().unwrap();
+      match pending.get_mut(&event.title) {
+        Some(earlier) => {
+          merge(earlier, event.to_owned());
+          false
+        }
+        None => {
+          pending.insert(event.title.to_owned(), event.to_owned());
+          true
+        }
+      }
+    };
+    if !opened {
+      return Ok(());
+    }
+
+    let notifiers = self.notifiers.to_owned();
+    let pending = self.pending.clone();
+    let window = self.window;
+    let title = event.title.to_owned();
+    actix_rt::spawn(async move {
+      actix_rt::time::delay_for(window).await;
+      flush_resource(&notifiers, &pending, &title).await;
+    });
+    Ok(())
+  }
+
+  fn delivers(&self) -> bool {
+    self.notifiers.iter().any(|x| x.delivers())
+  }
+
+  async fn retry_outbox(&self) {
+    for notifier in &self.notifiers {
+      notifier.retry_outbox().await;
+    }
+  }
+
+  async fn flush(&self) {
+    let titles: Vec<String> = self.pending.lock().unwrap().keys().cloned().collect();
+    for title in titles {
+      flush_resource(&self.notifiers, &self.pending, &title).await;
+    }
+  }
+}
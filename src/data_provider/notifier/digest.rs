@@ -0,0 +1,280 @@
+use super::super::persist;
+use super::super::wiki::promotional_codes::PromotionalCodes;
+use super::super::wiki::WikiResource;
+use super::coalesce::merge;
+use super::{code_key, ChangeEvent, Notifier, NotifyError};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+
+// What a digest holds until it's sent, persisted so a restart doesn't lose it
+#[derive(Serialize, Deserialize)]
+struct DigestState {
+  opened_at: DateTime<Utc>,
+  events: Vec<ChangeEvent>,
+}
+
+// Accumulates the events of a channel and sends them as one message, daily at
+// <NAME>_DIGEST_AT (UTC, ex: "18:00") or as soon as <NAME>_DIGEST_MAX events are held
+pub struct DigestNotifier {
+  key: String,
+  at: Option<NaiveTime>,
+  max_events: Option<usize>,
+  inner: Arc<dyn Notifier>,
+  // Serializes the read-modify-write of the persisted state
+  state: Mutex<()>,
+}
+
+impl DigestNotifier {
+  pub fn new(
+    name: &str,
+    at: Option<NaiveTime>,
+    max_events: Option<usize>,
+    inner: Arc<dyn Notifier>,
+  ) -> DigestNotifier {
+    DigestNotifier {
+      key: format!("notifier_digest@{}", name),
+      at,
+      max_events,
+      inner,
+      state: Mutex::new(()),
+    }
+  }
+
+  // The notifier is returned as is without either variable
+  pub fn wrap_from_env(name: &str, inner: Arc<dyn Notifier>) -> Arc<dyn Notifier> {
+    let var = |suffix| env::var(format!("{}_{}", name.to_uppercase(), suffix)).ok();
+    let at = var("DIGEST_AT").and_then(|x| {
+      let at = NaiveTime::parse_from_str(x.trim(), "%H:%M").ok();
+      if at.is_none() {
+        warn!(
+          "Ignoring the digest time of {}, expected HH:MM: {}",
+          name, x
+        );
+      }
+      at
+    });
+    let max_events = var("DIGEST_MAX")
+      .and_then(|x| x.parse().ok())
+      .filter(|&x| x > 0);
+    if at.is_none() && max_events.is_none() {
+      return inner;
+    }
+    Arc::new(DigestNotifier::new(name, at, max_events, inner))
+  }
+
+  async fn load(&self) -> Option<DigestState> {
+    persist::get_by_key(&self.key).await
+  }
+
+  async fn save(&self, state: &Option<DigestState>) -> Result<(), NotifyError> {
+    persist::set_by_key(&self.key, state)
+      .await
+      .map_err(|err| NotifyError::Failed(format!("{:?}", err)))
+  }
+
+  // Whether a scheduled time passed since the digest was opened
+  fn due(&self, state: &DigestState, now: DateTime<Utc>) -> bool {
+    let at = match self.at {
+      Some(at) => at,
+      None => return false,
+    };
+    let today = DateTime::<Utc>::from_utc(now.naive_utc().date().and_time(at), Utc);
+    let last = if today <= now {
+      today
+    } else {
+      today - Duration::days(1)
+    };
+    state.opened_at < last
+  }
+
+  // The state is cleared once handed over, the inner notifier keeps what it fails
+  // to deliver in its outbox
+  async fn send(&self, state: DigestState) -> Result<(), NotifyError> {
+    self.save(&None).await?;
+    match combine(state.events)? {
+      Some(digest) => self.inner.notify(&digest.enriched()).await,
+      None => Ok(()),
+    }
+  }
+
+  async fn send_if_due(&self) -> Result<(), NotifyError> {
+    let _state = self.state.lock().await;
+    match self.load().await {
+      Some(state) if self.due(&state, Utc::now()) => self.send(state).await,
+      _ => Ok(()),
+    }
+  }
+}
+
+// Codes added and then removed within the window cancel out
+fn drop_transient_codes(event: &mut ChangeEvent) -> Result<(), NotifyError> {
+  let keys = |diff: &Value| -> HashSet<String> {
+    diff["codes"]
+      .as_array()
+      .map(|x| x.iter().filter_map(code_key).collect())
+      .unwrap_or_default()
+  };
+  let transient: HashSet<String> = keys(&event.added)
+    .intersection(&keys(&event.removed))
+    .cloned()
+    .collect();
+  if transient.is_empty() {
+    return Ok(());
+  }
+
+  for diff in [&mut event.added, &mut event.removed].iter_mut() {
+    if let Some(codes) = diff.get_mut("codes").and_then(Value::as_array_mut) {
+      codes.retain(|x| code_key(x).map_or(true, |x| !transient.contains(&x)));
+    }
+  }
+  event.resummarize_codes()?;
+  Ok(())
+}
+
+// One event per resource, merged in order. A digest of a single resource is sent
+// as its merged event, one of several resources as a "Digest" event with a section
+// per resource and the diffs keyed by title.
+pub fn combine(events: Vec<ChangeEvent>) -> Result<Option<ChangeEvent>, NotifyError> {
+  let mut resources: Vec<ChangeEvent> = vec![];
+  for event in events {
+    match resources.iter_mut().find(|x| x.title == event.title) {
+      Some(earlier) => merge(earlier, event),
+      None => resources.push(event),
+    }
+  }
+  for resource in resources.iter_mut() {
+    if resource.title == PromotionalCodes::get_title() {
+      drop_transient_codes(resource)?;
+    }
+  }
+  resources.retain(|x| x.added_count + x.removed_count + x.modified_count > 0);
+
+  if resources.len() <= 1 {
+    return Ok(resources.pop());
+  }
+  let sections: Vec<String> = resources
+    .iter()
+    .map(|x| format!("{}:\n{}", x.title, x.summary))
+    .collect();
+  let by_title = |diff: fn(&ChangeEvent) -> &Value| {
+    Value::Object(
+      resources
+        .iter()
+        .map(|x| (x.title.to_owned(), diff(x).to_owned()))
+        .collect::<Map<String, Value>>(),
+    )
+  };
+  let latest = resources.iter().max_by_key(|x| x.fetched_at);
+  Ok(Some(ChangeEvent {
+    resource_type: "digest".to_owned(),
+    title: "Digest".to_owned(),
+    summary: sections.join("\n\n"),
+    added_count: resources.iter().map(|x| x.added_count).sum(),
+    removed_count: resources.iter().map(|x| x.removed_count).sum(),
+    modified_count: resources.iter().map(|x| x.modified_count).sum(),
+    added: by_title(|x| &x.added),
+    removed: by_title(|x| &x.removed),
+    modified: by_title(|x| &x.modified),
+    previous: by_title(|x| &x.previous),
+    fetched_at: latest.map_or_else(Utc::now, |x| x.fetched_at),
+    revision: None,
+    codes: vec![],
+  }))
+}
+
+#[async_trait]
+impl Notifier for DigestNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let _state = self.state.lock().await;
+    let mut state = self.load().await.unwrap_or_else(|| DigestState {
+      opened_at: Utc::now(),
+      events: vec![],
+    });
+    state.events.push(event.to_owned());
+
+    let full = self.max_events.map_or(false, |x| state.events.len() >= x);
+    if full || self.due(&state, Utc::now()) {
+      return self.send(state).await;
+    }
+    self.save(&Some(state)).await
+  }
+
+  fn delivers(&self) -> bool {
+    self.inner.delivers()
+  }
+
+  // The outbox tick doubles as the digest clock
+  async fn retry_outbox(&self) {
+    if let Err(err) = self.send_if_due().await {
+      error!("Failed to send the digest {}: {}", self.key, err);
+    }
+    self.inner.retry_outbox().await;
+  }
+
+  // The digest is persisted, it's sent on schedule after the restart
+  async fn flush(&self) {
+    self.inner.flush().await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, test_event, RecordingNotifier, Reply};
+  use super::*;
+  use serde_json::json;
+
+  fn digest(inner: Arc<dyn Notifier>) -> DigestNotifier {
+    DigestNotifier::new("test/digest/grouping", None, Some(3), inner)
+  }
+
+  #[actix_rt::test]
+  async fn groups_the_events_of_a_window() {
+    let inner = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let added = codes_event(&["GENSHINGIFT", "TRANSIENT1"]);
+    let mut removed = codes_event(&[]);
+    removed.removed = json!({ "codes": [{ "code": "TRANSIENT1", "server": "All" }] });
+    removed.removed_count = 1;
+
+    digest(inner.clone()).notify(&added).await.unwrap();
+    digest(inner.clone()).notify(&removed).await.unwrap();
+    assert!(inner.events().is_empty());
+    // Held through a restart, a new notifier picks the digest up
+    digest(inner.clone())
+      .notify(&test_event("Material_Schedule"))
+      .await
+      .unwrap();
+
+    let events = inner.events();
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(event.title, "Digest");
+    assert_eq!(
+      (event.added_count, event.removed_count),
+      (2, 0),
+      "TRANSIENT1 came and went within the window"
+    );
+    assert_eq!(
+      event.summary,
+      "Promotional_Codes:\n\
+       Promotional codes updated:\n\
+       - GENSHINGIFT: Primogem ×60 (https://genshin.hoyoverse.com/en/gift?code=GENSHINGIFT)\n\n\
+       Material_Schedule:\n\
+       1 added"
+    );
+    let codes: Vec<&str> = event.codes.iter().map(|x| x.code.as_str()).collect();
+    assert_eq!(codes, vec!["GENSHINGIFT"]);
+    assert_eq!(event.removed["Promotional_Codes"]["codes"], json!([]));
+    assert!(
+      persist::get_by_key::<DigestState>("notifier_digest@test/digest/grouping")
+        .await
+        .is_none()
+    );
+  }
+}
@@ -0,0 +1,261 @@
+use super::{modified_codes, removed_codes, ChangeEvent, EnrichedCode, Notifier, NotifyError};
+use async_trait::async_trait;
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Discord rejects messages with more embeds than this
+const MAX_EMBEDS: usize = 10;
+const MAX_RETRIES: u32 = 3;
+
+#[derive(Deserialize)]
+struct RateLimit {
+  retry_after: f64,
+}
+
+// Posts every new or updated promotional code to DISCORD_WEBHOOK_URL as an embed,
+// other resources are ignored
+pub struct DiscordNotifier {
+  webhook_url: String,
+  client: reqwest::Client,
+  // The messages already posted of the events that failed midway, so a retry only
+  // posts the rest. Keyed by event_key, then by the codes of the message.
+  sent: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl DiscordNotifier {
+  pub fn new(webhook_url: String) -> DiscordNotifier {
+    DiscordNotifier {
+      webhook_url,
+      client: reqwest::Client::new(),
+      sent: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn from_env() -> Option<DiscordNotifier> {
+    let webhook_url = env::var("DISCORD_WEBHOOK_URL").ok()?;
+    Some(DiscordNotifier::new(webhook_url)).filter(|x| !x.webhook_url.is_empty())
+  }
+
+  // 429s are retried after the retry_after (seconds) Discord asks for
+  async fn post(&self, body: &Value) -> Result<(), NotifyError> {
+    let mut attempts = 0;
+    loop {
+      let res = self
+        .client
+        .post(&self.webhook_url)
+        .json(body)
+        .send()
+        .await
+        .map_err(|err| NotifyError::Failed(err.to_string()))?;
+
+      if res.status() != reqwest::StatusCode::TOO_MANY_REQUESTS || attempts >= MAX_RETRIES {
+        return res
+          .error_for_status()
+          .map(|_| ())
+          .map_err(|err| NotifyError::Failed(err.to_string()));
+      }
+
+      let retry_after = res.json::<RateLimit>().await.map_or(1.0, |x| x.retry_after);
+      warn!(
+        "Discord rate limited the webhook, retrying in {}s",
+        retry_after
+      );
+      actix_rt::time::delay_for(Duration::from_secs_f64(retry_after)).await;
+      attempts += 1;
+    }
+  }
+}
+
+fn field(name: &str, value: &Option<String>) -> Value {
+  json!({
+    "name": name,
+    "value": value.as_deref().unwrap_or("Unknown"),
+    "inline": true,
+  })
+}
+
+fn embed(code: &EnrichedCode) -> Value {
+  let expires = code
+    .expires
+    .as_ref()
+    .map(|x| format!("{} ({})", x, code.expires_in));
+  json!({
+    "title": code.code,
+    "url": code.redeem_url,
+    "fields": [
+      field("Reward", &Some(code.reward_summary.to_owned())),
+      field("Servers", &code.server),
+      field("Expires", &expires),
+    ],
+  })
+}
+
+// The same event, even enriched again by the outbox, has the same key
+fn event_key(event: &ChangeEvent) -> String {
+  format!("{}@{}", event.title, event.fetched_at.timestamp_millis())
+}
+
+// The messages of an event, each with the codes it's about as its key. The
+// expiries of the embeds change with every enrich, the codes don't.
+fn messages(event: &ChangeEvent) -> Vec<(String, Value)> {
+  let mut messages = vec![];
+  let modified = modified_codes(event);
+  for (content, codes) in &[
+    ("New promotional codes", &event.codes),
+    ("Updated promotional codes", &modified),
+  ] {
+    for codes in codes.chunks(MAX_EMBEDS) {
+      let key: Vec<&str> = codes.iter().map(|x| x.code.as_str()).collect();
+      let embeds: Vec<Value> = codes.iter().map(embed).collect();
+      messages.push((
+        format!("{}: {}", content, key.join(",")),
+        json!({ "content": content, "embeds": embeds }),
+      ));
+    }
+  }
+
+  let removed: Vec<String> = removed_codes(event)
+    .iter()
+    .map(|x| format!("`{}`", x.code))
+    .collect();
+  if !removed.is_empty() {
+    let content = format!("Expired/removed promotional codes: {}", removed.join(", "));
+    messages.push((content.to_owned(), json!({ "content": content })));
+  }
+  messages
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+  // A message failing leaves the ones before it recorded as sent, the retry of the
+  // event picks up from there
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let key = event_key(event);
+    for (message, body) in messages(event) {
+      let posted = {
+        let sent = self.sent.lock().unwrap();
+        sent.get(&key).map_or(false, |x| x.contains(&message))
+      };
+      if posted {
+        continue;
+      }
+
+      self.post(&body).await?;
+      self
+        .sent
+        .lock()
+        .unwrap()
+        .entry(key.to_owned())
+        .or_default()
+        .insert(message);
+    }
+    self.sent.lock().unwrap().remove(&key);
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, MockServer};
+  use super::*;
+  use std::time::Instant;
+
+  #[actix_rt::test]
+  async fn posts_an_embed_per_code() {
+    let server = MockServer::ok();
+    let event = codes_event(&["GENSHINGIFT"]);
+    DiscordNotifier::new(server.url.to_owned())
+      .notify(&event)
+      .await
+      .unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "POST");
+    assert_eq!(
+      requests[0].json(),
+      json!({
+        "content": "New promotional codes",
+        "embeds": [{
+          "title": "GENSHINGIFT",
+          "url": "https://genshin.hoyoverse.com/en/gift?code=GENSHINGIFT",
+          "fields": [
+            {"name": "Reward", "value": event.codes[0].reward_summary, "inline": true},
+            {"name": "Servers", "value": "All", "inline": true},
+            {"name": "Expires", "value": "Indefinite (no known expiry)", "inline": true},
+          ],
+        }],
+      })
+    );
+  }
+
+  #[actix_rt::test]
+  async fn splits_more_than_ten_embeds() {
+    let server = MockServer::ok();
+    let codes: Vec<String> = (0..11).map(|x| format!("CODE{:04}", x)).collect();
+    let codes: Vec<&str> = codes.iter().map(String::as_str).collect();
+    DiscordNotifier::new(server.url.to_owned())
+      .notify(&codes_event(&codes))
+      .await
+      .unwrap();
+
+    let embeds: Vec<usize> = server
+      .requests()
+      .iter()
+      .map(|x| x.json()["embeds"].as_array().unwrap().len())
+      .collect();
+    assert_eq!(embeds, vec![10, 1]);
+  }
+
+  #[actix_rt::test]
+  async fn resumes_after_the_messages_already_posted() {
+    let server = MockServer::start(vec![(204, ""), (500, ""), (204, "")]);
+    let codes: Vec<String> = (0..11).map(|x| format!("CODE{:04}", x)).collect();
+    let codes: Vec<&str> = codes.iter().map(String::as_str).collect();
+    let event = codes_event(&codes);
+    let notifier = DiscordNotifier::new(server.url.to_owned());
+    assert!(notifier.notify(&event).await.is_err());
+    notifier.notify(&event.enriched()).await.unwrap();
+
+    let embeds: Vec<usize> = server
+      .requests()
+      .iter()
+      .map(|x| x.json()["embeds"].as_array().unwrap().len())
+      .collect();
+    assert_eq!(embeds, vec![10, 1, 1]);
+
+    // Delivered, the same event is posted in full again
+    notifier.notify(&event).await.unwrap();
+    assert_eq!(server.requests().len(), 5);
+  }
+
+  #[actix_rt::test]
+  async fn waits_for_retry_after() {
+    let server = MockServer::start(vec![(429, r#"{"retry_after": 0.2}"#), (204, "")]);
+    let started = Instant::now();
+    DiscordNotifier::new(server.url.to_owned())
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await
+      .unwrap();
+
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].body, requests[1].body);
+  }
+
+  #[actix_rt::test]
+  async fn fails_on_other_errors() {
+    let server = MockServer::start(vec![(400, r#"{"message": "Invalid"}"#)]);
+    let result = DiscordNotifier::new(server.url.to_owned())
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await;
+    assert!(result.is_err());
+    assert_eq!(server.requests().len(), 1);
+  }
+}
@@ -0,0 +1,269 @@
+use super::{
+  escape_html, modified_codes, ChangeEvent, EnrichedCode, MessageTemplate, Notifier, NotifyError,
+};
+use async_trait::async_trait;
+use lettre::message::{Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use std::env;
+
+const DEFAULT_SMTP_PORT: u16 = 587;
+
+// Mails the new promotional codes of every update to EMAIL_TO, other resources
+// are ignored
+pub struct EmailNotifier {
+  from: Mailbox,
+  to: Vec<Mailbox>,
+  transport: SmtpTransport,
+  // Only the subject is rendered through it
+  template: MessageTemplate,
+}
+
+impl EmailNotifier {
+  pub fn new(from: Mailbox, to: Vec<Mailbox>, transport: SmtpTransport) -> EmailNotifier {
+    EmailNotifier {
+      from,
+      to,
+      transport,
+      template: MessageTemplate::from_env(),
+    }
+  }
+
+  // SMTP_HOST, SMTP_USERNAME, SMTP_PASSWORD, EMAIL_FROM and EMAIL_TO (comma
+  // separated) are required, SMTP_PORT defaults to 587. The connection is
+  // upgraded with STARTTLS.
+  pub fn from_env() -> Option<EmailNotifier> {
+    let var = |name| env::var(name).ok().filter(|x: &String| !x.is_empty());
+    let host = var("SMTP_HOST")?;
+    let credentials = Credentials::new(var("SMTP_USERNAME")?, var("SMTP_PASSWORD")?);
+    let port = var("SMTP_PORT")
+      .and_then(|x| x.parse().ok())
+      .unwrap_or(DEFAULT_SMTP_PORT);
+
+    let from = var("EMAIL_FROM")?.parse().ok()?;
+    let to: Vec<Mailbox> = var("EMAIL_TO")?
+      .split(',')
+      .map(str::trim)
+      .filter(|x| !x.is_empty())
+      .filter_map(|x| x.parse().ok())
+      .collect();
+    if to.is_empty() {
+      return None;
+    }
+
+    let transport = SmtpTransport::starttls_relay(&host)
+      .ok()?
+      .port(port)
+      .credentials(credentials)
+      .build();
+    Some(EmailNotifier::new(from, to, transport))
+  }
+
+  fn message(&self, subject: &str, text: String, html: String) -> Result<Message, NotifyError> {
+    let builder = self.to.iter().fold(
+      Message::builder().from(self.from.to_owned()),
+      |builder, to| builder.to(to.to_owned()),
+    );
+    builder
+      .subject(subject)
+      .multipart(
+        MultiPart::alternative()
+          .singlepart(SinglePart::plain(text))
+          .singlepart(SinglePart::html(html)),
+      )
+      .map_err(|err| NotifyError::Failed(err.to_string()))
+  }
+}
+
+// Ex: "GENSHINGIFT: 50 Primogems, expires in 3 days - https://genshin.hoyoverse.com/..."
+fn text_line(code: &EnrichedCode) -> String {
+  let mut line = format!("{}: {}", code.code, code.reward_summary);
+  if let Some(server) = &code.server {
+    line += &format!(" ({})", server);
+  }
+  if code.expires_at.is_some() {
+    line += &format!(", {}", code.expires_in);
+  }
+  line + " - " + &code.redeem_url
+}
+
+fn html_line(code: &EnrichedCode) -> String {
+  let mut line = format!(
+    "<li><a href=\"{}\"><code>{}</code></a> {}",
+    escape_html(&code.redeem_url),
+    escape_html(&code.code),
+    escape_html(&code.reward_summary)
+  );
+  if let Some(server) = &code.server {
+    line += &format!(" ({})", escape_html(server));
+  }
+  if code.expires_at.is_some() {
+    line += &format!(", {}", escape_html(&code.expires_in));
+  }
+  line + "</li>"
+}
+
+#[async_trait]
+impl Notifier for EmailNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let modified = modified_codes(event);
+    if event.codes.is_empty() && modified.is_empty() {
+      return Ok(());
+    }
+
+    let mut text: Vec<String> = vec![];
+    let mut html: Vec<String> = vec![];
+    let sections = [
+      ("New promotional codes", &event.codes),
+      ("Updated promotional codes", &modified),
+    ];
+    for (heading, codes) in sections.iter().filter(|(_, codes)| !codes.is_empty()) {
+      let lines: Vec<String> = codes.iter().map(text_line).collect();
+      let items: Vec<String> = codes.iter().map(html_line).collect();
+      text.push(format!("{}:\n\n{}\n", heading, lines.join("\n")));
+      html.push(format!("<p>{}:</p><ul>{}</ul>", heading, items.join("")));
+    }
+    let message = self.message(
+      &self.template.render(event)?.title,
+      text.join("\n"),
+      html.join(""),
+    )?;
+
+    // The SMTP transport blocks, so it runs on the thread pool
+    let transport = self.transport.clone();
+    actix_web::web::block(move || transport.send(&message))
+      .await
+      .map(|_| ())
+      .map_err(|err| NotifyError::Failed(err.to_string()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::codes_event;
+  use super::*;
+  use std::io::{BufRead, BufReader, Write};
+  use std::net::{TcpListener, TcpStream};
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+
+  type Mails = Arc<Mutex<Vec<String>>>;
+
+  // A local SMTP server accepting every mail, without TLS or auth. Returns its port
+  // and what the client sent for each mail.
+  fn smtp_sink() -> (u16, Mails) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mails: Mails = Arc::new(Mutex::new(vec![]));
+
+    let received = mails.clone();
+    thread::spawn(move || {
+      for stream in listener.incoming().flatten() {
+        serve(stream, &received);
+      }
+    });
+    (port, mails)
+  }
+
+  // Answers just enough SMTP, the mail is recorded before its DATA is acknowledged
+  fn serve(mut stream: TcpStream, mails: &Mails) -> Option<()> {
+    let mut reader = BufReader::new(stream.try_clone().ok()?);
+    stream.write_all(b"220 sink ESMTP\r\n").ok()?;
+
+    let mut transcript = String::new();
+    let mut in_data = false;
+    loop {
+      let mut line = String::new();
+      if reader.read_line(&mut line).ok()? == 0 {
+        return Some(());
+      }
+      transcript.push_str(&line);
+
+      let reply: &[u8] = if in_data {
+        if line != ".\r\n" {
+          continue;
+        }
+        in_data = false;
+        mails.lock().unwrap().push(std::mem::take(&mut transcript));
+        b"250 queued\r\n"
+      } else {
+        match line.get(..4).map(str::to_uppercase).as_deref() {
+          Some("EHLO") => b"250 sink\r\n",
+          Some("DATA") => {
+            in_data = true;
+            b"354 go ahead\r\n"
+          }
+          Some("QUIT") => {
+            stream.write_all(b"221 bye\r\n").ok()?;
+            return Some(());
+          }
+          _ => b"250 ok\r\n",
+        }
+      };
+      stream.write_all(reply).ok()?;
+    }
+  }
+
+  fn notifier(port: u16) -> EmailNotifier {
+    let to = vec![
+      "a@example.com".parse().unwrap(),
+      "b@example.com".parse().unwrap(),
+    ];
+    let transport = SmtpTransport::builder_dangerous("127.0.0.1")
+      .port(port)
+      .build();
+    EmailNotifier::new("mona@example.com".parse().unwrap(), to, transport)
+  }
+
+  // Undoes the quoted-printable soft line breaks and escaped equal signs
+  fn unfold(mail: &str) -> String {
+    mail.replace("=\r\n", "").replace("=3D", "=")
+  }
+
+  #[actix_rt::test]
+  async fn mails_the_new_codes() {
+    let (port, mails) = smtp_sink();
+    let mut event = codes_event(&["GENSHINGIFT", "5SM6VJVQL4ZC"]);
+    for code in event.codes.iter_mut() {
+      code.reward_summary = "Primogem x60".to_owned();
+    }
+    notifier(port).notify(&event).await.unwrap();
+
+    let mails = mails.lock().unwrap();
+    assert_eq!(mails.len(), 1);
+    let mail = unfold(&mails[0]);
+    assert!(mail.contains("MAIL FROM:<mona@example.com>"));
+    assert!(mail.contains("RCPT TO:<a@example.com>"));
+    assert!(mail.contains("RCPT TO:<b@example.com>"));
+    assert!(mail.contains("Subject: 2 new Genshin codes\r\n"));
+    assert!(mail.contains("multipart/alternative"));
+    assert!(mail.contains("text/plain"));
+    assert!(mail.contains("text/html"));
+    assert!(mail.contains(
+      "GENSHINGIFT: Primogem x60 (All) - https://genshin.hoyoverse.com/en/gift?code=GENSHINGIFT"
+    ));
+    assert!(mail.contains(
+      "<li><a href=\"https://genshin.hoyoverse.com/en/gift?code=5SM6VJVQL4ZC\">\
+        <code>5SM6VJVQL4ZC</code></a> Primogem x60 (All)</li>"
+    ));
+  }
+
+  #[actix_rt::test]
+  async fn skips_events_without_codes() {
+    let (port, mails) = smtp_sink();
+    notifier(port).notify(&codes_event(&[])).await.unwrap();
+    assert!(mails.lock().unwrap().is_empty());
+  }
+
+  #[actix_rt::test]
+  async fn reports_connection_failures() {
+    // Nothing listens on the port once the listener is dropped
+    let port = TcpListener::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap()
+      .port();
+    let result = notifier(port).notify(&codes_event(&["GENSHINGIFT"])).await;
+    assert!(result.is_err());
+  }
+}
@@ -0,0 +1,122 @@
+use super::{ChangeEvent, Notifier, NotifyError};
+use async_std::fs;
+use async_std::prelude::*;
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use chrono::Utc;
+use serde_json::json;
+use std::env;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+  // One JSON record per line, the event with the time it was logged
+  Json,
+  // "<time> <summary>", easier to tail
+  Text,
+}
+
+// Appends every change to a local file, ex: NOTIFY_FILE=data/changes.log. Once the
+// file would grow past NOTIFY_FILE_MAX_BYTES it's moved to `<path>.1`, replacing
+// the previous one, and a new file is started.
+pub struct FileNotifier {
+  path: PathBuf,
+  format: FileFormat,
+  max_bytes: u64,
+  // Held for the whole append, so lines are never interleaved or lost to a rotation
+  lock: Mutex<()>,
+}
+
+fn io_error(err: std::io::Error) -> NotifyError {
+  NotifyError::Failed(err.to_string())
+}
+
+impl FileNotifier {
+  pub fn new(path: PathBuf, format: FileFormat, max_bytes: u64) -> FileNotifier {
+    FileNotifier {
+      path,
+      format,
+      max_bytes,
+      lock: Mutex::new(()),
+    }
+  }
+
+  // NOTIFY_FILE_FORMAT is "json" (default) or "text"
+  pub fn from_env() -> Option<FileNotifier> {
+    let path = env::var("NOTIFY_FILE").ok().filter(|x| !x.is_empty())?;
+    let format = match env::var("NOTIFY_FILE_FORMAT").as_deref() {
+      Ok("text") => FileFormat::Text,
+      _ => FileFormat::Json,
+    };
+    let max_bytes = env::var("NOTIFY_FILE_MAX_BYTES")
+      .ok()
+      .and_then(|x| x.parse().ok())
+      .filter(|&x| x > 0)
+      .unwrap_or(DEFAULT_MAX_BYTES);
+    Some(FileNotifier::new(PathBuf::from(path), format, max_bytes))
+  }
+
+  fn line(&self, event: &ChangeEvent) -> Result<String, NotifyError> {
+    let logged_at = Utc::now();
+    let line = match self.format {
+      FileFormat::Json => {
+        let record = json!({ "logged_at": logged_at, "event": event });
+        serde_json::to_string(&record).map_err(|err| NotifyError::Failed(err.to_string()))?
+      }
+      FileFormat::Text => format!(
+        "{} {}",
+        logged_at.to_rfc3339(),
+        event.summary.lines().collect::<Vec<_>>().join("; ")
+      ),
+    };
+    Ok(line + "\n")
+  }
+
+  fn rotated_path(&self) -> PathBuf {
+    let mut path = self.path.clone().into_os_string();
+    path.push(".1");
+    path.into()
+  }
+
+  async fn rotate_if_needed(&self, incoming: u64) -> Result<(), NotifyError> {
+    let len = match fs::metadata(&self.path).await {
+      Ok(metadata) => metadata.len(),
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+      Err(err) => return Err(io_error(err)),
+    };
+    if len == 0 || len + incoming <= self.max_bytes {
+      return Ok(());
+    }
+    fs::rename(&self.path, self.rotated_path())
+      .await
+      .map_err(io_error)
+  }
+
+  async fn append(&self, line: &str) -> Result<(), NotifyError> {
+    let _guard = self.lock.lock().await;
+    if let Some(dir) = self.path.parent().filter(|x| !x.as_os_str().is_empty()) {
+      fs::create_dir_all(dir).await.map_err(io_error)?;
+    }
+    self.rotate_if_needed(line.len() as u64).await?;
+
+    let mut file = fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)
+      .await
+      .map_err(io_error)?;
+    file.write_all(line.as_bytes()).await.map_err(io_error)?;
+    file.flush().await.map_err(io_error)
+  }
+}
+
+#[async_trait]
+impl Notifier for FileNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let line = self.line(event)?;
+    self.append(&line).await
+  }
+}
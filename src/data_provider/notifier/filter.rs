@@ -0,0 +1,162 @@
+use super::super::wiki::promotional_codes::PromotionalCodes;
+use super::super::wiki::WikiResource;
+use super::{ChangeEvent, Notifier, NotifyError};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::env;
+use std::sync::Arc;
+
+// The promotional codes of some servers, ex: "Europe" and "All" for an EU channel.
+// Codes without a server are kept, other resources go through untouched.
+#[derive(Debug, Clone)]
+pub struct ServerFilter {
+  servers: Vec<String>,
+}
+
+impl ServerFilter {
+  pub fn new(servers: &[String]) -> ServerFilter {
+    ServerFilter {
+      servers: servers.iter().map(|x| x.trim().to_lowercase()).collect(),
+    }
+  }
+
+  // A code lists its servers comma separated, ex: "Europe, Asia"
+  fn matches(&self, code: &Value) -> bool {
+    match code["server"].as_str() {
+      Some(server) => server
+        .split(',')
+        .any(|x| self.servers.contains(&x.trim().to_lowercase())),
+      None => true,
+    }
+  }
+
+  fn filter_codes(&self, codes: Option<&mut Value>) -> usize {
+    match codes.and_then(Value::as_array_mut) {
+      Some(codes) => {
+        let count = codes.len();
+        codes.retain(|x| self.matches(x));
+        count - codes.len()
+      }
+      None => 0,
+    }
+  }
+
+  // None when nothing is left for this channel
+  pub fn apply(&self, event: &ChangeEvent) -> Result<Option<ChangeEvent>, NotifyError> {
+    if self.servers.is_empty() || event.title != PromotionalCodes::get_title() {
+      return Ok(Some(event.to_owned()));
+    }
+
+    let mut event = event.to_owned();
+    let added_filtered = self.filter_codes(event.added.get_mut("codes"));
+    let removed_filtered = self.filter_codes(event.removed.get_mut("codes"));
+    let modified_filtered = self.filter_codes(Some(&mut event.modified));
+    if added_filtered + removed_filtered + modified_filtered == 0 {
+      return Ok(Some(event));
+    }
+
+    if !event.resummarize_codes()? {
+      return Ok(None);
+    }
+    Ok(Some(event))
+  }
+}
+
+// Only lets through the codes a channel cares about, see ServerFilter
+pub struct ServerFilterNotifier {
+  filter: ServerFilter,
+  inner: Arc<dyn Notifier>,
+}
+
+impl ServerFilterNotifier {
+  pub fn new(servers: Vec<String>, inner: Arc<dyn Notifier>) -> ServerFilterNotifier {
+    ServerFilterNotifier {
+      filter: ServerFilter::new(&servers),
+      inner,
+    }
+  }
+
+  // <NAME>_SERVERS is comma separated, ex: DISCORD_SERVERS=Europe,All. The
+  // notifier is returned as is without it.
+  pub fn wrap_from_env(name: &str, inner: Arc<dyn Notifier>) -> Arc<dyn Notifier> {
+    let servers: Vec<String> = env::var(format!("{}_SERVERS", name.to_uppercase()))
+      .unwrap_or_default()
+      .split(',')
+      .map(|x| x.trim().to_owned())
+      .filter(|x| !x.is_empty())
+      .collect();
+    if servers.is_empty() {
+      return inner;
+    }
+    Arc::new(ServerFilterNotifier::new(servers, inner))
+  }
+}
+
+#[async_trait]
+impl Notifier for ServerFilterNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    match self.filter.apply(event)? {
+      Some(event) => self.inner.notify(&event).await,
+      None => Ok(()),
+    }
+  }
+
+  fn delivers(&self) -> bool {
+    self.inner.delivers()
+  }
+
+  async fn retry_outbox(&self) {
+    self.inner.retry_outbox().await;
+  }
+
+  async fn flush(&self) {
+    self.inner.flush().await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, RecordingNotifier, Reply};
+  use super::*;
+
+  // ASIAONLY1 for Asia, GENSHINGIFT for every server
+  fn event() -> ChangeEvent {
+    let mut event = codes_event(&["ASIAONLY1", "GENSHINGIFT"]);
+    event.added["codes"][0]["server"] = "Asia".into();
+    event.enriched()
+  }
+
+  fn codes_of(event: &ChangeEvent) -> Vec<&str> {
+    event.codes.iter().map(|x| x.code.as_str()).collect()
+  }
+
+  #[actix_rt::test]
+  async fn delivers_the_codes_of_each_channel() {
+    let europe = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let everyone = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let servers = vec!["Europe".to_owned(), "All".to_owned()];
+    let filtered = ServerFilterNotifier::new(servers, europe.clone());
+
+    filtered.notify(&event()).await.unwrap();
+    everyone.notify(&event()).await.unwrap();
+
+    let events = europe.events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(codes_of(&events[0]), vec!["GENSHINGIFT"]);
+    assert_eq!(events[0].added_count, 1);
+    assert!(!events[0].summary.contains("ASIAONLY1"));
+    assert_eq!(
+      codes_of(&everyone.events()[0]),
+      vec!["ASIAONLY1", "GENSHINGIFT"]
+    );
+  }
+
+  #[actix_rt::test]
+  async fn drops_an_event_left_empty() {
+    let europe = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let filtered = ServerFilterNotifier::new(vec!["Europe".to_owned()], europe.clone());
+
+    assert!(filtered.notify(&event()).await.is_ok());
+    assert!(europe.events().is_empty());
+  }
+}
@@ -0,0 +1,281 @@
+use super::{escape_html, modified_codes, ChangeEvent, EnrichedCode, Notifier, NotifyError};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const MAX_RETRIES: u32 = 3;
+
+#[derive(Deserialize)]
+struct MatrixError {
+  errcode: Option<String>,
+  error: Option<String>,
+  retry_after_ms: Option<u64>,
+}
+
+// Sends the new promotional codes of every update to MATRIX_ROOM_ID on
+// MATRIX_HOMESERVER as MATRIX_ACCESS_TOKEN's user, other resources are ignored
+pub struct MatrixNotifier {
+  homeserver: reqwest::Url,
+  room_id: String,
+  access_token: String,
+  client: reqwest::Client,
+  // Makes the transaction IDs of messages sent within the same millisecond unique
+  sent: AtomicU64,
+}
+
+impl MatrixNotifier {
+  pub fn new(homeserver: reqwest::Url, room_id: String, access_token: String) -> MatrixNotifier {
+    MatrixNotifier {
+      homeserver,
+      room_id,
+      access_token,
+      client: reqwest::Client::new(),
+      sent: AtomicU64::new(0),
+    }
+  }
+
+  pub fn from_env() -> Option<MatrixNotifier> {
+    let var = |name| env::var(name).ok().filter(|x: &String| !x.is_empty());
+    let homeserver = match reqwest::Url::parse(&var("MATRIX_HOMESERVER")?) {
+      Ok(homeserver) => homeserver,
+      Err(err) => {
+        warn!("Ignoring invalid MATRIX_HOMESERVER: {}", err);
+        return None;
+      }
+    };
+    Some(MatrixNotifier::new(
+      homeserver,
+      var("MATRIX_ROOM_ID")?,
+      var("MATRIX_ACCESS_TOKEN")?,
+    ))
+  }
+
+  fn transaction_id(&self) -> String {
+    let sent = self.sent.fetch_add(1, Ordering::Relaxed);
+    format!("mona_spy.{}.{}", Utc::now().timestamp_millis(), sent)
+  }
+
+  // Room IDs contain "!" and ":", so the path is built from encoded segments
+  fn send_url(&self, transaction_id: &str) -> Result<reqwest::Url, NotifyError> {
+    let mut url = self.homeserver.to_owned();
+    url
+      .path_segments_mut()
+      .map_err(|_| NotifyError::Failed("MATRIX_HOMESERVER can't be a base".to_owned()))?
+      .pop_if_empty()
+      .extend(&["_matrix", "client", "r0", "rooms"])
+      .push(&self.room_id)
+      .extend(&["send", "m.room.message", transaction_id]);
+    Ok(url)
+  }
+
+  // Retries keep the transaction ID, so the homeserver drops a message that did go
+  // through before. M_LIMIT_EXCEEDED is retried after the retry_after_ms it asks for.
+  async fn send(&self, content: &Value) -> Result<(), NotifyError> {
+    let url = self.send_url(&self.transaction_id())?;
+
+    let mut attempts = 0;
+    loop {
+      let res = self
+        .client
+        .put(url.to_owned())
+        .bearer_auth(&self.access_token)
+        .json(content)
+        .send()
+        .await
+        .map_err(|err| NotifyError::Failed(err.to_string()))?;
+      if res.status().is_success() {
+        return Ok(());
+      }
+
+      let status = res.status();
+      let err: MatrixError = res
+        .json()
+        .await
+        .map_err(|err| NotifyError::Failed(err.to_string()))?;
+      match (err.errcode.as_deref(), err.retry_after_ms) {
+        (Some("M_LIMIT_EXCEEDED"), retry_after_ms) if attempts < MAX_RETRIES => {
+          let retry_after_ms = retry_after_ms.unwrap_or(1000);
+          warn!(
+            "Matrix rate limited the notifier, retrying in {}ms",
+            retry_after_ms
+          );
+          actix_rt::time::delay_for(Duration::from_millis(retry_after_ms)).await;
+          attempts += 1;
+        }
+        (errcode, _) => {
+          return Err(NotifyError::Failed(format!(
+            "Matrix error {} {}: {}",
+            status,
+            errcode.unwrap_or_default(),
+            err.error.unwrap_or_default()
+          )))
+        }
+      }
+    }
+  }
+}
+
+// Ex: "GENSHINGIFT: 50 Primogems, expires in 3 days - https://..."
+fn text_line(code: &EnrichedCode) -> String {
+  let mut line = format!("{}: {}", code.code, code.reward_summary);
+  if code.expires_at.is_some() {
+    line += &format!(", {}", code.expires_in);
+  }
+  line + " - " + &code.redeem_url
+}
+
+fn html_row(code: &EnrichedCode) -> String {
+  let expires = match &code.expires {
+    Some(expires) => format!("{} ({})", expires, code.expires_in),
+    None => "Unknown".to_owned(),
+  };
+  format!(
+    "<tr><td><a href=\"{}\"><code>{}</code></a></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+    escape_html(&code.redeem_url),
+    escape_html(&code.code),
+    escape_html(&code.reward_summary),
+    escape_html(code.server.as_deref().unwrap_or("Unknown")),
+    escape_html(&expires)
+  )
+}
+
+#[async_trait]
+impl Notifier for MatrixNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let modified = modified_codes(event);
+    if event.codes.is_empty() && modified.is_empty() {
+      return Ok(());
+    }
+
+    let mut body: Vec<String> = vec![];
+    let mut html: Vec<String> = vec![];
+    let sections = [
+      ("New promotional codes", &event.codes),
+      ("Updated promotional codes", &modified),
+    ];
+    for (heading, codes) in sections.iter().filter(|(_, codes)| !codes.is_empty()) {
+      let text: Vec<String> = codes.iter().map(text_line).collect();
+      let rows: Vec<String> = codes.iter().map(html_row).collect();
+      body.push(format!("{}:\n{}", heading, text.join("\n")));
+      html.push(format!(
+        "<p>{}:</p><table><tr><th>Code</th><th>Reward</th><th>Server</th><th>Expires</th></tr>{}</table>",
+        heading,
+        rows.join("")
+      ));
+    }
+    let content = json!({
+      "msgtype": "m.text",
+      "body": body.join("\n\n"),
+      "format": "org.matrix.custom.html",
+      "formatted_body": html.join(""),
+    });
+    self.send(&content).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, MockServer};
+  use super::*;
+
+  fn notifier(server: &MockServer) -> MatrixNotifier {
+    MatrixNotifier::new(
+      reqwest::Url::parse(&server.url).unwrap(),
+      "!room:example.org".to_owned(),
+      "token".to_owned(),
+    )
+  }
+
+  // The transaction ID is the last segment of the path
+  fn transaction_id(path: &str) -> &str {
+    path.rsplit('/').next().unwrap()
+  }
+
+  #[actix_rt::test]
+  async fn sends_the_message_event() {
+    let server = MockServer::start(vec![(200, r#"{"event_id": "$1"}"#)]);
+    let event = codes_event(&["GENSHINGIFT"]);
+    notifier(&server).notify(&event).await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "PUT");
+    assert!(requests[0].path.starts_with("/_matrix/client/r0/rooms/"));
+    assert!(requests[0].path.contains("/send/m.room.message/mona_spy."));
+    assert_eq!(requests[0].header("authorization"), Some("Bearer token"));
+
+    let code = &event.codes[0];
+    assert_eq!(
+      requests[0].json(),
+      json!({
+        "msgtype": "m.text",
+        "body": format!(
+          "New promotional codes:\nGENSHINGIFT: {} - {}",
+          code.reward_summary, code.redeem_url
+        ),
+        "format": "org.matrix.custom.html",
+        "formatted_body": format!(
+          "<p>New promotional codes:</p><table>\
+            <tr><th>Code</th><th>Reward</th><th>Server</th><th>Expires</th></tr>\
+            <tr><td><a href=\"{}\"><code>GENSHINGIFT</code></a></td><td>{}</td>\
+            <td>All</td><td>Indefinite (no known expiry)</td></tr></table>",
+          code.redeem_url, code.reward_summary
+        ),
+      })
+    );
+  }
+
+  #[actix_rt::test]
+  async fn uses_a_transaction_id_per_message() {
+    let server = MockServer::start(vec![(200, r#"{"event_id": "$1"}"#)]);
+    let notifier = notifier(&server);
+    let event = codes_event(&["GENSHINGIFT"]);
+    notifier.notify(&event).await.unwrap();
+    notifier.notify(&event).await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_ne!(
+      transaction_id(&requests[0].path),
+      transaction_id(&requests[1].path)
+    );
+  }
+
+  #[actix_rt::test]
+  async fn retries_with_the_same_transaction_id_when_rate_limited() {
+    let server = MockServer::start(vec![
+      (
+        429,
+        r#"{"errcode": "M_LIMIT_EXCEEDED", "error": "Too many requests", "retry_after_ms": 10}"#,
+      ),
+      (200, r#"{"event_id": "$1"}"#),
+    ]);
+    notifier(&server)
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await
+      .unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].path, requests[1].path);
+    assert_eq!(requests[0].body, requests[1].body);
+  }
+
+  #[actix_rt::test]
+  async fn reports_other_errors() {
+    let server = MockServer::start(vec![(
+      403,
+      r#"{"errcode": "M_FORBIDDEN", "error": "Not in the room"}"#,
+    )]);
+    let err = notifier(&server)
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await
+      .unwrap_err();
+    assert!(err.to_string().contains("M_FORBIDDEN: Not in the room"));
+  }
+}
@@ -0,0 +1,124 @@
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// A request as the mock server received it, header names are lowercased
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+  pub method: String,
+  pub path: String,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+impl MockRequest {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    let name = name.to_lowercase();
+    self
+      .headers
+      .iter()
+      .find(|(key, _)| *key == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  pub fn text(&self) -> String {
+    String::from_utf8_lossy(&self.body).into_owned()
+  }
+
+  pub fn json(&self) -> Value {
+    serde_json::from_slice(&self.body).expect("the request body isn't JSON")
+  }
+}
+
+// A local HTTP server standing in for the services notified, it answers with the
+// replies (status and body) in order, repeating the last one once they run out
+pub struct MockServer {
+  pub url: String,
+  requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockServer {
+  pub fn start(replies: Vec<(u16, &str)>) -> MockServer {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+    let replies: Vec<(u16, String)> = replies
+      .into_iter()
+      .map(|(status, body)| (status, body.to_owned()))
+      .collect();
+
+    let received = requests.clone();
+    thread::spawn(move || {
+      for (idx, stream) in listener.incoming().enumerate() {
+        let mut stream = match stream {
+          Ok(stream) => stream,
+          Err(_) => continue,
+        };
+        let request = match read_request(&stream) {
+          Some(request) => request,
+          None => continue,
+        };
+        received.lock().unwrap().push(request);
+
+        let (status, body) = &replies[idx.min(replies.len() - 1)];
+        let response = format!(
+          "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+          status,
+          body.len(),
+          body
+        );
+        let _ = stream.write_all(response.as_bytes());
+      }
+    });
+
+    MockServer { url, requests }
+  }
+
+  // Always answers 200 with an empty JSON object
+  pub fn ok() -> MockServer {
+    MockServer::start(vec![(200, "{}")])
+  }
+
+  pub fn requests(&self) -> Vec<MockRequest> {
+    self.requests.lock().unwrap().to_owned()
+  }
+}
+
+// Every request is sent with a Content-Length, each on its own connection
+fn read_request(stream: &TcpStream) -> Option<MockRequest> {
+  let mut reader = BufReader::new(stream);
+  let mut line = String::new();
+  reader.read_line(&mut line).ok()?;
+  let mut parts = line.split_whitespace();
+  let method = parts.next()?.to_owned();
+  let path = parts.next()?.to_owned();
+
+  let mut headers = vec![];
+  loop {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    let (name, value) = line.split_at(line.find(':')?);
+    headers.push((name.to_lowercase(), value[1..].trim().to_owned()));
+  }
+
+  let length = headers
+    .iter()
+    .find(|(name, _)| name == "content-length")
+    .and_then(|(_, value)| value.parse().ok())
+    .unwrap_or(0);
+  let mut body = vec![0; length];
+  reader.read_exact(&mut body).ok()?;
+
+  Some(MockRequest {
+    method,
+    path,
+    headers,
+    body,
+  })
+}
@@ -0,0 +1,490 @@
+mod audit;
+mod broadcast;
+mod coalesce;
+mod digest;
+mod discord;
+#[cfg(feature = "email")]
+mod email;
+mod file;
+mod filter;
+mod matrix;
+#[cfg(test)]
+mod mock_server;
+mod push;
+mod retry;
+mod slack;
+mod telegram;
+mod template;
+mod throttle;
+mod trigger;
+mod webhook;
+
+pub use audit::{AuditRecord, Outcome};
+pub use broadcast::{Broadcast, EventBroadcaster};
+pub use coalesce::CoalescingNotifier;
+pub use digest::DigestNotifier;
+pub use discord::DiscordNotifier;
+#[cfg(feature = "email")]
+pub use email::EmailNotifier;
+pub use file::FileNotifier;
+pub use filter::{ServerFilter, ServerFilterNotifier};
+pub use matrix::MatrixNotifier;
+#[cfg(test)]
+pub use mock_server::{MockRequest, MockServer};
+pub use push::PushNotifier;
+pub use retry::RetryingNotifier;
+pub use slack::SlackNotifier;
+pub use telegram::TelegramNotifier;
+pub use template::MessageTemplate;
+pub use throttle::ThrottledNotifier;
+pub use trigger::TriggerNotifier;
+pub use webhook::{RegisteredWebhookNotifier, WebhookNotifier};
+
+use super::subscription;
+use super::wiki::promotional_codes::{EnrichedCode, PromotionalCode, PromotionalCodes};
+use super::wiki::{Diff, WikiResource};
+use crate::config::Config;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use derive_more::{Display, Error};
+use futures::FutureExt;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::env;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+
+// A change of a resource, the diffs are serialized so any notifier can take any resource
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChangeEvent {
+  pub resource_type: String,
+  pub title: String,
+  // Human readable, see WikiResource::summarize_diff
+  pub summary: String,
+  pub added_count: usize,
+  pub removed_count: usize,
+  // Entries changed in place, ex: a code whose expiry was filled in
+  #[serde(default)]
+  pub modified_count: usize,
+  // The differences as resources, what the subscribers get
+  pub added: Value,
+  pub removed: Value,
+  // The entries changed in place as they are now, see WikiResource::diff
+  #[serde(default)]
+  pub modified: Value,
+  // The same entries as they were, for the summaries
+  #[serde(default)]
+  pub previous: Value,
+  pub fetched_at: DateTime<Utc>,
+  pub revision: Option<u64>,
+  // The added promotional codes ready to be shown, see ChangeEvent::enrich
+  #[serde(default)]
+  pub codes: Vec<EnrichedCode>,
+}
+
+impl ChangeEvent {
+  // The promotional codes of a diff, also the ones within a digest, see DigestNotifier
+  fn diff_codes(&self, diff: &Value, now: DateTime<Utc>) -> Vec<EnrichedCode> {
+    let title = PromotionalCodes::get_title();
+    let diff = match diff.get(title) {
+      _ if self.title == title => diff,
+      Some(diff) => diff,
+      None => return vec![],
+    };
+    serde_json::from_value::<PromotionalCodes>(diff.to_owned())
+      .map(|x| x.enriched_codes(now))
+      .unwrap_or_default()
+  }
+
+  // Fills `codes` right before dispatching, so the expiries are counted from when
+  // the notification is sent. Whatever changes the diff enriches it again.
+  pub fn enrich(&mut self) {
+    self.codes = self.diff_codes(&self.added, Utc::now());
+  }
+
+  pub fn enriched(&self) -> ChangeEvent {
+    let mut event = self.to_owned();
+    event.enrich();
+    event
+  }
+
+  // Recounts and summarizes a promotional codes event whose diffs were edited, ex:
+  // filtered by server. Returns false when nothing is left to notify.
+  fn resummarize_codes(&mut self) -> Result<bool, NotifyError> {
+    let codes = |value: Option<&Value>| match value {
+      None | Some(Value::Null) => Ok(vec![]),
+      Some(value) => serde_json::from_value::<Vec<PromotionalCode>>(value.to_owned())
+        .map_err(|err| NotifyError::Failed(err.to_string())),
+    };
+    // The previous codes are looked up by the modified ones still listed
+    let diff = Diff {
+      added: codes(self.added.get("codes"))?,
+      removed: codes(self.removed.get("codes"))?,
+      modified: codes(Some(&self.modified))?,
+      previous: codes(Some(&self.previous))?,
+    };
+    self.added_count = diff.added.len();
+    self.removed_count = diff.removed.len();
+    self.modified_count = diff.modified.len();
+    self.summary = PromotionalCodes::summarize_entries(&diff);
+    self.enrich();
+    Ok(!diff.is_empty())
+  }
+}
+
+#[derive(Debug, Display, Error)]
+pub enum NotifyError {
+  #[display(fmt = "Failed to notify: {}", _0)]
+  Failed(#[error(not(source))] String),
+  // Failed, but kept in the outbox for later, see RetryingNotifier
+  #[display(fmt = "Failed to notify, kept in the outbox: {}", _0)]
+  Outboxed(#[error(not(source))] String),
+  // Held back to be sent later, ex: over the rate limit, nobody heard of it yet
+  #[display(fmt = "Notification deferred: {}", _0)]
+  Deferred(#[error(not(source))] String),
+}
+
+#[async_trait]
+pub trait Notifier: Send + Sync {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError>;
+
+  // Whether a success means someone outside of the process heard about the change,
+  // the log, the live feed and the ones succeeding without anyone to reach don't
+  fn delivers(&self) -> bool {
+    true
+  }
+
+  // Sends whatever is held back, called on shutdown
+  async fn flush(&self) {}
+
+  // Delivers the events that failed before, called on every outbox tick
+  async fn retry_outbox(&self) {}
+}
+
+pub type Notifiers = Vec<Arc<dyn Notifier>>;
+
+// Codes are matched trimmed and case-insensitively
+fn code_key(code: &Value) -> Option<String> {
+  code["code"].as_str().map(|x| x.trim().to_uppercase())
+}
+
+// The codes that expired or were pulled from the page, enriched like the added ones
+fn removed_codes(event: &ChangeEvent) -> Vec<EnrichedCode> {
+  event.diff_codes(&event.removed, Utc::now())
+}
+
+// The codes still listed with other fields, ex: an expiry filled in
+fn modified_codes(event: &ChangeEvent) -> Vec<EnrichedCode> {
+  let title = PromotionalCodes::get_title();
+  let modified = match event.modified.get(title) {
+    _ if event.title == title => &event.modified,
+    Some(modified) => modified,
+    None => return vec![],
+  };
+  serde_json::from_value::<PromotionalCodes>(json!({ "codes": modified }))
+    .map(|x| x.enriched_codes(Utc::now()))
+    .unwrap_or_default()
+}
+
+fn escape_html(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+// Logs every change, LOG_FORMAT=json logs it as a single JSON line for log pipelines,
+// anything else keeps the human readable line
+pub struct LogNotifier;
+
+#[async_trait]
+impl Notifier for LogNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    if env::var("LOG_FORMAT").as_deref() != Ok("json") {
+      info!(
+        "Resource Updated: type={} {}",
+        event.resource_type, event.summary
+      );
+      return Ok(());
+    }
+
+    let line = serde_json::json!({
+      "event": "resource_updated",
+      "title": event.title,
+      "summary": event.summary,
+      "added_count": event.added_count,
+      "removed_count": event.removed_count,
+      "modified_count": event.modified_count,
+      "added": event.added,
+      "removed": event.removed,
+      "modified": event.modified,
+    });
+    info!("{}", line);
+    Ok(())
+  }
+
+  fn delivers(&self) -> bool {
+    false
+  }
+}
+
+// Pushes the added entries to every subscribed channel, removals aren't pushed
+pub struct SubscriptionNotifier;
+
+#[async_trait]
+impl Notifier for SubscriptionNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    if event.added_count == 0 {
+      return Ok(());
+    }
+    subscription::notify(&event.added, &event.resource_type)
+      .await
+      .map_err(|err| NotifyError::Failed(err.to_string()))
+  }
+
+  // Succeeds without any subscriber, the announcement is left to the real channels
+  fn delivers(&self) -> bool {
+    false
+  }
+}
+
+// DISCORD_WEBHOOK_URL adds the Discord notifier, SLACK_WEBHOOK_URL the Slack one,
+// TELEGRAM_BOT_TOKEN and TELEGRAM_CHAT_ID the Telegram one, the webhook URLs of the
+// config the JSON webhook one, MATRIX_ROOM_ID the Matrix one, NTFY_TOPIC_URL and
+// PUSHOVER_TOKEN the push ones and SMTP_HOST the email one (feature "email")
+pub fn default_notifiers(config: &Config) -> Notifiers {
+  // The ones reaching out to other services are retried, see RetryingNotifier, can
+  // be limited to some servers and kinds of change, see ServerFilterNotifier and
+  // TriggerNotifier, rate limited, see ThrottledNotifier, and sent as digests, see
+  // DigestNotifier
+  let retrying = |name, notifier: Arc<dyn Notifier>| -> Arc<dyn Notifier> {
+    let retrying = Arc::new(RetryingNotifier::new(
+      name,
+      config.notify_max_attempts,
+      notifier,
+    ));
+    let per_minute = config.max_per_minute.get(name).copied();
+    let throttled = ThrottledNotifier::wrap(name, per_minute, retrying);
+    let digest = DigestNotifier::wrap_from_env(name, throttled);
+    ServerFilterNotifier::wrap_from_env(name, TriggerNotifier::wrap_from_env(name, digest))
+  };
+  let mut notifiers: Notifiers = vec![Arc::new(LogNotifier), Arc::new(SubscriptionNotifier)];
+  if let Some(file) = FileNotifier::from_env() {
+    notifiers.push(Arc::new(file));
+  }
+  if let Some(discord) = DiscordNotifier::from_env() {
+    notifiers.push(retrying("discord", Arc::new(discord)));
+  }
+  if let Some(slack) = SlackNotifier::from_env() {
+    notifiers.push(retrying("slack", Arc::new(slack)));
+  }
+  if let Some(telegram) = TelegramNotifier::from_env() {
+    notifiers.push(retrying("telegram", Arc::new(telegram)));
+  }
+  if let Some(webhook) = WebhookNotifier::from_config(config) {
+    notifiers.push(retrying("webhook", Arc::new(webhook)));
+  }
+  // The ones added through /subscriptions, none until then
+  notifiers.push(retrying(
+    "registered_webhooks",
+    Arc::new(RegisteredWebhookNotifier::new(config.webhook_timeout)),
+  ));
+  if let Some(matrix) = MatrixNotifier::from_env() {
+    notifiers.push(retrying("matrix", Arc::new(matrix)));
+  }
+  if let Some(ntfy) = PushNotifier::ntfy_from_env() {
+    notifiers.push(retrying("ntfy", Arc::new(ntfy)));
+  }
+  if let Some(pushover) = PushNotifier::pushover_from_env() {
+    notifiers.push(retrying("pushover", Arc::new(pushover)));
+  }
+  #[cfg(feature = "email")]
+  if let Some(email) = EmailNotifier::from_env() {
+    notifiers.push(retrying("email", Arc::new(email)));
+  }
+
+  // The changes of a resource within the coalesce window are merged
+  match config.notify_coalesce {
+    Some(window) => vec![Arc::new(CoalescingNotifier::new(notifiers, window))],
+    None => notifiers,
+  }
+}
+
+const OUTBOX_INTERVAL: u64 = 60;
+
+pub async fn retry_loop(notifiers: Notifiers) {
+  loop {
+    actix_rt::time::delay_for(Duration::from_secs(OUTBOX_INTERVAL)).await;
+    for notifier in &notifiers {
+      notifier.retry_outbox().await;
+    }
+  }
+}
+
+pub async fn audit_log(notifier: Option<&str>, outcome: Option<Outcome>) -> Vec<AuditRecord> {
+  audit::records(notifier, outcome).await
+}
+
+pub async fn flush_all(notifiers: &[Arc<dyn Notifier>]) {
+  for notifier in notifiers {
+    notifier.flush().await;
+  }
+}
+
+// Every notifier runs even when an earlier one failed or panicked, returns how many
+// of the delivering ones succeeded, the deferred ones aren't. A panic is only
+// logged, the resource is persisted by then and its lock still has to be released.
+pub async fn notify_all(notifiers: &[Arc<dyn Notifier>], event: &ChangeEvent) -> usize {
+  let event = &event.enriched();
+  let mut succeeded = 0;
+  for notifier in notifiers {
+    match AssertUnwindSafe(notifier.notify(event))
+      .catch_unwind()
+      .await
+    {
+      Ok(Ok(())) if notifier.delivers() => succeeded += 1,
+      Ok(Ok(())) => {}
+      Ok(Err(err @ NotifyError::Deferred(_))) => info!("{}: {}", event.title, err),
+      Ok(Err(err)) => error!("{}: {}", event.title, err),
+      Err(_) => error!("{}: a notifier panicked", event.title),
+    }
+  }
+  succeeded
+}
+
+// Stand-ins for the real notifiers in tests
+#[cfg(test)]
+pub struct NoopNotifier;
+
+#[cfg(test)]
+#[async_trait]
+impl Notifier for NoopNotifier {
+  async fn notify(&self, _event: &ChangeEvent) -> Result<(), NotifyError> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+#[derive(Clone, Copy)]
+pub enum Reply {
+  Succeed,
+  Fail,
+  Panic,
+}
+
+// Keeps every event it's given, then replies as told
+#[cfg(test)]
+pub struct RecordingNotifier {
+  pub events: std::sync::Mutex<Vec<ChangeEvent>>,
+  reply: Reply,
+}
+
+#[cfg(test)]
+impl RecordingNotifier {
+  pub fn new(reply: Reply) -> RecordingNotifier {
+    RecordingNotifier {
+      events: std::sync::Mutex::new(vec![]),
+      reply,
+    }
+  }
+
+  pub fn events(&self) -> Vec<ChangeEvent> {
+    self.events.lock().unwrap().to_owned()
+  }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Notifier for RecordingNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    self.events.lock().unwrap().push(event.to_owned());
+    match self.reply {
+      Reply::Succeed => Ok(()),
+      Reply::Fail => Err(NotifyError::Failed("told to fail".to_owned())),
+      Reply::Panic => panic!("told to panic"),
+    }
+  }
+}
+
+#[cfg(test)]
+pub fn test_event(title: &str) -> ChangeEvent {
+  ChangeEvent {
+    resource_type: "test".to_owned(),
+    title: title.to_owned(),
+    summary: "1 added".to_owned(),
+    added_count: 1,
+    removed_count: 0,
+    modified_count: 0,
+    added: json!({}),
+    removed: json!({}),
+    modified: json!([]),
+    previous: json!([]),
+    fetched_at: Utc::now(),
+    revision: None,
+    codes: vec![],
+  }
+}
+
+// A Promotional_Codes event adding codes, each one for every server and without an
+// expiry, enriched as when dispatched
+#[cfg(test)]
+pub fn codes_event(codes: &[&str]) -> ChangeEvent {
+  let added: Vec<Value> = codes
+    .iter()
+    .map(|code| {
+      json!({
+        "code": code,
+        "server": "All",
+        "reward": "Primogem ×60",
+        "discovered": "June 30, 2021",
+        "expires": "Indefinite",
+      })
+    })
+    .collect();
+  ChangeEvent {
+    resource_type: std::any::type_name::<PromotionalCodes>().to_owned(),
+    title: PromotionalCodes::get_title().to_owned(),
+    summary: format!("{} added", codes.len()),
+    added_count: codes.len(),
+    added: json!({ "codes": added }),
+    removed: json!({ "codes": [] }),
+    ..test_event("")
+  }
+  .enriched()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[actix_rt::test]
+  async fn notify_all_runs_every_notifier() {
+    let failing = Arc::new(RecordingNotifier::new(Reply::Fail));
+    let panicking = Arc::new(RecordingNotifier::new(Reply::Panic));
+    let succeeding = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifiers: Notifiers = vec![failing.clone(), panicking.clone(), succeeding.clone()];
+
+    assert_eq!(notify_all(&notifiers, &test_event("Test")).await, 1);
+    assert_eq!(failing.events().len(), 1);
+    assert_eq!(panicking.events().len(), 1);
+    assert_eq!(succeeding.events().len(), 1);
+  }
+
+  #[actix_rt::test]
+  async fn notify_all_counts_nothing_when_every_notifier_fails() {
+    let notifiers: Notifiers = vec![
+      Arc::new(RecordingNotifier::new(Reply::Fail)),
+      Arc::new(RecordingNotifier::new(Reply::Panic)),
+    ];
+    assert_eq!(notify_all(&notifiers, &test_event("Test")).await, 0);
+  }
+
+  #[actix_rt::test]
+  async fn noop_notifier_succeeds() {
+    let notifiers: Notifiers = vec![Arc::new(NoopNotifier)];
+    assert_eq!(notify_all(&notifiers, &test_event("Test")).await, 1);
+  }
+}
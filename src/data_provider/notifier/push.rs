@@ -0,0 +1,236 @@
+use super::{modified_codes, ChangeEvent, MessageTemplate, Notifier, NotifyError};
+use async_trait::async_trait;
+use std::env;
+
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+
+pub enum PushTransport {
+  // PUT to the topic URL, ex: https://ntfy.sh/mona_spy
+  Ntfy {
+    topic_url: String,
+    priority: Option<String>,
+    token: Option<String>,
+  },
+  // The messages endpoint, PUSHOVER_URL unless testing
+  Pushover {
+    api_url: String,
+    token: String,
+    user: String,
+  },
+}
+
+// Pushes the new promotional codes of every update to a phone, other resources
+// are ignored. The push is rendered through the MessageTemplate, by default
+// tapping it opens the redemption page when a single code was added, the codes
+// endpoint under PUBLIC_URL otherwise.
+pub struct PushNotifier {
+  transport: PushTransport,
+  template: MessageTemplate,
+  client: reqwest::Client,
+}
+
+fn var(name: &str) -> Option<String> {
+  env::var(name).ok().filter(|x| !x.is_empty())
+}
+
+impl PushNotifier {
+  pub fn new(transport: PushTransport, template: MessageTemplate) -> PushNotifier {
+    PushNotifier {
+      transport,
+      template,
+      client: reqwest::Client::new(),
+    }
+  }
+
+  // NTFY_TOPIC_URL, with the optional NTFY_PRIORITY and NTFY_TOKEN
+  pub fn ntfy_from_env() -> Option<PushNotifier> {
+    let transport = PushTransport::Ntfy {
+      topic_url: var("NTFY_TOPIC_URL")?,
+      priority: var("NTFY_PRIORITY"),
+      token: var("NTFY_TOKEN"),
+    };
+    Some(PushNotifier::new(transport, MessageTemplate::from_env()))
+  }
+
+  // PUSHOVER_TOKEN is the application token, PUSHOVER_USER the user or group key
+  pub fn pushover_from_env() -> Option<PushNotifier> {
+    let transport = PushTransport::Pushover {
+      api_url: PUSHOVER_URL.to_owned(),
+      token: var("PUSHOVER_TOKEN")?,
+      user: var("PUSHOVER_USER")?,
+    };
+    Some(PushNotifier::new(transport, MessageTemplate::from_env()))
+  }
+
+  async fn push(&self, title: &str, message: &str, url: Option<&str>) -> Result<(), NotifyError> {
+    let req = match &self.transport {
+      PushTransport::Ntfy {
+        topic_url,
+        priority,
+        token,
+      } => {
+        let mut req = self
+          .client
+          .put(topic_url)
+          .header("Title", title)
+          .body(message.to_owned());
+        if let Some(priority) = priority {
+          req = req.header("Priority", priority);
+        }
+        if let Some(url) = url {
+          req = req.header("Click", url);
+        }
+        if let Some(token) = token {
+          req = req.bearer_auth(token);
+        }
+        req
+      }
+      PushTransport::Pushover {
+        api_url,
+        token,
+        user,
+      } => {
+        let mut form = vec![
+          ("token", token.as_str()),
+          ("user", user.as_str()),
+          ("title", title),
+          ("message", message),
+        ];
+        if let Some(url) = url {
+          form.push(("url", url));
+        }
+        self.client.post(api_url).form(&form)
+      }
+    };
+
+    req
+      .send()
+      .await
+      .and_then(|res| res.error_for_status())
+      .map(|_| ())
+      .map_err(|err| NotifyError::Failed(err.to_string()))
+  }
+}
+
+#[async_trait]
+impl Notifier for PushNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    if event.codes.is_empty() && modified_codes(event).is_empty() {
+      return Ok(());
+    }
+
+    let message = self.template.render(event)?;
+    self
+      .push(&message.title, &message.body, message.url.as_deref())
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, MockServer};
+  use super::*;
+
+  fn ntfy(server: &MockServer) -> PushNotifier {
+    let transport = PushTransport::Ntfy {
+      topic_url: server.url.to_owned() + "/mona_spy",
+      priority: Some("high".to_owned()),
+      token: Some("token".to_owned()),
+    };
+    PushNotifier::new(transport, MessageTemplate::default())
+  }
+
+  fn pushover(server: &MockServer) -> PushNotifier {
+    let transport = PushTransport::Pushover {
+      api_url: server.url.to_owned() + "/1/messages.json",
+      token: "app".to_owned(),
+      user: "user".to_owned(),
+    };
+    PushNotifier::new(transport, MessageTemplate::default())
+  }
+
+  fn form(body: &[u8]) -> Vec<(String, String)> {
+    let url = format!("http://localhost/?{}", String::from_utf8_lossy(body));
+    reqwest::Url::parse(&url)
+      .unwrap()
+      .query_pairs()
+      .map(|(name, value)| (name.into_owned(), value.into_owned()))
+      .collect()
+  }
+
+  #[actix_rt::test]
+  async fn puts_to_the_ntfy_topic() {
+    let server = MockServer::ok();
+    let event = codes_event(&["GENSHINGIFT"]);
+    ntfy(&server).notify(&event).await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    let request = &requests[0];
+    assert_eq!(
+      (request.method.as_str(), request.path.as_str()),
+      ("PUT", "/mona_spy")
+    );
+    assert_eq!(request.header("title"), Some("1 new Genshin code"));
+    assert_eq!(request.header("priority"), Some("high"));
+    assert_eq!(request.header("authorization"), Some("Bearer token"));
+    assert_eq!(
+      request.header("click"),
+      Some("https://genshin.hoyoverse.com/en/gift?code=GENSHINGIFT")
+    );
+    assert_eq!(request.text(), event.summary);
+  }
+
+  // Without PUBLIC_URL there's nothing to open for several codes
+  #[actix_rt::test]
+  async fn leaves_the_click_out_for_several_codes() {
+    let server = MockServer::ok();
+    ntfy(&server)
+      .notify(&codes_event(&["GENSHINGIFT", "5SM6VJVQL4ZC"]))
+      .await
+      .unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests[0].header("title"), Some("2 new Genshin codes"));
+    assert_eq!(requests[0].header("click"), None);
+  }
+
+  #[actix_rt::test]
+  async fn posts_the_pushover_form() {
+    let server = MockServer::start(vec![(200, r#"{"status": 1}"#)]);
+    let event = codes_event(&["GENSHINGIFT"]);
+    pushover(&server).notify(&event).await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, "POST");
+    assert_eq!(requests[0].path, "/1/messages.json");
+    assert_eq!(
+      requests[0].header("content-type"),
+      Some("application/x-www-form-urlencoded")
+    );
+    let pair = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+    assert_eq!(
+      form(&requests[0].body),
+      vec![
+        pair("token", "app"),
+        pair("user", "user"),
+        pair("title", "1 new Genshin code"),
+        pair("message", &event.summary),
+        pair(
+          "url",
+          "https://genshin.hoyoverse.com/en/gift?code=GENSHINGIFT"
+        ),
+      ]
+    );
+  }
+
+  #[actix_rt::test]
+  async fn fails_on_error_responses() {
+    let server = MockServer::start(vec![(400, r#"{"status": 0}"#)]);
+    let result = pushover(&server)
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await;
+    assert!(result.is_err());
+  }
+}
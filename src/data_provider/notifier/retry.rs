@@ -0,0 +1,342 @@
+use super::super::persist;
+use super::{audit, ChangeEvent, Notifier, NotifyError};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const BASE_DELAY_MS: u64 = 500;
+const BASE_DELAY_SECS: i64 = 60;
+// Retries of an entry of the outbox before it's given up on
+const MAX_OUTBOX_RETRIES: u32 = 8;
+const OUTBOX_KEY: &str = "notifier_outbox";
+
+// Every read-modify-write of the outbox, a failing notify can append while
+// retry_outbox is delivering
+static OUTBOX: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// An event a notifier still owes, retried by the outbox loop
+#[derive(Serialize, Deserialize, Clone)]
+struct OutboxEntry {
+  // Entries saved before the ids get one on the next load
+  #[serde(default)]
+  id: String,
+  notifier: String,
+  event: ChangeEvent,
+  attempts: u32,
+  #[serde(default)]
+  retry_at: i64,
+}
+
+impl OutboxEntry {
+  fn new(notifier: &str, event: &ChangeEvent, attempts: u32) -> OutboxEntry {
+    let mut entry = OutboxEntry {
+      id: new_id(),
+      notifier: notifier.to_owned(),
+      event: event.to_owned(),
+      attempts,
+      retry_at: 0,
+    };
+    entry.schedule(0);
+    entry
+  }
+
+  // Exponential backoff with jitter, doubling from a minute up to an hour
+  fn schedule(&mut self, retries: u32) {
+    let delay = BASE_DELAY_SECS << retries.min(6);
+    let jitter = i64::from(Utc::now().timestamp_subsec_millis()) % delay;
+    self.retry_at = Utc::now().timestamp() + delay + jitter;
+  }
+}
+
+fn new_id() -> String {
+  format!(
+    "{}-{}",
+    Utc::now().timestamp_millis(),
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+  )
+}
+
+async fn outbox() -> Vec<OutboxEntry> {
+  persist::get_by_key(OUTBOX_KEY).await.unwrap_or_default()
+}
+
+async fn save_outbox(outbox: &[OutboxEntry]) {
+  if let Err(err) = persist::set_by_key(OUTBOX_KEY, &outbox).await {
+    error!("Failed to save the notifier outbox: {:?}", err);
+  }
+}
+
+// Tries a flaky notifier up to max_attempts times with exponential backoff and
+// jitter, see NOTIFY_MAX_ATTEMPTS. An event that still fails is put in the outbox,
+// since the diff that produced it won't come back, and the outbox loop retries it
+// with a longer backoff until it's given up on. Every attempt goes to the audit
+// log, see GET /admin/notifications.
+pub struct RetryingNotifier {
+  name: &'static str,
+  inner: Arc<dyn Notifier>,
+  max_attempts: u32,
+  delay: Duration,
+}
+
+impl RetryingNotifier {
+  pub fn new(name: &'static str, max_attempts: u32, inner: Arc<dyn Notifier>) -> RetryingNotifier {
+    RetryingNotifier {
+      name,
+      inner,
+      max_attempts: max_attempts.max(1),
+      delay: Duration::from_millis(BASE_DELAY_MS),
+    }
+  }
+
+  async fn deliver(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let mut attempt = 1;
+    loop {
+      let result = self.inner.notify(event).await;
+      audit::record(self.name, event, &result, attempt).await;
+      let err = match result {
+        Ok(()) => return Ok(()),
+        Err(err) if attempt >= self.max_attempts => return Err(err),
+        Err(err) => err,
+      };
+
+      let delay = self.delay * (1 << (attempt - 1).min(6));
+      let jitter = delay.mul_f64(f64::from(Utc::now().timestamp_subsec_millis()) / 1000.0);
+      warn!(
+        "{} failed ({}), retrying in {:?}",
+        self.name,
+        err,
+        delay + jitter
+      );
+      actix_rt::time::delay_for(delay + jitter).await;
+      attempt += 1;
+    }
+  }
+
+  // The attempts of an entry made by the outbox loop
+  fn retries(&self, entry: &OutboxEntry) -> u32 {
+    entry.attempts.saturating_sub(self.max_attempts)
+  }
+}
+
+#[async_trait]
+impl Notifier for RetryingNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let err = match self.deliver(event).await {
+      Ok(()) => return Ok(()),
+      Err(err) => err,
+    };
+
+    let entry = OutboxEntry::new(self.name, event, self.max_attempts);
+    let _guard = OUTBOX.lock().await;
+    let mut entries = outbox().await;
+    entries.push(entry);
+    save_outbox(&entries).await;
+    Err(NotifyError::Outboxed(format!("{}: {}", self.name, err)))
+  }
+
+  fn delivers(&self) -> bool {
+    self.inner.delivers()
+  }
+
+  // The entries that are due get one attempt each, the ones failing their last
+  // retry are dropped. The lock isn't held while delivering, the results are
+  // applied by id to a fresh load afterwards.
+  async fn retry_outbox(&self) {
+    let now = Utc::now().timestamp();
+    let due: Vec<OutboxEntry> = {
+      let _guard = OUTBOX.lock().await;
+      let mut entries = outbox().await;
+      if entries.iter().any(|x| x.id.is_empty()) {
+        for entry in entries.iter_mut().filter(|x| x.id.is_empty()) {
+          entry.id = new_id();
+        }
+        save_outbox(&entries).await;
+      }
+      entries
+        .into_iter()
+        .filter(|x| x.notifier == self.name && x.retry_at <= now)
+        .collect()
+    };
+    if due.is_empty() {
+      return;
+    }
+
+    let mut delivered: Vec<String> = vec![];
+    let mut attempted: Vec<String> = vec![];
+    let mut given_up: Vec<String> = vec![];
+    for entry in &due {
+      // Enriched again, the expiries are counted from this attempt
+      let event = entry.event.enriched();
+      let attempt = entry.attempts + 1;
+      let result = self.inner.notify(&event).await;
+      audit::record(self.name, &event, &result, attempt).await;
+      match result {
+        Ok(()) => {
+          info!(
+            "{} delivered {} from the outbox after {} attempts",
+            self.name, entry.event.title, attempt
+          );
+          delivered.push(entry.id.clone());
+        }
+        Err(err) if self.retries(entry) + 1 >= MAX_OUTBOX_RETRIES => {
+          error!(
+            "{} gave up on {} after {} attempts: {}",
+            self.name, entry.event.title, attempt, err
+          );
+          given_up.push(entry.id.clone());
+        }
+        Err(err) => {
+          warn!(
+            "{} still fails for {}: {}",
+            self.name, entry.event.title, err
+          );
+          attempted.push(entry.id.clone());
+        }
+      }
+    }
+
+    let _guard = OUTBOX.lock().await;
+    let mut entries = outbox().await;
+    entries.retain(|x| !delivered.contains(&x.id) && !given_up.contains(&x.id));
+    for entry in entries.iter_mut().filter(|x| attempted.contains(&x.id)) {
+      entry.attempts += 1;
+      let retries = self.retries(entry);
+      entry.schedule(retries);
+    }
+    save_outbox(&entries).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::test_event;
+  use super::*;
+  use std::sync::atomic::AtomicUsize;
+
+  // Fails its first `failures` calls, then succeeds
+  struct FlakyNotifier {
+    failures: usize,
+    calls: AtomicUsize,
+  }
+
+  impl FlakyNotifier {
+    fn new(failures: usize) -> Arc<FlakyNotifier> {
+      Arc::new(FlakyNotifier {
+        failures,
+        calls: AtomicUsize::new(0),
+      })
+    }
+
+    fn calls(&self) -> usize {
+      self.calls.load(Ordering::SeqCst)
+    }
+  }
+
+  #[async_trait]
+  impl Notifier for FlakyNotifier {
+    async fn notify(&self, _event: &ChangeEvent) -> Result<(), NotifyError> {
+      if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+        Err(NotifyError::Failed("unavailable".to_owned()))
+      } else {
+        Ok(())
+      }
+    }
+  }
+
+  async fn entries_of(name: &str) -> Vec<OutboxEntry> {
+    let _guard = OUTBOX.lock().await;
+    outbox()
+      .await
+      .into_iter()
+      .filter(|x| x.notifier == name)
+      .collect()
+  }
+
+  // Skips the backoff of the entries of a notifier
+  async fn make_due(name: &str) {
+    let _guard = OUTBOX.lock().await;
+    let mut entries = outbox().await;
+    for entry in entries.iter_mut().filter(|x| x.notifier == name) {
+      entry.retry_at = 0;
+    }
+    save_outbox(&entries).await;
+  }
+
+  // Retries in-line without waiting out the backoff
+  fn retrying(name: &'static str, inner: Arc<FlakyNotifier>) -> RetryingNotifier {
+    RetryingNotifier {
+      delay: Duration::from_millis(1),
+      ..RetryingNotifier::new(name, 3, inner)
+    }
+  }
+
+  #[actix_rt::test]
+  async fn succeeds_after_two_failures() {
+    let name = "test/retry/two_failures";
+    let inner = FlakyNotifier::new(2);
+    let notifier = retrying(name, inner.clone());
+
+    notifier.notify(&test_event("Test")).await.unwrap();
+    assert_eq!(inner.calls(), 3);
+    assert!(entries_of(name).await.is_empty());
+  }
+
+  #[actix_rt::test]
+  async fn delivers_from_the_outbox_later() {
+    let name = "test/retry/outbox";
+    let inner = FlakyNotifier::new(3);
+    let notifier = retrying(name, inner.clone());
+
+    let before = Utc::now().timestamp();
+    assert!(notifier.notify(&test_event("Test")).await.is_err());
+    assert_eq!(inner.calls(), 3);
+    let entries = entries_of(name).await;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].event.title, "Test");
+    assert_eq!(entries[0].attempts, 3);
+    assert!(entries[0].retry_at >= before + BASE_DELAY_SECS);
+
+    // Not due yet, the next tick leaves it alone
+    notifier.retry_outbox().await;
+    assert_eq!(inner.calls(), 3);
+    assert_eq!(entries_of(name).await.len(), 1);
+
+    make_due(name).await;
+    notifier.retry_outbox().await;
+    assert_eq!(inner.calls(), 4);
+    assert!(entries_of(name).await.is_empty());
+  }
+
+  #[actix_rt::test]
+  async fn drops_an_entry_after_its_last_retry() {
+    let name = "test/retry/dropped";
+    let inner = FlakyNotifier::new(usize::MAX);
+    let notifier = retrying(name, inner.clone());
+
+    assert!(notifier.notify(&test_event("Test")).await.is_err());
+    for retry in 1..MAX_OUTBOX_RETRIES {
+      make_due(name).await;
+      notifier.retry_outbox().await;
+      let entries = entries_of(name).await;
+      assert_eq!(entries.len(), 1);
+      assert_eq!(entries[0].attempts, 3 + retry);
+    }
+
+    make_due(name).await;
+    notifier.retry_outbox().await;
+    assert_eq!(inner.calls(), 3 + MAX_OUTBOX_RETRIES as usize);
+    assert!(entries_of(name).await.is_empty());
+
+    // Nothing is left to retry
+    make_due(name).await;
+    notifier.retry_outbox().await;
+    assert_eq!(inner.calls(), 3 + MAX_OUTBOX_RETRIES as usize);
+  }
+}
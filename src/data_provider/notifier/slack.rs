@@ -0,0 +1,244 @@
+use super::{modified_codes, removed_codes, ChangeEvent, EnrichedCode, Notifier, NotifyError};
+use async_trait::async_trait;
+use log::warn;
+use serde_json::{json, Value};
+use std::env;
+use std::time::Duration;
+
+// Slack rejects messages with more blocks than this, one goes to the header
+const MAX_BLOCKS: usize = 50;
+const MAX_RETRIES: u32 = 3;
+
+// Posts every new promotional code to SLACK_WEBHOOK_URL, an incoming webhook, with
+// Block Kit. `text` is the fallback Slack shows in the notification previews.
+pub struct SlackNotifier {
+  webhook_url: String,
+  client: reqwest::Client,
+}
+
+impl SlackNotifier {
+  pub fn new(webhook_url: String) -> SlackNotifier {
+    SlackNotifier {
+      webhook_url,
+      client: reqwest::Client::new(),
+    }
+  }
+
+  pub fn from_env() -> Option<SlackNotifier> {
+    let webhook_url = env::var("SLACK_WEBHOOK_URL").ok()?;
+    Some(SlackNotifier::new(webhook_url)).filter(|x| !x.webhook_url.is_empty())
+  }
+
+  // 429s are retried after the Retry-After (seconds) Slack asks for
+  async fn post(&self, body: &Value) -> Result<(), NotifyError> {
+    let mut attempts = 0;
+    loop {
+      let res = self
+        .client
+        .post(&self.webhook_url)
+        .json(body)
+        .send()
+        .await
+        .map_err(|err| NotifyError::Failed(err.to_string()))?;
+
+      if res.status() != reqwest::StatusCode::TOO_MANY_REQUESTS || attempts >= MAX_RETRIES {
+        return res
+          .error_for_status()
+          .map(|_| ())
+          .map_err(|err| NotifyError::Failed(err.to_string()));
+      }
+
+      let retry_after = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(1);
+      warn!(
+        "Slack rate limited the webhook, retrying in {}s",
+        retry_after
+      );
+      actix_rt::time::delay_for(Duration::from_secs(retry_after)).await;
+      attempts += 1;
+    }
+  }
+}
+
+fn section(code: &EnrichedCode) -> Value {
+  let mut text = format!("`{}`\n{}", code.code, code.reward_summary);
+  if let Some(server) = &code.server {
+    text += &format!("\nServers: {}", server);
+  }
+  if let Some(expires) = &code.expires {
+    text += &format!("\nExpires: {} ({})", expires, code.expires_in);
+  }
+  json!({
+    "type": "section",
+    "text": { "type": "mrkdwn", "text": text },
+    "accessory": {
+      "type": "button",
+      "text": { "type": "plain_text", "text": "Redeem" },
+      "url": code.redeem_url,
+    },
+  })
+}
+
+// The expired or removed codes, in one section after the added ones
+fn removed_section(codes: &[EnrichedCode]) -> Value {
+  let codes: Vec<String> = codes.iter().map(|x| format!("`{}`", x.code)).collect();
+  json!({
+    "type": "section",
+    "text": {
+      "type": "mrkdwn",
+      "text": format!("*Expired/removed:* {}", codes.join(", ")),
+    },
+  })
+}
+
+// A header, then a section per code. The text is the notification fallback, ex:
+// "New promotional codes".
+fn payload(header: &str, text: &str, codes: &[EnrichedCode]) -> Value {
+  let mut blocks = vec![json!({
+    "type": "header",
+    "text": { "type": "plain_text", "text": header },
+  })];
+  blocks.extend(codes.iter().map(section));
+  let codes: Vec<&str> = codes.iter().map(|x| x.code.as_str()).collect();
+  json!({
+    "text": format!("{}: {}", text, codes.join(", ")),
+    "blocks": blocks,
+  })
+}
+
+#[async_trait]
+impl Notifier for SlackNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let header = event.title.replace('_', " ");
+    for codes in event.codes.chunks(MAX_BLOCKS - 1) {
+      self
+        .post(&payload(&header, "New promotional codes", codes))
+        .await?;
+    }
+
+    let modified = modified_codes(event);
+    for codes in modified.chunks(MAX_BLOCKS - 1) {
+      self
+        .post(&payload(
+          &format!("{} (updated)", header),
+          "Updated promotional codes",
+          codes,
+        ))
+        .await?;
+    }
+
+    let removed = removed_codes(event);
+    if !removed.is_empty() {
+      let codes: Vec<&str> = removed.iter().map(|x| x.code.as_str()).collect();
+      self
+        .post(&json!({
+          "text": format!("Expired/removed promotional codes: {}", codes.join(", ")),
+          "blocks": [removed_section(&removed)],
+        }))
+        .await?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, MockServer};
+  use super::*;
+
+  #[test]
+  fn serializes_the_blocks() {
+    let event = codes_event(&["GENSHINGIFT"]);
+    assert_eq!(
+      payload("Promotional Codes", "New promotional codes", &event.codes),
+      json!({
+        "text": "New promotional codes: GENSHINGIFT",
+        "blocks": [
+          {
+            "type": "header",
+            "text": { "type": "plain_text", "text": "Promotional Codes" },
+          },
+          {
+            "type": "section",
+            "text": {
+              "type": "mrkdwn",
+              "text": format!(
+                "`GENSHINGIFT`\n{}\nServers: All\nExpires: Indefinite (no known expiry)",
+                event.codes[0].reward_summary
+              ),
+            },
+            "accessory": {
+              "type": "button",
+              "text": { "type": "plain_text", "text": "Redeem" },
+              "url": "https://genshin.hoyoverse.com/en/gift?code=GENSHINGIFT",
+            },
+          },
+        ],
+      })
+    );
+  }
+
+  #[actix_rt::test]
+  async fn posts_the_added_then_the_removed_codes() {
+    let server = MockServer::ok();
+    let mut event = codes_event(&["GENSHINGIFT"]);
+    event.removed = json!({ "codes": [{ "code": "DS6ACT4S8VEV", "server": "All" }] });
+    event.removed_count = 1;
+    SlackNotifier::new(server.url.to_owned())
+      .notify(&event)
+      .await
+      .unwrap();
+
+    let bodies: Vec<Value> = server.requests().iter().map(|x| x.json()).collect();
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(
+      bodies[0]["blocks"][0]["text"]["text"],
+      json!("Promotional Codes")
+    );
+    assert_eq!(
+      bodies[0]["blocks"][1]["accessory"]["url"],
+      json!("https://genshin.hoyoverse.com/en/gift?code=GENSHINGIFT")
+    );
+    assert_eq!(
+      bodies[1],
+      json!({
+        "text": "Expired/removed promotional codes: DS6ACT4S8VEV",
+        "blocks": [{
+          "type": "section",
+          "text": { "type": "mrkdwn", "text": "*Expired/removed:* `DS6ACT4S8VEV`" },
+        }],
+      })
+    );
+  }
+
+  #[actix_rt::test]
+  async fn splits_more_than_fifty_blocks() {
+    let server = MockServer::ok();
+    let codes: Vec<String> = (0..50).map(|x| format!("CODE{:04}", x)).collect();
+    let codes: Vec<&str> = codes.iter().map(String::as_str).collect();
+    SlackNotifier::new(server.url.to_owned())
+      .notify(&codes_event(&codes))
+      .await
+      .unwrap();
+
+    let blocks: Vec<usize> = server
+      .requests()
+      .iter()
+      .map(|x| x.json()["blocks"].as_array().unwrap().len())
+      .collect();
+    assert_eq!(blocks, vec![MAX_BLOCKS, 2]);
+  }
+
+  #[actix_rt::test]
+  async fn fails_on_error_responses() {
+    let server = MockServer::start(vec![(404, "no_service")]);
+    let result = SlackNotifier::new(server.url.to_owned())
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await;
+    assert!(result.is_err());
+  }
+}
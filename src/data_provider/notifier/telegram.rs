@@ -0,0 +1,292 @@
+use super::{modified_codes, removed_codes, ChangeEvent, EnrichedCode, Notifier, NotifyError};
+use async_trait::async_trait;
+use log::warn;
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+use std::time::Duration;
+
+// Telegram rejects longer messages
+const MAX_MESSAGE_LENGTH: usize = 4096;
+const MAX_RETRIES: u32 = 3;
+const API_URL: &str = "https://api.telegram.org";
+const SPECIAL_CHARACTERS: &str = "_*[]()~`>#+-=|{}.!\\";
+
+#[derive(Deserialize)]
+struct ResponseParameters {
+  retry_after: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BotResponse {
+  ok: bool,
+  error_code: Option<u16>,
+  description: Option<String>,
+  parameters: Option<ResponseParameters>,
+}
+
+// Sends the new promotional codes of every update to TELEGRAM_CHAT_ID through the
+// Bot API, other resources are ignored
+pub struct TelegramNotifier {
+  api_url: String,
+  token: String,
+  chat_id: String,
+  client: reqwest::Client,
+}
+
+impl TelegramNotifier {
+  pub fn new(token: String, chat_id: String) -> TelegramNotifier {
+    TelegramNotifier {
+      api_url: API_URL.to_owned(),
+      token,
+      chat_id,
+      client: reqwest::Client::new(),
+    }
+  }
+
+  pub fn from_env() -> Option<TelegramNotifier> {
+    let token = env::var("TELEGRAM_BOT_TOKEN")
+      .ok()
+      .filter(|x| !x.is_empty())?;
+    let chat_id = env::var("TELEGRAM_CHAT_ID")
+      .ok()
+      .filter(|x| !x.is_empty())?;
+    Some(TelegramNotifier::new(token, chat_id))
+  }
+
+  // 429s are retried after the retry_after (seconds) the Bot API asks for
+  async fn send_message(&self, text: &str) -> Result<(), NotifyError> {
+    let url = format!("{}/bot{}/sendMessage", self.api_url, self.token);
+    let body = json!({
+      "chat_id": self.chat_id,
+      "text": text,
+      "parse_mode": "MarkdownV2",
+      "disable_web_page_preview": true,
+    });
+
+    let mut attempts = 0;
+    loop {
+      let res: BotResponse = self
+        .client
+        .post(&url)
+        .json(&body)
+        .send()
+        .await
+        .map_err(|err| NotifyError::Failed(err.to_string()))?
+        .json()
+        .await
+        .map_err(|err| NotifyError::Failed(err.to_string()))?;
+
+      if res.ok {
+        return Ok(());
+      }
+
+      let retry_after = res.parameters.and_then(|x| x.retry_after);
+      match (res.error_code, retry_after) {
+        (Some(429), Some(retry_after)) if attempts < MAX_RETRIES => {
+          warn!(
+            "Telegram rate limited the bot, retrying in {}s",
+            retry_after
+          );
+          actix_rt::time::delay_for(Duration::from_secs(retry_after)).await;
+          attempts += 1;
+        }
+        (error_code, _) => {
+          return Err(NotifyError::Failed(format!(
+            "Telegram error {}: {}",
+            error_code.unwrap_or_default(),
+            res.description.unwrap_or_default()
+          )))
+        }
+      }
+    }
+  }
+}
+
+fn escape(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    if SPECIAL_CHARACTERS.contains(c) {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+// Inside inline code only ` and \ have to be escaped
+fn escape_code(text: &str) -> String {
+  text.replace('\\', "\\\\").replace('`', "\\`")
+}
+
+// Inside a link URL only ) and \ have to be escaped
+fn escape_url(url: &str) -> String {
+  url.replace('\\', "\\\\").replace(')', "\\)")
+}
+
+fn code_line(code: &EnrichedCode) -> String {
+  let mut line = format!(
+    "[`{}`]({}) {}",
+    escape_code(&code.code),
+    escape_url(&code.redeem_url),
+    escape(&code.reward_summary)
+  );
+  if let Some(server) = &code.server {
+    line += &format!(" \\({}\\)", escape(server));
+  }
+  if code.expires_at.is_some() {
+    line += &format!(", {}", escape(&code.expires_in));
+  }
+  line
+}
+
+// Splits between lines so no message goes over MAX_MESSAGE_LENGTH, a single line
+// longer than that is sent on its own and left for Telegram to reject
+fn chunk(lines: Vec<String>) -> Vec<String> {
+  let mut messages: Vec<String> = vec![];
+  let mut message = String::new();
+
+  for line in lines {
+    let length = message.chars().count() + line.chars().count() + 1;
+    if !message.is_empty() && length > MAX_MESSAGE_LENGTH {
+      messages.push(std::mem::take(&mut message));
+    }
+    if !message.is_empty() {
+      message.push('\n');
+    }
+    message.push_str(&line);
+  }
+  if !message.is_empty() {
+    messages.push(message);
+  }
+  messages
+}
+
+#[async_trait]
+impl Notifier for TelegramNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let codes: Vec<String> = event.codes.iter().map(code_line).collect();
+    let modified: Vec<String> = modified_codes(event).iter().map(code_line).collect();
+    let removed: Vec<String> = removed_codes(event)
+      .iter()
+      .map(|x| format!("`{}`", escape_code(&x.code)))
+      .collect();
+
+    let mut lines: Vec<String> = vec![];
+    if !codes.is_empty() {
+      lines.push("*New promotional codes*".to_owned());
+      lines.extend(codes);
+    }
+    if !modified.is_empty() {
+      lines.push("*Updated promotional codes*".to_owned());
+      lines.extend(modified);
+    }
+    if !removed.is_empty() {
+      lines.push("*Expired/removed promotional codes*".to_owned());
+      lines.extend(removed);
+    }
+    for message in chunk(lines) {
+      self.send_message(&message).await?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, MockServer};
+  use super::*;
+
+  const OK: &str = r#"{"ok": true, "result": {}}"#;
+
+  fn notifier(server: &MockServer) -> TelegramNotifier {
+    TelegramNotifier {
+      api_url: server.url.to_owned(),
+      ..TelegramNotifier::new("TOKEN".to_owned(), "-100123".to_owned())
+    }
+  }
+
+  #[actix_rt::test]
+  async fn escapes_the_markdown_of_rewards() {
+    let server = MockServer::start(vec![(200, OK)]);
+    let mut event = codes_event(&["GENSHINGIFT"]);
+    event.codes[0].reward_summary = "Hero's Wit ×3 - Mora ×10.000".to_owned();
+    notifier(&server).notify(&event).await.unwrap();
+
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "/botTOKEN/sendMessage");
+    assert_eq!(
+      requests[0].json(),
+      json!({
+        "chat_id": "-100123",
+        "text": "*New promotional codes*\n\
+          [`GENSHINGIFT`](https://genshin.hoyoverse.com/en/gift?code=GENSHINGIFT) \
+          Hero's Wit ×3 \\- Mora ×10\\.000 \\(All\\)",
+        "parse_mode": "MarkdownV2",
+        "disable_web_page_preview": true,
+      })
+    );
+  }
+
+  #[actix_rt::test]
+  async fn chunks_long_messages() {
+    let server = MockServer::start(vec![(200, OK)]);
+    let codes: Vec<String> = (0..100).map(|x| format!("CODE{:08}", x)).collect();
+    let codes: Vec<&str> = codes.iter().map(String::as_str).collect();
+    notifier(&server)
+      .notify(&codes_event(&codes))
+      .await
+      .unwrap();
+
+    let texts: Vec<String> = server
+      .requests()
+      .iter()
+      .map(|x| x.json()["text"].as_str().unwrap().to_owned())
+      .collect();
+    assert!(texts.len() > 1);
+    assert!(texts
+      .iter()
+      .all(|x| x.chars().count() <= MAX_MESSAGE_LENGTH));
+    // Split between lines, every code is sent once
+    for code in &codes {
+      let sent = texts
+        .iter()
+        .map(|x| x.matches(&format!("[`{}`]", code)).count())
+        .sum::<usize>();
+      assert_eq!(sent, 1, "{}", code);
+    }
+  }
+
+  #[actix_rt::test]
+  async fn retries_when_rate_limited() {
+    let server = MockServer::start(vec![
+      (
+        429,
+        r#"{"ok": false, "error_code": 429, "description": "Too Many Requests", "parameters": {"retry_after": 0}}"#,
+      ),
+      (200, OK),
+    ]);
+    notifier(&server)
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await
+      .unwrap();
+    assert_eq!(server.requests().len(), 2);
+  }
+
+  #[actix_rt::test]
+  async fn reports_api_errors() {
+    let server = MockServer::start(vec![(
+      400,
+      r#"{"ok": false, "error_code": 400, "description": "Bad Request: chat not found"}"#,
+    )]);
+    let err = notifier(&server)
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await
+      .unwrap_err();
+    assert!(err
+      .to_string()
+      .contains("Telegram error 400: Bad Request: chat not found"));
+    assert_eq!(server.requests().len(), 1);
+  }
+}
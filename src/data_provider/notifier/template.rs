@@ -0,0 +1,212 @@
+use super::{modified_codes, removed_codes, ChangeEvent, EnrichedCode, NotifyError};
+use log::warn;
+use serde::Serialize;
+use std::env;
+use tinytemplate::TinyTemplate;
+
+const DEFAULT_TITLE: &str = "{{ if has_codes }}{{ if single_code }}1 new Genshin code{{ else }}\
+  {code_count} new Genshin codes{{ endif }}{{ else }}{resource} updated{{ endif }}";
+const DEFAULT_BODY: &str = "{summary}";
+const DEFAULT_URL: &str = "{url}";
+
+// What the templates can refer to, ex: "{code_count} new codes"
+#[derive(Serialize)]
+struct TemplateContext<'a> {
+  resource: &'a str,
+  summary: &'a str,
+  added_count: usize,
+  removed_count: usize,
+  modified_count: usize,
+  // See EnrichedCode for the fields of a code, ex: {reward_summary} or {expires_in}
+  codes: &'a [EnrichedCode],
+  removed_codes: Vec<EnrichedCode>,
+  modified_codes: Vec<EnrichedCode>,
+  code_count: usize,
+  has_codes: bool,
+  single_code: bool,
+  // The redemption link of the only added code, the codes endpoint otherwise
+  url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedMessage {
+  pub title: String,
+  pub body: String,
+  pub url: Option<String>,
+}
+
+// Renders a change into the title, body and link the simpler notifiers send. Each
+// part is a tinytemplate replaced through NOTIFY_TEMPLATE_TITLE, NOTIFY_TEMPLATE_BODY
+// and NOTIFY_TEMPLATE_URL, the built-in one is used for the others.
+#[derive(Debug, Clone)]
+pub struct MessageTemplate {
+  title: String,
+  body: String,
+  url: String,
+  codes_url: Option<String>,
+}
+
+impl Default for MessageTemplate {
+  fn default() -> Self {
+    MessageTemplate {
+      title: DEFAULT_TITLE.to_owned(),
+      body: DEFAULT_BODY.to_owned(),
+      url: DEFAULT_URL.to_owned(),
+      codes_url: None,
+    }
+  }
+}
+
+impl MessageTemplate {
+  // PUBLIC_URL is where this service is reachable, for the link to the codes endpoint
+  pub fn from_env() -> MessageTemplate {
+    let var = |name, default: &str| {
+      env::var(name)
+        .ok()
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| default.to_owned())
+    };
+    MessageTemplate {
+      title: var("NOTIFY_TEMPLATE_TITLE", DEFAULT_TITLE),
+      body: var("NOTIFY_TEMPLATE_BODY", DEFAULT_BODY),
+      url: var("NOTIFY_TEMPLATE_URL", DEFAULT_URL),
+      codes_url: env::var("PUBLIC_URL")
+        .ok()
+        .filter(|x| !x.is_empty())
+        .map(|x| x.trim_end_matches('/').to_owned() + "/codes?active=true"),
+    }
+  }
+
+  fn render_parts(&self, context: &TemplateContext) -> Result<RenderedMessage, String> {
+    let mut templates = TinyTemplate::new();
+    templates.set_default_formatter(&tinytemplate::format_unescaped);
+    let parts = [
+      ("title", self.title.as_str()),
+      ("body", self.body.as_str()),
+      ("url", self.url.as_str()),
+    ];
+    for &(name, text) in &parts {
+      templates
+        .add_template(name, text)
+        .map_err(|err| err.to_string())?;
+    }
+
+    let render = |name| {
+      templates
+        .render(name, context)
+        .map(|x| x.trim().to_owned())
+        .map_err(|err| err.to_string())
+    };
+    Ok(RenderedMessage {
+      title: render("title")?,
+      body: render("body")?,
+      url: Some(render("url")?).filter(|x| !x.is_empty()),
+    })
+  }
+
+  // A custom template that fails to render falls back to the built-in one
+  pub fn render(&self, event: &ChangeEvent) -> Result<RenderedMessage, NotifyError> {
+    let added = &event.codes;
+    let single_code = match added.as_slice() {
+      [code] => Some(code.redeem_url.to_owned()),
+      _ => None,
+    };
+
+    let context = TemplateContext {
+      resource: &event.title,
+      summary: &event.summary,
+      added_count: event.added_count,
+      removed_count: event.removed_count,
+      modified_count: event.modified_count,
+      codes: added,
+      removed_codes: removed_codes(event),
+      modified_codes: modified_codes(event),
+      code_count: added.len(),
+      has_codes: !added.is_empty(),
+      single_code: added.len() == 1,
+      url: single_code
+        .or_else(|| self.codes_url.to_owned())
+        .unwrap_or_default(),
+    };
+
+    self.render_parts(&context).or_else(|err| {
+      warn!(
+        "Notification template failed, using the default one: {}",
+        err
+      );
+      let default = MessageTemplate {
+        codes_url: self.codes_url.to_owned(),
+        ..MessageTemplate::default()
+      };
+      default.render_parts(&context).map_err(NotifyError::Failed)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::codes_event;
+  use super::*;
+  use serde_json::json;
+
+  // GENSHINGIFT and 5SM6VJVQL4ZC added, OLDCODE12345 removed
+  fn event() -> ChangeEvent {
+    let mut event = codes_event(&["GENSHINGIFT", "5SM6VJVQL4ZC"]);
+    event.summary = "2 added, 1 removed".to_owned();
+    event.removed_count = 1;
+    event.removed = json!({ "codes": [{ "code": "OLDCODE12345", "server": "All" }] });
+    event
+  }
+
+  fn custom(title: &str, body: &str, url: &str) -> MessageTemplate {
+    MessageTemplate {
+      title: title.to_owned(),
+      body: body.to_owned(),
+      url: url.to_owned(),
+      codes_url: Some("https://mona.test/codes?active=true".to_owned()),
+    }
+  }
+
+  #[test]
+  fn renders_the_default_template() {
+    assert_eq!(
+      MessageTemplate::default().render(&event()).unwrap(),
+      RenderedMessage {
+        title: "2 new Genshin codes".to_owned(),
+        body: "2 added, 1 removed".to_owned(),
+        url: None,
+      }
+    );
+  }
+
+  #[test]
+  fn renders_a_custom_template() {
+    let template = custom(
+      "{added_count} added, {removed_count} removed",
+      "{{ for code in codes }}{code.normalized} {{ endfor }}\
+       | {{ for code in removed_codes }}{code.code}{{ endfor }}",
+      "{url}",
+    );
+    assert_eq!(
+      template.render(&event()).unwrap(),
+      RenderedMessage {
+        title: "2 added, 1 removed".to_owned(),
+        body: "GENSHINGIFT 5SM6VJVQL4ZC | OLDCODE12345".to_owned(),
+        url: Some("https://mona.test/codes?active=true".to_owned()),
+      }
+    );
+  }
+
+  #[test]
+  fn falls_back_to_the_default_template() {
+    let template = custom("{{ if has_codes }}never closed", "{unknown_field}", "{url}");
+    assert_eq!(
+      template.render(&event()).unwrap(),
+      RenderedMessage {
+        title: "2 new Genshin codes".to_owned(),
+        body: "2 added, 1 removed".to_owned(),
+        url: Some("https://mona.test/codes?active=true".to_owned()),
+      }
+    );
+  }
+}
@@ -0,0 +1,226 @@
+use super::digest::combine;
+use super::{ChangeEvent, Notifier, NotifyError};
+use async_trait::async_trait;
+use log::{error, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+  // Held back once the bucket ran dry, sent as one message when it refills
+  suppressed: Vec<ChangeEvent>,
+}
+
+// Lets through at most <NAME>_MAX_PER_MINUTE events a minute, with bursts of as
+// many, so a vandalism revert doesn't get the channel banned. The events beyond
+// the limit are deferred, then merged into a single "N further changes
+// suppressed" message sent on the outbox tick once there's a token again.
+pub struct ThrottledNotifier {
+  name: String,
+  per_minute: f64,
+  bucket: Mutex<Bucket>,
+  inner: Arc<dyn Notifier>,
+}
+
+impl ThrottledNotifier {
+  pub fn new(name: &str, per_minute: u32, inner: Arc<dyn Notifier>) -> ThrottledNotifier {
+    ThrottledNotifier {
+      name: name.to_owned(),
+      per_minute: f64::from(per_minute),
+      bucket: Mutex::new(Bucket {
+        tokens: f64::from(per_minute),
+        updated_at: Instant::now(),
+        suppressed: vec![],
+      }),
+      inner,
+    }
+  }
+
+  // The notifier is returned as is without a limit
+  pub fn wrap(name: &str, per_minute: Option<u32>, inner: Arc<dyn Notifier>) -> Arc<dyn Notifier> {
+    match per_minute {
+      Some(per_minute) => Arc::new(ThrottledNotifier::new(name, per_minute, inner)),
+      None => inner,
+    }
+  }
+
+  fn take_token(&self, bucket: &mut Bucket) -> bool {
+    let now = Instant::now();
+    let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * self.per_minute / 60.0).min(self.per_minute);
+    bucket.updated_at = now;
+    if bucket.tokens < 1.0 {
+      return false;
+    }
+    bucket.tokens -= 1.0;
+    true
+  }
+
+  // The suppressed events, empty while there's none or no token to send them
+  fn take_suppressed(&self, force: bool) -> Vec<ChangeEvent> {
+    let mut bucket = self.bucket.lock().unwrap();
+    if bucket.suppressed.is_empty() || !(self.take_token(&mut bucket) || force) {
+      return vec![];
+    }
+    std::mem::take(&mut bucket.suppressed)
+  }
+
+  // The suppressed events as one
+  fn summarize(suppressed: &[ChangeEvent]) -> Result<Option<ChangeEvent>, NotifyError> {
+    let count = suppressed.len();
+    Ok(combine(suppressed.to_vec())?.map(|mut event| {
+      event.summary = format!(
+        "{} further change{} suppressed\n{}",
+        count,
+        if count == 1 { "" } else { "s" },
+        event.summary
+      );
+      event.enriched()
+    }))
+  }
+
+  // Suppressed events that couldn't be sent are held again, ahead of the ones
+  // suppressed since, unless the inner notifier kept them in its outbox
+  async fn send_suppressed(&self, force: bool) {
+    let suppressed = self.take_suppressed(force);
+    if suppressed.is_empty() {
+      return;
+    }
+    let event = match ThrottledNotifier::summarize(&suppressed) {
+      Ok(Some(event)) => event,
+      Ok(None) => return,
+      Err(err) => {
+        error!(
+          "{} can't combine the suppressed changes: {}",
+          self.name, err
+        );
+        return;
+      }
+    };
+    match self.inner.notify(&event).await {
+      Ok(()) => {}
+      Err(err @ NotifyError::Outboxed(_)) => warn!(
+        "{} failed to send the suppressed changes: {}",
+        self.name, err
+      ),
+      Err(err) => {
+        error!(
+          "{} failed to send the suppressed changes, holding them: {}",
+          self.name, err
+        );
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.suppressed.splice(0..0, suppressed);
+      }
+    }
+  }
+}
+
+#[async_trait]
+impl Notifier for ThrottledNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    {
+      let mut bucket = self.bucket.lock().unwrap();
+      if !self.take_token(&mut bucket) {
+        warn!(
+          "{} is over its rate limit, holding {}",
+          self.name, event.title
+        );
+        bucket.suppressed.push(event.to_owned());
+        return Err(NotifyError::Deferred(format!(
+          "{} is over its rate limit",
+          self.name
+        )));
+      }
+    }
+    self.inner.notify(event).await
+  }
+
+  fn delivers(&self) -> bool {
+    self.inner.delivers()
+  }
+
+  async fn retry_outbox(&self) {
+    self.send_suppressed(false).await;
+    self.inner.retry_outbox().await;
+  }
+
+  // Nothing is kept across restarts, so what's held is sent regardless of the limit
+  async fn flush(&self) {
+    self.send_suppressed(true).await;
+    self.inner.flush().await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, notify_all, Notifiers, RecordingNotifier, Reply};
+  use super::*;
+
+  #[actix_rt::test]
+  async fn suppresses_the_events_over_the_limit_into_one() {
+    let inner = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifier = ThrottledNotifier::new("test", 3, inner.clone());
+    let codes: Vec<String> = (0..10).map(|x| format!("THROTTLED{}", x)).collect();
+    for (idx, code) in codes.iter().enumerate() {
+      let result = notifier.notify(&codes_event(&[code.as_str()])).await;
+      match idx {
+        0..=2 => assert!(result.is_ok()),
+        _ => assert!(matches!(result, Err(NotifyError::Deferred(_)))),
+      }
+    }
+    assert_eq!(inner.events().len(), 3);
+
+    // No token left for the summary yet, it's sent on shutdown regardless
+    notifier.retry_outbox().await;
+    assert_eq!(inner.events().len(), 3);
+    notifier.flush().await;
+
+    let events = inner.events();
+    assert_eq!(events.len(), 4);
+    let delivered: Vec<&str> = events[..3]
+      .iter()
+      .map(|x| x.codes[0].code.as_str())
+      .collect();
+    assert_eq!(delivered, vec!["THROTTLED0", "THROTTLED1", "THROTTLED2"]);
+    let summary = &events[3];
+    assert!(summary
+      .summary
+      .starts_with("7 further changes suppressed\n"));
+    assert_eq!(summary.added_count, 7);
+    let suppressed: Vec<&str> = summary.codes.iter().map(|x| x.code.as_str()).collect();
+    assert_eq!(
+      suppressed,
+      codes[3..].iter().map(String::as_str).collect::<Vec<_>>()
+    );
+  }
+
+  // Nobody heard of a deferred event, so it isn't counted as announced
+  #[actix_rt::test]
+  async fn counts_nothing_for_the_events_over_the_limit() {
+    let inner = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifiers: Notifiers = vec![Arc::new(ThrottledNotifier::new("test", 1, inner))];
+    assert_eq!(notify_all(&notifiers, &codes_event(&["FIRST"])).await, 1);
+    assert_eq!(notify_all(&notifiers, &codes_event(&["SECOND"])).await, 0);
+  }
+
+  #[actix_rt::test]
+  async fn holds_the_suppressed_events_it_failed_to_send() {
+    let failing = Arc::new(RecordingNotifier::new(Reply::Fail));
+    let notifier = ThrottledNotifier::new("test", 1, failing.clone());
+    assert!(notifier.notify(&codes_event(&["FIRST"])).await.is_err());
+    assert!(notifier.notify(&codes_event(&["SECOND"])).await.is_err());
+
+    notifier.flush().await;
+    assert_eq!(failing.events().len(), 2);
+    let held: Vec<String> = notifier
+      .bucket
+      .lock()
+      .unwrap()
+      .suppressed
+      .iter()
+      .map(|x| x.codes[0].code.to_owned())
+      .collect();
+    assert_eq!(held, vec!["SECOND"]);
+  }
+}
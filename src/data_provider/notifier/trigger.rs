@@ -0,0 +1,130 @@
+use super::super::wiki::promotional_codes::PromotionalCodes;
+use super::super::wiki::WikiResource;
+use super::{ChangeEvent, Notifier, NotifyError};
+use async_trait::async_trait;
+use log::warn;
+use serde_json::Value;
+use std::env;
+use std::sync::Arc;
+
+// Which kinds of change a channel is notified about, <NAME>_NOTIFY_ON is a comma
+// separated subset of "added", "removed" and "modified", ex:
+// DISCORD_NOTIFY_ON=added,removed. Only additions by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Triggers {
+  pub added: bool,
+  pub removed: bool,
+  pub modified: bool,
+}
+
+impl Default for Triggers {
+  fn default() -> Self {
+    Triggers {
+      added: true,
+      removed: false,
+      modified: false,
+    }
+  }
+}
+
+impl Triggers {
+  pub fn from_env(name: &str) -> Triggers {
+    let variable = format!("{}_NOTIFY_ON", name.to_uppercase());
+    let value = match env::var(&variable) {
+      Ok(value) if !value.trim().is_empty() => value,
+      _ => return Triggers::default(),
+    };
+
+    let mut triggers = Triggers {
+      added: false,
+      removed: false,
+      modified: false,
+    };
+    for kind in value.split(',').map(|x| x.trim().to_lowercase()) {
+      match kind.as_str() {
+        "added" => triggers.added = true,
+        "removed" => triggers.removed = true,
+        "modified" => triggers.modified = true,
+        _ => warn!("Ignoring {} in {}", kind, variable),
+      }
+    }
+    triggers
+  }
+
+  fn all(&self) -> bool {
+    self.added && self.removed && self.modified
+  }
+
+  // None when nothing the channel cares about is left, see ChangeEvent::modified
+  // for the modifications
+  fn apply(&self, event: &ChangeEvent) -> Result<Option<ChangeEvent>, NotifyError> {
+    if self.all() {
+      return Ok(Some(event.to_owned()));
+    }
+    // The entries of the other resources are replaced whole, ex: a day of the
+    // schedule, so a changed one also goes out with the additions
+    if event.title != PromotionalCodes::get_title() {
+      let wanted = (self.added && event.added_count > 0)
+        || (self.removed && event.removed_count > 0)
+        || ((self.added || self.modified) && event.modified_count > 0);
+      return Ok(Some(event.to_owned()).filter(|_| wanted));
+    }
+
+    let mut event = event.to_owned();
+    let clear = |diff: &mut Value, field: &str| {
+      if let Some(entries) = diff.get_mut(field).and_then(Value::as_array_mut) {
+        entries.clear();
+      }
+    };
+    if !self.added {
+      clear(&mut event.added, "codes");
+    }
+    if !self.modified {
+      event.modified = Value::Array(vec![]);
+    }
+    if !self.removed {
+      clear(&mut event.removed, "codes");
+    }
+    if !event.resummarize_codes()? {
+      return Ok(None);
+    }
+    Ok(Some(event))
+  }
+}
+
+pub struct TriggerNotifier {
+  triggers: Triggers,
+  inner: Arc<dyn Notifier>,
+}
+
+impl TriggerNotifier {
+  pub fn new(triggers: Triggers, inner: Arc<dyn Notifier>) -> TriggerNotifier {
+    TriggerNotifier { triggers, inner }
+  }
+
+  pub fn wrap_from_env(name: &str, inner: Arc<dyn Notifier>) -> Arc<dyn Notifier> {
+    Arc::new(TriggerNotifier::new(Triggers::from_env(name), inner))
+  }
+}
+
+#[async_trait]
+impl Notifier for TriggerNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    match self.triggers.apply(event)? {
+      Some(event) => self.inner.notify(&event).await,
+      None => Ok(()),
+    }
+  }
+
+  fn delivers(&self) -> bool {
+    self.inner.delivers()
+  }
+
+  async fn retry_outbox(&self) {
+    self.inner.retry_outbox().await;
+  }
+
+  async fn flush(&self) {
+    self.inner.flush().await;
+  }
+}
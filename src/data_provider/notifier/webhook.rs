@@ -0,0 +1,474 @@
+use super::super::subscription;
+use super::template::RenderedMessage;
+use super::{ChangeEvent, EnrichedCode, MessageTemplate, Notifier, NotifyError, ServerFilter};
+use crate::config::Config;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::time::Duration;
+const SIGNATURE_HEADER: &str = "X-MonaSpy-Signature";
+const TIMESTAMP_HEADER: &str = "X-MonaSpy-Timestamp";
+const HMAC_BLOCK_SIZE: usize = 64;
+
+// The body every webhook receives, fields are only ever added to it
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+  resource: &'a str,
+  added: &'a Value,
+  removed: &'a Value,
+  modified: &'a Value,
+  fetched_at: DateTime<Utc>,
+  revid: Option<u64>,
+  codes: &'a [EnrichedCode],
+  // Rendered through the MessageTemplate, for receivers that only show text
+  title: &'a str,
+  text: &'a str,
+}
+
+impl<'a> WebhookPayload<'a> {
+  fn new(event: &'a ChangeEvent, message: &'a RenderedMessage) -> Self {
+    WebhookPayload {
+      resource: &event.title,
+      added: &event.added,
+      removed: &event.removed,
+      modified: &event.modified,
+      fetched_at: event.fetched_at,
+      revid: event.revision,
+      codes: &event.codes,
+      title: &message.title,
+      text: &message.body,
+    }
+  }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+  let mut block = [0u8; HMAC_BLOCK_SIZE];
+  if key.len() > HMAC_BLOCK_SIZE {
+    let digest = Sha256::digest(key);
+    block[..digest.len()].copy_from_slice(&digest);
+  } else {
+    block[..key.len()].copy_from_slice(key);
+  }
+
+  let pad = |byte: u8| block.iter().map(move |x| x ^ byte);
+  let inner = Sha256::new()
+    .chain(pad(0x36).collect::<Vec<u8>>())
+    .chain(message)
+    .finalize();
+  Sha256::new()
+    .chain(pad(0x5c).collect::<Vec<u8>>())
+    .chain(inner)
+    .finalize()
+    .to_vec()
+}
+
+// With a secret the request carries X-MonaSpy-Timestamp, the unix time it was sent
+// at, and X-MonaSpy-Signature, "sha256=<hex>" of the HMAC-SHA256 of
+// "<timestamp>.<body>". Receivers verify it by computing the same HMAC over the raw
+// body they got, comparing in constant time, and rejecting old timestamps so a
+// captured request can't be replayed.
+fn signed(
+  request: reqwest::RequestBuilder,
+  secret: Option<&str>,
+  body: Vec<u8>,
+) -> reqwest::RequestBuilder {
+  let request = request.header(CONTENT_TYPE, "application/json");
+  let secret = match secret {
+    Some(secret) => secret,
+    None => return request.body(body),
+  };
+
+  let timestamp = Utc::now().timestamp().to_string();
+  let mut signed_data = format!("{}.", timestamp).into_bytes();
+  signed_data.extend_from_slice(&body);
+  let signature: String = hmac_sha256(secret.as_bytes(), &signed_data)
+    .iter()
+    .map(|x| format!("{:02x}", x))
+    .collect();
+  request
+    .header(TIMESTAMP_HEADER, timestamp)
+    .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+    .body(body)
+}
+
+// POSTs every change as JSON to each of the configured URLs, with the extra headers,
+// signed when there's a secret, see `signed`
+pub struct WebhookNotifier {
+  urls: Vec<String>,
+  headers: Vec<(String, String)>,
+  secret: Option<String>,
+  template: MessageTemplate,
+  client: reqwest::Client,
+}
+
+impl WebhookNotifier {
+  pub fn new(
+    urls: Vec<String>,
+    headers: Vec<(String, String)>,
+    secret: Option<String>,
+    timeout: Duration,
+  ) -> WebhookNotifier {
+    WebhookNotifier {
+      urls,
+      headers,
+      secret,
+      template: MessageTemplate::from_env(),
+      client: reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .unwrap_or_default(),
+    }
+  }
+
+  // The URLs and the timeout come from the config, WEBHOOK_HEADERS holds
+  // "Name: value" pairs separated by semicolons and WEBHOOK_SECRET signs the payloads
+  pub fn from_config(config: &Config) -> Option<WebhookNotifier> {
+    let urls: Vec<String> = config.webhook_urls.iter().map(|x| x.to_string()).collect();
+    if urls.is_empty() {
+      return None;
+    }
+
+    let headers = env::var("WEBHOOK_HEADERS")
+      .unwrap_or_default()
+      .split(';')
+      .filter_map(|x| {
+        let (name, value) = x.split_at(x.find(':')?);
+        Some((name.trim().to_owned(), value[1..].trim().to_owned()))
+      })
+      .collect();
+    let secret = env::var("WEBHOOK_SECRET").ok().filter(|x| !x.is_empty());
+    Some(WebhookNotifier::new(
+      urls,
+      headers,
+      secret,
+      config.webhook_timeout,
+    ))
+  }
+
+  // The body is serialized once, so the signature covers exactly what is sent
+  async fn post(&self, url: &str, body: Vec<u8>) -> Result<(), String> {
+    let mut request = self.client.post(url);
+    for (name, value) in &self.headers {
+      request = request.header(name.as_str(), value.as_str());
+    }
+
+    signed(request, self.secret.as_deref(), body)
+      .send()
+      .await
+      .and_then(|x| x.error_for_status())
+      .map(|_| ())
+      .map_err(|err| format!("{}: {}", url, err))
+  }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+  // Every URL is tried, the failures are reported together
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let message = self.template.render(event)?;
+    let body = serde_json::to_vec(&WebhookPayload::new(event, &message))
+      .map_err(|err| NotifyError::Failed(err.to_string()))?;
+
+    let mut errors: Vec<String> = vec![];
+    for url in &self.urls {
+      if let Err(err) = self.post(url, body.to_owned()).await {
+        errors.push(err);
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(NotifyError::Failed(errors.join(", ")));
+    }
+    Ok(())
+  }
+
+  // Succeeds without any registered webhook, the announcement is left to the real
+  // channels
+  fn delivers(&self) -> bool {
+    false
+  }
+}
+
+// Delivers to the webhooks added through the API, read on every change so they're
+// picked up without a restart. The ones with a secret are signed, see `signed`.
+pub struct RegisteredWebhookNotifier {
+  template: MessageTemplate,
+  client: reqwest::Client,
+}
+
+impl RegisteredWebhookNotifier {
+  pub fn new(timeout: Duration) -> RegisteredWebhookNotifier {
+    RegisteredWebhookNotifier {
+      template: MessageTemplate::from_env(),
+      client: reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .unwrap_or_default(),
+    }
+  }
+
+  async fn post(
+    &self,
+    webhook: &subscription::WebhookSubscription,
+    body: Vec<u8>,
+  ) -> Result<(), String> {
+    let request = self.client.post(&webhook.url);
+    signed(request, webhook.secret.as_deref(), body)
+      .send()
+      .await
+      .and_then(|x| x.error_for_status())
+      .map(|_| ())
+      .map_err(|err| format!("{}: {}", webhook.url, err))
+  }
+}
+
+#[async_trait]
+impl Notifier for RegisteredWebhookNotifier {
+  async fn notify(&self, event: &ChangeEvent) -> Result<(), NotifyError> {
+    let mut errors: Vec<String> = vec![];
+    for webhook in subscription::webhooks().await {
+      let event = match ServerFilter::new(&webhook.servers).apply(event)? {
+        Some(event) => event,
+        None => continue,
+      };
+      let message = self.template.render(&event)?;
+      let body = serde_json::to_vec(&WebhookPayload::new(&event, &message))
+        .map_err(|err| NotifyError::Failed(err.to_string()))?;
+      if let Err(err) = self.post(&webhook, body).await {
+        errors.push(err);
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(NotifyError::Failed(errors.join(", ")));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{codes_event, MockRequest, MockServer};
+  use super::*;
+  use actix_web::error::ResponseError;
+  use actix_web::http::StatusCode;
+  use serde_json::json;
+  use std::net::TcpListener;
+
+  fn notifier(urls: Vec<String>, timeout: Duration) -> WebhookNotifier {
+    let headers = vec![("Authorization".to_owned(), "Bearer token".to_owned())];
+    WebhookNotifier::new(urls, headers, None, timeout)
+  }
+
+  // Receivers depend on these fields, only ever add to them
+  #[test]
+  fn keeps_the_payload_schema() {
+    let event = codes_event(&["GENSHINGIFT"]);
+    let message = RenderedMessage {
+      title: "New promotional code".to_owned(),
+      body: "GENSHINGIFT".to_owned(),
+      url: None,
+    };
+    let payload = serde_json::to_value(&WebhookPayload::new(&event, &message)).unwrap();
+
+    let mut fields: Vec<&str> = payload
+      .as_object()
+      .unwrap()
+      .keys()
+      .map(String::as_str)
+      .collect();
+    fields.sort_unstable();
+    assert_eq!(
+      fields,
+      vec![
+        "added",
+        "codes",
+        "fetched_at",
+        "modified",
+        "removed",
+        "resource",
+        "revid",
+        "text",
+        "title"
+      ]
+    );
+    assert_eq!(payload["resource"], json!("Promotional_Codes"));
+    assert_eq!(payload["added"], event.added);
+    assert_eq!(payload["revid"], Value::Null);
+    assert_eq!(payload["codes"][0]["code"], json!("GENSHINGIFT"));
+    assert_eq!(payload["text"], json!("GENSHINGIFT"));
+  }
+
+  #[actix_rt::test]
+  async fn posts_to_every_url_with_the_headers() {
+    let servers = vec![MockServer::ok(), MockServer::ok()];
+    let urls = servers.iter().map(|x| x.url.to_owned() + "/hook").collect();
+    let event = codes_event(&["GENSHINGIFT"]);
+    notifier(urls, Duration::from_secs(5))
+      .notify(&event)
+      .await
+      .unwrap();
+
+    for server in &servers {
+      let requests = server.requests();
+      assert_eq!(requests.len(), 1);
+      assert_eq!(requests[0].method, "POST");
+      assert_eq!(requests[0].path, "/hook");
+      assert_eq!(requests[0].header("authorization"), Some("Bearer token"));
+      assert_eq!(requests[0].header("content-type"), Some("application/json"));
+      assert_eq!(requests[0].header(SIGNATURE_HEADER), None);
+      let payload = requests[0].json();
+      assert_eq!(payload["resource"], json!("Promotional_Codes"));
+      assert_eq!(payload["added"], event.added);
+    }
+  }
+
+  #[actix_rt::test]
+  async fn reports_non_2xx_responses() {
+    let failing = MockServer::start(vec![(500, "{}")]);
+    let succeeding = MockServer::ok();
+    let urls = vec![failing.url.to_owned(), succeeding.url.to_owned()];
+    let err = notifier(urls, Duration::from_secs(5))
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await
+      .unwrap_err();
+
+    assert!(err.to_string().contains(&failing.url));
+    assert!(!err.to_string().contains(&succeeding.url));
+    // The other URLs are still delivered to
+    assert_eq!(succeeding.requests().len(), 1);
+  }
+
+  #[actix_rt::test]
+  async fn reports_timeouts() {
+    // Accepts connections without ever answering
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let result = notifier(vec![url], Duration::from_millis(200))
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await;
+    assert!(result.is_err());
+  }
+
+  // The registry is shared by the whole process, only this test writes to it
+  #[actix_rt::test]
+  async fn delivers_to_the_webhooks_until_removed() {
+    let server = MockServer::ok();
+    let webhook = subscription::add_webhook(&(server.url.to_owned() + "/hook"), None, vec![])
+      .await
+      .unwrap();
+    assert!(subscription::webhooks()
+      .await
+      .iter()
+      .any(|x| x.id == webhook.id));
+
+    let duplicate = server.url.replace("http://", "HTTP://") + "/hook";
+    for (url, status) in &[
+      (duplicate.as_str(), StatusCode::CONFLICT),
+      ("not a url", StatusCode::BAD_REQUEST),
+      ("ftp://mona.test/hook", StatusCode::BAD_REQUEST),
+    ] {
+      let err = subscription::add_webhook(url, None, vec![])
+        .await
+        .unwrap_err();
+      assert_eq!(err.status_code(), *status);
+    }
+
+    let notifier = RegisteredWebhookNotifier::new(Duration::from_secs(10));
+    let event = codes_event(&["GENSHINGIFT"]);
+    notifier.notify(&event).await.unwrap();
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].path, "/hook");
+    assert_eq!(requests[0].json()["added"], event.added);
+
+    subscription::remove_webhook(&webhook.id).await.unwrap();
+    notifier.notify(&event).await.unwrap();
+    assert_eq!(server.requests().len(), 1);
+    let err = subscription::remove_webhook(&webhook.id).await.unwrap_err();
+    assert_eq!(err.status_code(), StatusCode::NOT_FOUND);
+  }
+
+  fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+  }
+
+  // RFC 4231, test cases 2 and 6
+  #[test]
+  fn computes_the_hmac_sha256() {
+    assert_eq!(
+      hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+      "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    assert_eq!(
+      hex(&hmac_sha256(
+        &[0xaa; 131],
+        b"Test Using Larger Than Block-Size Key - Hash Key First"
+      )),
+      "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+    );
+  }
+
+  // What a receiver does: HMAC "<timestamp>.<raw body>" with the shared secret and
+  // compare it to the signature, then check the timestamp is recent
+  fn verify(secret: &str, timestamp: &str, body: &[u8], signature: &str) -> bool {
+    let mut signed_data = format!("{}.", timestamp).into_bytes();
+    signed_data.extend_from_slice(body);
+    let expected = format!(
+      "sha256={}",
+      hex(&hmac_sha256(secret.as_bytes(), &signed_data))
+    );
+    let sent_at = timestamp.parse::<i64>().unwrap_or_default();
+    expected == signature && (Utc::now().timestamp() - sent_at).abs() < 300
+  }
+
+  #[actix_rt::test]
+  async fn signs_the_payloads_with_the_secret() {
+    let server = MockServer::ok();
+    let notifier = WebhookNotifier::new(
+      vec![server.url.to_owned()],
+      vec![],
+      Some("secret".to_owned()),
+      Duration::from_secs(5),
+    );
+    notifier
+      .notify(&codes_event(&["GENSHINGIFT"]))
+      .await
+      .unwrap();
+    notifier
+      .notify(&codes_event(&["GENSHINGIFT", "5SM6VJVQL4ZC"]))
+      .await
+      .unwrap();
+
+    let requests = server.requests();
+    let signature = |x: &MockRequest| x.header(SIGNATURE_HEADER).unwrap().to_owned();
+    let timestamp = |x: &MockRequest| x.header(TIMESTAMP_HEADER).unwrap().to_owned();
+    for request in &requests {
+      assert!(verify(
+        "secret",
+        &timestamp(request),
+        &request.body,
+        &signature(request)
+      ));
+      assert!(!verify(
+        "other secret",
+        &timestamp(request),
+        &request.body,
+        &signature(request)
+      ));
+    }
+
+    // Another body doesn't validate with the signature of the first one
+    assert_ne!(signature(&requests[0]), signature(&requests[1]));
+    assert!(!verify(
+      "secret",
+      &timestamp(&requests[0]),
+      &requests[1].body,
+      &signature(&requests[0])
+    ));
+  }
+}
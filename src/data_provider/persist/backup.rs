@@ -0,0 +1,123 @@
+use super::{get_raw, keys, set_raw, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Every persisted value as it was stored, Stored envelopes included, so the
+// metadata survives a move between environments
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupArchive {
+  pub created_at: DateTime<Utc>,
+  pub entries: Vec<BackupEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupEntry {
+  pub key: String,
+  pub value: Value,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct ImportReport {
+  pub imported: Vec<String>,
+  pub skipped: Vec<String>,
+}
+
+pub async fn export_all() -> Result<BackupArchive> {
+  let mut entries = Vec::new();
+  for key in keys().await? {
+    let json_data = match get_raw(&key).await? {
+      Some(json_data) => json_data,
+      None => continue,
+    };
+    entries.push(BackupEntry {
+      key,
+      value: serde_json::from_slice(&json_data)?,
+    });
+  }
+
+  Ok(BackupArchive {
+    created_at: Utc::now(),
+    entries,
+  })
+}
+
+// Without overwrite the keys that already hold a value are left alone and reported
+pub async fn import_all(archive: BackupArchive, overwrite: bool) -> Result<ImportReport> {
+  let mut report = ImportReport::default();
+  for entry in archive.entries {
+    if !overwrite && get_raw(&entry.key).await?.is_some() {
+      report.skipped.push(entry.key);
+      continue;
+    }
+
+    set_raw(&entry.key, serde_json::to_vec(&entry.value)?).await?;
+    report.imported.push(entry.key);
+  }
+
+  Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{delete, get_by_key, set_by_key};
+  use super::*;
+  use serde_json::json;
+
+  const FIRST: &str = "test/backup/Promotional_Codes";
+  const SECOND: &str = "test/backup/Material_Schedule";
+
+  // The test backend is shared, so only the entries of these tests are imported
+  async fn export(keys: &[&str]) -> BackupArchive {
+    let mut archive = export_all().await.unwrap();
+    archive.entries.retain(|x| keys.contains(&x.key.as_str()));
+    archive
+  }
+
+  #[actix_rt::test]
+  async fn round_trips_through_export_and_import() {
+    set_by_key(FIRST, &json!({"codes": ["GENSHINGIFT"]}))
+      .await
+      .unwrap();
+    set_by_key(SECOND, &json!({"days": {"Monday": ["Freedom"]}}))
+      .await
+      .unwrap();
+    let archive = export(&[FIRST, SECOND]).await;
+    assert_eq!(archive.entries.len(), 2);
+
+    delete(FIRST, true).await.unwrap();
+    delete(SECOND, true).await.unwrap();
+    let report = import_all(archive, false).await.unwrap();
+    assert_eq!(report.imported.len(), 2);
+    assert!(report.skipped.is_empty());
+    assert_eq!(
+      get_by_key::<Value>(FIRST).await,
+      Some(json!({"codes": ["GENSHINGIFT"]}))
+    );
+    assert_eq!(
+      get_by_key::<Value>(SECOND).await,
+      Some(json!({"days": {"Monday": ["Freedom"]}}))
+    );
+  }
+
+  #[actix_rt::test]
+  async fn skips_the_keys_holding_a_value() {
+    let key = "test/backup/skipped";
+    set_by_key(key, &json!({"codes": ["OLD"]})).await.unwrap();
+    let archive = BackupArchive {
+      created_at: Utc::now(),
+      entries: vec![BackupEntry {
+        key: key.to_owned(),
+        value: json!({"codes": ["NEW"]}),
+      }],
+    };
+
+    let report = import_all(archive, false).await.unwrap();
+    assert_eq!(report.skipped, vec![key.to_owned()]);
+    assert!(report.imported.is_empty());
+    assert_eq!(
+      get_by_key::<Value>(key).await,
+      Some(json!({"codes": ["OLD"]}))
+    );
+  }
+}
@@ -0,0 +1,222 @@
+use super::{PersistBackend, Result, StoredKey};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Keeps the latest value of every key read or written in memory, shared by all
+// the actix workers. Writes from other processes aren't seen until refresh.
+pub struct CachedBackend<B> {
+  backend: B,
+  cache: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+}
+
+impl<B: PersistBackend> CachedBackend<B> {
+  pub fn new(backend: B) -> CachedBackend<B> {
+    CachedBackend {
+      backend,
+      cache: Arc::new(RwLock::new(HashMap::new())),
+    }
+  }
+
+  fn cached(&self, key: &str) -> Option<Vec<u8>> {
+    self.cache.read().unwrap().get(key).cloned()
+  }
+
+  fn store(&self, key: &str, value: Option<Vec<u8>>) {
+    let mut cache = self.cache.write().unwrap();
+    match value {
+      Some(value) => cache.insert(key.to_owned(), value),
+      None => cache.remove(key),
+    };
+  }
+}
+
+#[async_trait]
+impl<B: PersistBackend> PersistBackend for CachedBackend<B> {
+  async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    if let Some(value) = self.cached(key) {
+      return Ok(Some(value));
+    }
+
+    let value = self.backend.get_raw(key).await?;
+    self.store(key, value.clone());
+    Ok(value)
+  }
+
+  // The cache is only updated once the backend took the value
+  async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()> {
+    self.backend.set_raw(key, value).await?;
+    self.store(key, Some(value.to_vec()));
+    Ok(())
+  }
+
+  async fn delete_raw(&self, key: &str) -> Result<()> {
+    self.backend.delete_raw(key).await?;
+    self.store(key, None);
+    Ok(())
+  }
+
+  async fn list_keys(&self) -> Result<Vec<StoredKey>> {
+    self.backend.list_keys().await
+  }
+
+  fn supports_compression(&self) -> bool {
+    self.backend.supports_compression()
+  }
+
+  async fn flush(&self) -> Result<()> {
+    self.backend.flush().await
+  }
+
+  async fn compact(&self) -> Result<()> {
+    self.backend.compact().await
+  }
+
+  async fn refresh(&self, key: &str) -> Result<()> {
+    let value = self.backend.get_raw(key).await?;
+    self.store(key, value);
+    Ok(())
+  }
+
+  async fn quarantine(&self, key: &str) -> Result<()> {
+    self.backend.quarantine(key).await?;
+    self.store(key, None);
+    Ok(())
+  }
+
+  async fn try_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+    self.backend.try_lock(key, token, ttl).await
+  }
+
+  async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+    self.backend.unlock(key, token).await
+  }
+}
+
+#[async_trait]
+impl PersistBackend for Box<dyn PersistBackend> {
+  async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    self.as_ref().get_raw(key).await
+  }
+
+  async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()> {
+    self.as_ref().set_raw(key, value).await
+  }
+
+  async fn delete_raw(&self, key: &str) -> Result<()> {
+    self.as_ref().delete_raw(key).await
+  }
+
+  async fn list_keys(&self) -> Result<Vec<StoredKey>> {
+    self.as_ref().list_keys().await
+  }
+
+  fn supports_compression(&self) -> bool {
+    self.as_ref().supports_compression()
+  }
+
+  async fn flush(&self) -> Result<()> {
+    self.as_ref().flush().await
+  }
+
+  async fn compact(&self) -> Result<()> {
+    self.as_ref().compact().await
+  }
+
+  async fn refresh(&self, key: &str) -> Result<()> {
+    self.as_ref().refresh(key).await
+  }
+
+  async fn quarantine(&self, key: &str) -> Result<()> {
+    self.as_ref().quarantine(key).await
+  }
+
+  async fn try_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+    self.as_ref().try_lock(key, token, ttl).await
+  }
+
+  async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+    self.as_ref().unlock(key, token).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use async_trait::async_trait;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Mutex;
+  use std::thread;
+
+  // Counts the reads that reach it
+  #[derive(Default)]
+  struct CountingBackend {
+    values: Mutex<HashMap<String, Vec<u8>>>,
+    reads: AtomicUsize,
+  }
+
+  #[async_trait]
+  impl PersistBackend for CountingBackend {
+    async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+      self.reads.fetch_add(1, Ordering::SeqCst);
+      Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()> {
+      self
+        .values
+        .lock()
+        .unwrap()
+        .insert(key.to_owned(), value.to_vec());
+      Ok(())
+    }
+
+    async fn delete_raw(&self, key: &str) -> Result<()> {
+      self.values.lock().unwrap().remove(key);
+      Ok(())
+    }
+
+    async fn list_keys(&self) -> Result<Vec<StoredKey>> {
+      Ok(vec![])
+    }
+  }
+
+  #[actix_rt::test]
+  async fn serves_a_set_to_concurrent_readers_from_memory() {
+    let cache = Arc::new(CachedBackend::new(CountingBackend::default()));
+    cache.set_raw("Promotional_Codes", b"[1]").await.unwrap();
+
+    let readers: Vec<_> = (0..8)
+      .map(|_| {
+        let cache = cache.clone();
+        thread::spawn(move || futures::executor::block_on(cache.get_raw("Promotional_Codes")))
+      })
+      .collect();
+    for reader in readers {
+      assert_eq!(reader.join().unwrap().unwrap(), Some(b"[1]".to_vec()));
+    }
+    assert_eq!(cache.backend.reads.load(Ordering::SeqCst), 0);
+  }
+
+  #[actix_rt::test]
+  async fn refresh_picks_up_outside_writes() {
+    let cache = CachedBackend::new(CountingBackend::default());
+    cache.set_raw("Promotional_Codes", b"[1]").await.unwrap();
+    cache
+      .backend
+      .set_raw("Promotional_Codes", b"[2]")
+      .await
+      .unwrap();
+    assert_eq!(
+      cache.get_raw("Promotional_Codes").await.unwrap(),
+      Some(b"[1]".to_vec())
+    );
+
+    cache.refresh("Promotional_Codes").await.unwrap();
+    assert_eq!(
+      cache.get_raw("Promotional_Codes").await.unwrap(),
+      Some(b"[2]".to_vec())
+    );
+  }
+}
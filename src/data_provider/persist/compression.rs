@@ -0,0 +1,35 @@
+use super::Result;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::env;
+use std::io::{Read, Write};
+
+const DEFAULT_THRESHOLD: usize = 64 * 1024;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Payloads above PERSIST_COMPRESS_THRESHOLD bytes are gzipped. The gzip magic bytes
+// mark the encoding, JSON never starts with them so uncompressed data reads as is
+pub fn compress(data: Vec<u8>) -> Result<Vec<u8>> {
+  let threshold = env::var("PERSIST_COMPRESS_THRESHOLD")
+    .ok()
+    .and_then(|x| x.parse().ok())
+    .unwrap_or(DEFAULT_THRESHOLD);
+  if data.len() <= threshold {
+    return Ok(data);
+  }
+
+  let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+  encoder.write_all(&data)?;
+  Ok(encoder.finish()?)
+}
+
+pub fn decompress(data: Vec<u8>) -> Result<Vec<u8>> {
+  if !data.starts_with(&GZIP_MAGIC) {
+    return Ok(data);
+  }
+
+  let mut decompressed = Vec::new();
+  GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+  Ok(decompressed)
+}
@@ -0,0 +1,187 @@
+use super::{DataPersistError, Result};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, NewAead};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "encryption")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "encryption")]
+use std::env;
+
+// Marks encrypted payloads, followed by the nonce and the ciphertext. Data written
+// before encryption was enabled doesn't start with it and reads as is
+const MAGIC: &[u8] = b"MSENC1";
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+  data.starts_with(MAGIC)
+}
+
+// The key is derived from PERSIST_ENCRYPTION_KEY, encryption is off while it's unset
+#[cfg(feature = "encryption")]
+fn cipher() -> Option<ChaCha20Poly1305> {
+  let secret = env::var("PERSIST_ENCRYPTION_KEY")
+    .ok()
+    .filter(|x| !x.is_empty())?;
+  Some(cipher_from(&secret))
+}
+
+#[cfg(feature = "encryption")]
+fn cipher_from(secret: &str) -> ChaCha20Poly1305 {
+  let key = Sha256::digest(secret.as_bytes());
+  ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+#[cfg(feature = "encryption")]
+pub fn encrypt(data: Vec<u8>) -> Result<Vec<u8>> {
+  encrypt_with(cipher().as_ref(), data)
+}
+
+#[cfg(feature = "encryption")]
+fn encrypt_with(cipher: Option<&ChaCha20Poly1305>, data: Vec<u8>) -> Result<Vec<u8>> {
+  let cipher = match cipher {
+    Some(cipher) => cipher,
+    None => return Ok(data),
+  };
+
+  let nonce: [u8; NONCE_LEN] = rand::random();
+  let ciphertext = cipher
+    .encrypt(Nonce::from_slice(&nonce), data.as_slice())
+    .map_err(|_| DataPersistError::EncryptionFailed)?;
+  Ok([MAGIC, &nonce, &ciphertext].concat())
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn encrypt(data: Vec<u8>) -> Result<Vec<u8>> {
+  Ok(data)
+}
+
+// A wrong or missing key fails with DecryptionFailed instead of handing garbage to serde
+#[cfg(feature = "encryption")]
+pub fn decrypt(data: Vec<u8>) -> Result<Vec<u8>> {
+  decrypt_with(cipher().as_ref(), data)
+}
+
+#[cfg(feature = "encryption")]
+fn decrypt_with(cipher: Option<&ChaCha20Poly1305>, data: Vec<u8>) -> Result<Vec<u8>> {
+  if !is_encrypted(&data) {
+    return Ok(data);
+  }
+
+  let cipher = cipher.ok_or(DataPersistError::DecryptionFailed)?;
+  let payload = &data[MAGIC.len()..];
+  if payload.len() < NONCE_LEN {
+    return Err(DataPersistError::DecryptionFailed);
+  }
+  let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+  cipher
+    .decrypt(Nonce::from_slice(nonce), ciphertext)
+    .map_err(|_| DataPersistError::DecryptionFailed)
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn decrypt(data: Vec<u8>) -> Result<Vec<u8>> {
+  if is_encrypted(&data) {
+    return Err(DataPersistError::DecryptionFailed);
+  }
+  Ok(data)
+}
+
+// Backends storing JSON, ex: as JSONB, get the encrypted payload hex encoded in a
+// JSON object instead of the raw bytes
+#[derive(Serialize, Deserialize)]
+struct EncryptedJson {
+  msenc: String,
+}
+
+pub fn encrypt_as_json(data: Vec<u8>) -> Result<Vec<u8>> {
+  let data = encrypt(data)?;
+  if !is_encrypted(&data) {
+    return Ok(data);
+  }
+  let msenc = data.iter().map(|x| format!("{:02x}", x)).collect();
+  Ok(serde_json::to_vec(&EncryptedJson { msenc })?)
+}
+
+// Anything else, ex: a plain resource, is returned as is
+pub fn unwrap_json(data: Vec<u8>) -> Vec<u8> {
+  let hex = match serde_json::from_slice::<EncryptedJson>(&data) {
+    Ok(encrypted) => encrypted.msenc,
+    Err(_) => return data,
+  };
+  let bytes: Option<Vec<u8>> = (0..hex.len())
+    .step_by(2)
+    .map(|idx| u8::from_str_radix(hex.get(idx..idx + 2)?, 16).ok())
+    .collect();
+  match bytes {
+    Some(bytes) if is_encrypted(&bytes) => bytes,
+    _ => data,
+  }
+}
+
+// The environment is shared by every test, so the ciphers are built from the secret
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+  use super::*;
+
+  const PAYLOAD: &[u8] = br#"{"codes":[{"code":"GENSHINGIFT"}]}"#;
+
+  #[test]
+  fn round_trips_with_the_same_key() {
+    let cipher = cipher_from("secret");
+    let encrypted = encrypt_with(Some(&cipher), PAYLOAD.to_vec()).unwrap();
+    assert!(is_encrypted(&encrypted));
+    assert!(!encrypted.windows(PAYLOAD.len()).any(|x| x == PAYLOAD));
+    // A random nonce each time
+    assert_ne!(
+      encrypted,
+      encrypt_with(Some(&cipher), PAYLOAD.to_vec()).unwrap()
+    );
+
+    assert_eq!(
+      decrypt_with(Some(&cipher), encrypted).unwrap(),
+      PAYLOAD.to_vec()
+    );
+  }
+
+  #[test]
+  fn fails_clearly_with_a_wrong_or_missing_key() {
+    let encrypted = encrypt_with(Some(&cipher_from("secret")), PAYLOAD.to_vec()).unwrap();
+    let wrong = cipher_from("other");
+    assert!(matches!(
+      decrypt_with(Some(&wrong), encrypted.clone()),
+      Err(DataPersistError::DecryptionFailed)
+    ));
+    assert!(matches!(
+      decrypt_with(None, encrypted),
+      Err(DataPersistError::DecryptionFailed)
+    ));
+  }
+
+  #[test]
+  fn reads_legacy_plain_values() {
+    let cipher = cipher_from("secret");
+    assert_eq!(
+      decrypt_with(Some(&cipher), PAYLOAD.to_vec()).unwrap(),
+      PAYLOAD.to_vec()
+    );
+    // Without a key nothing is encrypted
+    assert_eq!(
+      encrypt_with(None, PAYLOAD.to_vec()).unwrap(),
+      PAYLOAD.to_vec()
+    );
+  }
+
+  #[test]
+  fn round_trips_through_json() {
+    let cipher = cipher_from("secret");
+    let encrypted = encrypt_with(Some(&cipher), PAYLOAD.to_vec()).unwrap();
+    let msenc = encrypted.iter().map(|x| format!("{:02x}", x)).collect();
+    let json = serde_json::to_vec(&EncryptedJson { msenc }).unwrap();
+
+    assert_eq!(unwrap_json(json), encrypted);
+    assert_eq!(unwrap_json(PAYLOAD.to_vec()), PAYLOAD.to_vec());
+  }
+}
@@ -0,0 +1,342 @@
+use super::{
+  compression, encryption, file_name, key_from_file_stem, legacy_file_name, PersistBackend, Result,
+  StoredKey,
+};
+use async_std::fs;
+use async_std::prelude::*;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::de::IgnoredAny;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+// One JSON file per resource under PERSIST_DIR, for environments without redis
+pub struct FileBackend {
+  dir: PathBuf,
+}
+
+impl FileBackend {
+  pub fn new(dir: PathBuf) -> FileBackend {
+    FileBackend { dir }
+  }
+
+  fn path(&self, key: &str) -> PathBuf {
+    self.dir.join(file_name(key))
+  }
+
+  fn legacy_path(&self, key: &str) -> Option<PathBuf> {
+    legacy_file_name(key).map(|name| self.dir.join(name))
+  }
+
+  async fn read_value(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+    let data = match read(path).await? {
+      Some(data) if !is_json(&data) => data,
+      data => return Ok(data),
+    };
+
+    match read(&path.with_extension("json.bak")).await? {
+      Some(backup) if is_json(&backup) => {
+        warn!(
+          "{} is corrupted, falling back to its backup",
+          path.display()
+        );
+        Ok(Some(backup))
+      }
+      _ => Ok(Some(data)),
+    }
+  }
+
+  // The value and its backup
+  async fn remove_value(&self, path: &Path) -> Result<()> {
+    remove(path).await?;
+    remove(&path.with_extension("json.bak")).await
+  }
+}
+
+async fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+  match fs::read(path).await {
+    Ok(data) => Ok(Some(data)),
+    Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+    Err(err) => Err(err.into()),
+  }
+}
+
+async fn remove(path: &Path) -> Result<()> {
+  match fs::remove_file(path).await {
+    Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+    _ => Ok(()),
+  }
+}
+
+// Compressed payloads are checked once decompressed, encrypted ones can't be checked
+// without the key and are trusted
+fn is_json(data: &[u8]) -> bool {
+  if encryption::is_encrypted(data) {
+    return true;
+  }
+
+  match compression::decompress(data.to_vec()) {
+    Ok(data) => serde_json::from_slice::<IgnoredAny>(&data).is_ok(),
+    Err(_) => false,
+  }
+}
+
+// Writes go to a `.tmp` sibling that is synced and renamed over the target, the
+// previous version is kept as `.bak` in case the target still ends up unreadable.
+// A value still under its legacy file name is read from there until it's written.
+#[async_trait]
+impl PersistBackend for FileBackend {
+  async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    match (
+      self.read_value(&self.path(key)).await?,
+      self.legacy_path(key),
+    ) {
+      (None, Some(legacy_path)) => self.read_value(&legacy_path).await,
+      (data, _) => Ok(data),
+    }
+  }
+
+  async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()> {
+    fs::create_dir_all(&self.dir).await?;
+    let path = self.path(key);
+    let tmp_path = path.with_extension("json.tmp");
+
+    let mut file = fs::File::create(&tmp_path).await?;
+    file.write_all(value).await?;
+    file.sync_all().await?;
+
+    match read(&path).await? {
+      Some(previous) if is_json(&previous) => {
+        fs::write(path.with_extension("json.bak"), previous).await?
+      }
+      _ => {}
+    }
+    fs::rename(&tmp_path, &path).await?;
+    match self.legacy_path(key) {
+      Some(legacy_path) => self.remove_value(&legacy_path).await,
+      None => Ok(()),
+    }
+  }
+
+  // The backup goes too, otherwise get_raw would fall back to it
+  async fn delete_raw(&self, key: &str) -> Result<()> {
+    self.remove_value(&self.path(key)).await?;
+    match self.legacy_path(key) {
+      Some(legacy_path) => self.remove_value(&legacy_path).await,
+      None => Ok(()),
+    }
+  }
+
+  // Only the `.json` files, leaving out the backups, locks and quarantined values.
+  // The legacy file names, which can't be read back, are listed as they are.
+  async fn list_keys(&self) -> Result<Vec<StoredKey>> {
+    let mut entries = match fs::read_dir(&self.dir).await {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+      Err(err) => return Err(err.into()),
+    };
+
+    let mut keys = vec![];
+    while let Some(entry) = entries.next().await {
+      let path = entry?.path();
+      if path.extension().and_then(|x| x.to_str()) != Some("json") {
+        continue;
+      }
+      let metadata = fs::metadata(&path).await?;
+      let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+      keys.push(StoredKey {
+        key: key_from_file_stem(&stem).unwrap_or_else(|| stem.into_owned()),
+        fetched_at: metadata.modified().ok().map(DateTime::<Utc>::from),
+        size: metadata.len() as usize,
+      });
+    }
+    Ok(keys)
+  }
+
+  async fn flush(&self) -> Result<()> {
+    let mut entries = match fs::read_dir(&self.dir).await {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+      Err(err) => return Err(err.into()),
+    };
+
+    while let Some(entry) = entries.next().await {
+      fs::File::open(entry?.path()).await?.sync_all().await?;
+    }
+    Ok(())
+  }
+
+  // The lock is a file created exclusively holding the token, it's taken over
+  // once it wasn't touched for longer than ttl
+  async fn try_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+    fs::create_dir_all(&self.dir).await?;
+    let path = self.path(key).with_extension("lock");
+
+    for _ in 0..2 {
+      let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await;
+      match file {
+        Ok(mut file) => {
+          file.write_all(token.as_bytes()).await?;
+          file.sync_all().await?;
+          return Ok(true);
+        }
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+          let modified = fs::metadata(&path).await?.modified()?;
+          if SystemTime::now() < modified + ttl {
+            return Ok(false);
+          }
+          remove(&path).await?;
+        }
+        Err(err) => return Err(err.into()),
+      }
+    }
+    Ok(false)
+  }
+
+  async fn quarantine(&self, key: &str) -> Result<()> {
+    let path = self.path(key);
+    let corrupt_path = path.with_extension(format!("json.corrupt-{}", Utc::now().timestamp()));
+    match fs::rename(&path, corrupt_path).await {
+      Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+      _ => Ok(()),
+    }
+  }
+
+  async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+    let path = self.path(key).with_extension("lock");
+    if read(&path).await?.as_deref() == Some(token.as_bytes()) {
+      remove(&path).await?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use log::{Level, Log, Metadata, Record};
+  use once_cell::sync::Lazy;
+  use std::sync::Mutex;
+
+  // Keeps the warnings of every test, so one can check it logged its own
+  struct CaptureLogger;
+
+  static LOGGER: CaptureLogger = CaptureLogger;
+  static WARNINGS: Lazy<Mutex<Vec<String>>> = Lazy::new(|| {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Warn);
+    Mutex::new(vec![])
+  });
+
+  impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+      metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+      if self.enabled(record.metadata()) {
+        WARNINGS.lock().unwrap().push(record.args().to_string());
+      }
+    }
+
+    fn flush(&self) {}
+  }
+
+  pub fn capture_warnings() {
+    Lazy::force(&WARNINGS);
+  }
+
+  pub fn warned(message: &str) -> bool {
+    WARNINGS.lock().unwrap().iter().any(|x| x.contains(message))
+  }
+
+  // Each test gets a directory of its own, emptied first
+  pub fn temp_backend(name: &str) -> FileBackend {
+    let dir = std::env::temp_dir().join(format!("mona_spy-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    FileBackend::new(dir)
+  }
+
+  #[actix_rt::test]
+  async fn round_trips_raw_values() {
+    let backend = temp_backend("file_round_trip");
+    let key = "genshin-impact.fandom.com/en/Promotional_Codes";
+    assert_eq!(backend.get_raw(key).await.unwrap(), None);
+
+    backend.set_raw(key, br#"{"codes":[]}"#).await.unwrap();
+    assert_eq!(
+      backend.get_raw(key).await.unwrap(),
+      Some(br#"{"codes":[]}"#.to_vec())
+    );
+
+    backend.delete_raw(key).await.unwrap();
+    assert_eq!(backend.get_raw(key).await.unwrap(), None);
+    // Deleting a missing key isn't an error
+    backend.delete_raw(key).await.unwrap();
+  }
+
+  #[actix_rt::test]
+  async fn falls_back_to_the_backup_of_a_corrupt_file() {
+    capture_warnings();
+    let backend = temp_backend("file_backup");
+    let key = "genshin-impact.fandom.com/en/Promotional_Codes";
+    backend.set_raw(key, br#"{"codes":[1]}"#).await.unwrap();
+    backend.set_raw(key, br#"{"codes":[2]}"#).await.unwrap();
+
+    let path = backend.path(key);
+    std::fs::write(&path, b"{\"codes\":[2").unwrap();
+    assert_eq!(
+      backend.get_raw(key).await.unwrap(),
+      Some(br#"{"codes":[1]}"#.to_vec())
+    );
+    assert!(warned(&format!(
+      "{} is corrupted, falling back to its backup",
+      path.display()
+    )));
+  }
+
+  #[actix_rt::test]
+  async fn lists_the_stored_resources() {
+    let backend = temp_backend("file_list");
+    backend
+      .set_raw("genshin-impact.fandom.com/en/Promotional_Codes", b"[]")
+      .await
+      .unwrap();
+    // The second write leaves a backup, which isn't listed
+    backend.set_raw("Other", b"[1]").await.unwrap();
+    backend.set_raw("Other", b"[1,2,3]").await.unwrap();
+
+    let mut keys = backend.list_keys().await.unwrap();
+    keys.sort_by(|a, b| a.key.cmp(&b.key));
+    let listed: Vec<(&str, usize)> = keys.iter().map(|x| (x.key.as_str(), x.size)).collect();
+    assert_eq!(
+      listed,
+      vec![
+        ("Other", 7),
+        ("genshin-impact.fandom.com/en/Promotional_Codes", 2)
+      ]
+    );
+    assert!(keys.iter().all(|x| x.fetched_at.is_some()));
+  }
+
+  #[actix_rt::test]
+  async fn moves_a_value_off_its_legacy_file_name() {
+    let backend = temp_backend("file_legacy");
+    let key = "genshin/Promotional Codes";
+    std::fs::create_dir_all(&backend.dir).unwrap();
+    std::fs::write(backend.dir.join("genshin_Promotional_Codes.json"), b"[1]").unwrap();
+    assert_eq!(backend.get_raw(key).await.unwrap(), Some(b"[1]".to_vec()));
+
+    backend.set_raw(key, b"[2]").await.unwrap();
+    let keys = backend.list_keys().await.unwrap();
+    let listed: Vec<&str> = keys.iter().map(|x| x.key.as_str()).collect();
+    assert_eq!(listed, vec![key]);
+    assert_eq!(backend.get_raw(key).await.unwrap(), Some(b"[2]".to_vec()));
+  }
+}
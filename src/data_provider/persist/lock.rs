@@ -0,0 +1,91 @@
+use super::{backend, DataPersistError, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DEFAULT_LOCK_TTL: u64 = 120;
+const DEFAULT_LOCK_TIMEOUT: u64 = 30;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+// An advisory lock held on a key, released through unlock
+pub struct Lock {
+  key: String,
+  token: String,
+}
+
+fn lock_key(key: &str) -> String {
+  key.to_owned() + "@lock"
+}
+
+// Unique per process and acquisition, so a holder only ever releases its own lock
+fn new_token() -> String {
+  static COUNTER: AtomicU64 = AtomicU64::new(0);
+  format!(
+    "{}-{}-{}",
+    process::id(),
+    COUNTER.fetch_add(1, Ordering::SeqCst),
+    chrono::Utc::now().timestamp_nanos()
+  )
+}
+
+fn env_secs(name: &str, default: u64) -> Duration {
+  Duration::from_secs(
+    env::var(name)
+      .ok()
+      .and_then(|x| x.parse().ok())
+      .unwrap_or(default),
+  )
+}
+
+// Waits up to PERSIST_LOCK_TIMEOUT seconds for the key, the lock itself expires
+// after PERSIST_LOCK_TTL seconds so a crashed holder can't block it forever
+pub async fn lock(key: &str) -> Result<Lock> {
+  let lock = Lock {
+    key: lock_key(key),
+    token: new_token(),
+  };
+  let ttl = env_secs("PERSIST_LOCK_TTL", DEFAULT_LOCK_TTL);
+  let deadline = Instant::now() + env_secs("PERSIST_LOCK_TIMEOUT", DEFAULT_LOCK_TIMEOUT);
+
+  loop {
+    if backend().try_lock(&lock.key, &lock.token, ttl).await? {
+      return Ok(lock);
+    }
+    if Instant::now() >= deadline {
+      return Err(DataPersistError::LockTimeout);
+    }
+    actix_rt::time::delay_for(LOCK_RETRY_INTERVAL).await;
+  }
+}
+
+pub async fn unlock(lock: Lock) -> Result<()> {
+  backend().unlock(&lock.key, &lock.token).await
+}
+
+// Fallback for backends without a shared locking primitive, only guards against
+// concurrent updates within this process
+static LOCAL_LOCKS: Lazy<Mutex<HashMap<String, (String, Instant)>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn local_try_lock(key: &str, token: &str, ttl: Duration) -> bool {
+  let mut locks = LOCAL_LOCKS.lock().unwrap();
+  let now = Instant::now();
+  match locks.get(key) {
+    Some((_, expires_at)) if *expires_at > now => false,
+    _ => {
+      locks.insert(key.to_owned(), (token.to_owned(), now + ttl));
+      true
+    }
+  }
+}
+
+pub fn local_unlock(key: &str, token: &str) {
+  let mut locks = LOCAL_LOCKS.lock().unwrap();
+  if locks.get(key).map_or(false, |(holder, _)| holder == token) {
+    locks.remove(key);
+  }
+}
@@ -0,0 +1,1200 @@
+mod backup;
+mod cached_backend;
+mod compression;
+mod encryption;
+mod file_backend;
+mod lock;
+#[cfg(feature = "postgres")]
+mod postgres_backend;
+mod redis_backend;
+#[cfg(feature = "s3")]
+mod s3_backend;
+#[cfg(feature = "sqlite")]
+mod sqlite_backend;
+
+pub use backup::{export_all, import_all, BackupArchive, BackupEntry, ImportReport};
+pub use cached_backend::CachedBackend;
+pub use file_backend::FileBackend;
+pub use lock::{lock, unlock};
+#[cfg(feature = "postgres")]
+pub use postgres_backend::PostgresBackend;
+pub use redis_backend::RedisBackend;
+#[cfg(feature = "s3")]
+pub use s3_backend::S3Backend;
+#[cfg(feature = "sqlite")]
+pub use sqlite_backend::SqliteBackend;
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeZone, Utc};
+use derive_more::{Display, Error};
+use log::{error, info, warn};
+use once_cell::sync::OnceCell;
+use redis::RedisError;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Error as JsonError;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::Error as IoError;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "DataPersistError")]
+#[allow(clippy::enum_variant_names)]
+pub enum DataPersistError {
+  RedisError(RedisError),
+  JsonError(JsonError),
+  IoError(IoError),
+  #[cfg(feature = "sqlite")]
+  SqliteError(rusqlite::Error),
+  #[cfg(feature = "postgres")]
+  PostgresError(sqlx::Error),
+  #[cfg(feature = "postgres")]
+  MigrateError(sqlx::migrate::MigrateError),
+  #[cfg(feature = "s3")]
+  S3Error(#[error(not(source))] String),
+  ShuttingDown,
+  LockTimeout,
+  DecryptionFailed,
+  #[cfg(feature = "encryption")]
+  EncryptionFailed,
+}
+
+type Result<T> = std::result::Result<T, DataPersistError>;
+
+// Provenance of a persisted resource, so every cached copy knows when and from
+// where it came
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Stored<T> {
+  pub data: T,
+  pub fetched_at: DateTime<Utc>,
+  // The one of the first page, see WikiResource::get_title
+  pub revision: Option<u64>,
+  // The revision of every page the data was parsed from, missing on older entries
+  #[serde(default)]
+  pub revisions: BTreeMap<String, u64>,
+  // Hash of the wiki text the data was parsed from, missing on older entries
+  #[serde(default)]
+  pub content_hash: Option<String>,
+  // Checksum of the serialized data, verified on load to catch corrupted values
+  #[serde(default)]
+  pub checksum: Option<String>,
+  pub source_url: String,
+  #[serde(default = "first_schema_version")]
+  pub schema_version: u32,
+}
+
+// A key as listed by the backend, size is the one of the raw (maybe compressed) value
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct StoredKey {
+  pub key: String,
+  pub fetched_at: Option<DateTime<Utc>>,
+  pub size: usize,
+}
+
+fn first_schema_version() -> u32 {
+  1
+}
+
+// Data persisted with an older schema goes through migrate when loaded, bump
+// SCHEMA_VERSION whenever the persisted shape changes. New fields should have a
+// serde default so the default migrate can read the older payloads.
+pub trait Versioned: DeserializeOwned {
+  const SCHEMA_VERSION: u32 = 1;
+
+  // Relies on the serde defaults of the new fields unless overridden
+  fn migrate(_version: u32, value: Value) -> Result<Self> {
+    Ok(serde_json::from_value(value)?)
+  }
+}
+
+#[async_trait]
+pub trait PersistBackend: Send + Sync {
+  async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>>;
+  async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()>;
+  // Deleting a missing key isn't an error
+  async fn delete_raw(&self, key: &str) -> Result<()>;
+  // Every key holding a value, lock keys left out, with the time it was last
+  // written when the backend tracks it. File based backends read the key back from
+  // the file name, see file_stem.
+  async fn list_keys(&self) -> Result<Vec<StoredKey>>;
+
+  // Backends storing the JSON itself, ex: as JSONB, can't take gzipped or encrypted
+  // payloads
+  fn supports_compression(&self) -> bool {
+    true
+  }
+
+  // Makes sure everything written so far is durable, called once on shutdown
+  async fn flush(&self) -> Result<()> {
+    Ok(())
+  }
+
+  // Drops what the backend keeps by itself beyond the latest values, ex: the SQLite
+  // history, called with the daily prune
+  async fn compact(&self) -> Result<()> {
+    Ok(())
+  }
+
+  // Reloads the key from the underlying storage, only caching backends keep anything
+  async fn refresh(&self, _key: &str) -> Result<()> {
+    Ok(())
+  }
+
+  // Takes the advisory lock on key for token unless someone else holds it, the lock
+  // expires after ttl. Backends shared between processes should override both.
+  async fn try_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+    Ok(lock::local_try_lock(key, token, ttl))
+  }
+
+  async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+    lock::local_unlock(key, token);
+    Ok(())
+  }
+
+  // Moves a corrupted value aside under `<key>.corrupt-<timestamp>` for forensics,
+  // the key itself is left empty so the next load doesn't find it again
+  async fn quarantine(&self, key: &str) -> Result<()> {
+    if let Some(value) = self.get_raw(key).await? {
+      let corrupt_key = format!("{}.corrupt-{}", key, Utc::now().timestamp());
+      self.set_raw(&corrupt_key, &value).await?;
+      self.delete_raw(key).await?;
+    }
+    Ok(())
+  }
+}
+
+// Where the data lives, PERSIST_BACKEND, see Config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+  Redis,
+  File,
+  #[cfg(feature = "sqlite")]
+  Sqlite,
+  #[cfg(feature = "postgres")]
+  Postgres,
+  #[cfg(feature = "s3")]
+  S3,
+}
+
+impl FromStr for BackendKind {
+  type Err = String;
+
+  fn from_str(value: &str) -> std::result::Result<BackendKind, String> {
+    match value {
+      "redis" => Ok(BackendKind::Redis),
+      "file" => Ok(BackendKind::File),
+      #[cfg(feature = "sqlite")]
+      "sqlite" => Ok(BackendKind::Sqlite),
+      #[cfg(feature = "postgres")]
+      "postgres" => Ok(BackendKind::Postgres),
+      #[cfg(feature = "s3")]
+      "s3" => Ok(BackendKind::S3),
+      _ => Err(format!(
+        "unknown backend {:?}, or its feature isn't enabled",
+        value
+      )),
+    }
+  }
+}
+
+// `cache` keeps the values in memory, only safe when this process is the single
+// writer of the backend. The connection settings of each backend, ex: REDIS_URL,
+// are still read by the backend itself.
+pub fn create_backend(kind: BackendKind, dir: &Path, cache: bool) -> Arc<dyn PersistBackend> {
+  let backend: Box<dyn PersistBackend> = match kind {
+    BackendKind::Redis => Box::new(RedisBackend::from_env()),
+    BackendKind::File => Box::new(FileBackend::new(dir.to_owned())),
+    #[cfg(feature = "sqlite")]
+    BackendKind::Sqlite => {
+      Box::new(SqliteBackend::from_env().expect("Failed to open the SQLite database"))
+    }
+    #[cfg(feature = "postgres")]
+    BackendKind::Postgres => Box::new(PostgresBackend::from_env()),
+    #[cfg(feature = "s3")]
+    BackendKind::S3 => Box::new(S3Backend::from_env()),
+  };
+  if cache {
+    Arc::new(CachedBackend::new(backend))
+  } else {
+    Arc::from(backend)
+  }
+}
+
+// Set once by main. The handlers, the notifiers and the background loops reach it
+// through the functions of this module.
+static BACKEND: OnceCell<Arc<dyn PersistBackend>> = OnceCell::new();
+
+pub fn init(backend: Arc<dyn PersistBackend>) {
+  if BACKEND.set(backend).is_err() {
+    warn!("The persist backend was already set, keeping the first one");
+  }
+}
+
+fn backend() -> &'static dyn PersistBackend {
+  BACKEND.get_or_init(default_backend).as_ref()
+}
+
+#[cfg(not(test))]
+fn default_backend() -> Arc<dyn PersistBackend> {
+  panic!("persist::init must be called before the backend is used")
+}
+
+// Every test run writes to its own directory
+#[cfg(test)]
+fn default_backend() -> Arc<dyn PersistBackend> {
+  let dir = env::temp_dir().join(format!("mona_spy-test-{}", std::process::id()));
+  Arc::new(FileBackend::new(dir))
+}
+
+// Picks up a value and its snapshots written by someone else when the cache is
+// enabled
+pub async fn refresh(key: &str) -> Result<()> {
+  backend().refresh(key).await?;
+  backend().refresh(&snapshots_key(key)).await
+}
+
+// Keys are type names, ex: "alloc::vec::Vec<...>", which aren't valid file names
+fn key_stem(key: &str) -> String {
+  key
+    .chars()
+    .map(|c| match c {
+      'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+      _ => '_',
+    })
+    .collect()
+}
+
+// Keeps the ASCII letters, digits and hyphens, anything else is escaped as _XX so
+// the key can be read back from the file name
+fn file_stem(key: &str) -> String {
+  let mut stem = String::with_capacity(key.len());
+  for byte in key.bytes() {
+    match byte {
+      b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' => stem.push(byte as char),
+      _ => stem += &format!("_{:02X}", byte),
+    }
+  }
+  stem
+}
+
+// None for the names written before the keys were escaped, ex:
+// genshin_en_Promotional_Codes
+fn key_from_file_stem(stem: &str) -> Option<String> {
+  let is_hex = |x: &u8| x.is_ascii_digit() || (b'A'..=b'F').contains(x);
+  let mut bytes = Vec::with_capacity(stem.len());
+  let mut chars = stem.bytes();
+  while let Some(byte) = chars.next() {
+    if byte != b'_' {
+      bytes.push(byte);
+      continue;
+    }
+    let hex = [chars.next()?, chars.next()?];
+    if !hex.iter().all(is_hex) {
+      return None;
+    }
+    bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+  }
+  String::from_utf8(bytes).ok()
+}
+
+fn file_name(key: &str) -> String {
+  file_stem(key) + ".json"
+}
+
+// The name the key was stored under before file_stem, still read until the key is
+// written again. None when it's the same name.
+fn legacy_file_name(key: &str) -> Option<String> {
+  let name = key_stem(key) + ".json";
+  match name == file_name(key) {
+    true => None,
+    false => Some(name),
+  }
+}
+
+pub async fn get<T: DeserializeOwned>() -> Option<T> {
+  get_by_key(std::any::type_name::<T>()).await
+}
+
+pub async fn set<T: Serialize>(data: &T) -> Result<()> {
+  set_by_key(std::any::type_name::<T>(), data).await
+}
+
+// Values wrapped in a Stored envelope are unwrapped, so callers that only want the
+// data don't care how it was persisted. Corrupted values are quarantined.
+pub async fn get_by_key<T: DeserializeOwned>(key: &str) -> Option<T> {
+  let json_data = get_raw(key).await.ok()??;
+  let value = match serde_json::from_slice::<Value>(&json_data) {
+    Ok(value) => value,
+    Err(err) => {
+      error!("{} isn't valid JSON, quarantining it: {}", key, err);
+      quarantine(key).await;
+      return None;
+    }
+  };
+  if !checksum_matches(&value) {
+    error!("Checksum mismatch for {}, quarantining it", key);
+    quarantine(key).await;
+    return None;
+  }
+
+  match T::deserialize(&value) {
+    Ok(data) => Some(data),
+    Err(_) => Stored::<T>::deserialize(&value).ok().map(|x| x.data),
+  }
+}
+
+// Values in a Stored envelope carry the checksum of their data, others can't be
+// checked
+fn checksum_matches(value: &Value) -> bool {
+  match (
+    value.get("data"),
+    value.get("checksum").and_then(Value::as_str),
+  ) {
+    (Some(data), Some(expected)) => checksum(data).ok().as_deref() == Some(expected),
+    _ => true,
+  }
+}
+
+// Values persisted as the bare resource, before the envelope existed, come back
+// with an unknown fetch time and source
+pub async fn get_with_meta<T: Versioned>(key: &str) -> Option<Stored<T>> {
+  try_get_with_meta(key).await.ok().flatten()
+}
+
+// Like get_with_meta, but a value that can't be decrypted is an error rather than
+// missing, so whoever writes the key next can tell it would overwrite it
+pub async fn try_get_with_meta<T: Versioned>(key: &str) -> Result<Option<Stored<T>>> {
+  let json_data = match get_raw(key).await? {
+    Some(json_data) => json_data,
+    None => return Ok(None),
+  };
+  let value = match serde_json::from_slice::<Value>(&json_data) {
+    Ok(value) => value,
+    Err(err) => {
+      error!("{} isn't valid JSON, quarantining it: {}", key, err);
+      quarantine(key).await;
+      return Ok(None);
+    }
+  };
+  if !checksum_matches(&value) {
+    error!("Checksum mismatch for {}, quarantining it", key);
+    quarantine(key).await;
+    return Ok(None);
+  }
+
+  let stored = match Stored::<Value>::deserialize(&value) {
+    Ok(stored) => stored,
+    Err(_) => Stored {
+      data: value,
+      fetched_at: Utc.timestamp(0, 0),
+      revision: None,
+      revisions: BTreeMap::new(),
+      content_hash: None,
+      checksum: None,
+      source_url: String::new(),
+      schema_version: first_schema_version(),
+    },
+  };
+
+  // A payload that can't be read even once migrated is set aside, otherwise the next
+  // update would overwrite it as if nothing was persisted
+  let version = stored.schema_version;
+  match migrate(stored) {
+    Ok(stored) => Ok(Some(stored)),
+    Err(err) => {
+      error!(
+        "Failed to load {} with schema version {}, quarantining it: {:?}",
+        key, version, err
+      );
+      quarantine(key).await;
+      Ok(None)
+    }
+  }
+}
+
+fn migrate<T: Versioned>(stored: Stored<Value>) -> Result<Stored<T>> {
+  let version = stored.schema_version;
+  let data = if version < T::SCHEMA_VERSION {
+    T::migrate(version, stored.data)?
+  } else {
+    serde_json::from_value(stored.data)?
+  };
+  Ok(Stored {
+    data,
+    fetched_at: stored.fetched_at,
+    revision: stored.revision,
+    revisions: stored.revisions,
+    content_hash: stored.content_hash,
+    checksum: stored.checksum,
+    source_url: stored.source_url,
+    schema_version: T::SCHEMA_VERSION,
+  })
+}
+
+async fn quarantine(key: &str) {
+  if let Err(err) = backend().quarantine(key).await {
+    error!("Failed to quarantine {}: {:?}", key, err);
+  }
+}
+
+// Computed over the JSON value, whose keys are sorted, so it doesn't depend on the
+// field order of the type that was serialized
+pub fn checksum<T: Serialize>(data: &T) -> Result<String> {
+  let json_data = serde_json::to_vec(&serde_json::to_value(data)?)?;
+  Ok(format!("{:x}", Sha256::digest(&json_data)))
+}
+
+// Clock for the freshness checks, swappable so tests can fake the age of the data.
+// Each thread has its own, so the clock of a test doesn't leak into the others.
+thread_local! {
+  static CLOCK: Cell<fn() -> DateTime<Utc>> = Cell::new(Utc::now);
+}
+
+fn now() -> DateTime<Utc> {
+  CLOCK.with(|clock| clock.get())()
+}
+
+#[cfg(test)]
+pub fn set_clock(clock: fn() -> DateTime<Utc>) {
+  CLOCK.with(|x| x.set(clock));
+}
+
+// None when the value is missing or was fetched longer than max_age ago
+pub async fn get_fresh<T: Versioned>(key: &str, max_age: chrono::Duration) -> Option<Stored<T>> {
+  let stored = get_with_meta::<T>(key).await?;
+  if now() - stored.fetched_at > max_age {
+    return None;
+  }
+  Some(stored)
+}
+
+pub fn age<T>(stored: &Stored<T>) -> chrono::Duration {
+  now() - stored.fetched_at
+}
+
+// How long ago the value was fetched, for health reporting
+pub async fn staleness<T: Versioned>(key: &str) -> Option<chrono::Duration> {
+  let stored = get_with_meta::<T>(key).await?;
+  Some(now() - stored.fetched_at)
+}
+
+pub async fn set_by_key<T: Serialize>(key: &str, data: &T) -> Result<()> {
+  set_raw(key, serde_json::to_vec(&data)?).await
+}
+
+// PERSIST_ENCRYPTION_KEY encrypts what's written once compressed, with the
+// encryption feature enabled. Backends storing JSON get it uncompressed and
+// encrypted within a JSON object.
+async fn set_raw(key: &str, json_data: Vec<u8>) -> Result<()> {
+  let json_data = if backend().supports_compression() {
+    encryption::encrypt(compression::compress(json_data)?)?
+  } else {
+    encryption::encrypt_as_json(json_data)?
+  };
+
+  let _write = WriteGuard::new()?;
+  backend().set_raw(key, &json_data).await
+}
+
+// Where older versions recorded every key written, before the backends could list
+// them. Left alone, it's only skipped when listing.
+const LEGACY_KEYS_INDEX: &str = "mona_spy@keys";
+
+// Removes the value from the backend, along with its snapshots when `history` is set
+pub async fn delete(key: &str, history: bool) -> Result<()> {
+  let _write = WriteGuard::new()?;
+  backend().delete_raw(key).await?;
+  if history {
+    backend().delete_raw(&snapshots_key(key)).await?;
+  }
+  Ok(())
+}
+
+// Locks, quarantined copies and the legacy index hold no value of their own
+fn is_internal_key(key: &str) -> bool {
+  key == LEGACY_KEYS_INDEX || key.ends_with("@lock") || key.contains(".corrupt-")
+}
+
+// Every key worth backing up, snapshots included, sorted
+async fn keys() -> Result<Vec<String>> {
+  let mut keys: Vec<String> = backend()
+    .list_keys()
+    .await?
+    .into_iter()
+    .map(|x| x.key)
+    .filter(|x| !is_internal_key(x))
+    .collect();
+  keys.sort();
+  Ok(keys)
+}
+
+#[derive(Deserialize)]
+struct FetchedAt {
+  fetched_at: DateTime<Utc>,
+}
+
+// The resources as their keys were written whatever the backend, snapshots and
+// internal keys left out. The fetch time of the envelope is preferred over the
+// backend's write time.
+pub async fn list_keys() -> Result<Vec<StoredKey>> {
+  let mut stored_keys = backend().list_keys().await?;
+  stored_keys.retain(|x| !is_internal_key(&x.key) && !x.key.ends_with(SNAPSHOTS_SUFFIX));
+  for stored_key in stored_keys.iter_mut() {
+    let envelope = get_raw(&stored_key.key)
+      .await
+      .ok()
+      .flatten()
+      .and_then(|data| serde_json::from_slice::<FetchedAt>(&data).ok());
+    if let Some(envelope) = envelope {
+      stored_key.fetched_at = Some(envelope.fetched_at);
+    }
+  }
+  stored_keys.sort_by(|a, b| a.key.cmp(&b.key));
+  Ok(stored_keys)
+}
+
+// Copies the value of `from` and its snapshots to `to`, unless `to` already holds a
+// value. Returns whether anything was copied, the old keys are left as they were.
+pub async fn migrate_key(from: &str, to: &str) -> Result<bool> {
+  if get_raw(to).await?.is_some() {
+    return Ok(false);
+  }
+  let value = match get_raw(from).await? {
+    Some(value) => value,
+    None => return Ok(false),
+  };
+
+  if let Some(snapshots) = get_raw(&snapshots_key(from)).await? {
+    set_raw(&snapshots_key(to), snapshots).await?;
+  }
+  set_raw(to, value).await?;
+  Ok(true)
+}
+
+// A value that can't be decrypted fails with DecryptionFailed when encryption is
+// built in, it's most likely there with another PERSIST_ENCRYPTION_KEY
+async fn get_raw(key: &str) -> Result<Option<Vec<u8>>> {
+  let data = match backend().get_raw(key).await.ok().flatten() {
+    Some(data) => data,
+    None => return Ok(None),
+  };
+  let data = if backend().supports_compression() {
+    data
+  } else {
+    encryption::unwrap_json(data)
+  };
+  let data = match encryption::decrypt(data) {
+    Ok(data) => data,
+    #[cfg(feature = "encryption")]
+    Err(err) => {
+      error!(
+        "Failed to decrypt {}, check PERSIST_ENCRYPTION_KEY: {:?}",
+        key, err
+      );
+      return Err(err);
+    }
+    #[cfg(not(feature = "encryption"))]
+    Err(err) => {
+      error!(
+        "{} is encrypted, enable the encryption feature to read it: {:?}",
+        key, err
+      );
+      return Ok(None);
+    }
+  };
+  match compression::decompress(data) {
+    Ok(data) => Ok(Some(data)),
+    Err(err) => {
+      error!("Failed to decompress {}, quarantining it: {:?}", key, err);
+      quarantine(key).await;
+      Ok(None)
+    }
+  }
+}
+
+// Writes the bytes as they are, ex: a value encrypted with another key
+#[cfg(all(test, feature = "encryption"))]
+pub async fn set_backend_raw(key: &str, value: &[u8]) -> Result<()> {
+  backend().set_raw(key, value).await
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotMeta {
+  pub id: u64,
+  pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+  meta: SnapshotMeta,
+  body: Value,
+}
+
+// The history of a key lives in a single value next to it, bounded by its
+// RetentionPolicy
+fn snapshots_key(key: &str) -> String {
+  key.to_owned() + SNAPSHOTS_SUFFIX
+}
+
+const SNAPSHOTS_SUFFIX: &str = "@snapshots";
+const DEFAULT_SNAPSHOT_MAX_COUNT: usize = 100;
+
+// PERSIST_SNAPSHOT_MAX_COUNT and PERSIST_SNAPSHOT_MAX_AGE (days) apply to every
+// resource, suffixing them with the key (without its namespace) overrides them for
+// one resource, ex: PERSIST_SNAPSHOT_MAX_AGE_PROMOTIONAL_CODES=30
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+  pub max_count: Option<usize>,
+  pub max_age: Option<chrono::Duration>,
+}
+
+impl RetentionPolicy {
+  pub fn for_key(key: &str) -> RetentionPolicy {
+    let title = key.rsplit('/').next().unwrap_or(key);
+    let suffix = key_stem(title).to_uppercase();
+    // A value that doesn't parse is reported and left for the default
+    let var = |name: &str| {
+      let (name, value) = match env::var(format!("{}_{}", name, suffix)) {
+        Ok(value) => (format!("{}_{}", name, suffix), value),
+        Err(_) => (name.to_owned(), env::var(name).ok()?),
+      };
+      match value.trim().parse::<usize>() {
+        Ok(value) => Some(value),
+        Err(err) => {
+          warn!("Ignoring invalid {}: {}", name, err);
+          None
+        }
+      }
+    };
+
+    RetentionPolicy {
+      max_count: Some(var("PERSIST_SNAPSHOT_MAX_COUNT").unwrap_or(DEFAULT_SNAPSHOT_MAX_COUNT)),
+      max_age: var("PERSIST_SNAPSHOT_MAX_AGE").map(|x| chrono::Duration::days(x as i64)),
+    }
+  }
+
+  // Returns how many snapshots were removed, the latest one is always kept
+  fn apply(&self, snapshots: &mut Vec<Snapshot>, now: DateTime<Utc>) -> usize {
+    let latest = match snapshots.pop() {
+      Some(latest) => latest,
+      None => return 0,
+    };
+    let before = snapshots.len();
+
+    if let Some(max_age) = self.max_age {
+      snapshots.retain(|x| now - x.meta.timestamp <= max_age);
+    }
+    if let Some(max_count) = self.max_count {
+      let max_count = max_count.saturating_sub(1);
+      if snapshots.len() > max_count {
+        snapshots.drain(..snapshots.len() - max_count);
+      }
+    }
+
+    let removed = before - snapshots.len();
+    snapshots.push(latest);
+    removed
+  }
+}
+
+// Applies the retention policies to every history, returns how many snapshots were
+// removed per resource key
+pub async fn prune() -> Result<BTreeMap<String, usize>> {
+  let now = Utc::now();
+  let mut report = BTreeMap::new();
+
+  for key in keys().await? {
+    let resource_key = match key.strip_suffix(SNAPSHOTS_SUFFIX) {
+      Some(resource_key) => resource_key,
+      None => continue,
+    };
+    let mut snapshots: Vec<Snapshot> = match get_by_key(&key).await {
+      Some(snapshots) => snapshots,
+      None => continue,
+    };
+
+    let removed = RetentionPolicy::for_key(resource_key).apply(&mut snapshots, now);
+    if removed > 0 {
+      set_by_key(&key, &snapshots).await?;
+    }
+    report.insert(resource_key.to_owned(), removed);
+  }
+
+  Ok(report)
+}
+
+const PRUNE_INTERVAL: u64 = 24 * 3600;
+
+pub async fn prune_loop() {
+  loop {
+    actix_rt::time::delay_for(Duration::from_secs(PRUNE_INTERVAL)).await;
+    match prune().await {
+      Ok(report) => info!("Pruned snapshots: {:?}", report),
+      Err(err) => error!("Failed to prune snapshots: {:?}", err),
+    }
+    if let Err(err) = backend().compact().await {
+      error!("Failed to compact the persisted data: {:?}", err);
+    }
+  }
+}
+
+pub async fn set_snapshot<T: Serialize>(
+  key: &str,
+  body: &T,
+  timestamp: DateTime<Utc>,
+) -> Result<SnapshotMeta> {
+  let resource_key = key;
+  let key = snapshots_key(key);
+  let mut snapshots: Vec<Snapshot> = get_by_key(&key).await.unwrap_or_default();
+
+  let meta = SnapshotMeta {
+    id: snapshots.last().map_or(0, |x| x.meta.id + 1),
+    timestamp,
+  };
+  snapshots.push(Snapshot {
+    meta,
+    body: serde_json::to_value(body)?,
+  });
+  RetentionPolicy::for_key(resource_key).apply(&mut snapshots, Utc::now());
+
+  set_by_key(&key, &snapshots).await?;
+  Ok(meta)
+}
+
+pub async fn list_snapshots(key: &str) -> Vec<SnapshotMeta> {
+  let snapshots: Vec<Snapshot> = get_by_key(&snapshots_key(key)).await.unwrap_or_default();
+  snapshots.into_iter().map(|x| x.meta).collect()
+}
+
+pub async fn get_snapshot<T: DeserializeOwned>(key: &str, id: u64) -> Option<T> {
+  let snapshots: Vec<Snapshot> = get_by_key(&snapshots_key(key)).await?;
+  let snapshot = snapshots.into_iter().find(|x| x.meta.id == id)?;
+  serde_json::from_value(snapshot.body).ok()
+}
+
+// Every snapshot that could be read, oldest first. The ones taken with an older
+// schema go through migrate, like get_with_meta.
+pub async fn get_snapshots<T: Versioned>(key: &str) -> Vec<(SnapshotMeta, Stored<T>)> {
+  let snapshots: Vec<Snapshot> = get_by_key(&snapshots_key(key)).await.unwrap_or_default();
+  snapshots
+    .into_iter()
+    .filter_map(|x| {
+      let stored = Stored::<Value>::deserialize(&x.body).ok()?;
+      Some((x.meta, migrate(stored).ok()?))
+    })
+    .collect()
+}
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static PENDING_WRITES: AtomicUsize = AtomicUsize::new(0);
+
+// Counts the writes in flight, so shutdown can wait for them to land
+struct WriteGuard;
+
+impl WriteGuard {
+  fn new() -> Result<WriteGuard> {
+    PENDING_WRITES.fetch_add(1, Ordering::SeqCst);
+    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+      PENDING_WRITES.fetch_sub(1, Ordering::SeqCst);
+      return Err(DataPersistError::ShuttingDown);
+    }
+    Ok(WriteGuard)
+  }
+}
+
+impl Drop for WriteGuard {
+  fn drop(&mut self) {
+    PENDING_WRITES.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+// Rejects new writes, waits for the in-flight ones and flushes the backend, so a
+// redeploy never leaves a half-written resource behind
+pub async fn shutdown() -> Result<()> {
+  SHUTTING_DOWN.store(true, Ordering::SeqCst);
+  while PENDING_WRITES.load(Ordering::SeqCst) > 0 {
+    actix_rt::time::delay_for(Duration::from_millis(50)).await;
+  }
+
+  backend().flush().await
+}
+
+impl From<RedisError> for DataPersistError {
+  fn from(e: RedisError) -> DataPersistError {
+    DataPersistError::RedisError(e)
+  }
+}
+
+impl From<JsonError> for DataPersistError {
+  fn from(e: JsonError) -> DataPersistError {
+    DataPersistError::JsonError(e)
+  }
+}
+
+impl From<IoError> for DataPersistError {
+  fn from(e: IoError) -> DataPersistError {
+    DataPersistError::IoError(e)
+  }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for DataPersistError {
+  fn from(e: rusqlite::Error) -> DataPersistError {
+    DataPersistError::SqliteError(e)
+  }
+}
+
+#[cfg(feature = "postgres")]
+impl From<sqlx::Error> for DataPersistError {
+  fn from(e: sqlx::Error) -> DataPersistError {
+    DataPersistError::PostgresError(e)
+  }
+}
+
+#[cfg(feature = "postgres")]
+impl From<sqlx::migrate::MigrateError> for DataPersistError {
+  fn from(e: sqlx::migrate::MigrateError) -> DataPersistError {
+    DataPersistError::MigrateError(e)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::wiki::promotional_codes::PromotionalCodes;
+  use super::super::wiki::WikiResource;
+  use super::*;
+  use serde_json::json;
+
+  fn codes() -> PromotionalCodes {
+    PromotionalCodes::from_wikitext(include_str!("../wiki/fixtures/promotional_codes.wikitext"))
+  }
+
+  fn json<T: Serialize>(data: &T) -> Value {
+    serde_json::to_value(data).unwrap()
+  }
+
+  // The test backend is a FileBackend in a directory of its own, see default_backend
+  #[actix_rt::test]
+  async fn round_trips_promotional_codes() {
+    let key = "test/persist/round_trip";
+    let codes = codes();
+    assert!(codes.count() > 0);
+
+    set_by_key(key, &codes).await.unwrap();
+    let loaded: PromotionalCodes = get_by_key(key).await.unwrap();
+    assert_eq!(json(&loaded), json(&codes));
+  }
+
+  fn history(timestamps: &[DateTime<Utc>]) -> Vec<Snapshot> {
+    timestamps
+      .iter()
+      .enumerate()
+      .map(|(id, &timestamp)| Snapshot {
+        meta: SnapshotMeta {
+          id: id as u64,
+          timestamp,
+        },
+        body: Value::Null,
+      })
+      .collect()
+  }
+
+  fn ids(snapshots: &[Snapshot]) -> Vec<u64> {
+    snapshots.iter().map(|x| x.meta.id).collect()
+  }
+
+  #[test]
+  fn keeps_at_most_max_count_snapshots() {
+    let now = Utc::now();
+    let mut snapshots = history(&[now; 5]);
+    let policy = RetentionPolicy {
+      max_count: Some(3),
+      max_age: None,
+    };
+
+    assert_eq!(policy.apply(&mut snapshots, now), 2);
+    assert_eq!(ids(&snapshots), vec![2, 3, 4]);
+  }
+
+  #[test]
+  fn drops_snapshots_older_than_max_age() {
+    let now = Utc::now();
+    let days = |x| now - chrono::Duration::days(x);
+    let mut snapshots = history(&[days(40), days(31), days(29), days(1)]);
+    let policy = RetentionPolicy {
+      max_count: None,
+      max_age: Some(chrono::Duration::days(30)),
+    };
+
+    assert_eq!(policy.apply(&mut snapshots, now), 2);
+    assert_eq!(ids(&snapshots), vec![2, 3]);
+  }
+
+  #[test]
+  fn applies_the_stricter_of_both_rules() {
+    let now = Utc::now();
+    let days = |x| now - chrono::Duration::days(x);
+    let policy = RetentionPolicy {
+      max_count: Some(2),
+      max_age: Some(chrono::Duration::days(30)),
+    };
+
+    // The count removes more than the age
+    let mut snapshots = history(&[days(3), days(2), days(1), now]);
+    assert_eq!(policy.apply(&mut snapshots, now), 2);
+    assert_eq!(ids(&snapshots), vec![2, 3]);
+
+    // The age removes more than the count
+    let mut snapshots = history(&[days(50), days(40), now]);
+    assert_eq!(policy.apply(&mut snapshots, now), 2);
+    assert_eq!(ids(&snapshots), vec![2]);
+  }
+
+  #[test]
+  fn keeps_the_latest_snapshot() {
+    let now = Utc::now();
+    let policy = RetentionPolicy {
+      max_count: Some(0),
+      max_age: Some(chrono::Duration::days(1)),
+    };
+
+    let mut snapshots = history(&[now - chrono::Duration::days(90)]);
+    assert_eq!(policy.apply(&mut snapshots, now), 0);
+    assert_eq!(ids(&snapshots), vec![0]);
+
+    let mut snapshots = history(&[]);
+    assert_eq!(policy.apply(&mut snapshots, now), 0);
+    assert!(snapshots.is_empty());
+  }
+
+  #[actix_rt::test]
+  async fn records_snapshots_next_to_the_latest_value() {
+    let key = "test/persist/snapshots";
+    set_by_key(key, &json!({"codes": [2]})).await.unwrap();
+    set_snapshot(key, &json!({"codes": [1]}), Utc::now())
+      .await
+      .unwrap();
+    set_snapshot(key, &json!({"codes": [2]}), Utc::now())
+      .await
+      .unwrap();
+
+    let snapshots: Vec<u64> = list_snapshots(key).await.iter().map(|x| x.id).collect();
+    assert_eq!(snapshots, vec![0, 1]);
+    assert_eq!(
+      get_snapshot::<Value>(key, 0).await,
+      Some(json!({"codes": [1]}))
+    );
+    assert_eq!(get_by_key::<Value>(key).await, Some(json!({"codes": [2]})));
+  }
+
+  fn stored(data: PromotionalCodes) -> Stored<PromotionalCodes> {
+    Stored {
+      checksum: checksum(&data).ok(),
+      data,
+      fetched_at: Utc.ymd(2021, 6, 30).and_hms(12, 0, 0),
+      revision: Some(1234),
+      revisions: vec![("Promotional_Codes".to_owned(), 1234)]
+        .into_iter()
+        .collect(),
+      content_hash: Some("hash".to_owned()),
+      source_url: "https://genshin-impact.fandom.com/wiki/Promotional_Codes".to_owned(),
+      schema_version: PromotionalCodes::SCHEMA_VERSION,
+    }
+  }
+
+  #[actix_rt::test]
+  async fn round_trips_the_envelope() {
+    let key = "test/persist/envelope";
+    let stored = stored(codes());
+    set_by_key(key, &stored).await.unwrap();
+
+    let loaded = get_with_meta::<PromotionalCodes>(key).await.unwrap();
+    assert_eq!(json(&loaded), json(&stored));
+    // Callers only after the data don't see the envelope
+    let data: PromotionalCodes = get_by_key(key).await.unwrap();
+    assert_eq!(json(&data), json(&stored.data));
+  }
+
+  #[actix_rt::test]
+  async fn lists_keys_as_they_were_written() {
+    let stored = stored(codes());
+    set_by_key("test/list/Promotional_Codes", &stored)
+      .await
+      .unwrap();
+    set_by_key("test/list/Bare", &json!([1, 2, 3]))
+      .await
+      .unwrap();
+    // Neither the history nor a quarantined copy is a resource of its own
+    set_snapshot("test/list/Promotional_Codes", &stored, stored.fetched_at)
+      .await
+      .unwrap();
+    backend()
+      .set_raw("test/list/Bare.corrupt-1", b"[1,2")
+      .await
+      .unwrap();
+
+    let keys: Vec<StoredKey> = list_keys()
+      .await
+      .unwrap()
+      .into_iter()
+      .filter(|x| x.key.starts_with("test/list/"))
+      .collect();
+    let names: Vec<&str> = keys.iter().map(|x| x.key.as_str()).collect();
+    assert_eq!(names, vec!["test/list/Bare", "test/list/Promotional_Codes"]);
+    assert_eq!(keys[0].size, 7);
+    // The fetch time of the envelope wins over the time of the file
+    assert_eq!(keys[1].fetched_at, Some(stored.fetched_at));
+  }
+
+  #[test]
+  fn reads_the_key_back_from_the_file_name() {
+    let key = "genshin-impact.fandom.com/pt-br/Códigos_Promocionais";
+    assert_eq!(key_from_file_stem(&file_stem(key)).as_deref(), Some(key));
+    assert_eq!(key_from_file_stem("genshin_en_Promotional_Codes"), None);
+  }
+
+  #[actix_rt::test]
+  async fn reads_a_bare_resource() {
+    let key = "test/persist/bare";
+    let codes = codes();
+    set_by_key(key, &codes).await.unwrap();
+
+    let loaded = get_with_meta::<PromotionalCodes>(key).await.unwrap();
+    assert_eq!(json(&loaded.data), json(&codes));
+    assert_eq!(loaded.fetched_at, Utc.timestamp(0, 0));
+    assert_eq!(loaded.revision, None);
+    assert_eq!(loaded.checksum, None);
+  }
+
+  #[actix_rt::test]
+  async fn quarantines_a_value_failing_its_checksum() {
+    let key = "test/persist/corrupt";
+    set_by_key(key, &stored(codes())).await.unwrap();
+
+    // Still valid JSON, only the checksum can tell
+    let mut raw = backend().get_raw(key).await.unwrap().unwrap();
+    let at = raw.windows(11).position(|x| x == b"GENSHINGIFT").unwrap();
+    raw[at + 10] = b'U';
+    backend().set_raw(key, &raw).await.unwrap();
+
+    assert!(get_by_key::<PromotionalCodes>(key).await.is_none());
+    let corrupt_name = file_name(key) + ".corrupt-";
+    let dir = env::temp_dir().join(format!("mona_spy-test-{}", std::process::id()));
+    let quarantined = std::fs::read_dir(dir).unwrap().any(|x| {
+      x.unwrap()
+        .file_name()
+        .to_string_lossy()
+        .starts_with(&corrupt_name)
+    });
+    assert!(quarantined);
+    assert!(backend().get_raw(key).await.unwrap().is_none());
+  }
+
+  fn in_two_hours() -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::hours(2)
+  }
+
+  #[actix_rt::test]
+  async fn ages_the_data_with_the_clock() {
+    let key = "test/persist/freshness";
+    let stored = Stored {
+      fetched_at: Utc::now(),
+      ..stored(codes())
+    };
+    set_by_key(key, &stored).await.unwrap();
+    let max_age = chrono::Duration::hours(1);
+    assert!(get_fresh::<PromotionalCodes>(key, max_age).await.is_some());
+
+    set_clock(in_two_hours);
+    assert!(get_fresh::<PromotionalCodes>(key, max_age).await.is_none());
+    let staleness = staleness::<PromotionalCodes>(key).await.unwrap();
+    assert!(staleness >= chrono::Duration::hours(2));
+    assert!(age(&stored) >= chrono::Duration::hours(2));
+    set_clock(Utc::now);
+  }
+
+  #[actix_rt::test]
+  async fn compresses_large_values() {
+    let key = "test/persist/large";
+    let codes: Vec<String> = (0..10_000).map(|x| format!("CODE{:08}", x)).collect();
+    let large = json!({ "codes": codes });
+    set_by_key(key, &large).await.unwrap();
+
+    let raw = backend().get_raw(key).await.unwrap().unwrap();
+    assert!(raw.starts_with(&[0x1f, 0x8b]));
+    assert!(raw.len() < serde_json::to_vec(&large).unwrap().len());
+    assert_eq!(get_by_key::<Value>(key).await, Some(large));
+  }
+
+  #[actix_rt::test]
+  async fn reads_legacy_uncompressed_values() {
+    let key = "test/persist/legacy";
+    let codes = codes();
+    backend()
+      .set_raw(key, &serde_json::to_vec(&codes).unwrap())
+      .await
+      .unwrap();
+
+    let loaded: PromotionalCodes = get_by_key(key).await.unwrap();
+    assert_eq!(json(&loaded), json(&codes));
+  }
+
+  // v1 was written before the expired codes existed
+  #[actix_rt::test]
+  async fn migrates_a_v1_resource() {
+    let key = "test/persist/v1";
+    let codes = codes();
+    let mut data = json(&codes);
+    data.as_object_mut().unwrap().remove("expired");
+    let v1 = json!({
+      "data": data,
+      "fetched_at": Utc::now(),
+      "revision": null,
+      "source_url": "",
+      "schema_version": 1,
+    });
+    backend()
+      .set_raw(key, &serde_json::to_vec(&v1).unwrap())
+      .await
+      .unwrap();
+
+    let loaded = get_with_meta::<PromotionalCodes>(key).await.unwrap();
+    assert_eq!(loaded.schema_version, PromotionalCodes::SCHEMA_VERSION);
+    assert!(loaded.data.difference(&codes).empty());
+    assert!(codes.difference(&loaded.data).empty());
+    assert!(loaded.data.diff(&codes).is_empty());
+  }
+
+  // v2 was normalized on every load rather than when parsed
+  #[actix_rt::test]
+  async fn normalizes_a_v2_resource_once() {
+    let key = "test/persist/v2";
+    let codes = codes();
+    let mut data = json(&codes);
+    let reward = data["codes"][0]["reward"].as_str().unwrap().to_owned();
+    data["codes"][0]["reward"] = json!(format!(" {} ", reward.replace(' ', "\u{00A0}")));
+    let v2 = json!({
+      "data": data,
+      "fetched_at": Utc::now(),
+      "revision": null,
+      "source_url": "",
+      "schema_version": 2,
+    });
+    backend()
+      .set_raw(key, &serde_json::to_vec(&v2).unwrap())
+      .await
+      .unwrap();
+
+    let loaded = get_with_meta::<PromotionalCodes>(key).await.unwrap();
+    assert_eq!(loaded.schema_version, PromotionalCodes::SCHEMA_VERSION);
+    assert_eq!(json(&loaded.data), json(&codes));
+  }
+}
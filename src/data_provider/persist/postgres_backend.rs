@@ -0,0 +1,216 @@
+use super::{PersistBackend, Result, StoredKey};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::env;
+use std::time::Duration;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+// One row per resource with the body as JSONB, so the web dynos stay stateless.
+// The pool is created on the first call, which also runs the migrations.
+pub struct PostgresBackend {
+  url: String,
+  max_connections: u32,
+  pool: Mutex<Option<PgPool>>,
+}
+
+impl PostgresBackend {
+  pub fn new(url: String, max_connections: u32) -> PostgresBackend {
+    PostgresBackend {
+      url,
+      max_connections,
+      pool: Mutex::new(None),
+    }
+  }
+
+  // DATABASE_MAX_CONNECTIONS bounds the pool, defaults to 5
+  pub fn from_env() -> PostgresBackend {
+    PostgresBackend::new(
+      env::var("DATABASE_URL").unwrap_or_default(),
+      env::var("DATABASE_MAX_CONNECTIONS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(5),
+    )
+  }
+
+  async fn pool(&self) -> Result<PgPool> {
+    let mut pool = self.pool.lock().await;
+    if let Some(pool) = pool.as_ref() {
+      return Ok(pool.clone());
+    }
+
+    let new_pool = PgPoolOptions::new()
+      .max_connections(self.max_connections)
+      .connect(&self.url)
+      .await?;
+    MIGRATOR.run(&new_pool).await?;
+    *pool = Some(new_pool.clone());
+    Ok(new_pool)
+  }
+}
+
+#[async_trait]
+impl PersistBackend for PostgresBackend {
+  async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    let pool = self.pool().await?;
+    let row: Option<(Value,)> = sqlx::query_as("SELECT body FROM resources WHERE name = $1")
+      .bind(key)
+      .fetch_optional(&pool)
+      .await?;
+
+    match row {
+      Some((body,)) => Ok(Some(serde_json::to_vec(&body)?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()> {
+    let pool = self.pool().await?;
+    let body: Value = serde_json::from_slice(value)?;
+    sqlx::query(
+      "INSERT INTO resources (name, body, updated_at) VALUES ($1, $2, now())
+        ON CONFLICT (name) DO UPDATE SET body = EXCLUDED.body, updated_at = EXCLUDED.updated_at",
+    )
+    .bind(key)
+    .bind(body)
+    .execute(&pool)
+    .await?;
+    Ok(())
+  }
+
+  async fn delete_raw(&self, key: &str) -> Result<()> {
+    let pool = self.pool().await?;
+    sqlx::query("DELETE FROM resources WHERE name = $1")
+      .bind(key)
+      .execute(&pool)
+      .await?;
+    Ok(())
+  }
+
+  async fn list_keys(&self) -> Result<Vec<StoredKey>> {
+    let pool = self.pool().await?;
+    let rows: Vec<(String, DateTime<Utc>, i32)> =
+      sqlx::query_as("SELECT name, updated_at, octet_length(body::text) FROM resources")
+        .fetch_all(&pool)
+        .await?;
+
+    Ok(
+      rows
+        .into_iter()
+        .map(|(key, updated_at, size)| StoredKey {
+          key,
+          fetched_at: Some(updated_at),
+          size: size as usize,
+        })
+        .collect(),
+    )
+  }
+
+  fn supports_compression(&self) -> bool {
+    false
+  }
+
+  // Takes the row over only when the previous holder's lock expired, shared by
+  // every dyno using the database
+  async fn try_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+    let pool = self.pool().await?;
+    let result = sqlx::query(
+      "INSERT INTO locks (name, token, expires_at) VALUES ($1, $2, now() + make_interval(secs => $3))
+        ON CONFLICT (name) DO UPDATE SET token = EXCLUDED.token, expires_at = EXCLUDED.expires_at
+        WHERE locks.expires_at < now()",
+    )
+    .bind(key)
+    .bind(token)
+    .bind(ttl.as_secs_f64())
+    .execute(&pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+  }
+
+  async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+    let pool = self.pool().await?;
+    sqlx::query("DELETE FROM locks WHERE name = $1 AND token = $2")
+      .bind(key)
+      .bind(token)
+      .execute(&pool)
+      .await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::super::wiki::promotional_codes::PromotionalCodes;
+  use super::super::super::wiki::WikiResource;
+  use super::*;
+
+  // Runs against the database at DATABASE_URL, skipped without it
+  #[actix_rt::test]
+  async fn round_trips_promotional_codes() {
+    let url = match env::var("DATABASE_URL") {
+      Ok(url) => url,
+      Err(_) => return,
+    };
+    let backend = PostgresBackend::new(url, 1);
+    let key = format!("mona_spy-test-{}/Promotional_Codes", std::process::id());
+    let codes =
+      PromotionalCodes::from_wikitext(include_str!("../wiki/fixtures/promotional_codes.wikitext"));
+    let value = serde_json::to_value(&codes).unwrap();
+
+    backend
+      .set_raw(&key, &serde_json::to_vec(&value).unwrap())
+      .await
+      .unwrap();
+    // JSONB doesn't keep the key order, the values are compared instead
+    let stored = backend.get_raw(&key).await.unwrap().unwrap();
+    assert_eq!(serde_json::from_slice::<Value>(&stored).unwrap(), value);
+    assert!(backend
+      .list_keys()
+      .await
+      .unwrap()
+      .iter()
+      .any(|x| x.key == key));
+
+    backend.delete_raw(&key).await.unwrap();
+    assert_eq!(backend.get_raw(&key).await.unwrap(), None);
+  }
+
+  #[actix_rt::test]
+  async fn lists_the_stored_resources() {
+    let url = match env::var("DATABASE_URL") {
+      Ok(url) => url,
+      Err(_) => return,
+    };
+    let backend = PostgresBackend::new(url, 1);
+    let prefix = format!("mona_spy-test-{}/list/", std::process::id());
+    let first = prefix.to_owned() + "Promotional_Codes";
+    let second = prefix.to_owned() + "Other";
+    let before = Utc::now();
+    backend.set_raw(&first, br#"{"codes":[]}"#).await.unwrap();
+    backend.set_raw(&second, b"[1,2,3]").await.unwrap();
+
+    let mut keys: Vec<StoredKey> = backend
+      .list_keys()
+      .await
+      .unwrap()
+      .into_iter()
+      .filter(|x| x.key.starts_with(&prefix))
+      .collect();
+    keys.sort_by(|a, b| a.key.cmp(&b.key));
+    // The size is the one of the JSONB as text, which adds a space after the colons
+    // and commas
+    let listed: Vec<(&str, usize)> = keys.iter().map(|x| (x.key.as_str(), x.size)).collect();
+    assert_eq!(listed, vec![(second.as_str(), 9), (first.as_str(), 13)]);
+    // updated_at is stored with microsecond precision
+    let before = before - chrono::Duration::milliseconds(1);
+    assert!(keys.iter().all(|x| x.fetched_at.unwrap() >= before));
+
+    backend.delete_raw(&first).await.unwrap();
+    backend.delete_raw(&second).await.unwrap();
+  }
+}
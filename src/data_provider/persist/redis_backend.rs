@@ -0,0 +1,225 @@
+use super::{PersistBackend, Result, StoredKey};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, RedisResult, Script};
+use std::env;
+use std::time::Duration;
+
+// Shares one multiplexed connection between calls, so replicas can share state
+// without reconnecting on every get/set
+pub struct RedisBackend {
+  url: String,
+  prefix: String,
+  ttl: Option<usize>,
+  connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl RedisBackend {
+  pub fn new(url: String, prefix: String, ttl: Option<usize>) -> RedisBackend {
+    RedisBackend {
+      url,
+      prefix,
+      ttl,
+      connection: Mutex::new(None),
+    }
+  }
+
+  // REDIS_KEY_PREFIX namespaces the keys, REDIS_TTL (seconds) makes them expire
+  pub fn from_env() -> RedisBackend {
+    RedisBackend::new(
+      env::var("REDIS_URL").unwrap_or_default(),
+      env::var("REDIS_KEY_PREFIX").unwrap_or_default(),
+      env::var("REDIS_TTL").ok().and_then(|x| x.parse().ok()),
+    )
+  }
+
+  async fn connection(&self) -> Result<MultiplexedConnection> {
+    let mut connection = self.connection.lock().await;
+    if let Some(connection) = connection.as_ref() {
+      return Ok(connection.clone());
+    }
+
+    let client = redis::Client::open(self.url.as_str())?;
+    let new_connection = client.get_multiplexed_async_std_connection().await?;
+    *connection = Some(new_connection.clone());
+    Ok(new_connection)
+  }
+
+  // Drops the cached connection so the next call reconnects
+  async fn reset(&self) {
+    *self.connection.lock().await = None;
+  }
+
+  fn key(&self, key: &str) -> String {
+    self.prefix.to_owned() + key
+  }
+}
+
+async fn list_keys(con: &mut MultiplexedConnection, prefix: &str) -> RedisResult<Vec<StoredKey>> {
+  let mut names = vec![];
+  let mut iter = con.scan_match::<_, String>(prefix.to_owned() + "*").await?;
+  while let Some(name) = iter.next_item().await {
+    if !name.ends_with("@lock") {
+      names.push(name);
+    }
+  }
+
+  let mut keys = vec![];
+  for name in names {
+    let size: usize = con.strlen(&name).await?;
+    keys.push(StoredKey {
+      key: name[prefix.len()..].to_owned(),
+      fetched_at: None,
+      size,
+    });
+  }
+  Ok(keys)
+}
+
+#[async_trait]
+impl PersistBackend for RedisBackend {
+  async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    let mut con = self.connection().await?;
+
+    let result = con.get(self.key(key)).await;
+    if result.is_err() {
+      self.reset().await;
+    }
+    Ok(result?)
+  }
+
+  async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()> {
+    let mut con = self.connection().await?;
+
+    let result = match self.ttl {
+      Some(ttl) => con.set_ex::<_, _, ()>(self.key(key), value, ttl).await,
+      None => con.set::<_, _, ()>(self.key(key), value).await,
+    };
+    if result.is_err() {
+      self.reset().await;
+    }
+    Ok(result?)
+  }
+
+  async fn delete_raw(&self, key: &str) -> Result<()> {
+    let mut con = self.connection().await?;
+
+    let result = con.del::<_, ()>(self.key(key)).await;
+    if result.is_err() {
+      self.reset().await;
+    }
+    Ok(result?)
+  }
+
+  // SCAN over the prefix, redis doesn't know when a key was written
+  async fn list_keys(&self) -> Result<Vec<StoredKey>> {
+    let mut con = self.connection().await?;
+
+    let result = list_keys(&mut con, &self.prefix).await;
+    if result.is_err() {
+      self.reset().await;
+    }
+    Ok(result?)
+  }
+
+  // SET NX with an expiry, so the lock is shared by every replica
+  async fn try_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+    let mut con = self.connection().await?;
+
+    let result: RedisResult<Option<String>> = redis::cmd("SET")
+      .arg(self.key(key))
+      .arg(token)
+      .arg("NX")
+      .arg("PX")
+      .arg(ttl.as_millis() as u64)
+      .query_async(&mut con)
+      .await;
+    if result.is_err() {
+      self.reset().await;
+    }
+    Ok(result?.is_some())
+  }
+
+  // Only deletes the lock if it's still ours, it may have expired and been retaken
+  async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+    let mut con = self.connection().await?;
+
+    let result: RedisResult<i32> = Script::new(
+      r#"if redis.call("GET", KEYS[1]) == ARGV[1] then
+        return redis.call("DEL", KEYS[1])
+      end
+      return 0"#,
+    )
+    .key(self.key(key))
+    .arg(token)
+    .invoke_async(&mut con)
+    .await;
+    if result.is_err() {
+      self.reset().await;
+    }
+    result?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::super::wiki::promotional_codes::PromotionalCodes;
+  use super::super::super::wiki::WikiResource;
+  use super::*;
+
+  // Runs against the redis at TEST_REDIS_URL, the tests are skipped without it
+  fn test_backend(name: &str, ttl: Option<usize>) -> Option<RedisBackend> {
+    let url = env::var("TEST_REDIS_URL").ok()?;
+    let prefix = format!("mona_spy-test-{}-{}:", name, std::process::id());
+    Some(RedisBackend::new(url, prefix, ttl))
+  }
+
+  #[test]
+  fn prefixes_the_keys() {
+    let backend = RedisBackend::new(String::new(), "mona_spy:".to_owned(), None);
+    assert_eq!(
+      backend.key("Promotional_Codes"),
+      "mona_spy:Promotional_Codes"
+    );
+  }
+
+  #[actix_rt::test]
+  async fn round_trips_promotional_codes() {
+    let backend = match test_backend("round_trip", None) {
+      Some(backend) => backend,
+      None => return,
+    };
+    let codes =
+      PromotionalCodes::from_wikitext(include_str!("../wiki/fixtures/promotional_codes.wikitext"));
+    let value = serde_json::to_vec(&codes).unwrap();
+
+    backend.set_raw("Promotional_Codes", &value).await.unwrap();
+    assert_eq!(
+      backend.get_raw("Promotional_Codes").await.unwrap(),
+      Some(value.clone())
+    );
+    let keys = backend.list_keys().await.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].key, "Promotional_Codes");
+    assert_eq!(keys[0].size, value.len());
+
+    backend.delete_raw("Promotional_Codes").await.unwrap();
+    assert_eq!(backend.get_raw("Promotional_Codes").await.unwrap(), None);
+  }
+
+  #[actix_rt::test]
+  async fn expires_the_keys_after_the_ttl() {
+    let backend = match test_backend("ttl", Some(60)) {
+      Some(backend) => backend,
+      None => return,
+    };
+
+    backend.set_raw("Promotional_Codes", b"{}").await.unwrap();
+    let mut con = backend.connection().await.unwrap();
+    let ttl: i64 = con.ttl(backend.key("Promotional_Codes")).await.unwrap();
+    assert!(ttl > 0 && ttl <= 60);
+    backend.delete_raw("Promotional_Codes").await.unwrap();
+  }
+}
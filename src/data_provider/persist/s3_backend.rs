@@ -0,0 +1,277 @@
+use super::{
+  file_name, key_from_file_stem, legacy_file_name, DataPersistError, PersistBackend, Result,
+  StoredKey,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use log::warn;
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{
+  DeleteObjectRequest, GetObjectError, GetObjectRequest, ListObjectsV2Request, PutObjectRequest,
+  S3Client, S3,
+};
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+
+const MAX_RETRIES: u32 = 4;
+const RETRY_BASE_DELAY: u64 = 200;
+
+// One JSON object per resource under `<prefix>/`, for platforms with ephemeral
+// disks. A custom endpoint makes it work with any S3-compatible storage, ex: MinIO
+pub struct S3Backend {
+  client: S3Client,
+  bucket: String,
+  prefix: String,
+}
+
+impl S3Backend {
+  pub fn new(region: Region, bucket: String, prefix: String) -> S3Backend {
+    S3Backend {
+      client: S3Client::new(region),
+      bucket,
+      prefix,
+    }
+  }
+
+  // Credentials come from the usual AWS_ACCESS_KEY_ID/AWS_SECRET_ACCESS_KEY, S3_ENDPOINT
+  // overrides the AWS endpoint for the S3_REGION
+  pub fn from_env() -> S3Backend {
+    let name = env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".into());
+    let region = match env::var("S3_ENDPOINT") {
+      Ok(endpoint) => Region::Custom { name, endpoint },
+      Err(_) => name.parse().unwrap_or_default(),
+    };
+
+    S3Backend::new(
+      region,
+      env::var("S3_BUCKET").unwrap_or_default(),
+      env::var("S3_PREFIX").unwrap_or_else(|_| "mona_spy".into()),
+    )
+  }
+
+  fn key(&self, key: &str) -> String {
+    self.prefix.to_owned() + "/" + &file_name(key)
+  }
+
+  fn legacy_key(&self, key: &str) -> Option<String> {
+    legacy_file_name(key).map(|name| self.prefix.to_owned() + "/" + &name)
+  }
+
+  async fn get_object(&self, key: String) -> Result<Option<Vec<u8>>> {
+    let request = GetObjectRequest {
+      bucket: self.bucket.to_owned(),
+      key,
+      ..Default::default()
+    };
+
+    let output = match with_retry(|| self.client.get_object(request.clone())).await {
+      Ok(output) => output,
+      Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(None),
+      // Some S3-compatible servers answer a missing key with a bare 404
+      Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => return Ok(None),
+      Err(err) => return Err(s3_error(err)),
+    };
+
+    match output.body {
+      Some(body) => Ok(Some(body.map_ok(|x| x.to_vec()).try_concat().await?)),
+      None => Ok(Some(vec![])),
+    }
+  }
+
+  // S3 answers a missing key like a deleted one
+  async fn delete_object(&self, key: String) -> Result<()> {
+    let request = DeleteObjectRequest {
+      bucket: self.bucket.to_owned(),
+      key,
+      ..Default::default()
+    };
+
+    with_retry(|| self.client.delete_object(request.clone()))
+      .await
+      .map_err(s3_error)?;
+    Ok(())
+  }
+}
+
+fn is_throttled<E>(err: &RusotoError<E>) -> bool {
+  match err {
+    RusotoError::Unknown(response) => matches!(response.status.as_u16(), 429 | 503),
+    _ => false,
+  }
+}
+
+// Throttled requests (429 or S3's 503 SlowDown) are retried with exponential backoff
+async fn with_retry<T, E, F, Fut>(mut request: F) -> std::result::Result<T, RusotoError<E>>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = std::result::Result<T, RusotoError<E>>>,
+{
+  let mut attempts = 0;
+  loop {
+    match request().await {
+      Err(err) if is_throttled(&err) && attempts < MAX_RETRIES => {
+        let delay = RETRY_BASE_DELAY << attempts;
+        warn!("S3 request throttled, retrying in {}ms", delay);
+        actix_rt::time::delay_for(Duration::from_millis(delay)).await;
+        attempts += 1;
+      }
+      result => return result,
+    }
+  }
+}
+
+fn s3_error<E: std::error::Error + 'static>(err: RusotoError<E>) -> DataPersistError {
+  DataPersistError::S3Error(err.to_string())
+}
+
+// A value still under its legacy object name is read from there until it's written
+#[async_trait]
+impl PersistBackend for S3Backend {
+  async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    match (self.get_object(self.key(key)).await?, self.legacy_key(key)) {
+      (None, Some(legacy_key)) => self.get_object(legacy_key).await,
+      (data, _) => Ok(data),
+    }
+  }
+
+  async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()> {
+    let request = || PutObjectRequest {
+      bucket: self.bucket.to_owned(),
+      key: self.key(key),
+      body: Some(value.to_vec().into()),
+      content_type: Some("application/json".into()),
+      ..Default::default()
+    };
+
+    with_retry(|| self.client.put_object(request()))
+      .await
+      .map_err(s3_error)?;
+    match self.legacy_key(key) {
+      Some(legacy_key) => self.delete_object(legacy_key).await,
+      None => Ok(()),
+    }
+  }
+
+  async fn delete_raw(&self, key: &str) -> Result<()> {
+    self.delete_object(self.key(key)).await?;
+    match self.legacy_key(key) {
+      Some(legacy_key) => self.delete_object(legacy_key).await,
+      None => Ok(()),
+    }
+  }
+
+  // Pages through the objects under the prefix, a page holds up to 1000 of them. The
+  // legacy object names, which can't be read back, are listed as they are.
+  async fn list_keys(&self) -> Result<Vec<StoredKey>> {
+    let prefix = self.prefix.to_owned() + "/";
+    let mut keys = vec![];
+    let mut continuation_token = None;
+
+    loop {
+      let request = ListObjectsV2Request {
+        bucket: self.bucket.to_owned(),
+        prefix: Some(prefix.to_owned()),
+        continuation_token: continuation_token.to_owned(),
+        ..Default::default()
+      };
+      let output = with_retry(|| self.client.list_objects_v2(request.clone()))
+        .await
+        .map_err(s3_error)?;
+
+      for object in output.contents.unwrap_or_default() {
+        let name = object.key.unwrap_or_default();
+        let key = match name[prefix.len()..].strip_suffix(".json") {
+          Some(stem) => key_from_file_stem(stem).unwrap_or_else(|| stem.to_owned()),
+          None => continue,
+        };
+        keys.push(StoredKey {
+          key,
+          fetched_at: object
+            .last_modified
+            .and_then(|x| x.parse::<DateTime<Utc>>().ok()),
+          size: object.size.unwrap_or_default() as usize,
+        });
+      }
+
+      continuation_token = output.next_continuation_token;
+      if continuation_token.is_none() {
+        break;
+      }
+    }
+    Ok(keys)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rusoto_core::request::BufferedHttpResponse;
+  use std::convert::TryFrom;
+
+  fn response<E>(status: u16) -> RusotoError<E> {
+    RusotoError::Unknown(BufferedHttpResponse {
+      status: TryFrom::try_from(status).unwrap(),
+      body: Default::default(),
+      headers: Default::default(),
+    })
+  }
+
+  #[actix_rt::test]
+  async fn retries_throttled_requests() {
+    let mut attempts = 0;
+    let result: std::result::Result<u32, RusotoError<GetObjectError>> = with_retry(|| {
+      attempts += 1;
+      let result = match attempts {
+        1 => Err(response(503)),
+        2 => Err(response(429)),
+        attempts => Ok(attempts),
+      };
+      async move { result }
+    })
+    .await;
+    assert_eq!(result.unwrap(), 3);
+  }
+
+  #[actix_rt::test]
+  async fn gives_up_on_other_errors() {
+    let mut attempts = 0;
+    let result: std::result::Result<(), RusotoError<GetObjectError>> = with_retry(|| {
+      attempts += 1;
+      async { Err(response(403)) }
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+  }
+
+  // Runs against an S3-compatible server, ex: MinIO, at TEST_S3_ENDPOINT with the
+  // bucket TEST_S3_BUCKET. Skipped without them.
+  #[actix_rt::test]
+  async fn round_trips_values() {
+    let (endpoint, bucket) = match (env::var("TEST_S3_ENDPOINT"), env::var("TEST_S3_BUCKET")) {
+      (Ok(endpoint), Ok(bucket)) => (endpoint, bucket),
+      _ => return,
+    };
+    let region = Region::Custom {
+      name: "us-east-1".to_owned(),
+      endpoint,
+    };
+    let prefix = format!("mona_spy-test-{}", std::process::id());
+    let backend = S3Backend::new(region, bucket, prefix);
+    let key = "genshin-impact.fandom.com/en/Promotional_Codes";
+
+    backend.set_raw(key, br#"{"codes":[]}"#).await.unwrap();
+    assert_eq!(
+      backend.get_raw(key).await.unwrap(),
+      Some(br#"{"codes":[]}"#.to_vec())
+    );
+    let keys = backend.list_keys().await.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].key, key);
+
+    backend.delete_raw(key).await.unwrap();
+    assert_eq!(backend.get_raw(key).await.unwrap(), None);
+  }
+}
@@ -0,0 +1,285 @@
+use super::{DataPersistError, PersistBackend, Result, StoredKey};
+use actix_web::error::BlockingError;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_HISTORY_KEEP: usize = 100;
+
+// Keeps every snapshot of the resources instead of overwriting, get returns the
+// latest one. The other keys, ex: the notifier outbox or the keys index, only keep
+// their latest row. All the queries go through a single connection guarded by a
+// mutex, on the thread pool since rusqlite blocks.
+pub struct SqliteBackend {
+  connection: Arc<Mutex<Connection>>,
+  history_keep: usize,
+}
+
+// The resources are namespaced, ex: "<host>/<lang>/Promotional_Codes", their
+// snapshots and locks are suffixed with "@"
+fn keeps_history(key: &str) -> bool {
+  key.contains('/') && !key.contains('@')
+}
+
+impl SqliteBackend {
+  pub fn new(path: &str, history_keep: usize) -> Result<SqliteBackend> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch(
+      "CREATE TABLE IF NOT EXISTS resources (
+        key TEXT NOT NULL,
+        fetched_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+        body TEXT NOT NULL
+      );
+      CREATE INDEX IF NOT EXISTS resources_key ON resources (key, fetched_at);
+      CREATE TABLE IF NOT EXISTS locks (
+        key TEXT PRIMARY KEY,
+        token TEXT NOT NULL,
+        expires_at INTEGER NOT NULL
+      );",
+    )?;
+
+    Ok(SqliteBackend {
+      connection: Arc::new(Mutex::new(connection)),
+      history_keep,
+    })
+  }
+
+  // SQLITE_HISTORY_KEEP bounds the snapshots kept per resource, defaults to 100
+  pub fn from_env() -> Result<SqliteBackend> {
+    SqliteBackend::new(
+      &env::var("SQLITE_PATH").unwrap_or_else(|_| "mona_spy.sqlite".into()),
+      env::var("SQLITE_HISTORY_KEEP")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .filter(|&x| x > 0)
+        .unwrap_or(DEFAULT_HISTORY_KEEP),
+    )
+  }
+
+  async fn run<T, F>(&self, query: F) -> Result<T>
+  where
+    F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+  {
+    let connection = self.connection.clone();
+    actix_web::web::block(move || query(&*connection.lock().unwrap()))
+      .await
+      .map_err(|err| match err {
+        BlockingError::Error(err) => err,
+        BlockingError::Canceled => DataPersistError::IoError(std::io::Error::new(
+          std::io::ErrorKind::Interrupted,
+          "The SQLite query was canceled",
+        )),
+      })
+  }
+}
+
+// Deletes all but the `keep` latest snapshots of every resource and reclaims the space
+fn prune(connection: &Connection, keep: usize) -> Result<()> {
+  connection.execute(
+    "DELETE FROM resources WHERE rowid IN (
+        SELECT rowid FROM (
+          SELECT rowid, ROW_NUMBER() OVER (
+            PARTITION BY key ORDER BY fetched_at DESC, rowid DESC
+          ) AS position FROM resources
+        ) WHERE position > ?1
+      )",
+    params![keep as i64],
+  )?;
+  connection.execute_batch("VACUUM")?;
+  Ok(())
+}
+
+#[async_trait]
+impl PersistBackend for SqliteBackend {
+  async fn get_raw(&self, key: &str) -> Result<Option<Vec<u8>>> {
+    let key = key.to_owned();
+    self
+      .run(move |connection| {
+        let body = connection
+          .query_row(
+            "SELECT body FROM resources WHERE key = ?1
+              ORDER BY fetched_at DESC, rowid DESC LIMIT 1",
+            params![key],
+            |row| row.get(0),
+          )
+          .optional()?;
+        Ok(body)
+      })
+      .await
+  }
+
+  async fn set_raw(&self, key: &str, value: &[u8]) -> Result<()> {
+    let key = key.to_owned();
+    let value = value.to_vec();
+    self
+      .run(move |connection| {
+        let transaction = connection.unchecked_transaction()?;
+        if !keeps_history(&key) {
+          transaction.execute("DELETE FROM resources WHERE key = ?1", params![key])?;
+        }
+        transaction.execute(
+          "INSERT INTO resources (key, body) VALUES (?1, ?2)",
+          params![key, value],
+        )?;
+        transaction.commit()?;
+        Ok(())
+      })
+      .await
+  }
+
+  // Every snapshot of the key goes
+  async fn delete_raw(&self, key: &str) -> Result<()> {
+    let key = key.to_owned();
+    self
+      .run(move |connection| {
+        connection.execute("DELETE FROM resources WHERE key = ?1", params![key])?;
+        Ok(())
+      })
+      .await
+  }
+
+  // SQLite takes the bare columns from the row holding the MAX, so the size is the
+  // one of the latest snapshot
+  async fn list_keys(&self) -> Result<Vec<StoredKey>> {
+    self
+      .run(|connection| {
+        let mut statement = connection
+          .prepare("SELECT key, MAX(fetched_at), LENGTH(body) FROM resources GROUP BY key")?;
+        let rows = statement.query_map(params![], |row| {
+          let fetched_at: String = row.get(1)?;
+          let size: i64 = row.get(2)?;
+          Ok(StoredKey {
+            key: row.get(0)?,
+            fetched_at: fetched_at.parse::<DateTime<Utc>>().ok(),
+            size: size as usize,
+          })
+        })?;
+        let keys = rows.collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+      })
+      .await
+  }
+
+  async fn compact(&self) -> Result<()> {
+    let keep = self.history_keep;
+    self.run(move |connection| prune(connection, keep)).await
+  }
+
+  // Takes the row over only when the previous holder's lock expired
+  async fn try_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+    let key = key.to_owned();
+    let token = token.to_owned();
+    self
+      .run(move |connection| {
+        let now = Utc::now().timestamp_millis();
+        let changed = connection.execute(
+          "INSERT INTO locks (key, token, expires_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT (key) DO UPDATE SET token = excluded.token, expires_at = excluded.expires_at
+            WHERE locks.expires_at < ?4",
+          params![key, token, now + ttl.as_millis() as i64, now],
+        )?;
+        Ok(changed == 1)
+      })
+      .await
+  }
+
+  async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+    let key = key.to_owned();
+    let token = token.to_owned();
+    self
+      .run(move |connection| {
+        connection.execute(
+          "DELETE FROM locks WHERE key = ?1 AND token = ?2",
+          params![key, token],
+        )?;
+        Ok(())
+      })
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const RESOURCE: &str = "genshin-impact.fandom.com/en/Promotional_Codes";
+
+  async fn rows(backend: &SqliteBackend, key: &str) -> i64 {
+    let key = key.to_owned();
+    backend
+      .run(move |connection| {
+        let count = connection.query_row(
+          "SELECT COUNT(*) FROM resources WHERE key = ?1",
+          params![key],
+          |row| row.get(0),
+        )?;
+        Ok(count)
+      })
+      .await
+      .unwrap()
+  }
+
+  #[actix_rt::test]
+  async fn keeps_every_snapshot_of_a_resource() {
+    let backend = SqliteBackend::new(":memory:", DEFAULT_HISTORY_KEEP).unwrap();
+    backend.set_raw(RESOURCE, br#"{"codes":[]}"#).await.unwrap();
+    backend
+      .set_raw(RESOURCE, br#"{"codes":[1]}"#)
+      .await
+      .unwrap();
+
+    assert_eq!(rows(&backend, RESOURCE).await, 2);
+    assert_eq!(
+      backend.get_raw(RESOURCE).await.unwrap(),
+      Some(br#"{"codes":[1]}"#.to_vec())
+    );
+  }
+
+  #[actix_rt::test]
+  async fn keeps_only_the_latest_row_of_other_keys() {
+    let backend = SqliteBackend::new(":memory:", DEFAULT_HISTORY_KEEP).unwrap();
+    backend.set_raw("mona_spy@keys", b"[]").await.unwrap();
+    backend.set_raw("mona_spy@keys", b"[\"a\"]").await.unwrap();
+
+    assert_eq!(rows(&backend, "mona_spy@keys").await, 1);
+    assert_eq!(
+      backend.get_raw("mona_spy@keys").await.unwrap(),
+      Some(b"[\"a\"]".to_vec())
+    );
+  }
+
+  #[actix_rt::test]
+  async fn compact_keeps_the_latest_snapshots() {
+    let backend = SqliteBackend::new(":memory:", 2).unwrap();
+    for body in &["1", "2", "3"] {
+      backend.set_raw(RESOURCE, body.as_bytes()).await.unwrap();
+    }
+
+    backend.compact().await.unwrap();
+    assert_eq!(rows(&backend, RESOURCE).await, 2);
+    assert_eq!(
+      backend.get_raw(RESOURCE).await.unwrap(),
+      Some(b"3".to_vec())
+    );
+  }
+
+  #[actix_rt::test]
+  async fn lists_the_stored_resources() {
+    let backend = SqliteBackend::new(":memory:", DEFAULT_HISTORY_KEEP).unwrap();
+    let before = Utc::now();
+    backend.set_raw(RESOURCE, br#"{"codes":[]}"#).await.unwrap();
+    backend.set_raw("other/Resource", b"[1,2,3]").await.unwrap();
+
+    let mut keys = backend.list_keys().await.unwrap();
+    keys.sort_by(|a, b| a.key.cmp(&b.key));
+    let listed: Vec<(&str, usize)> = keys.iter().map(|x| (x.key.as_str(), x.size)).collect();
+    assert_eq!(listed, vec![(RESOURCE, 12), ("other/Resource", 7)]);
+    // fetched_at is stored with millisecond precision
+    let before = before - chrono::Duration::milliseconds(1);
+    assert!(keys.iter().all(|x| x.fetched_at.unwrap() >= before));
+  }
+}
@@ -1,14 +1,22 @@
+mod webhooks;
+
+pub use webhooks::{add_webhook, remove_webhook, webhooks, WebhookSubscription, WebhookSummary};
+
 use super::persist;
 use crate::interface::SubscribeBody;
 
 use actix_web::http::StatusCode;
+use async_std::sync::Mutex;
 use derive_more::{Display, Error};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::Value;
 use std::cmp;
 use std::collections::HashMap;
 use std::hash::Hash;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(PartialEq, Eq, Hash, Serialize, Deserialize)]
 struct Subscrition {
@@ -32,6 +40,7 @@ pub struct PushResponse {
 }
 
 #[derive(Debug, Display, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum SubscritionError {
   #[display(fmt = "Error: The provided URL didn't respond the request with the provided ID")]
   SyncError(reqwest::Error),
@@ -49,10 +58,7 @@ impl actix_web::error::ResponseError for SubscritionError {
 
 type Result<T> = std::result::Result<T, SubscritionError>;
 
-pub async fn notify<T: Serialize + Clone>(resource: &T) -> Result<()>
-where
-  T: std::fmt::Debug,
-{
+pub async fn notify(resource: &Value, resource_type: &str) -> Result<()> {
   let subscriptions: HashMap<String, Subscrition> = match persist::get().await {
     Some(subscription) => subscription,
     None => return Ok(()),
@@ -60,38 +66,140 @@ where
 
   for (id, subscription) in subscriptions {
     let body = PushBody {
-      id,
+      id: id.to_owned(),
       token: subscription.token,
       resource: Some(resource.clone()),
-      resource_type: Some(std::any::type_name::<T>().to_owned()),
+      resource_type: Some(resource_type.to_owned()),
       expiration: subscription.expiration,
     };
+    let body =
+      serde_json::to_value(&body).map_err(|err| SubscritionError::DataPersistError(err.into()))?;
 
-    let client = reqwest::Client::new();
-    let resp = client
-      .post(subscription.uri.as_str())
-      .json(&body)
-      .send()
-      .await;
-
-    match resp {
-      Ok(resp) => match resp.status() {
-        reqwest::StatusCode::OK => {}
-        code => println!(
-          ">>> PushNotificationError for {} <<<\nStatus Code {}\n{:?}\n>>> End <<<",
-          &subscription.uri, &code, &resp
-        ),
-      },
-      err => println!(
-        ">>> PushNotificationError for {} <<<\n{:?}\n>>> End <<<",
-        &subscription.uri, err
-      ),
-    };
+    if !push(&subscription.uri, &body).await {
+      let created_at = now();
+      enqueue(PendingNotification {
+        id,
+        uri: subscription.uri,
+        body,
+        attempts: 1,
+        created_at,
+        next_attempt_at: created_at + RETRY_BASE_DELAY,
+      })
+      .await?;
+    }
   }
 
   Ok(())
 }
 
+async fn push(uri: &str, body: &Value) -> bool {
+  let client = reqwest::Client::new();
+  let resp = client.post(uri).json(body).send().await;
+
+  match resp {
+    Ok(resp) => match resp.status() {
+      reqwest::StatusCode::OK => return true,
+      code => warn!(
+        "PushNotificationError for {}: Status Code {} {:?}",
+        uri, &code, &resp
+      ),
+    },
+    err => warn!("PushNotificationError for {}: {:?}", uri, err),
+  };
+  false
+}
+
+// Failed notifications are kept in persist and retried with exponential backoff,
+// so a network blip on the subscriber side doesn't lose an update
+#[derive(Serialize, Deserialize, Clone)]
+struct PendingNotification {
+  id: String,
+  uri: String,
+  body: Value,
+  attempts: u32,
+  created_at: u64,
+  next_attempt_at: u64,
+}
+
+const RETRY_BASE_DELAY: u64 = 60;
+const RETRY_MAX_DELAY: u64 = 3600;
+const RETRY_MAX_AGE: u64 = 24 * 3600;
+const RETRY_INTERVAL: u64 = 30;
+
+// Serializes the read-modify-write of the pending queue within this process, a
+// retry holds it throughout so the notifications enqueued meanwhile aren't lost
+static QUEUE: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap()
+    .as_secs()
+}
+
+async fn enqueue(notification: PendingNotification) -> Result<()> {
+  let _queue = QUEUE.lock().await;
+  let mut queue: Vec<PendingNotification> = persist::get().await.unwrap_or_default();
+  queue.push(notification);
+  persist::set(&queue)
+    .await
+    .map_err(SubscritionError::DataPersistError)
+}
+
+pub async fn retry_pending() -> Result<()> {
+  let _queue = QUEUE.lock().await;
+  let queue: Vec<PendingNotification> = match persist::get().await {
+    Some(queue) => queue,
+    None => return Ok(()),
+  };
+  if queue.is_empty() {
+    return Ok(());
+  }
+
+  let mut remaining: Vec<PendingNotification> = Vec::new();
+  for mut notification in queue {
+    let now = now();
+    if notification.next_attempt_at > now {
+      remaining.push(notification);
+      continue;
+    }
+
+    if push(&notification.uri, &notification.body).await {
+      info!(
+        "Delivered pending notification {} after {} attempts",
+        notification.id, notification.attempts
+      );
+      continue;
+    }
+
+    if now - notification.created_at > RETRY_MAX_AGE {
+      error!(
+        "Giving up on notification {} for {} after {} attempts",
+        notification.id, notification.uri, notification.attempts
+      );
+      continue;
+    }
+
+    let delay = RETRY_BASE_DELAY << notification.attempts.min(6);
+    notification.attempts += 1;
+    notification.next_attempt_at = now + cmp::min(delay, RETRY_MAX_DELAY);
+    remaining.push(notification);
+  }
+
+  persist::set(&remaining)
+    .await
+    .map_err(SubscritionError::DataPersistError)
+}
+
+pub async fn retry_loop() {
+  loop {
+    actix_rt::time::delay_for(Duration::from_secs(RETRY_INTERVAL)).await;
+    if let Err(err) = retry_pending().await {
+      error!("Failed to retry pending notifications: {:?}", err);
+    }
+  }
+}
+
 pub async fn try_sync(subscribe_body: &SubscribeBody, expiration: u64) -> Result<()> {
   let body = PushBody::<()> {
     id: subscribe_body.id.to_owned(),
@@ -106,10 +214,10 @@ pub async fn try_sync(subscribe_body: &SubscribeBody, expiration: u64) -> Result
     .json(&body)
     .send()
     .await
-    .map_err(|err| SubscritionError::SyncError(err))?
+    .map_err(SubscritionError::SyncError)?
     .json()
     .await
-    .map_err(|err| SubscritionError::SyncError(err))?;
+    .map_err(SubscritionError::SyncError)?;
 
   if resp.id != subscribe_body.id {
     return Err(SubscritionError::DifferentIdSyncError);
@@ -132,10 +240,7 @@ pub async fn subscribe(body: SubscribeBody) -> Result<()> {
 
   try_sync(&body, expiration).await?;
 
-  let mut subscriptions: HashMap<String, Subscrition> = match persist::get().await {
-    Some(subscription) => subscription,
-    None => HashMap::new(),
-  };
+  let mut subscriptions: HashMap<String, Subscrition> = persist::get().await.unwrap_or_default();
 
   let id = body.id;
   let uri = body.uri;
@@ -164,3 +269,36 @@ pub async fn subscribe(body: SubscribeBody) -> Result<()> {
     Err(err) => Err(SubscritionError::DataPersistError(err)),
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::future;
+
+  fn pending(id: String) -> PendingNotification {
+    let created_at = now();
+    PendingNotification {
+      id,
+      uri: "http://127.0.0.1:9/unreachable".to_owned(),
+      body: Value::Null,
+      attempts: 1,
+      created_at,
+      next_attempt_at: created_at + RETRY_MAX_AGE,
+    }
+  }
+
+  #[actix_rt::test]
+  async fn keeps_every_notification_enqueued_concurrently() {
+    let ids: Vec<String> = (0..20).map(|x| format!("concurrent-{}", x)).collect();
+    let enqueued = ids.iter().map(|id| enqueue(pending(id.to_owned())));
+    for result in future::join_all(enqueued).await {
+      result.unwrap();
+    }
+    retry_pending().await.unwrap();
+
+    let queue: Vec<PendingNotification> = persist::get().await.unwrap();
+    for id in &ids {
+      assert!(queue.iter().any(|x| &x.id == id), "{} was lost", id);
+    }
+  }
+}
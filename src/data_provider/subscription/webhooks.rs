@@ -0,0 +1,135 @@
+use super::super::persist;
+use actix_web::http::StatusCode;
+use async_std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use derive_more::{Display, Error};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const WEBHOOKS_KEY: &str = "webhook_subscriptions";
+
+// Serializes the read-modify-write of the registry within this process
+static WRITE: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+// A webhook added through the API, delivered along the WEBHOOK_URLS ones
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookSubscription {
+  pub id: String,
+  pub url: String,
+  // Signs the payloads, see RegisteredWebhookNotifier
+  pub secret: Option<String>,
+  // Only the promotional codes of these servers, every code when empty
+  #[serde(default)]
+  pub servers: Vec<String>,
+  pub created_at: DateTime<Utc>,
+}
+
+// What the API shows of a subscription, the secret is never sent back
+#[derive(Debug, Serialize)]
+pub struct WebhookSummary {
+  pub id: String,
+  pub url: String,
+  pub servers: Vec<String>,
+  pub signed: bool,
+  pub created_at: DateTime<Utc>,
+}
+
+impl From<&WebhookSubscription> for WebhookSummary {
+  fn from(subscription: &WebhookSubscription) -> Self {
+    WebhookSummary {
+      id: subscription.id.to_owned(),
+      url: subscription.url.to_owned(),
+      servers: subscription.servers.to_owned(),
+      signed: subscription.secret.is_some(),
+      created_at: subscription.created_at,
+    }
+  }
+}
+
+#[derive(Debug, Display, Error)]
+pub enum WebhookError {
+  #[display(fmt = "Invalid webhook URL: {}", _0)]
+  InvalidUrl(#[error(not(source))] String),
+  #[display(fmt = "A subscription already exists for this URL")]
+  Duplicate,
+  #[display(fmt = "Unknown subscription")]
+  NotFound,
+  #[display(fmt = "The subscriptions weren't saved")]
+  DataPersistError(persist::DataPersistError),
+}
+
+impl actix_web::error::ResponseError for WebhookError {
+  fn status_code(&self) -> StatusCode {
+    match self {
+      WebhookError::InvalidUrl(_) => StatusCode::BAD_REQUEST,
+      WebhookError::Duplicate => StatusCode::CONFLICT,
+      WebhookError::NotFound => StatusCode::NOT_FOUND,
+      WebhookError::DataPersistError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+}
+
+type Result<T> = std::result::Result<T, WebhookError>;
+
+pub async fn webhooks() -> Vec<WebhookSubscription> {
+  persist::get_by_key(WEBHOOKS_KEY).await.unwrap_or_default()
+}
+
+async fn save(webhooks: &[WebhookSubscription]) -> Result<()> {
+  persist::set_by_key(WEBHOOKS_KEY, &webhooks)
+    .await
+    .map_err(WebhookError::DataPersistError)
+}
+
+// Only http(s) URLs, compared once parsed so "HTTPS://Host" is a duplicate of
+// "https://host"
+fn parse_url(url: &str) -> Result<String> {
+  let parsed = reqwest::Url::parse(url.trim())
+    .map_err(|err| WebhookError::InvalidUrl(format!("{}, {}", url, err)))?;
+  match parsed.scheme() {
+    "http" | "https" if parsed.host().is_some() => Ok(parsed.to_string()),
+    _ => Err(WebhookError::InvalidUrl(url.to_owned())),
+  }
+}
+
+pub async fn add_webhook(
+  url: &str,
+  secret: Option<String>,
+  servers: Vec<String>,
+) -> Result<WebhookSubscription> {
+  let url = parse_url(url)?;
+  let _write = WRITE.lock().await;
+  let mut subscriptions = webhooks().await;
+  if subscriptions.iter().any(|x| x.url == url) {
+    return Err(WebhookError::Duplicate);
+  }
+
+  let created_at = Utc::now();
+  let hash = Sha256::digest(format!("{}{}", url, created_at.to_rfc3339()).as_bytes());
+  let subscription = WebhookSubscription {
+    id: hash.iter().take(8).map(|x| format!("{:02x}", x)).collect(),
+    url,
+    secret: secret.filter(|x| !x.is_empty()),
+    servers: servers
+      .into_iter()
+      .map(|x| x.trim().to_owned())
+      .filter(|x| !x.is_empty())
+      .collect(),
+    created_at,
+  };
+  subscriptions.push(subscription.clone());
+  save(&subscriptions).await?;
+  Ok(subscription)
+}
+
+pub async fn remove_webhook(id: &str) -> Result<()> {
+  let _write = WRITE.lock().await;
+  let mut subscriptions = webhooks().await;
+  let count = subscriptions.len();
+  subscriptions.retain(|x| x.id != id);
+  if subscriptions.len() == count {
+    return Err(WebhookError::NotFound);
+  }
+  save(&subscriptions).await
+}
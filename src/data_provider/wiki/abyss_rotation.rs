@@ -0,0 +1,248 @@
+use super::super::persist::DataPersistError;
+use super::{
+  get_cell_content, get_cell_content_as_string, get_cell_parts, migrate_unnormalized,
+  normalize_value, CellPart, Diff, ResourceDiff, Versioned, WikiResource,
+};
+use parse_wiki_text::Node;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+// Sections whose prose describes the blessing of the current period
+const BLESSING_SECTIONS: [&str; 2] = ["Blessing", "Ley Line Disorder"];
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AbyssRotation {
+  blessing: Option<String>,
+  floors: BTreeMap<String, Vec<String>>,
+}
+
+// The blessing or the lineup of a floor, an entry of a Diff
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum AbyssEntry {
+  Blessing { blessing: String },
+  Floor { floor: String, enemies: Vec<String> },
+}
+
+// v2 normalizes the resource when it's parsed instead of when it's loaded
+impl Versioned for AbyssRotation {
+  const SCHEMA_VERSION: u32 = 2;
+
+  fn migrate(_version: u32, value: Value) -> Result<Self, DataPersistError> {
+    migrate_unnormalized(value)
+  }
+}
+
+fn is_blessing_section(heading: &str) -> bool {
+  BLESSING_SECTIONS.iter().any(|x| heading.contains(x))
+}
+
+// Ex: "Floor 12" or "Floor 12 (First Half)" become "Floor 12"
+fn floor_name(heading: &str) -> Option<String> {
+  let number: String = heading
+    .strip_prefix("Floor")?
+    .trim_start()
+    .chars()
+    .take_while(char::is_ascii_digit)
+    .collect();
+  match number.as_str() {
+    "" => None,
+    number => Some(format!("Floor {}", number)),
+  }
+}
+
+// Enemies are the linked pages of the floor tables, in order of first appearance
+fn table_enemies(node: &Node) -> Vec<String> {
+  let rows = match node {
+    Node::Table { rows, .. } => rows,
+    _ => return vec![],
+  };
+
+  let mut enemies: Vec<String> = Vec::new();
+  for part in rows
+    .iter()
+    .flat_map(|row| &row.cells)
+    .flat_map(|cell| get_cell_parts(&cell.content))
+  {
+    if let CellPart::Link { target, .. } = part {
+      if !enemies.contains(&target) {
+        enemies.push(target);
+      }
+    }
+  }
+  enemies
+}
+
+impl WikiResource for AbyssRotation {
+  type Item = AbyssEntry;
+
+  fn empty(&self) -> bool {
+    self.blessing.is_none() && self.floors.is_empty()
+  }
+
+  fn count(&self) -> usize {
+    self.floors.len()
+  }
+
+  fn normalize(self) -> Self {
+    let floors = self
+      .floors
+      .into_iter()
+      .map(|(floor, enemies)| (floor, enemies.iter().map(|x| normalize_value(x)).collect()))
+      .collect();
+
+    AbyssRotation {
+      blessing: self.blessing.map(|x| normalize_value(&x)),
+      floors,
+    }
+  }
+
+  fn diff(&self, previous: &Self) -> Diff<AbyssEntry> {
+    let floor = |(floor, enemies): (&String, &Vec<String>)| AbyssEntry::Floor {
+      floor: floor.to_owned(),
+      enemies: enemies.to_owned(),
+    };
+    let mut diff = Diff::default();
+    match (&self.blessing, &previous.blessing) {
+      (Some(blessing), None) => diff.added.push(AbyssEntry::Blessing {
+        blessing: blessing.to_owned(),
+      }),
+      (None, Some(blessing)) => diff.removed.push(AbyssEntry::Blessing {
+        blessing: blessing.to_owned(),
+      }),
+      (Some(blessing), Some(before)) if blessing != before => {
+        diff.modified.push(AbyssEntry::Blessing {
+          blessing: blessing.to_owned(),
+        });
+        diff.previous.push(AbyssEntry::Blessing {
+          blessing: before.to_owned(),
+        });
+      }
+      _ => {}
+    }
+    for (name, enemies) in &self.floors {
+      match previous.floors.get(name) {
+        None => diff.added.push(floor((name, enemies))),
+        Some(before) if before != enemies => {
+          diff.modified.push(floor((name, enemies)));
+          diff.previous.push(floor((name, before)));
+        }
+        Some(_) => {}
+      }
+    }
+    diff.removed.extend(
+      previous
+        .floors
+        .iter()
+        .filter(|(name, _)| !self.floors.contains_key(*name))
+        .map(floor),
+    );
+    diff
+  }
+
+  // The blessing is only kept when it changed, floors when their lineup did
+  fn difference(&self, other: &Self) -> Self {
+    let blessing = if self.blessing != other.blessing {
+      self.blessing.to_owned()
+    } else {
+      None
+    };
+
+    let floors = self
+      .floors
+      .iter()
+      .filter(|(floor, enemies)| other.floors.get(*floor) != Some(enemies))
+      .map(|(floor, enemies)| (floor.to_owned(), enemies.to_owned()))
+      .collect();
+
+    AbyssRotation { blessing, floors }
+  }
+
+  fn from_entries(entries: Vec<AbyssEntry>) -> Self {
+    let mut rotation = AbyssRotation {
+      blessing: None,
+      floors: BTreeMap::new(),
+    };
+    for entry in entries {
+      match entry {
+        AbyssEntry::Blessing { blessing } => rotation.blessing = Some(blessing),
+        AbyssEntry::Floor { floor, enemies } => {
+          rotation.floors.insert(floor, enemies);
+        }
+      }
+    }
+    rotation
+  }
+
+  // The first blessing found is kept, the lineups of a floor listed on several
+  // pages are combined like the tables of a floor in from
+  fn merge(mut self, other: Self) -> Self {
+    self.blessing = self.blessing.or(other.blessing);
+    for (floor, enemies) in other.floors {
+      let merged = self.floors.entry(floor).or_default();
+      for enemy in enemies {
+        if !merged.contains(&enemy) {
+          merged.push(enemy);
+        }
+      }
+    }
+    self
+  }
+
+  // The blessing is prose under its heading, the lineups are tables under a
+  // heading per floor
+  fn from(nodes: &[Node]) -> Self {
+    let mut blessing = String::new();
+    let mut floors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut heading = String::new();
+
+    for node in nodes {
+      match node {
+        Node::Heading { nodes, .. } => heading = get_cell_content_as_string(nodes),
+        Node::Table { .. } => {
+          if let Some(floor) = floor_name(&heading) {
+            let enemies = floors.entry(floor).or_default();
+            for enemy in table_enemies(node) {
+              if !enemies.contains(&enemy) {
+                enemies.push(enemy);
+              }
+            }
+          }
+        }
+        Node::ParagraphBreak { .. } if is_blessing_section(&heading) => blessing.push(' '),
+        node if is_blessing_section(&heading) => {
+          blessing.push_str(&get_cell_content(std::slice::from_ref(node)).concat());
+        }
+        _ => {}
+      }
+    }
+
+    let blessing = normalize_value(&blessing);
+    AbyssRotation {
+      blessing: Some(blessing).filter(|x| !x.is_empty()),
+      floors,
+    }
+  }
+
+  // Ex: "- Blessing: ..." followed by "- Floor 12: Ruin Guard, ..."
+  fn summarize_diff(diff: &ResourceDiff<Self>) -> String {
+    let blessing = diff
+      .added
+      .blessing
+      .iter()
+      .map(|x| format!("- Blessing: {}", x));
+    let floors = diff
+      .added
+      .floors
+      .iter()
+      .map(|(floor, enemies)| format!("- {}: {}", floor, enemies.join(", ")));
+
+    let lines: Vec<String> = blessing.chain(floors).collect();
+    format!("Spiral Abyss rotation updated:\n{}", lines.join("\n"))
+  }
+
+  fn get_title() -> &'static str {
+    "Spiral_Abyss/Floors"
+  }
+}
@@ -0,0 +1,172 @@
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::env;
+use std::sync::Mutex;
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_COOLDOWN_SECONDS: i64 = 300;
+
+// Guards every resource update, configurable through WIKI_BREAKER_FAILURES and
+// WIKI_BREAKER_COOLDOWN (seconds)
+pub static BREAKER: Lazy<CircuitBreaker> = Lazy::new(|| {
+  let env_number = |name| env::var(name).ok().and_then(|x| x.parse().ok());
+  CircuitBreaker::new(
+    env_number("WIKI_BREAKER_FAILURES")
+      .filter(|&x: &i64| x > 0)
+      .map_or(DEFAULT_FAILURE_THRESHOLD, |x| x as u32),
+    Duration::seconds(
+      env_number("WIKI_BREAKER_COOLDOWN")
+        .filter(|&x| x > 0)
+        .unwrap_or(DEFAULT_COOLDOWN_SECONDS),
+    ),
+  )
+});
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+  Closed,
+  // Fetches are skipped until the cool-down is over
+  Open,
+  // A single fetch is let through to test whether the wiki is back
+  HalfOpen,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct BreakerStatus {
+  pub state: BreakerState,
+  pub consecutive_failures: u32,
+  pub retry_at: Option<DateTime<Utc>>,
+}
+
+struct Circuit {
+  state: BreakerState,
+  consecutive_failures: u32,
+  opened_at: DateTime<Utc>,
+}
+
+// Opens after `threshold` consecutive failures, so a down wiki isn't polled on
+// every interval. Once the cool-down is over it half-opens, the trial fetch closes
+// it again or reopens it for another cool-down.
+pub struct CircuitBreaker {
+  threshold: u32,
+  cooldown: Duration,
+  circuit: Mutex<Circuit>,
+}
+
+impl CircuitBreaker {
+  pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+    CircuitBreaker {
+      threshold,
+      cooldown,
+      circuit: Mutex::new(Circuit {
+        state: BreakerState::Closed,
+        consecutive_failures: 0,
+        opened_at: Utc::now(),
+      }),
+    }
+  }
+
+  // Whether a fetch may go out, its outcome must be handed to `record`
+  pub fn allow(&self) -> bool {
+    let mut circuit = self.circuit.lock().unwrap();
+    match circuit.state {
+      BreakerState::Closed => true,
+      BreakerState::Open if Utc::now() >= circuit.opened_at + self.cooldown => {
+        info!("Wiki circuit half-open, trying a fetch");
+        circuit.state = BreakerState::HalfOpen;
+        true
+      }
+      // The trial fetch is still running
+      BreakerState::Open | BreakerState::HalfOpen => false,
+    }
+  }
+
+  pub fn record(&self, success: bool) {
+    let mut circuit = self.circuit.lock().unwrap();
+    if success {
+      if circuit.state != BreakerState::Closed {
+        info!("Wiki circuit closed, fetches resumed");
+      }
+      circuit.state = BreakerState::Closed;
+      circuit.consecutive_failures = 0;
+      return;
+    }
+
+    circuit.consecutive_failures += 1;
+    let reopen = circuit.state == BreakerState::HalfOpen;
+    if reopen || circuit.consecutive_failures >= self.threshold {
+      if circuit.state != BreakerState::Open {
+        warn!(
+          "Wiki circuit open after {} consecutive failures, skipping fetches for {}s",
+          circuit.consecutive_failures,
+          self.cooldown.num_seconds()
+        );
+      }
+      circuit.state = BreakerState::Open;
+      circuit.opened_at = Utc::now();
+    }
+  }
+
+  pub fn status(&self) -> BreakerStatus {
+    let circuit = self.circuit.lock().unwrap();
+    BreakerStatus {
+      state: circuit.state,
+      consecutive_failures: circuit.consecutive_failures,
+      retry_at: Some(circuit.opened_at + self.cooldown)
+        .filter(|_| circuit.state == BreakerState::Open),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fail(breaker: &CircuitBreaker, times: u32) {
+    for _ in 0..times {
+      assert!(breaker.allow());
+      breaker.record(false);
+    }
+  }
+
+  #[test]
+  fn opens_after_consecutive_failures() {
+    let breaker = CircuitBreaker::new(3, Duration::hours(1));
+    fail(&breaker, 2);
+    breaker.record(true);
+    fail(&breaker, 2);
+    assert_eq!(breaker.status().state, BreakerState::Closed);
+
+    fail(&breaker, 1);
+    let status = breaker.status();
+    assert_eq!(status.state, BreakerState::Open);
+    assert_eq!(status.consecutive_failures, 3);
+    assert!(status.retry_at.unwrap() > Utc::now() + Duration::minutes(59));
+    assert!(!breaker.allow());
+  }
+
+  #[test]
+  fn half_opens_once_cooled_down() {
+    let breaker = CircuitBreaker::new(1, Duration::zero());
+    fail(&breaker, 1);
+    assert_eq!(breaker.status().state, BreakerState::Open);
+
+    // A single trial fetch at a time
+    assert!(breaker.allow());
+    assert_eq!(breaker.status().state, BreakerState::HalfOpen);
+    assert!(!breaker.allow());
+
+    // A failing trial reopens it, a successful one closes it
+    breaker.record(false);
+    assert_eq!(breaker.status().state, BreakerState::Open);
+    assert!(breaker.allow());
+    breaker.record(true);
+    let status = breaker.status();
+    assert_eq!(status.state, BreakerState::Closed);
+    assert_eq!(status.consecutive_failures, 0);
+    assert_eq!(status.retry_at, None);
+  }
+}
@@ -0,0 +1,192 @@
+use super::super::persist::DataPersistError;
+use super::{
+  get_cell_content, get_cell_parts, migrate_unnormalized, normalize_value, CellPart, Diff,
+  ResourceDiff, Versioned, WikiResource,
+};
+use parse_wiki_text::Node;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+const WEEKDAYS: [&str; 7] = [
+  "Monday",
+  "Tuesday",
+  "Wednesday",
+  "Thursday",
+  "Friday",
+  "Saturday",
+  "Sunday",
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MaterialSchedule {
+  days: BTreeMap<String, Vec<String>>,
+}
+
+// The materials farmable on a day, an entry of a Diff
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ScheduleDay {
+  pub day: String,
+  pub materials: Vec<String>,
+}
+
+// v2 normalizes the resource when it's parsed instead of when it's loaded
+impl Versioned for MaterialSchedule {
+  const SCHEMA_VERSION: u32 = 2;
+
+  fn migrate(_version: u32, value: Value) -> Result<Self, DataPersistError> {
+    migrate_unnormalized(value)
+  }
+}
+
+impl WikiResource for MaterialSchedule {
+  type Item = ScheduleDay;
+
+  fn empty(&self) -> bool {
+    self.days.is_empty()
+  }
+
+  fn count(&self) -> usize {
+    self.days.len()
+  }
+
+  fn normalize(self) -> Self {
+    let days = self
+      .days
+      .into_iter()
+      .map(|(day, materials)| {
+        let materials = materials.iter().map(|x| normalize_value(x)).collect();
+        (day, materials)
+      })
+      .collect();
+
+    MaterialSchedule { days }
+  }
+
+  fn diff(&self, previous: &Self) -> Diff<ScheduleDay> {
+    let entry = |(day, materials): (&String, &Vec<String>)| ScheduleDay {
+      day: day.to_owned(),
+      materials: materials.to_owned(),
+    };
+    let mut diff = Diff::default();
+    for (day, materials) in &self.days {
+      match previous.days.get(day) {
+        None => diff.added.push(entry((day, materials))),
+        Some(before) if before != materials => {
+          diff.modified.push(entry((day, materials)));
+          diff.previous.push(entry((day, before)));
+        }
+        Some(_) => {}
+      }
+    }
+    diff.removed = previous
+      .days
+      .iter()
+      .filter(|(day, _)| !self.days.contains_key(*day))
+      .map(entry)
+      .collect();
+    diff
+  }
+
+  fn difference(&self, other: &Self) -> Self {
+    let mut difference: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for (day, materials) in &self.days {
+      if other.days.get(day) != Some(materials) {
+        difference.insert(day.to_owned(), materials.to_owned());
+      }
+    }
+    MaterialSchedule { days: difference }
+  }
+
+  fn from_entries(entries: Vec<ScheduleDay>) -> Self {
+    let days = entries.into_iter().map(|x| (x.day, x.materials)).collect();
+    MaterialSchedule { days }
+  }
+
+  // The materials of a day listed on several pages are combined, in order
+  fn merge(mut self, other: Self) -> Self {
+    for (day, materials) in other.days {
+      let merged = self.days.entry(day).or_default();
+      for material in materials {
+        if !merged.contains(&material) {
+          merged.push(material);
+        }
+      }
+    }
+    self
+  }
+
+  fn from(nodes: &[Node]) -> Self {
+    let mut days: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for node in nodes {
+      let rows = match node {
+        Node::Table { rows, .. } => rows,
+        _ => continue,
+      };
+
+      for row in rows {
+        let mut cells = row.cells.iter();
+        let weekdays: Vec<&str> = match cells.next() {
+          Some(cell) => {
+            let header = get_cell_content(&cell.content).join("");
+            WEEKDAYS
+              .iter()
+              .copied()
+              .filter(|day| header.contains(day))
+              .collect()
+          }
+          None => continue,
+        };
+
+        // Column headers and separator rows don't name any day
+        if weekdays.is_empty() {
+          continue;
+        }
+
+        // Links point at the canonical material page, plain text is kept as written
+        let materials: Vec<String> = cells
+          .flat_map(|cell| get_cell_parts(&cell.content))
+          .map(|part| match part {
+            CellPart::Link { target, .. } => target,
+            CellPart::Text(text) => text,
+          })
+          .map(|x| {
+            x.trim_matches(|c: char| c.is_whitespace() || c == ',' || c == '/')
+              .to_owned()
+          })
+          .filter(|x| !x.is_empty())
+          .collect();
+
+        for day in weekdays {
+          days
+            .entry(day.to_owned())
+            .or_default()
+            .extend(materials.iter().cloned());
+        }
+      }
+
+      if !days.is_empty() {
+        break;
+      }
+    }
+
+    MaterialSchedule { days }
+  }
+
+  // Ex: "- Monday: Freedom, Prosperity"
+  fn summarize_diff(diff: &ResourceDiff<Self>) -> String {
+    let lines: Vec<String> = diff
+      .added
+      .days
+      .iter()
+      .map(|(day, materials)| format!("- {}: {}", day, materials.join(", ")))
+      .collect();
+    format!("Farming schedule updated:\n{}", lines.join("\n"))
+  }
+
+  fn get_title() -> &'static str {
+    "Farming_Schedule"
+  }
+}
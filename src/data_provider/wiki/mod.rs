@@ -1,27 +1,95 @@
-use super::subscription;
+use super::notifier;
+use super::notifier::{ChangeEvent, Notifier};
+pub mod abyss_rotation;
+mod circuit_breaker;
+pub mod material_schedule;
+mod parse_cache;
+mod parse_limit;
 pub mod promotional_codes;
+mod rate_limit;
+mod snapshot_file;
+mod transclusion;
+
+pub use circuit_breaker::{BreakerState, BreakerStatus};
+pub use snapshot_file::render_snapshot_diff;
 
 use super::persist;
+use super::persist::{Stored, Versioned};
 use actix_web::error;
-use parse_wiki_text::Node;
+use actix_web::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use parse_wiki_text::{Node, Parameter, TableCell, TableRow};
+use rate_limit::RateLimiter;
 use serde::Serialize;
 use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
 use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
 
 type Result<T> = std::result::Result<T, WikiError>;
 
 #[derive(Debug)]
-pub struct WikiError;
+pub enum WikiError {
+  FetchError,
+  ContentTooLarge(usize),
+  SchemaDrift(Vec<String>),
+  Busy,
+  CircuitOpen,
+  // The persisted resource is there but can't be read, ex: encrypted with another key
+  Unreadable,
+}
 
-impl error::ResponseError for WikiError {}
+impl error::ResponseError for WikiError {
+  fn status_code(&self) -> StatusCode {
+    match self {
+      WikiError::Busy | WikiError::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+}
 
 impl fmt::Display for WikiError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "Ocurred an Error during Wiki fetching")
+    match self {
+      WikiError::FetchError => write!(f, "Ocurred an Error during Wiki fetching"),
+      WikiError::ContentTooLarge(length) => {
+        write!(
+          f,
+          "Wiki content is too large to be parsed ({} bytes)",
+          length
+        )
+      }
+      WikiError::SchemaDrift(warnings) => {
+        write!(f, "Wiki table schema changed: {}", warnings.join(", "))
+      }
+      WikiError::Busy => write!(f, "The resource is being updated, try again later"),
+      WikiError::CircuitOpen => write!(f, "The wiki is failing, fetches are paused"),
+      WikiError::Unreadable => write!(f, "The persisted resource can't be read"),
+    }
+  }
+}
+
+const DEFAULT_MAX_CONTENT_LENGTH: usize = 2 * 1024 * 1024;
+
+// Protects the parser from pathological pages, configurable through MAX_CONTENT_LENGTH
+fn check_content_length(wiki_text: &str) -> Result<()> {
+  let max_length = env::var("MAX_CONTENT_LENGTH")
+    .ok()
+    .and_then(|x| x.parse().ok())
+    .unwrap_or(DEFAULT_MAX_CONTENT_LENGTH);
+
+  if wiki_text.len() > max_length {
+    return Err(WikiError::ContentTooLarge(wiki_text.len()));
   }
+  Ok(())
 }
 
-fn get_cell_content<'a>(nodes: &'a Vec<Node>) -> Vec<&'a str> {
+fn get_cell_content<'a>(nodes: &'a [Node]) -> Vec<&'a str> {
   let mut content: Vec<&str> = Vec::new();
   for node in nodes {
     match node {
@@ -31,6 +99,14 @@ fn get_cell_content<'a>(nodes: &'a Vec<Node>) -> Vec<&'a str> {
       Node::Link { text, .. } => {
         content.append(&mut get_cell_content(text));
       }
+      Node::Image { text, .. } => {
+        content.append(&mut get_image_content(text));
+      }
+      Node::Template {
+        name, parameters, ..
+      } if is_item_template(name) => {
+        content.append(&mut get_template_content(parameters));
+      }
       _ => {}
     };
   }
@@ -38,50 +114,1189 @@ fn get_cell_content<'a>(nodes: &'a Vec<Node>) -> Vec<&'a str> {
   content
 }
 
-fn get_cell_content_as_string(nodes: &Vec<Node>) -> String {
-  get_cell_content(nodes).join("")
+// Images only carry text through their options, ex: [[File:Primogem.png|20px|alt=Primogem]]
+fn get_image_content<'a>(nodes: &'a [Node]) -> Vec<&'a str> {
+  let options: Vec<&str> = get_cell_content(nodes)
+    .into_iter()
+    .flat_map(|option| option.split('|'))
+    .map(str::trim)
+    .collect();
+
+  if let Some(alt) = options
+    .iter()
+    .find_map(|option| option.strip_prefix("alt="))
+  {
+    return vec![alt];
+  }
+
+  let is_format_option = |option: &&str| {
+    option.is_empty()
+      || option.contains('=')
+      || option.ends_with("px")
+      || IMAGE_FORMAT_OPTIONS.contains(option)
+  };
+
+  match options.into_iter().rev().find(|x| !is_format_option(x)) {
+    Some(caption) => vec![caption],
+    None => vec![],
+  }
+}
+
+const IMAGE_FORMAT_OPTIONS: &[&str] = &[
+  "border",
+  "center",
+  "frame",
+  "frameless",
+  "left",
+  "none",
+  "right",
+  "thumb",
+  "thumbnail",
+  "upright",
+];
+
+const ITEM_TEMPLATES: &[&str] = &["Item", "Card", "Reward"];
+
+// Only item templates carry an amount, any other template is left out of the cell
+fn is_item_template(name: &[Node]) -> bool {
+  let name = get_cell_content_as_string(name);
+  let name = name.trim();
+  let name = name.strip_prefix("Template:").unwrap_or(name);
+  ITEM_TEMPLATES
+    .iter()
+    .any(|template| template.eq_ignore_ascii_case(name))
+}
+
+// Item templates, ex: {{Item|Primogem|x=60}} or {{Item|Primogem|60}}, become "Primogem ×60"
+fn get_template_content<'a>(parameters: &'a [Parameter]) -> Vec<&'a str> {
+  let mut content: Vec<&str> = Vec::new();
+  let mut positional = 0;
+
+  for parameter in parameters {
+    let is_amount = match &parameter.name {
+      None => {
+        positional += 1;
+        positional == 2
+      }
+      Some(name) => get_cell_content_as_string(name).trim() == "x",
+    };
+
+    if positional > 2 || (parameter.name.is_some() && !is_amount) {
+      continue;
+    }
+
+    if is_amount {
+      content.push(" ×");
+    }
+    content.append(&mut get_cell_content(&parameter.value));
+  }
+
+  content
+}
+
+fn get_cell_content_as_string(nodes: &[Node]) -> String {
+  let content: String = get_cell_parts(nodes).iter().map(CellPart::text).collect();
+  normalize_text(&content).trim().to_owned()
+}
+
+// Editors swap hyphens for dashes or spaces for NBSPs without changing the meaning,
+// so every value is normalized before it gets compared
+fn normalize_text(value: &str) -> String {
+  let mut normalized = String::with_capacity(value.len());
+  let mut previous_whitespace = false;
+
+  for c in value.chars() {
+    let c = match c {
+      '\u{00A0}' | '\u{2007}' | '\u{202F}' => ' ',
+      '\u{2010}' | '\u{2011}' | '\u{2012}' | '\u{2013}' | '\u{2014}' | '\u{2015}' | '\u{2212}' => {
+        '-'
+      }
+      '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{2032}' => '\'',
+      '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{2033}' => '"',
+      c => c,
+    };
+
+    if c.is_whitespace() {
+      if !previous_whitespace {
+        normalized.push(' ');
+      }
+      previous_whitespace = true;
+    } else {
+      normalized.push(c);
+      previous_whitespace = false;
+    }
+  }
+
+  normalized
+}
+
+fn normalize_value(value: &str) -> String {
+  normalize_text(value).trim().to_owned()
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CellPart {
+  Text(String),
+  Link { target: String, text: String },
+}
+
+impl CellPart {
+  pub fn text(&self) -> &str {
+    match self {
+      CellPart::Text(text) => text,
+      CellPart::Link { text, .. } => text,
+    }
+  }
+}
+
+// Like get_cell_content, but keeps the target page of links, ex: [[:Category:Mora|mora]]
+// becomes Link { target: "Category:Mora", text: "mora" }
+fn get_cell_parts(nodes: &[Node]) -> Vec<CellPart> {
+  let mut parts: Vec<CellPart> = Vec::new();
+  for node in nodes {
+    match node {
+      Node::Link { target, text, .. } => {
+        let target = normalize_value(target.trim_start_matches(':'));
+        let text = if text.is_empty() {
+          target.to_owned()
+        } else {
+          normalize_text(&get_cell_content(text).join(""))
+        };
+        parts.push(CellPart::Link { target, text });
+      }
+      node => {
+        let text = normalize_text(&get_cell_content(std::slice::from_ref(node)).join(""));
+        match parts.last_mut() {
+          Some(CellPart::Text(previous)) => previous.push_str(&text),
+          _ if text.is_empty() => {}
+          _ => parts.push(CellPart::Text(text)),
+        }
+      }
+    };
+  }
+
+  parts
+}
+
+// Reads rowspan/colspan from the cell attributes, ex: `rowspan="2" | All`
+fn cell_span(cell: &TableCell, name: &str) -> usize {
+  let attributes = match &cell.attributes {
+    Some(attributes) => get_cell_content(attributes).concat(),
+    None => return 1,
+  };
+
+  attributes
+    .split_whitespace()
+    .filter_map(|x| x.strip_prefix(name)?.strip_prefix('='))
+    .filter_map(|x| x.trim_matches(|c| c == '"' || c == '\'').parse().ok())
+    .next()
+    .filter(|&span| span > 0)
+    .unwrap_or(1)
+}
+
+// Lays the rows out as a grid with one entry per column, cells spanning several
+// rows or columns are repeated on each of them so the columns don't shift
+fn table_grid<'a>(rows: &'a [TableRow<'a>]) -> Vec<Vec<&'a TableCell<'a>>> {
+  // Cells still spanning into the next rows, with how many rows are left
+  let mut pending: Vec<Option<(&TableCell, usize)>> = vec![];
+  let mut grid = Vec::with_capacity(rows.len());
+
+  for row in rows {
+    let mut line = vec![];
+    let mut cells = row.cells.iter();
+
+    loop {
+      let column = line.len();
+      if let Some(Some((cell, remaining))) = pending.get(column).copied() {
+        pending[column] = if remaining > 1 {
+          Some((cell, remaining - 1))
+        } else {
+          None
+        };
+        line.push(cell);
+        continue;
+      }
+
+      let cell = match cells.next() {
+        Some(cell) => cell,
+        None => break,
+      };
+      let rowspan = cell_span(cell, "rowspan");
+      for _ in 0..cell_span(cell, "colspan") {
+        if rowspan > 1 {
+          if pending.len() <= line.len() {
+            pending.resize(line.len() + 1, None);
+          }
+          pending[line.len()] = Some((cell, rowspan - 1));
+        }
+        line.push(cell);
+      }
+    }
+    grid.push(line);
+  }
+
+  grid
+}
+
+// Nodes between the given heading and the next heading of the same or higher level,
+// subsections included. Empty when the heading is missing, first match wins.
+fn section_nodes<'a>(nodes: &'a [Node<'a>], heading: &str, level: u8) -> &'a [Node<'a>] {
+  let is_heading = |node: &Node| match node {
+    Node::Heading {
+      level: node_level,
+      nodes,
+      ..
+    } => *node_level == level && get_cell_content_as_string(nodes).trim() == heading,
+    _ => false,
+  };
+
+  let start = match nodes.iter().position(is_heading) {
+    Some(idx) => idx + 1,
+    None => return &[],
+  };
+
+  let end = nodes[start..]
+    .iter()
+    .position(|node| match node {
+      Node::Heading {
+        level: node_level, ..
+      } => *node_level <= level,
+      _ => false,
+    })
+    .map_or(nodes.len(), |idx| start + idx);
+
+  &nodes[start..end]
+}
+
+// Resources used to be normalized every time they were loaded, the ones persisted
+// before they were normalized when parsed are normalized once, when migrated
+fn migrate_unnormalized<T: WikiResource>(
+  value: Value,
+) -> std::result::Result<T, persist::DataPersistError> {
+  Ok(serde_json::from_value::<T>(value)?.normalize())
 }
 
 pub trait WikiResource:
-  Sized + Serialize + serde::de::DeserializeOwned + std::fmt::Debug + Clone
+  Sized + Serialize + Versioned + std::fmt::Debug + Clone + Send + Sync + 'static
 {
-  fn from(nodes: &Vec<Node>) -> Self;
+  // Whether from_html should be tried when the wikitext yields an empty resource,
+  // ex: the table comes from a template expanded server-side
+  const HTML_FALLBACK: bool = false;
+
+  // One entry of the resource as listed in a Diff, ex: a code or a day
+  type Item: Serialize + Clone;
+
+  fn from(nodes: &[Node]) -> Self;
+  // The resource listing only these entries, ex: the added ones of a Diff for the
+  // subscribers
+  fn from_entries(entries: Vec<Self::Item>) -> Self;
+
+  // Scrapes the rendered page, only called when HTML_FALLBACK is set
+  fn from_html(_html: &str) -> Option<Self> {
+    None
+  }
+
+  // Parses local wikitext, ex: a fixture, without going through the network
+  fn from_wikitext(text: &str) -> Self {
+    Self::from(&create_configuration().parse(text).nodes).normalize()
+  }
+
   fn get_title() -> &'static str;
+  // Every page the resource is scraped from, ex: a main page and its archive. The
+  // first one stays the resource's identity, see get_title.
+  fn get_titles() -> Vec<&'static str> {
+    vec![Self::get_title()]
+  }
+  // Combines the resources parsed from each of get_titles, in order. Only called
+  // when there are several.
+  fn merge(self, other: Self) -> Self;
   fn difference(&self, other: &Self) -> Self;
+  // Entry by entry changes since previous, the modified entries are the ones still
+  // listed with other fields, ex: a code whose expiry was filled in
+  fn diff(&self, previous: &Self) -> Diff<Self::Item>;
   fn empty(&self) -> bool;
+  // Number of entries, ex: codes or days, reported in the structured logs
+  fn count(&self) -> usize;
+  // Applied once, when the resource is parsed, see migrate_unnormalized for the data
+  // persisted before that
+  fn normalize(self) -> Self;
+
+  // Human readable summary of a change, ex: for the feed, resources override it to
+  // list what changed
+  fn summarize_diff(diff: &ResourceDiff<Self>) -> String {
+    format!(
+      "{}: {} added, {} removed",
+      Self::get_title(),
+      diff.added.count(),
+      diff.removed.count()
+    )
+  }
+
+  // The summary of the notifications, the modified entries are listed with the
+  // added ones unless overridden
+  fn summarize_entries(entries: &Diff<Self::Item>) -> String {
+    let added = entries.added.iter().chain(&entries.modified).cloned();
+    Self::summarize_diff(&ResourceDiff {
+      added: Self::from_entries(added.collect()),
+      removed: Self::from_entries(entries.removed.to_owned()),
+    })
+  }
+
+  // Runs on every update with the previously persisted resource, ex: to carry over
+  // entries that left the page but should stay visible for a while
+  fn prune(self, _previous: Option<&Self>, _now: DateTime<Utc>) -> Self {
+    self
+  }
+
+  // Table headers the resource maps, checked against the parsed ones to catch
+  // editors renaming or reordering columns
+  fn expected_headers() -> &'static [&'static str] {
+    &[]
+  }
+  fn headers(_nodes: &[Node]) -> Vec<String> {
+    vec![]
+  }
+}
+
+const DEFAULT_SCHEMA_MIN_MATCH: f64 = 0.5;
+
+// Fails when too few expected headers were found (configurable through
+// SCHEMA_MIN_MATCH), otherwise returns the warnings about the differences
+fn check_schema<T: WikiResource>(nodes: &[Node]) -> Result<Vec<String>> {
+  let expected = T::expected_headers();
+  if expected.is_empty() {
+    return Ok(vec![]);
+  }
+
+  let headers = T::headers(nodes);
+  let missing: Vec<String> = expected
+    .iter()
+    .filter(|header| !headers.iter().any(|x| x == *header))
+    .map(|header| format!("Missing column \"{}\"", header))
+    .collect();
+  let unexpected = headers
+    .iter()
+    .filter(|header| !expected.contains(&header.as_str()))
+    .map(|header| format!("Unexpected column \"{}\"", header));
+
+  let matched = expected.len() - missing.len();
+  let warnings: Vec<String> = missing.into_iter().chain(unexpected).collect();
+
+  let min_match = env::var("SCHEMA_MIN_MATCH")
+    .ok()
+    .and_then(|x| x.parse().ok())
+    .unwrap_or(DEFAULT_SCHEMA_MIN_MATCH);
+
+  if (matched as f64) < min_match * expected.len() as f64 {
+    return Err(WikiError::SchemaDrift(warnings));
+  }
+  Ok(warnings)
+}
+
+pub const DEFAULT_WIKI_HOST: &str = "genshin-impact.fandom.com";
+pub const DEFAULT_WIKI_LANG: &str = "en";
+
+// The wiki and language a resource is read from. It namespaces the persisted keys,
+// ex: "genshin-impact.fandom.com/en/Promotional_Codes", since the same title
+// exists on several wikis. The client and the rate limiter are shared by every
+// request to the wiki, so its connections are pooled.
+#[derive(Debug, Clone)]
+pub struct WikiContext {
+  pub host: String,
+  pub lang: String,
+  pub client: reqwest::Client,
+  limiter: Arc<RateLimiter>,
+}
+
+impl WikiContext {
+  pub fn new(host: &str, lang: &str, requests_per_second: f64, burst: f64) -> Result<WikiContext> {
+    Ok(WikiContext {
+      host: host.to_owned(),
+      lang: lang.to_owned(),
+      client: create_client(host)?,
+      limiter: Arc::new(RateLimiter::new(requests_per_second, burst)),
+    })
+  }
+
+  // Logged-in requests get higher rate limits, WIKI_BOT_USERNAME and
+  // WIKI_BOT_PASSWORD are a bot password from Special:BotPasswords. The session
+  // cookie is kept by the shared client, without them requests stay anonymous.
+  pub async fn login_from_env(&self) -> Result<bool> {
+    let username = env::var("WIKI_BOT_USERNAME").ok().filter(|x| !x.is_empty());
+    let password = env::var("WIKI_BOT_PASSWORD").ok().filter(|x| !x.is_empty());
+    match (username, password) {
+      (Some(username), Some(password)) => {
+        self.login(&username, &password).await?;
+        Ok(true)
+      }
+      _ => Ok(false),
+    }
+  }
+
+  async fn login(&self, username: &str, password: &str) -> Result<()> {
+    self.limiter.acquire().await;
+    let tokens: Value = self
+      .client
+      .get(&self.api_url())
+      .query(&[
+        ("action", "query"),
+        ("meta", "tokens"),
+        ("type", "login"),
+        ("format", "json"),
+      ])
+      .send()
+      .await
+      .map_err(|_| WikiError::FetchError)?
+      .json()
+      .await
+      .map_err(|_| WikiError::FetchError)?;
+    let token = tokens["query"]["tokens"]["logintoken"]
+      .as_str()
+      .ok_or(WikiError::FetchError)?;
+
+    self.limiter.acquire().await;
+    let res: Value = self
+      .client
+      .post(&self.api_url())
+      .form(&[
+        ("action", "login"),
+        ("lgname", username),
+        ("lgpassword", password),
+        ("lgtoken", token),
+        ("format", "json"),
+      ])
+      .send()
+      .await
+      .map_err(|_| WikiError::FetchError)?
+      .json()
+      .await
+      .map_err(|_| WikiError::FetchError)?;
+
+    match res["login"]["result"].as_str() {
+      Some("Success") => Ok(()),
+      result => {
+        warn!(
+          "Wiki login as {} failed: {} {}",
+          username,
+          result.unwrap_or("Unknown"),
+          res["login"]["reason"].as_str().unwrap_or_default()
+        );
+        Err(WikiError::FetchError)
+      }
+    }
+  }
+
+  fn is_default(&self) -> bool {
+    self.host == DEFAULT_WIKI_HOST && self.lang == DEFAULT_WIKI_LANG
+  }
+
+  pub fn key(&self, title: &str) -> String {
+    format!("{}/{}/{}", self.host, self.lang, title)
+  }
+
+  // Fandom serves the other languages of a wiki under a path, ex: /pt-br/wiki/...
+  fn base_url(&self) -> String {
+    match self.lang.as_str() {
+      DEFAULT_WIKI_LANG => format!("https://{}", self.host),
+      lang => format!("https://{}/{}", self.host, lang),
+    }
+  }
+
+  fn page_url(&self, title: &str) -> String {
+    self.base_url() + "/wiki/" + title
+  }
+
+  fn api_url(&self) -> String {
+    self.base_url() + "/api.php"
+  }
+
+  fn rest_url(&self) -> String {
+    self.base_url() + "/rest.php/v1/page"
+  }
+}
+
+// Resources persisted before normalization existed are migrated when loaded
+pub async fn get_wiki_resource<T: WikiResource>(context: &WikiContext) -> Option<Stored<T>> {
+  try_get_wiki_resource(context).await.ok().flatten()
+}
+
+// Tells a resource that can't be read apart from a missing one, an update mustn't
+// overwrite the former
+async fn try_get_wiki_resource<T: WikiResource>(
+  context: &WikiContext,
+) -> Result<Option<Stored<T>>> {
+  let key = context.key(T::get_title());
+  let unreadable = |_: persist::DataPersistError| WikiError::Unreadable;
+  let stored = match persist::try_get_with_meta::<T>(&key)
+    .await
+    .map_err(unreadable)?
+  {
+    Some(stored) => stored,
+    None if context.is_default() => {
+      migrate_legacy_keys::<T>(context).await;
+      match persist::try_get_with_meta::<T>(&key)
+        .await
+        .map_err(unreadable)?
+      {
+        Some(stored) => stored,
+        None => return Ok(None),
+      }
+    }
+    None => return Ok(None),
+  };
+
+  let source_url = match stored.source_url.as_str() {
+    "" => context.page_url(T::get_title()),
+    _ => stored.source_url,
+  };
+  Ok(Some(Stored {
+    source_url,
+    ..stored
+  }))
+}
+
+// Resources were keyed by their type name, then by their title before namespaces
+// existed, both belong to the default wiki. The first one found is copied over.
+async fn migrate_legacy_keys<T: WikiResource>(context: &WikiContext) {
+  let key = context.key(T::get_title());
+  for legacy_key in &[T::get_title(), std::any::type_name::<T>()] {
+    match persist::migrate_key(legacy_key, &key).await {
+      Ok(true) => {
+        info!("Migrated {} to {}", legacy_key, key);
+        return;
+      }
+      Ok(false) => {}
+      Err(err) => warn!("Failed to migrate {} to {}: {:?}", legacy_key, key, err),
+    }
+  }
+}
+
+fn content_hash(wiki_text: &str) -> String {
+  format!("{:x}", Sha256::digest(wiki_text.as_bytes()))
+}
+
+pub fn breaker_status() -> BreakerStatus {
+  circuit_breaker::BREAKER.status()
+}
+
+// The read-modify-write runs under the resource lock, so concurrent updates don't
+// both diff against the same previous resource and notify twice
+pub async fn update_wiki_resource<T: WikiResource>(
+  context: &WikiContext,
+  notifiers: &[Arc<dyn Notifier>],
+) -> Result<Stored<T>> {
+  update_wiki_resource_with(context, notifiers, |title| {
+    fetch_page_resource::<T>(context, title)
+  })
+  .await
+}
+
+// Same as update_wiki_resource, with the pages read through fetch, ex: from fixtures
+async fn update_wiki_resource_with<T, F, Fut>(
+  context: &WikiContext,
+  notifiers: &[Arc<dyn Notifier>],
+  fetch: F,
+) -> Result<Stored<T>>
+where
+  T: WikiResource,
+  F: Fn(&'static str) -> Fut,
+  Fut: Future<Output = Result<(T, Vec<String>, String, Option<u64>)>>,
+{
+  let key = context.key(T::get_title());
+  let lock = persist::lock(&key).await.map_err(|err| match err {
+    persist::DataPersistError::LockTimeout => WikiError::Busy,
+    _ => WikiError::FetchError,
+  })?;
+
+  // Only fetch failures count towards the breaker, ex: a schema drift means the
+  // wiki is up
+  let result = if circuit_breaker::BREAKER.allow() {
+    let result = update_locked_wiki_resource(context, notifiers, fetch).await;
+    circuit_breaker::BREAKER.record(!matches!(result, Err(WikiError::FetchError)));
+    result
+  } else {
+    info!("Skipping the update of {}, the wiki circuit is open", key);
+    Err(WikiError::CircuitOpen)
+  };
+  if let Err(err) = persist::unlock(lock).await {
+    warn!("Failed to unlock {}: {:?}", key, err);
+  }
+  result
+}
+
+async fn update_locked_wiki_resource<T, F, Fut>(
+  context: &WikiContext,
+  notifiers: &[Arc<dyn Notifier>],
+  fetch: F,
+) -> Result<Stored<T>>
+where
+  T: WikiResource,
+  F: Fn(&'static str) -> Fut,
+  Fut: Future<Output = Result<(T, Vec<String>, String, Option<u64>)>>,
+{
+  let key = context.key(T::get_title());
+  let previous_resource = try_get_wiki_resource::<T>(context).await?.map(|x| x.data);
+
+  let pages = fetch_pages(T::get_titles(), fetch).await?;
+  let fetched_at = Utc::now();
+  let result: T = pages.resource.prune(previous_resource.as_ref(), fetched_at);
+  let stored = Stored {
+    checksum: persist::checksum(&result).ok(),
+    data: result,
+    fetched_at,
+    revision: pages.revisions.get(T::get_title()).copied(),
+    revisions: pages.revisions,
+    content_hash: Some(content_hash(&pages.wiki_texts)),
+    source_url: context.page_url(T::get_title()),
+    schema_version: T::SCHEMA_VERSION,
+  };
+  store_wiki_resource(
+    &key,
+    previous_resource,
+    &stored,
+    &pages.schema_warnings,
+    notifiers,
+  )
+  .await?;
+  Ok(stored)
+}
+
+// The pages of a resource merged in order, see WikiResource::get_titles. The
+// content hash covers all of them, the revisions are kept per title.
+struct Pages<T> {
+  resource: T,
+  schema_warnings: Vec<String>,
+  wiki_texts: String,
+  revisions: BTreeMap<String, u64>,
+}
+
+async fn fetch_pages<T, F, Fut>(titles: Vec<&'static str>, fetch: F) -> Result<Pages<T>>
+where
+  T: WikiResource,
+  F: Fn(&'static str) -> Fut,
+  Fut: Future<Output = Result<(T, Vec<String>, String, Option<u64>)>>,
+{
+  let mut resource: Option<T> = None;
+  let mut schema_warnings = vec![];
+  let mut wiki_texts = String::new();
+  let mut revisions = BTreeMap::new();
+  for title in titles {
+    let (parsed, warnings, wiki_text, revision) = fetch(title).await?;
+    schema_warnings.extend(warnings);
+    wiki_texts += &wiki_text;
+    if let Some(revision) = revision {
+      revisions.insert(title.to_owned(), revision);
+    }
+    resource = Some(match resource {
+      Some(merged) => merged.merge(parsed),
+      None => parsed,
+    });
+  }
+
+  Ok(Pages {
+    resource: resource.ok_or(WikiError::FetchError)?,
+    schema_warnings,
+    wiki_texts,
+    revisions,
+  })
+}
+
+// Persists the resource before anyone hears about it, so whatever the notifiers do
+// the update isn't lost
+async fn store_wiki_resource<T: WikiResource>(
+  key: &str,
+  previous_resource: Option<T>,
+  stored: &Stored<T>,
+  schema_warnings: &[String],
+  notifiers: &[Arc<dyn Notifier>],
+) -> Result<()> {
+  persist::set_by_key(key, stored)
+    .await
+    .map_err(|_| WikiError::FetchError)?;
+
+  // Identical updates would only fill the history with copies
+  let changed = match &previous_resource {
+    Some(previous) => {
+      !stored.data.difference(previous).empty() || !previous.difference(&stored.data).empty()
+    }
+    None => true,
+  };
+  if changed {
+    if let Err(err) = persist::set_snapshot(key, stored, stored.fetched_at).await {
+      warn!("Failed to record a snapshot of {}: {:?}", key, err);
+    }
+  }
+
+  wiki_resource_change_callback(key, previous_resource, stored, schema_warnings, notifiers).await;
+  Ok(())
+}
+
+// Fetches and parses a single page of the resource, along with the schema warnings,
+// the wikitext and its revision
+async fn fetch_page_resource<T: WikiResource>(
+  context: &WikiContext,
+  title: &str,
+) -> Result<(T, Vec<String>, String, Option<u64>)> {
+  let client = &context.client;
+  let (wiki_text, revision) = fetch_page(client, context, title).await?;
+  let wiki_text = transclusion::expand(client, context, wiki_text).await?;
+  check_content_length(&wiki_text)?;
+
+  let (parsed, schema_warnings) = match parse_wikitext::<T>(title, &wiki_text)? {
+    Some(parsed) => parsed,
+    None => {
+      warn!(
+        "{} has nothing in its wikitext, scraping the rendered page",
+        title
+      );
+      (
+        fetch_html_resource::<T>(client, context, title)
+          .await?
+          .normalize(),
+        vec![],
+      )
+    }
+  };
+  Ok((parsed, schema_warnings, wiki_text, revision))
+}
+
+// The resource and the schema warnings of a page's wikitext, taken from the parse
+// cache when the same wikitext was parsed before. None when the wikitext yields
+// nothing and the rendered page should be scraped instead, see HTML_FALLBACK.
+pub fn parse_wikitext<T: WikiResource>(
+  title: &str,
+  wiki_text: &str,
+) -> Result<Option<(T, Vec<String>)>> {
+  let cache_key = format!(
+    "{}@{}@{}",
+    std::any::type_name::<T>(),
+    title,
+    content_hash(wiki_text)
+  );
+  if let Some(parsed) = parse_cache::PARSES.get::<(T, Vec<String>)>(&cache_key) {
+    debug!("{} is unchanged, skipping the parse", title);
+    return Ok(Some(parsed));
+  }
+
+  let result = create_configuration().parse(wiki_text);
+  let parsed = T::from(&result.nodes).normalize();
+  if parsed.empty() && T::HTML_FALLBACK {
+    return Ok(None);
+  }
+  let schema_warnings = check_schema::<T>(&result.nodes)?;
+  // Only the wikitext parses, the rendered page isn't what the key hashes
+  parse_cache::PARSES.insert(cache_key, (parsed.clone(), schema_warnings.clone()));
+  Ok(Some((parsed, schema_warnings)))
+}
+
+// Skips the fetch when the persisted resource is younger than max_age, so a manual
+// refresh doesn't hammer the wiki
+pub async fn refresh_wiki_resource<T: WikiResource>(
+  context: &WikiContext,
+  notifiers: &[Arc<dyn Notifier>],
+  max_age: Duration,
+) -> Result<Stored<T>> {
+  match persist::get_fresh::<T>(&context.key(T::get_title()), max_age).await {
+    Some(stored) => Ok(stored),
+    None => update_wiki_resource::<T>(context, notifiers).await,
+  }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Freshness {
+  // Younger than max_age
+  Fresh,
+  // Older than max_age, a refresh runs in the background
+  Stale,
+  // Nothing was persisted yet, so it was fetched right away
+  Fetched,
+}
+
+static REFRESHING: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Takes the key off REFRESHING however the refresh ends, ex: panicking or dropped
+// along with the runtime, otherwise the resource would never refresh again
+struct Refreshing(String);
+
+impl Drop for Refreshing {
+  fn drop(&mut self) {
+    REFRESHING
+      .lock()
+      .unwrap_or_else(|err| err.into_inner())
+      .remove(&self.0);
+  }
+}
+
+// Stale-while-revalidate: a stale resource is returned as is while it's refreshed in
+// the background, only a cold start waits for the wiki
+pub async fn get_or_update<T: WikiResource + 'static>(
+  context: &WikiContext,
+  notifiers: &[Arc<dyn Notifier>],
+  max_age: Duration,
+) -> Result<(Stored<T>, Freshness)> {
+  get_or_update_with(context, notifiers, max_age, fetch_owned_page_resource::<T>).await
+}
+
+// fetch_page_resource holding its own context, so a background refresh can outlive
+// the caller
+async fn fetch_owned_page_resource<T: WikiResource>(
+  context: WikiContext,
+  title: &'static str,
+) -> Result<(T, Vec<String>, String, Option<u64>)> {
+  fetch_page_resource::<T>(&context, title).await
+}
+
+async fn get_or_update_with<T, F, Fut>(
+  context: &WikiContext,
+  notifiers: &[Arc<dyn Notifier>],
+  max_age: Duration,
+  fetch: F,
+) -> Result<(Stored<T>, Freshness)>
+where
+  T: WikiResource + 'static,
+  F: Fn(WikiContext, &'static str) -> Fut + Clone + 'static,
+  Fut: Future<Output = Result<(T, Vec<String>, String, Option<u64>)>> + 'static,
+{
+  let stored = match get_wiki_resource::<T>(context).await {
+    Some(stored) => stored,
+    None => {
+      let stored =
+        update_wiki_resource_with(context, notifiers, |title| fetch(context.clone(), title))
+          .await?;
+      return Ok((stored, Freshness::Fetched));
+    }
+  };
+
+  if persist::age(&stored) <= max_age {
+    return Ok((stored, Freshness::Fresh));
+  }
+  spawn_refresh(context, notifiers, fetch);
+  Ok((stored, Freshness::Stale))
+}
+
+// At most one background refresh runs per resource, later calls don't queue another
+fn spawn_refresh<T, F, Fut>(context: &WikiContext, notifiers: &[Arc<dyn Notifier>], fetch: F)
+where
+  T: WikiResource + 'static,
+  F: Fn(WikiContext, &'static str) -> Fut + 'static,
+  Fut: Future<Output = Result<(T, Vec<String>, String, Option<u64>)>> + 'static,
+{
+  let key = context.key(T::get_title());
+  if !REFRESHING.lock().unwrap().insert(key.to_owned()) {
+    return;
+  }
+
+  let refreshing = Refreshing(key);
+  let context = context.to_owned();
+  let notifiers = notifiers.to_vec();
+  actix_rt::spawn(async move {
+    let fetch = |title| fetch(context.clone(), title);
+    if let Err(err) = update_wiki_resource_with(&context, &notifiers, fetch).await {
+      warn!("Background refresh of {} failed: {}", refreshing.0, err);
+    }
+    drop(refreshing);
+  });
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ResourceDiff<T> {
+  pub added: T,
+  pub removed: T,
 }
 
-async fn get_wiki_resource<T: WikiResource>() -> Option<T> {
-  persist::get::<T>().await
+// The entries of a resource that changed, see WikiResource::diff. Modified entries
+// are listed as they are now, and in previous as they were.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Diff<I> {
+  pub added: Vec<I>,
+  pub removed: Vec<I>,
+  pub modified: Vec<I>,
+  pub previous: Vec<I>,
 }
 
-pub async fn update_wiki_resource<T: WikiResource>() -> Result<T> {
-  let previous_resource = get_wiki_resource::<T>().await;
+impl<I> Default for Diff<I> {
+  fn default() -> Self {
+    Diff {
+      added: vec![],
+      removed: vec![],
+      modified: vec![],
+      previous: vec![],
+    }
+  }
+}
+
+impl<I> Diff<I> {
+  pub fn is_empty(&self) -> bool {
+    self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+  }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum RefreshOutcome<T> {
+  Unchanged,
+  Updated { diff: ResourceDiff<T> },
+}
+
+// Unchanged when the wiki still serves the revision we already had, so callers can
+// tell it apart from a new revision without net differences
+pub async fn refresh_wiki_resource_diff<T: WikiResource>(
+  context: &WikiContext,
+  notifiers: &[Arc<dyn Notifier>],
+) -> Result<RefreshOutcome<T>> {
+  let previous = get_wiki_resource::<T>(context).await;
+  let current = update_wiki_resource::<T>(context, notifiers).await?;
+
+  let previous = match previous {
+    Some(previous)
+      if previous.revision.is_some()
+        && previous.revision == current.revision
+        && previous.revisions == current.revisions =>
+    {
+      return Ok(RefreshOutcome::Unchanged)
+    }
+    Some(previous) => previous.data,
+    // Nothing persisted yet, diffing against an empty resource reports it all as added
+    None => current.data.difference(&current.data),
+  };
+
+  let current = current.data;
+  Ok(RefreshOutcome::Updated {
+    diff: ResourceDiff {
+      added: current.difference(&previous),
+      removed: previous.difference(&current),
+    },
+  })
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry<T> {
+  pub id: u64,
+  pub timestamp: DateTime<Utc>,
+  pub diff: ResourceDiff<T>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPage<T> {
+  pub entries: Vec<HistoryEntry<T>>,
+  // Offset of the next page, missing on the last one
+  pub next_offset: Option<usize>,
+}
+
+// Each snapshot diffed against the one before it, newest first. The first snapshot
+// is reported as all added.
+pub async fn resource_history<T: WikiResource>(
+  context: &WikiContext,
+  offset: usize,
+  limit: usize,
+) -> HistoryPage<T> {
+  let snapshots = persist::get_snapshots::<T>(&context.key(T::get_title())).await;
+  let total = snapshots.len();
+
+  let mut previous: Option<T> = None;
+  let mut entries: Vec<HistoryEntry<T>> = Vec::with_capacity(total);
+  for (meta, stored) in snapshots {
+    let current = stored.data;
+    let base = previous
+      .replace(current.to_owned())
+      .unwrap_or_else(|| current.difference(&current));
+    entries.push(HistoryEntry {
+      id: meta.id,
+      timestamp: meta.timestamp,
+      diff: ResourceDiff {
+        added: current.difference(&base),
+        removed: base.difference(&current),
+      },
+    });
+  }
+
+  let entries: Vec<HistoryEntry<T>> = entries.into_iter().rev().skip(offset).take(limit).collect();
+  let next_offset = Some(offset + entries.len()).filter(|&x| x < total);
+  HistoryPage {
+    entries,
+    next_offset,
+  }
+}
+
+// Diffs two revisions of the resource page through the normal pipeline, without
+// touching the persisted resource
+pub async fn compare_revisions<T: WikiResource>(
+  context: &WikiContext,
+  old_rev: u64,
+  new_rev: u64,
+) -> Result<ResourceDiff<T>> {
+  let client = &context.client;
+  let old = fetch_revision_resource::<T>(client, context, old_rev).await?;
+  let new = fetch_revision_resource::<T>(client, context, new_rev).await?;
+
+  Ok(ResourceDiff {
+    added: new.difference(&old),
+    removed: old.difference(&new),
+  })
+}
+
+async fn fetch_revision_resource<T: WikiResource>(
+  client: &reqwest::Client,
+  context: &WikiContext,
+  revision: u64,
+) -> Result<T> {
+  let revision = revision.to_string();
+  let (wiki_text, _) = fetch_content(client, context, ("revids", revision.as_str())).await?;
+  let wiki_text = transclusion::expand(client, context, wiki_text).await?;
+  check_content_length(&wiki_text)?;
+
+  Ok(T::from_wikitext(&wiki_text))
+}
+
+// Responses are gzip compressed unless WIKI_GZIP=false, reqwest sends the
+// Accept-Encoding header and decodes the body transparently
+fn create_client(host: &str) -> Result<reqwest::Client> {
+  let gzip = env::var("WIKI_GZIP").map_or(true, |x| x != "false");
+  // Keeps the session of login_from_env
+  let builder = reqwest::Client::builder().gzip(gzip).cookie_store(true);
+
+  let builder = if bypasses_proxy(host) {
+    builder.no_proxy()
+  } else {
+    match env::var("WIKI_PROXY") {
+      Ok(url) => builder.proxy(reqwest::Proxy::all(&url).map_err(|_| WikiError::FetchError)?),
+      Err(_) => builder,
+    }
+  };
+  builder.build().map_err(|_| WikiError::FetchError)
+}
+
+// WIKI_PROXY takes precedence over HTTPS_PROXY/HTTP_PROXY, which reqwest already
+// picks up. NO_PROXY is checked here as reqwest doesn't, its entries match the host
+// or one of its parent domains, ex: ".fandom.com", and "*" matches every host
+fn bypasses_proxy(host: &str) -> bool {
+  let no_proxy = match env::var("NO_PROXY").or_else(|_| env::var("no_proxy")) {
+    Ok(no_proxy) => no_proxy,
+    Err(_) => return false,
+  };
+
+  no_proxy
+    .split(',')
+    .map(|x| x.trim().trim_start_matches('.'))
+    .filter(|x| !x.is_empty())
+    .any(|x| x == "*" || host == x || host.ends_with(&format!(".{}", x)))
+}
+
+async fn fetch_wiki_text(
+  client: &reqwest::Client,
+  context: &WikiContext,
+  title: &str,
+) -> Result<String> {
+  Ok(fetch_page(client, context, title).await?.0)
+}
+
+// WIKI_FETCH_STRATEGY=rest goes through the REST content API first, falling back to
+// the action API when it fails
+async fn fetch_page(
+  client: &reqwest::Client,
+  context: &WikiContext,
+  title: &str,
+) -> Result<(String, Option<u64>)> {
+  if env::var("WIKI_FETCH_STRATEGY").as_deref() == Ok("rest") {
+    match fetch_rest_content(client, context, title).await {
+      Ok(content) => return Ok(content),
+      Err(err) => warn!(
+        "REST fetch of {} failed, falling back to the action API: {}",
+        title, err
+      ),
+    }
+  }
+
+  fetch_content(client, context, ("titles", title)).await
+}
+
+async fn fetch_rest_content(
+  client: &reqwest::Client,
+  context: &WikiContext,
+  title: &str,
+) -> Result<(String, Option<u64>)> {
+  let mut url = reqwest::Url::parse(&context.rest_url()).map_err(|_| WikiError::FetchError)?;
+  url
+    .path_segments_mut()
+    .map_err(|_| WikiError::FetchError)?
+    .push(title);
+
+  context.limiter.acquire().await;
+  let res = client
+    .get(url)
+    .send()
+    .await
+    .and_then(|x| x.error_for_status())
+    .map_err(|_| WikiError::FetchError)?
+    .json::<Value>()
+    .await
+    .map_err(|_| WikiError::FetchError)?;
+
+  let wiki_text = match &res["source"] {
+    Value::String(source) => source.to_owned(),
+    _ => return Err(WikiError::FetchError),
+  };
+
+  check_content_length(&wiki_text)?;
+  Ok((wiki_text, res["latest"]["id"].as_u64()))
+}
 
-  let base_path = "https://genshin-impact.fandom.com/api.php";
+// Renders the page through action=parse, for resources whose wikitext hides the
+// data behind templates
+async fn fetch_html_resource<T: WikiResource>(
+  client: &reqwest::Client,
+  context: &WikiContext,
+  title: &str,
+) -> Result<T> {
   let query_string = [
-    ("action", "query"),
-    ("prop", "revisions"),
-    ("titles", T::get_title()),
-    ("rvslots", "*"),
-    ("rvprop", "content"),
+    ("action", "parse"),
+    ("page", title),
+    ("prop", "text"),
+    ("disableeditsection", "true"),
     ("formatversion", "2"),
     ("format", "json"),
   ];
 
-  let client = reqwest::Client::new();
+  context.limiter.acquire().await;
   let res = client
-    .get(base_path)
+    .get(&context.api_url())
     .query(&query_string)
     .send()
     .await
-    .map_err(|_| WikiError)?
-    .text()
+    .map_err(|_| WikiError::FetchError)?
+    .json::<Value>()
     .await
-    .map_err(|_| WikiError)?;
+    .map_err(|_| WikiError::FetchError)?;
 
-  let wiki_text_json = &serde_json::from_str::<Value>(res.as_str()).map_err(|_| WikiError)?
-    ["query"]["pages"][0]["revisions"][0]["slots"]["main"]["content"];
+  let html = match &res["parse"]["text"] {
+    Value::String(html) => html,
+    _ => return Err(WikiError::FetchError),
+  };
+  check_content_length(html)?;
+  let _permit = parse_limit::HTML_PARSES.acquire().await;
+  T::from_html(html).ok_or(WikiError::FetchError)
+}
+
+// The page is selected either by title or by revision id, returns the wiki text
+// along with its revision id
+async fn fetch_content(
+  client: &reqwest::Client,
+  context: &WikiContext,
+  page: (&str, &str),
+) -> Result<(String, Option<u64>)> {
+  let query_string = [
+    ("action", "query"),
+    ("prop", "revisions"),
+    page,
+    ("rvslots", "*"),
+    ("rvprop", "ids|content"),
+    ("formatversion", "2"),
+    ("format", "json"),
+  ];
+
+  let pages = query_pages(client, context, &query_string).await?;
+  let wiki_text_json = &pages[0]["revisions"][0]["slots"]["main"]["content"];
 
   let wiki_text = match wiki_text_json {
     Value::String(string) => string
@@ -89,33 +1304,186 @@ pub async fn update_wiki_resource<T: WikiResource>() -> Result<T> {
       .replace(r#"\""#, "\"")
       .replace(r#"\'"#, "\'")
       .replace(r#"\t"#, "\t"),
-    _ => return Err(WikiError),
+    _ => return Err(WikiError::FetchError),
   };
 
-  let result = create_configuration().parse(&wiki_text);
-  let result: T = T::from(&result.nodes);
-  persist::set(&result).await.map_err(|_| WikiError)?;
+  check_content_length(&wiki_text)?;
+  let revision = pages[0]["revisions"][0]["revid"].as_u64();
+  Ok((wiki_text, revision))
+}
 
-  wiki_resource_change_callback(previous_resource, &result).await;
+const MAX_CONTINUATIONS: usize = 10;
 
-  Ok(result)
+// Follows the API `continue` object until the result is complete, merging the pages
+async fn query_pages(
+  client: &reqwest::Client,
+  context: &WikiContext,
+  query_string: &[(&str, &str)],
+) -> Result<Value> {
+  let base_path = context.api_url();
+  follow_continuation(|continuation| {
+    let request = client
+      .get(&base_path)
+      .query(query_string)
+      .query(&continuation);
+    async move {
+      context.limiter.acquire().await;
+      let res = request
+        .send()
+        .await
+        .map_err(|_| WikiError::FetchError)?
+        .text()
+        .await
+        .map_err(|_| WikiError::FetchError)?;
+      serde_json::from_str::<Value>(res.as_str()).map_err(|_| WikiError::FetchError)
+    }
+  })
+  .await
 }
 
-async fn wiki_resource_change_callback<T: WikiResource>(previous: Option<T>, current: &T) {
-  let difference = match previous {
-    Some(previous) => current.difference(&previous),
-    None => current.to_owned(),
+// Requests through fetch with the continuation parameters of the previous response,
+// verbatim, until a response has none
+async fn follow_continuation<F, Fut>(mut fetch: F) -> Result<Value>
+where
+  F: FnMut(Vec<(String, String)>) -> Fut,
+  Fut: Future<Output = Result<Value>>,
+{
+  let mut pages: Vec<Value> = Vec::new();
+  let mut continuation: Vec<(String, String)> = Vec::new();
+
+  for _ in 0..MAX_CONTINUATIONS {
+    let res = fetch(continuation).await?;
+    if let Value::Array(new_pages) = &res["query"]["pages"] {
+      merge_pages(&mut pages, new_pages);
+    }
+
+    continuation = match &res["continue"] {
+      Value::Object(params) => params
+        .iter()
+        .map(|(key, value)| match value {
+          Value::String(value) => (key.to_owned(), value.to_owned()),
+          value => (key.to_owned(), value.to_string()),
+        })
+        .collect(),
+      _ => return Ok(Value::Array(pages)),
+    };
+  }
+
+  Err(WikiError::FetchError)
+}
+
+fn merge_pages(pages: &mut Vec<Value>, new_pages: &[Value]) {
+  for new_page in new_pages {
+    let page = pages
+      .iter_mut()
+      .find(|page| page["title"] == new_page["title"]);
+
+    let (page, new_page) = match (page, new_page) {
+      (Some(Value::Object(page)), Value::Object(new_page)) => (page, new_page),
+      _ => {
+        pages.push(new_page.to_owned());
+        continue;
+      }
+    };
+
+    for (key, value) in new_page {
+      match (page.get_mut(key), value) {
+        (Some(Value::Array(values)), Value::Array(new_values)) => {
+          values.extend(new_values.iter().cloned())
+        }
+        _ => {
+          page.insert(key.to_owned(), value.to_owned());
+        }
+      }
+    }
+  }
+}
+
+// The checksum of the last resource announced, so a restored backup or a restart
+// doesn't announce the same content twice
+fn notified_key(key: &str) -> String {
+  key.to_owned() + "@notified"
+}
+
+// The diffs go to every notifier, a failing one doesn't stop the others and the
+// resource is already persisted by then
+async fn wiki_resource_change_callback<T: WikiResource>(
+  key: &str,
+  previous: Option<T>,
+  stored: &Stored<T>,
+  schema_warnings: &[String],
+  notifiers: &[Arc<dyn Notifier>],
+) {
+  for warning in schema_warnings {
+    warn!(
+      "Schema drift in {}: {}",
+      std::any::type_name::<T>(),
+      warning
+    );
+  }
+
+  let current = &stored.data;
+  let entries = match previous {
+    Some(previous) => current.diff(&previous),
+    None => current.diff(&T::from_entries(vec![])),
   };
 
-  if difference.empty() {
+  // Removals alone are reported too, ex: a code pulled early
+  if entries.is_empty() {
     return;
   }
 
-  println!("Resource Updated, added {:?}", difference);
-  match subscription::notify(&difference).await {
-    Ok(_) => {}
-    Err(err) => println!("{:?}", err),
+  let checksum = match &stored.checksum {
+    Some(checksum) => Some(checksum.to_owned()),
+    None => persist::checksum(current).ok(),
   };
+  let notified_key = notified_key(key);
+  if checksum.is_some() && persist::get_by_key::<String>(&notified_key).await == checksum {
+    info!("{} was already announced, skipping the notifications", key);
+    return;
+  }
+
+  let event = match change_event(stored, &entries) {
+    Ok(event) => event,
+    Err(err) => {
+      error!("Failed to serialize the change: {:?}", err);
+      return;
+    }
+  };
+
+  // Only recorded once someone heard about it
+  if notifier::notify_all(notifiers, &event).await == 0 {
+    return;
+  }
+  if let Some(checksum) = checksum {
+    if let Err(err) = persist::set_by_key(&notified_key, &checksum).await {
+      warn!("Failed to record the announcement of {}: {:?}", key, err);
+    }
+  }
+}
+
+// The added and removed entries go out as resources, what the subscribers get, the
+// modified ones as they are now and as they were
+fn change_event<T: WikiResource>(
+  stored: &Stored<T>,
+  entries: &Diff<T::Item>,
+) -> serde_json::Result<ChangeEvent> {
+  Ok(ChangeEvent {
+    resource_type: std::any::type_name::<T>().to_owned(),
+    title: T::get_title().to_owned(),
+    summary: T::summarize_entries(entries),
+    added_count: entries.added.len(),
+    removed_count: entries.removed.len(),
+    modified_count: entries.modified.len(),
+    added: serde_json::to_value(T::from_entries(entries.added.to_owned()))?,
+    removed: serde_json::to_value(T::from_entries(entries.removed.to_owned()))?,
+    modified: serde_json::to_value(&entries.modified)?,
+    previous: serde_json::to_value(&entries.previous)?,
+    fetched_at: stored.fetched_at,
+    revision: stored.revision,
+    // Filled when dispatched, see ChangeEvent::enrich
+    codes: vec![],
+  })
 }
 
 pub fn create_configuration() -> ::parse_wiki_text::Configuration {
@@ -257,3 +1625,584 @@ pub fn create_configuration() -> ::parse_wiki_text::Configuration {
     redirect_magic_words: &["REDIRECT"],
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use super::notifier::{MockServer, NotifyError, RecordingNotifier, Reply};
+  use super::promotional_codes::PromotionalCodes;
+  use super::*;
+  use crate::config::Config;
+  use async_trait::async_trait;
+  use futures::future::{self, Ready};
+  use serde_json::json;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  const WIKI_TEXT: &str = include_str!("fixtures/promotional_codes.wikitext");
+
+  fn stored(key: &str) -> Stored<PromotionalCodes> {
+    let data = PromotionalCodes::from_wikitext(WIKI_TEXT);
+    Stored {
+      checksum: persist::checksum(&data).ok(),
+      data,
+      fetched_at: Utc::now(),
+      revision: Some(1),
+      revisions: vec![(PromotionalCodes::get_title().to_owned(), 1)]
+        .into_iter()
+        .collect(),
+      content_hash: Some(content_hash(WIKI_TEXT)),
+      source_url: format!("https://example.com/wiki/{}", key),
+      schema_version: PromotionalCodes::SCHEMA_VERSION,
+    }
+  }
+
+  // Looks the resource up when it's told about the change
+  struct PersistedNotifier {
+    key: String,
+    persisted: Mutex<Option<bool>>,
+  }
+
+  #[async_trait]
+  impl Notifier for PersistedNotifier {
+    async fn notify(&self, _event: &ChangeEvent) -> std::result::Result<(), NotifyError> {
+      let persisted = persist::get_by_key::<Value>(&self.key).await.is_some();
+      *self.persisted.lock().unwrap() = Some(persisted);
+      Ok(())
+    }
+  }
+
+  #[actix_rt::test]
+  async fn persists_before_notifying() {
+    let key = "test/store/persists_before_notifying";
+    let notifier = Arc::new(PersistedNotifier {
+      key: key.to_owned(),
+      persisted: Mutex::new(None),
+    });
+    let notifiers: notifier::Notifiers = vec![notifier.clone()];
+
+    store_wiki_resource(key, None, &stored(key), &[], &notifiers)
+      .await
+      .unwrap();
+    assert_eq!(*notifier.persisted.lock().unwrap(), Some(true));
+  }
+
+  fn codes_of(resource: &Value) -> Vec<&str> {
+    resource["codes"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|x| x["code"].as_str().unwrap())
+      .collect()
+  }
+
+  #[actix_rt::test]
+  async fn notifies_exactly_the_diff() {
+    let key = "test/store/notifies_the_diff";
+    let notifier = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifiers: notifier::Notifiers = vec![notifier.clone()];
+    // NTQ6ELU3ZYA5 replaced OLDCODE12345, the other codes didn't change
+    let previous =
+      PromotionalCodes::from_wikitext(&WIKI_TEXT.replace("NTQ6ELU3ZYA5", "OLDCODE12345"));
+
+    store_wiki_resource(key, Some(previous), &stored(key), &[], &notifiers)
+      .await
+      .unwrap();
+
+    let events = notifier.events();
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(
+      (event.added_count, event.removed_count, event.modified_count),
+      (1, 1, 0)
+    );
+    assert_eq!(codes_of(&event.added), vec!["NTQ6ELU3ZYA5"]);
+    assert_eq!(codes_of(&event.removed), vec!["OLDCODE12345"]);
+    assert_eq!(event.revision, Some(1));
+  }
+
+  #[actix_rt::test]
+  async fn notifies_the_modified_codes_with_what_changed() {
+    let key = "test/store/notifies_the_modified";
+    let notifier = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifiers: notifier::Notifiers = vec![notifier.clone()];
+    // The expiry of NTQ6ELU3ZYA5 was filled in
+    let previous = PromotionalCodes::from_wikitext(&WIKI_TEXT.replace("July 31, 2021", "Unknown"));
+
+    store_wiki_resource(key, Some(previous), &stored(key), &[], &notifiers)
+      .await
+      .unwrap();
+
+    let events = notifier.events();
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(
+      (event.added_count, event.removed_count, event.modified_count),
+      (0, 0, 1)
+    );
+    assert!(codes_of(&event.added).is_empty());
+    assert_eq!(event.modified[0]["expires"], "July 31, 2021");
+    assert_eq!(event.previous[0]["expires"], "Unknown");
+    assert!(event
+      .summary
+      .contains("- NTQ6ELU3ZYA5 changed, expires: Unknown → July 31, 2021"));
+  }
+
+  #[actix_rt::test]
+  async fn skips_what_was_announced_before_a_restart() {
+    let key = "test/store/announced_before_restart";
+    let before = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifiers: notifier::Notifiers = vec![before.clone()];
+    store_wiki_resource(key, None, &stored(key), &[], &notifiers)
+      .await
+      .unwrap();
+    assert_eq!(before.events().len(), 1);
+
+    // New notifiers over the same backend, without the previous value in hand as
+    // when it's restored from a backup
+    let after = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifiers: notifier::Notifiers = vec![after.clone()];
+    store_wiki_resource(key, None, &stored(key), &[], &notifiers)
+      .await
+      .unwrap();
+    assert!(after.events().is_empty());
+  }
+
+  #[actix_rt::test]
+  async fn failing_notifiers_keep_the_update() {
+    for (key, reply) in [
+      ("test/store/failing_notifier", Reply::Fail),
+      ("test/store/panicking_notifier", Reply::Panic),
+    ]
+    .iter()
+    {
+      let notifier = Arc::new(RecordingNotifier::new(*reply));
+      let notifiers: notifier::Notifiers = vec![notifier.clone()];
+
+      assert!(
+        store_wiki_resource(key, None, &stored(key), &[], &notifiers)
+          .await
+          .is_ok()
+      );
+      assert_eq!(notifier.events().len(), 1);
+      assert!(persist::get_with_meta::<PromotionalCodes>(key)
+        .await
+        .is_some());
+      // Nobody heard about it, so it's announced again on the next update
+      assert!(persist::get_by_key::<String>(&notified_key(key))
+        .await
+        .is_none());
+    }
+  }
+
+  // The log, the subscriptions and the registered webhooks succeed without anyone
+  // to reach, only the real channels count
+  #[actix_rt::test]
+  async fn failing_sinks_of_the_default_notifiers_keep_the_announcement() {
+    let key = "test/store/failing_sinks";
+    let server = MockServer::start(vec![(500, "{}")]);
+    let config = Config {
+      webhook_urls: vec![reqwest::Url::parse(&server.url).unwrap()],
+      notify_max_attempts: 1,
+      notify_coalesce: None,
+      ..Config::from_env().unwrap()
+    };
+    let notifiers = notifier::default_notifiers(&config);
+
+    store_wiki_resource(key, None, &stored(key), &[], &notifiers)
+      .await
+      .unwrap();
+    assert_eq!(server.requests().len(), 1);
+    assert!(persist::get_by_key::<String>(&notified_key(key))
+      .await
+      .is_none());
+  }
+
+  fn cell_parts(wiki_text: &str) -> Vec<CellPart> {
+    get_cell_parts(&create_configuration().parse(wiki_text).nodes)
+  }
+
+  fn link(target: &str, text: &str) -> CellPart {
+    CellPart::Link {
+      target: target.to_owned(),
+      text: text.to_owned(),
+    }
+  }
+
+  #[test]
+  fn keeps_the_targets_of_links() {
+    assert_eq!(
+      cell_parts("[[Mora|mora]] from [[:Category:Ley Line Outcrops|outcrops]] and [[Primogem]]"),
+      vec![
+        link("Mora", "mora"),
+        CellPart::Text(" from ".to_owned()),
+        link("Category:Ley Line Outcrops", "outcrops"),
+        CellPart::Text(" and ".to_owned()),
+        link("Primogem", "Primogem"),
+      ]
+    );
+  }
+
+  const SECTIONS: &str = concat!(
+    "== Available ==\n",
+    "first\n",
+    "=== Livestream ===\n",
+    "nested\n",
+    "== Expired ==\n",
+    "expired\n",
+    "== Available ==\n",
+    "again\n",
+  );
+
+  fn section_text(heading: &str, level: u8) -> String {
+    let nodes = create_configuration().parse(SECTIONS).nodes;
+    get_cell_content_as_string(section_nodes(&nodes, heading, level))
+  }
+
+  #[test]
+  fn keeps_nested_headings_in_the_section() {
+    assert_eq!(section_text("Available", 2), "first nested");
+    assert_eq!(section_text("Livestream", 3), "nested");
+  }
+
+  #[test]
+  fn takes_the_first_of_repeated_headings() {
+    assert_eq!(section_text("Available", 2), "first nested");
+    assert_eq!(section_text("Available", 3), "");
+    assert_eq!(section_text("Upcoming", 2), "");
+  }
+
+  fn cell_text(wiki_text: &str) -> String {
+    get_cell_content_as_string(&create_configuration().parse(wiki_text).nodes)
+  }
+
+  #[test]
+  fn renders_the_amount_of_item_templates_only() {
+    assert_eq!(cell_text("{{Item|Primogem|60}}"), "Primogem ×60");
+    assert_eq!(cell_text("{{item|Mora|x=10,000}}"), "Mora ×10,000");
+    assert_eq!(
+      cell_text("Primogem ×60 {{Color|buzzwords|limited}}"),
+      "Primogem ×60"
+    );
+  }
+
+  // An Available table with the given headers and a row of placeholders
+  fn schema(headers: &[&str]) -> Result<Vec<String>> {
+    let header_row: Vec<String> = headers.iter().map(|x| format!("! {}\n", x)).collect();
+    let row: Vec<&str> = headers.iter().map(|_| "| GENSHINGIFT\n").collect();
+    let wiki_text = format!(
+      "== Available ==\n{{|\n{}|-\n{}|}}\n",
+      header_row.concat(),
+      row.concat()
+    );
+    check_schema::<PromotionalCodes>(&create_configuration().parse(&wiki_text).nodes)
+  }
+
+  #[test]
+  fn aborts_when_every_header_was_renamed() {
+    match schema(&["Gift", "Region", "Prize", "Found", "Ends"]) {
+      Err(WikiError::SchemaDrift(warnings)) => {
+        assert_eq!(warnings.len(), 10);
+        assert!(warnings.contains(&"Missing column \"Code\"".to_owned()));
+        assert!(warnings.contains(&"Unexpected column \"Gift\"".to_owned()));
+      }
+      result => panic!("expected a schema drift, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn warns_about_an_extra_column() {
+    let warnings = schema(&["Code", "Server", "Reward", "Discovered", "Expires", "Notes"]).unwrap();
+    assert_eq!(warnings, vec!["Unexpected column \"Notes\"".to_owned()]);
+  }
+
+  #[actix_rt::test]
+  async fn merges_the_pages_of_every_continuation() {
+    let mut responses = vec![
+      json!({
+        "continue": {"rvcontinue": "20210630|1234", "continue": "||"},
+        "query": {"pages": [{"title": "Promotional_Codes", "revisions": [{"revid": 1}]}]}
+      }),
+      json!({
+        "query": {"pages": [
+          {"title": "Promotional_Codes", "revisions": [{"revid": 2}]},
+          {"title": "Promotional_Codes/Archive", "revisions": [{"revid": 3}]}
+        ]}
+      }),
+    ]
+    .into_iter();
+    let mut requests = vec![];
+
+    let pages = follow_continuation(|continuation| {
+      requests.push(continuation);
+      let response = responses.next().ok_or(WikiError::FetchError);
+      async move { response }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].is_empty());
+    let mut continuation = requests[1].clone();
+    continuation.sort();
+    assert_eq!(
+      continuation,
+      vec![
+        ("continue".to_owned(), "||".to_owned()),
+        ("rvcontinue".to_owned(), "20210630|1234".to_owned()),
+      ]
+    );
+    assert_eq!(
+      pages,
+      json!([
+        {"title": "Promotional_Codes", "revisions": [{"revid": 1}, {"revid": 2}]},
+        {"title": "Promotional_Codes/Archive", "revisions": [{"revid": 3}]}
+      ])
+    );
+  }
+
+  #[actix_rt::test]
+  async fn skips_the_snapshot_of_an_unchanged_update() {
+    let key = "test/store/unchanged_update";
+    let first = stored(key);
+    store_wiki_resource(key, None, &first, &[], &[])
+      .await
+      .unwrap();
+    let second = Stored {
+      fetched_at: Utc::now(),
+      ..stored(key)
+    };
+    store_wiki_resource(key, Some(first.data), &second, &[], &[])
+      .await
+      .unwrap();
+
+    assert_eq!(persist::list_snapshots(key).await.len(), 1);
+  }
+
+  type Fetched = Result<(PromotionalCodes, Vec<String>, String, Option<u64>)>;
+
+  // Every page of the resource reads as wiki_text
+  fn fixture_fetch(wiki_text: impl Into<String>) -> impl Fn(&'static str) -> Ready<Fetched> {
+    let wiki_text = wiki_text.into();
+    move |_title| {
+      let parsed = PromotionalCodes::from_wikitext(&wiki_text);
+      future::ready(Ok((parsed, vec![], wiki_text.to_owned(), Some(1))))
+    }
+  }
+
+  // Every page is read through its own fixture, ex: a main page and its archive
+  #[actix_rt::test]
+  async fn merges_the_pages_of_a_resource() {
+    let title = PromotionalCodes::get_title();
+    let archive = include_str!("fixtures/two_tables.wikitext");
+    let fetch = |page: &'static str| {
+      let (wiki_text, revision) = if page == title {
+        (WIKI_TEXT, 1)
+      } else {
+        (archive, 2)
+      };
+      let parsed = PromotionalCodes::from_wikitext(wiki_text);
+      future::ready::<Fetched>(Ok((parsed, vec![], wiki_text.to_owned(), Some(revision))))
+    };
+
+    let pages = fetch_pages(vec![title, "Promotional_Codes/Archive"], fetch)
+      .await
+      .unwrap();
+    for code in &["GENSHINGIFT", "FIRSTTABLE1", "SECONDTABLE1"] {
+      assert!(pages.resource.find(code).is_some());
+    }
+    assert_eq!(
+      pages.revisions,
+      vec![
+        (title.to_owned(), 1),
+        ("Promotional_Codes/Archive".to_owned(), 2)
+      ]
+      .into_iter()
+      .collect::<BTreeMap<_, _>>()
+    );
+    assert_eq!(pages.wiki_texts, WIKI_TEXT.to_owned() + archive);
+  }
+
+  // Encrypted with a key this process doesn't have, no PERSIST_ENCRYPTION_KEY is set
+  // while testing
+  #[cfg(feature = "encryption")]
+  #[actix_rt::test]
+  async fn aborts_the_update_of_a_resource_it_cant_decrypt() {
+    let context = WikiContext::new("unreadable.test", DEFAULT_WIKI_LANG, 100.0, 100.0).unwrap();
+    let key = context.key(PromotionalCodes::get_title());
+    persist::set_backend_raw(&key, b"MSENC1 encrypted with another key")
+      .await
+      .unwrap();
+
+    let result = update_wiki_resource_with(&context, &[], fixture_fetch(WIKI_TEXT)).await;
+    assert!(matches!(result, Err(WikiError::Unreadable)));
+    // Still there, not overwritten by the fetch
+    assert!(matches!(
+      persist::try_get_with_meta::<PromotionalCodes>(&key).await,
+      Err(persist::DataPersistError::DecryptionFailed)
+    ));
+  }
+
+  #[actix_rt::test]
+  async fn keeps_the_namespaces_apart() {
+    let first = WikiContext::new("first.test", DEFAULT_WIKI_LANG, 100.0, 100.0).unwrap();
+    let second = WikiContext::new("first.test", "pt-br", 100.0, 100.0).unwrap();
+    update_wiki_resource_with(&first, &[], fixture_fetch(WIKI_TEXT))
+      .await
+      .unwrap();
+    let two_tables = include_str!("fixtures/two_tables.wikitext");
+    update_wiki_resource_with(&second, &[], fixture_fetch(two_tables))
+      .await
+      .unwrap();
+
+    // Each namespace has its own value and history
+    let has = |codes: &PromotionalCodes, code| codes.find(code).is_some();
+    for (context, code, other) in &[
+      (&first, "GENSHINGIFT", "FIRSTTABLE1"),
+      (&second, "FIRSTTABLE1", "GENSHINGIFT"),
+    ] {
+      let stored = get_wiki_resource::<PromotionalCodes>(context)
+        .await
+        .unwrap();
+      assert!(has(&stored.data, code) && !has(&stored.data, other));
+
+      let key = context.key(PromotionalCodes::get_title());
+      let snapshots = persist::list_snapshots(&key).await;
+      assert_eq!(snapshots.len(), 1);
+      let snapshot: Stored<PromotionalCodes> =
+        persist::get_snapshot(&key, snapshots[0].id).await.unwrap();
+      assert!(has(&snapshot.data, code) && !has(&snapshot.data, other));
+    }
+  }
+
+  #[actix_rt::test]
+  async fn reports_a_removal_exactly_once() {
+    let context = WikiContext::new("removal.test", DEFAULT_WIKI_LANG, 100.0, 100.0).unwrap();
+    let notifier = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifiers: notifier::Notifiers = vec![notifier.clone()];
+    let pulled = concat!(
+      "|-\n",
+      "| LS6T4L9ZZ7DN\n",
+      "| Asia, TW, HK, MO\n",
+      "| Adventurer's Experience ×5, Fine Enhancement Ore ×5\n",
+      "| June 28, 2021\n",
+      "| Unknown\n",
+    );
+    assert!(WIKI_TEXT.contains(pulled));
+    let without = WIKI_TEXT.replace(pulled, "");
+
+    update_wiki_resource_with(&context, &notifiers, fixture_fetch(WIKI_TEXT))
+      .await
+      .unwrap();
+    for _ in 0..2 {
+      update_wiki_resource_with(&context, &notifiers, fixture_fetch(without.as_str()))
+        .await
+        .unwrap();
+    }
+
+    // The first update announces every code, the second only the removal
+    let events = notifier.events();
+    assert_eq!(events.len(), 2);
+    let event = &events[1];
+    assert_eq!(
+      (event.added_count, event.removed_count, event.modified_count),
+      (0, 1, 0)
+    );
+    assert!(codes_of(&event.added).is_empty());
+    assert_eq!(codes_of(&event.removed), vec!["LS6T4L9ZZ7DN"]);
+    assert_eq!(
+      event.summary,
+      "Promotional codes updated:\n- LS6T4L9ZZ7DN expired/removed"
+    );
+  }
+
+  fn in_two_hours() -> DateTime<Utc> {
+    Utc::now() + Duration::hours(2)
+  }
+
+  #[actix_rt::test]
+  async fn serves_stale_data_while_refreshing_once() {
+    let context = WikiContext::new("stale.test", DEFAULT_WIKI_LANG, 100.0, 100.0).unwrap();
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let counted_fetches = fetches.clone();
+    let fetch = move |_context: WikiContext, title: &'static str| {
+      let fetches = counted_fetches.clone();
+      async move {
+        fetches.fetch_add(1, Ordering::SeqCst);
+        actix_rt::time::delay_for(std::time::Duration::from_millis(200)).await;
+        fixture_fetch(WIKI_TEXT)(title).await
+      }
+    };
+    let max_age = Duration::hours(1);
+    let get =
+      || get_or_update_with::<PromotionalCodes, _, _>(&context, &[], max_age, fetch.clone());
+
+    // Cold start, waits for the fetch
+    let (stored, freshness) = get().await.unwrap();
+    assert_eq!(freshness, Freshness::Fetched);
+    assert!(stored.data.find("GENSHINGIFT").is_some());
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+    // Fresh hit, no fetch
+    assert_eq!(get().await.unwrap().1, Freshness::Fresh);
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+    // Stale, served right away while a single refresh runs
+    persist::set_clock(in_two_hours);
+    assert_eq!(get().await.unwrap().1, Freshness::Stale);
+    assert_eq!(get().await.unwrap().1, Freshness::Stale);
+    actix_rt::time::delay_for(std::time::Duration::from_millis(500)).await;
+    persist::set_clock(Utc::now);
+    assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    let key = context.key(PromotionalCodes::get_title());
+    assert!(!REFRESHING.lock().unwrap().contains(&key));
+  }
+
+  #[actix_rt::test]
+  async fn refreshes_again_after_a_refresh_panicked() {
+    let context = WikiContext::new("panicking.test", DEFAULT_WIKI_LANG, 100.0, 100.0).unwrap();
+    let fetch = |_context: WikiContext, title: &'static str| async move {
+      if title == PromotionalCodes::get_title() {
+        panic!("the fetch panicked");
+      }
+      fixture_fetch(WIKI_TEXT)(title).await
+    };
+
+    spawn_refresh::<PromotionalCodes, _, _>(&context, &[], fetch);
+    actix_rt::time::delay_for(std::time::Duration::from_millis(200)).await;
+    let key = context.key(PromotionalCodes::get_title());
+    assert!(!REFRESHING.lock().unwrap().contains(&key));
+  }
+
+  #[actix_rt::test]
+  async fn notifies_once_for_concurrent_updates() {
+    let context = WikiContext::new("concurrent.test", DEFAULT_WIKI_LANG, 100.0, 100.0).unwrap();
+    let notifier = Arc::new(RecordingNotifier::new(Reply::Succeed));
+    let notifiers: notifier::Notifiers = vec![notifier.clone()];
+    // Slow enough for the second update to wait on the lock of the first
+    let slow_fetch = |_title: &'static str| async {
+      actix_rt::time::delay_for(std::time::Duration::from_millis(300)).await;
+      let parsed = PromotionalCodes::from_wikitext(WIKI_TEXT);
+      Ok((parsed, vec![], WIKI_TEXT.to_owned(), Some(1)))
+    };
+
+    let (first, second) = futures::join!(
+      update_wiki_resource_with(&context, &notifiers, slow_fetch),
+      update_wiki_resource_with(&context, &notifiers, slow_fetch),
+    );
+    assert!(first.is_ok());
+    assert!(second.is_ok());
+    assert_eq!(notifier.events().len(), 1);
+  }
+
+  #[test]
+  fn parse_wikitext_reuses_the_cached_parse() {
+    let uncached = PromotionalCodes::from_wikitext(WIKI_TEXT);
+    let (first, _) = parse_wikitext::<PromotionalCodes>("Test_Codes", WIKI_TEXT)
+      .unwrap()
+      .unwrap();
+    let (second, _) = parse_wikitext::<PromotionalCodes>("Test_Codes", WIKI_TEXT)
+      .unwrap()
+      .unwrap();
+    assert_eq!(first.count(), uncached.count());
+    assert_eq!(second.count(), uncached.count());
+  }
+}
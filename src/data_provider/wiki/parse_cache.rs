@@ -0,0 +1,58 @@
+use once_cell::sync::Lazy;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::env;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_PARSE_CACHE_SIZE: usize = 8;
+
+// The resources parsed from the latest wikitexts, keyed by page and content hash.
+// An unchanged page then skips the parse even when the wiki serves no revision to
+// compare, ex: a page refetched after a transclusion changed. WIKI_PARSE_CACHE_SIZE
+// bounds the entries, 0 disables it.
+pub static PARSES: Lazy<ParseCache> = Lazy::new(|| {
+  ParseCache::new(
+    env::var("WIKI_PARSE_CACHE_SIZE")
+      .ok()
+      .and_then(|x| x.parse().ok())
+      .unwrap_or(DEFAULT_PARSE_CACHE_SIZE),
+  )
+});
+
+type Entry = (String, Arc<dyn Any + Send + Sync>);
+
+// Least recently used first, the entries are few so a scan is enough
+pub struct ParseCache {
+  capacity: usize,
+  entries: Mutex<VecDeque<Entry>>,
+}
+
+impl ParseCache {
+  pub fn new(capacity: usize) -> ParseCache {
+    ParseCache {
+      capacity,
+      entries: Mutex::new(VecDeque::with_capacity(capacity)),
+    }
+  }
+
+  pub fn get<T: Clone + Send + Sync + 'static>(&self, key: &str) -> Option<T> {
+    let mut entries = self.entries.lock().unwrap();
+    let idx = entries.iter().position(|(x, _)| x == key)?;
+    let entry = entries.remove(idx)?;
+    let value = entry.1.downcast_ref::<T>().cloned();
+    entries.push_back(entry);
+    value
+  }
+
+  pub fn insert<T: Send + Sync + 'static>(&self, key: String, value: T) {
+    if self.capacity == 0 {
+      return;
+    }
+    let mut entries = self.entries.lock().unwrap();
+    entries.retain(|(x, _)| *x != key);
+    if entries.len() >= self.capacity {
+      entries.pop_front();
+    }
+    entries.push_back((key, Arc::new(value)));
+  }
+}
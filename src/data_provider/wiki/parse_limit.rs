@@ -0,0 +1,50 @@
+use async_std::channel::{self, Receiver, Sender};
+use once_cell::sync::Lazy;
+use std::env;
+
+const DEFAULT_HTML_PARSES: usize = 2;
+
+// Caps the HTML fallback parses running at once, configurable through
+// WIKI_HTML_CONCURRENCY. A wiki-wide template breakage sends every resource down
+// that path, and the DOM parses are much heavier than the wikitext ones.
+pub static HTML_PARSES: Lazy<Semaphore> = Lazy::new(|| {
+  Semaphore::new(
+    env::var("WIKI_HTML_CONCURRENCY")
+      .ok()
+      .and_then(|x| x.parse().ok())
+      .filter(|&x| x > 0)
+      .unwrap_or(DEFAULT_HTML_PARSES),
+  )
+});
+
+// The permits are tokens in a channel, taking one waits until another is given back
+pub struct Semaphore {
+  sender: Sender<()>,
+  receiver: Receiver<()>,
+}
+
+pub struct Permit<'a> {
+  semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+  pub fn new(permits: usize) -> Semaphore {
+    let (sender, receiver) = channel::bounded(permits);
+    for _ in 0..permits {
+      let _ = sender.try_send(());
+    }
+    Semaphore { sender, receiver }
+  }
+
+  pub async fn acquire(&self) -> Permit<'_> {
+    // Both ends live in here, so the channel never closes
+    let _ = self.receiver.recv().await;
+    Permit { semaphore: self }
+  }
+}
+
+impl Drop for Permit<'_> {
+  fn drop(&mut self) {
+    let _ = self.semaphore.sender.try_send(());
+  }
+}
@@ -1,10 +1,53 @@
-use super::{get_cell_content_as_string, WikiResource};
-use parse_wiki_text::Node;
+use super::super::persist::DataPersistError;
+use super::{
+  get_cell_content_as_string, migrate_unnormalized, normalize_value, section_nodes, table_grid,
+  Diff, ResourceDiff, Versioned, WikiResource,
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use log::warn;
+use parse_wiki_text::{Node, TableRow};
+use scraper::{ElementRef, Html, Selector};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::iter;
+
+pub const REDEEM_URL: &str = "https://genshin.hoyoverse.com/en/gift?code=";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PromotionalCodes {
   codes: Vec<PromotionalCode>,
+  // Codes that left the page recently, kept for CODE_EXPIRY_GRACE_DAYS after expiring
+  #[serde(default)]
+  expired: Vec<PromotionalCode>,
+}
+
+// Rendered in the summary as "expires: June 10, 2021 → June 17, 2021"
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct FieldChange {
+  field: CodeField,
+  from: Option<String>,
+  to: Option<String>,
+  summary: String,
+}
+
+impl FieldChange {
+  fn new(field: CodeField, from: Option<String>, to: Option<String>) -> FieldChange {
+    let summary = format!(
+      "{}: {} → {}",
+      field.name(),
+      from.as_deref().unwrap_or("none"),
+      to.as_deref().unwrap_or("none")
+    );
+    FieldChange {
+      field,
+      from,
+      to,
+      summary,
+    }
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -14,6 +57,328 @@ pub struct PromotionalCode {
   reward: Option<String>,
   discovered: Option<String>,
   expires: Option<String>,
+  // Parsed from `discovered` on normalize, which is kept as is when unreadable
+  #[serde(default)]
+  discovered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CodeStatus {
+  Available,
+  // Still listed, but its expiry (actual or inferred) has passed
+  LikelyExpired,
+  Expired,
+}
+
+// Inferred expiries are a best guess, the wiki didn't list one
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry {
+  pub date: NaiveDate,
+  pub inferred: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CodeLookup<'a> {
+  #[serde(flatten)]
+  pub code: &'a PromotionalCode,
+  pub status: CodeStatus,
+  // Ex: "2.0", the version of the livestream the code was given away in
+  pub version: Option<String>,
+  pub expiry: Option<Expiry>,
+}
+
+// Whether a pasted code looks like one, see PromotionalCodes::check
+#[derive(Debug, Serialize)]
+pub struct CodeCheck<'a> {
+  // Uppercased, without spaces
+  pub normalized: String,
+  pub plausible: bool,
+  pub issues: Vec<String>,
+  pub known: Option<CodeLookup<'a>>,
+  // The known code it matches once lookalikes (ex: O and 0) are confused
+  pub did_you_mean: Option<String>,
+}
+
+// A code as every notifier shows it, see PromotionalCodes::enriched_codes
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EnrichedCode {
+  // As listed on the wiki
+  pub code: String,
+  // Uppercased letters and digits, what the redemption page expects
+  pub normalized: String,
+  pub server: Option<String>,
+  pub reward: Option<String>,
+  // Ex: "Primogem ×60, Mora ×10,000", "Unknown reward" without one
+  pub reward_summary: String,
+  pub redeem_url: String,
+  pub expires: Option<String>,
+  pub expires_at: Option<DateTime<Utc>>,
+  // Ex: "expires in 3 days", relative to when the notification was sent
+  pub expires_in: String,
+}
+
+const REWARD_SUMMARY_LENGTH: usize = 80;
+const EXPIRY_MONTH_DAYS: i64 = 30;
+
+// The wiki lists one reward item per line or comma, they're joined on one line
+fn reward_summary(reward: Option<&str>) -> String {
+  let items: Vec<String> = reward
+    .unwrap_or_default()
+    .split(|c| c == ',' || c == '\n')
+    .map(|x| x.split_whitespace().collect::<Vec<_>>().join(" "))
+    .filter(|x| !x.is_empty())
+    .collect();
+  if items.is_empty() {
+    return "Unknown reward".to_owned();
+  }
+
+  let summary = items.join(", ");
+  if summary.chars().count() <= REWARD_SUMMARY_LENGTH {
+    return summary;
+  }
+  summary
+    .chars()
+    .take(REWARD_SUMMARY_LENGTH - 1)
+    .chain(iter::once('…'))
+    .collect()
+}
+
+// Codes without a readable expiry (ex: "Indefinite") have no end to count down to
+fn expires_in(expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> String {
+  let left = match expires_at {
+    Some(expires_at) => expires_at - now,
+    None => return "no known expiry".to_owned(),
+  };
+  let plural = |count: i64, unit: &str| {
+    format!(
+      "expires in {} {}{}",
+      count,
+      unit,
+      if count == 1 { "" } else { "s" }
+    )
+  };
+
+  if left <= Duration::zero() {
+    "expired".to_owned()
+  } else if left < Duration::hours(1) {
+    "expires in less than an hour".to_owned()
+  } else if left < Duration::days(1) {
+    plural(left.num_hours(), "hour")
+  } else if left <= Duration::days(EXPIRY_MONTH_DAYS) {
+    plural(left.num_days(), "day")
+  } else {
+    "expires in over a month".to_owned()
+  }
+}
+
+// Used until there are codes to learn the lengths from
+const DEFAULT_CODE_LENGTHS: (usize, usize) = (10, 16);
+
+pub const CODE_SORT_KEYS: [&str; 5] = ["code", "reward", "discovered", "expires", "status"];
+
+impl CodeLookup<'_> {
+  // Dates are compared by day, codes without a readable one come first
+  pub fn compare(&self, other: &CodeLookup, key: &str) -> Ordering {
+    match key {
+      "code" => self.code.key().cmp(&other.code.key()),
+      "reward" => self.code.reward.cmp(&other.code.reward),
+      "discovered" => self.code.discovered_at.cmp(&other.code.discovered_at),
+      "expires" => {
+        let expires = |x: &CodeLookup| x.expiry.map(|x| x.date);
+        expires(self).cmp(&expires(other))
+      }
+      "status" => (self.status as u8).cmp(&(other.status as u8)),
+      _ => Ordering::Equal,
+    }
+  }
+}
+
+impl PromotionalCodes {
+  // Users paste codes sloppily, so the match ignores case and surrounding spaces
+  pub fn find(&self, code: &str) -> Option<CodeLookup<'_>> {
+    let code = code.trim();
+    self.lookups().find(|x| match &x.code.code {
+      Some(x) => x.trim().eq_ignore_ascii_case(code),
+      None => false,
+    })
+  }
+
+  // The listed codes as the notifiers show them, the expiry is counted from `now`
+  pub fn enriched_codes(&self, now: DateTime<Utc>) -> Vec<EnrichedCode> {
+    self.codes.iter().filter_map(|x| x.enriched(now)).collect()
+  }
+
+  // The format is learned from the codes listed so far: uppercase letters and
+  // digits, as long as the shortest and longest of them
+  pub fn check(&self, input: &str) -> CodeCheck<'_> {
+    let normalized: String = input
+      .chars()
+      .filter(|c| !c.is_whitespace() && *c != '\u{200b}')
+      .flat_map(char::to_uppercase)
+      .collect();
+
+    let lengths = self
+      .lookups()
+      .filter_map(|x| x.code.key())
+      .map(|x| x.len())
+      .fold(None, |range: Option<(usize, usize)>, length| match range {
+        Some((min, max)) => Some((min.min(length), max.max(length))),
+        None => Some((length, length)),
+      })
+      .unwrap_or(DEFAULT_CODE_LENGTHS);
+
+    let mut issues = vec![];
+    if normalized.chars().any(|c| !c.is_ascii_alphanumeric()) {
+      issues.push("Contains characters other than letters and digits".to_owned());
+    }
+    let length = normalized.chars().count();
+    if length < lengths.0 || length > lengths.1 {
+      issues.push(format!(
+        "Codes are {} to {} characters long, this one has {}",
+        lengths.0, lengths.1, length
+      ));
+    }
+
+    let known = self
+      .lookups()
+      .find(|x| x.code.key().as_deref() == Some(normalized.as_str()));
+    let did_you_mean = match known {
+      Some(_) => None,
+      None => self
+        .lookups()
+        .filter(|x| x.code.key().map(|x| confusable(&x)) == Some(confusable(&normalized)))
+        .find_map(|x| x.code.code.to_owned()),
+    };
+
+    CodeCheck {
+      plausible: issues.is_empty(),
+      normalized,
+      issues,
+      known,
+      did_you_mean,
+    }
+  }
+
+  // Filters compose with AND semantics, a missing filter matches every code.
+  // Rewards aren't parsed into items yet, so the raw reward text is matched
+  pub fn filter(&self, reward: Option<&str>, active: Option<bool>) -> Vec<CodeLookup<'_>> {
+    let reward = reward.map(|x| x.trim().to_lowercase());
+    self
+      .lookups()
+      .filter(|x| match active {
+        Some(active) => (x.status == CodeStatus::Available) == active,
+        None => true,
+      })
+      .filter(|x| match (&reward, &x.code.reward) {
+        (Some(reward), Some(code_reward)) => code_reward.to_lowercase().contains(reward),
+        (Some(_), None) => false,
+        (None, _) => true,
+      })
+      .collect()
+  }
+
+  // Codes discovered on or after the day of `since`, newest first. Discovery dates
+  // only have day precision and codes without a readable one are left out
+  pub fn recent(&self, since: DateTime<Utc>) -> Vec<CodeLookup<'_>> {
+    let since = since.date().and_hms(0, 0, 0);
+    let mut recent: Vec<(DateTime<Utc>, CodeLookup)> = self
+      .lookups()
+      .filter_map(|x| Some((x.code.discovered_at?, x)))
+      .filter(|(discovered, _)| *discovered >= since)
+      .collect();
+
+    recent.sort_by(|(a, _), (b, _)| b.cmp(a));
+    recent.into_iter().map(|(_, x)| x).collect()
+  }
+
+  // Available codes are sometimes split across tables, ex: one per platform
+  pub fn from_with_headers(nodes: &[Node], header_map: &HeaderMap) -> Self {
+    let codes = available_tables(nodes)
+      .into_iter()
+      .flat_map(|rows| get_codes(rows, header_map))
+      .collect();
+
+    PromotionalCodes {
+      codes,
+      expired: vec![],
+    }
+  }
+
+  fn lookups(&self) -> impl Iterator<Item = CodeLookup<'_>> {
+    let today = Utc::now().naive_utc().date();
+    let available = self.codes.iter().map(move |code| {
+      let expiry = code.expiry();
+      let status = match expiry {
+        Some(expiry) if expiry.date < today => CodeStatus::LikelyExpired,
+        _ => CodeStatus::Available,
+      };
+      CodeLookup {
+        code,
+        status,
+        version: code.version(),
+        expiry,
+      }
+    });
+    let expired = self.expired.iter().map(|code| CodeLookup {
+      code,
+      status: CodeStatus::Expired,
+      version: code.version(),
+      expiry: code.expiry(),
+    });
+    available.chain(expired)
+  }
+}
+
+const DEFAULT_EXPIRY_GRACE_DAYS: i64 = 7;
+// Livestream codes are redeemable for about a day after the stream
+const LIVESTREAM_CODE_LIFETIME_DAYS: i64 = 1;
+const LIVESTREAM_MARKERS: [&str; 3] = ["livestream", "special program", "version"];
+
+// Ex: "Version 2.0 Special Program" or "2.1 Livestream" are tagged "2.0" and "2.1"
+fn livestream_version(text: &str) -> Option<String> {
+  let lowercase = text.to_lowercase();
+  if !LIVESTREAM_MARKERS.iter().any(|x| lowercase.contains(x)) {
+    return None;
+  }
+
+  text
+    .split(|c: char| !c.is_ascii_digit() && c != '.')
+    .map(|x| x.trim_matches('.'))
+    .find(|x| {
+      let mut parts = x.split('.');
+      let is_number = |x: Option<&str>| x.map_or(false, |x| !x.is_empty());
+      is_number(parts.next()) && is_number(parts.next()) && parts.next().is_none()
+    })
+    .map(str::to_owned)
+}
+
+// Dates are written like "June 30, 2021", "30 June 2021" or "2021-06-30", anything
+// else (ex: "Unknown") has no date to go by
+fn parse_date(date: &str) -> Option<NaiveDate> {
+  ["%B %d, %Y", "%b %d, %Y", "%d %B %Y", "%d %b %Y", "%Y-%m-%d"]
+    .iter()
+    .find_map(|format| NaiveDate::parse_from_str(date.trim(), format).ok())
+}
+
+// The discovery as a date, for sorting and the recent codes, set while parsing
+fn discovered_at(discovered: Option<&str>) -> Option<DateTime<Utc>> {
+  parse_date(discovered?).map(|x| DateTime::from_utc(x.and_hms(0, 0, 0), Utc))
+}
+
+// Lookalikes folded together, so "GENSH1NG0LD" is compared as "GENSHINGOLD"
+fn confusable(code: &str) -> String {
+  code
+    .chars()
+    .map(|c| match c {
+      '0' => 'O',
+      '1' | 'L' => 'I',
+      '5' => 'S',
+      '8' => 'B',
+      '2' => 'Z',
+      c => c,
+    })
+    .collect()
 }
 
 impl PromotionalCode {
@@ -24,83 +389,962 @@ impl PromotionalCode {
       reward: None,
       discovered: None,
       expires: None,
+      discovered_at: None,
     }
   }
+
+  pub fn version(&self) -> Option<String> {
+    livestream_version(self.reward.as_deref()?)
+  }
+
+  // The listed expiry date when there's one, livestream codes without it are
+  // guessed from their discovery
+  pub fn expiry(&self) -> Option<Expiry> {
+    if let Some(date) = self.expires.as_deref().and_then(parse_date) {
+      return Some(Expiry {
+        date,
+        inferred: false,
+      });
+    }
+
+    self.version()?;
+    let discovered = parse_date(self.discovered.as_deref()?)?;
+    Some(Expiry {
+      date: discovered + Duration::days(LIVESTREAM_CODE_LIFETIME_DAYS),
+      inferred: true,
+    })
+  }
+
+  // Expiry dates are inclusive, the code lasts until the end of that day (UTC)
+  fn enriched(&self, now: DateTime<Utc>) -> Option<EnrichedCode> {
+    let code = self.code.as_deref()?.trim().to_owned();
+    let normalized = self.key()?;
+    let expires_at = self
+      .expiry()
+      .map(|x| DateTime::from_utc((x.date + Duration::days(1)).and_hms(0, 0, 0), Utc));
+    Some(EnrichedCode {
+      redeem_url: REDEEM_URL.to_owned() + &normalized,
+      code,
+      normalized,
+      server: self.server.to_owned(),
+      reward: self.reward.to_owned(),
+      reward_summary: reward_summary(self.reward.as_deref()),
+      expires: self.expires.to_owned(),
+      expires_at,
+      expires_in: expires_in(expires_at, now),
+    })
+  }
+
+  fn field(&self, field: CodeField) -> &Option<String> {
+    match field {
+      CodeField::Code => &self.code,
+      CodeField::Server => &self.server,
+      CodeField::Reward => &self.reward,
+      CodeField::Discovered => &self.discovered,
+      CodeField::Expires => &self.expires,
+    }
+  }
+
+  // The wiki toggles the casing of codes or slips zero-width spaces into them, so
+  // they're compared by their uppercased alphanumerics. The displayed code is kept.
+  fn key(&self) -> Option<String> {
+    let key: String = self
+      .code
+      .as_ref()?
+      .chars()
+      .filter(|c| c.is_alphanumeric())
+      .flat_map(char::to_uppercase)
+      .collect();
+    Some(key).filter(|x| !x.is_empty())
+  }
+
+  // Server lists are compared as sets, "Europe, Asia" is the same as "Asia, Europe"
+  fn compared_field(&self, field: CodeField) -> Option<String> {
+    match field {
+      CodeField::Code => self.key(),
+      CodeField::Server => self.server.as_deref().map(|x| {
+        let mut servers: Vec<&str> = x
+          .split(',')
+          .map(str::trim)
+          .filter(|x| !x.is_empty())
+          .collect();
+        servers.sort_unstable();
+        servers.dedup();
+        servers.join(", ")
+      }),
+      field => self.field(field).clone(),
+    }
+  }
+
+  // Equality used when diffing, only the normalized fields are compared
+  fn same_as(&self, other: &PromotionalCode) -> bool {
+    CODE_FIELDS
+      .iter()
+      .all(|&field| self.compared_field(field) == other.compared_field(field))
+  }
+
+  fn same_code(&self, other: &PromotionalCode) -> bool {
+    self.key().is_some() && self.key() == other.key()
+  }
+
+  fn changes_to(&self, other: &PromotionalCode) -> Vec<FieldChange> {
+    CODE_FIELDS
+      .iter()
+      .filter(|&&field| self.compared_field(field) != other.compared_field(field))
+      .map(|&field| FieldChange::new(field, self.field(field).clone(), other.field(field).clone()))
+      .collect()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeField {
+  Code,
+  Server,
+  Reward,
+  Discovered,
+  Expires,
+}
+
+const CODE_FIELDS: [CodeField; 5] = [
+  CodeField::Code,
+  CodeField::Server,
+  CodeField::Reward,
+  CodeField::Discovered,
+  CodeField::Expires,
+];
+
+impl CodeField {
+  fn name(self) -> &'static str {
+    match self {
+      CodeField::Code => "code",
+      CodeField::Server => "server",
+      CodeField::Reward => "reward",
+      CodeField::Discovered => "discovered",
+      CodeField::Expires => "expires",
+    }
+  }
+
+  // The header used on the page today
+  fn header(self) -> &'static str {
+    match self {
+      CodeField::Code => "Code",
+      CodeField::Server => "Server",
+      CodeField::Reward => "Reward",
+      CodeField::Discovered => "Discovered",
+      CodeField::Expires => "Expires",
+    }
+  }
+}
+
+// Header names accepted for each field, so a renamed column doesn't need a
+// recompile. PROMOTIONAL_CODES_HEADERS replaces the names of the given fields,
+// ex: {"reward": ["Reward", "Rewards"]}
+#[derive(Debug, Clone)]
+pub struct HeaderMap(HashMap<CodeField, HashSet<String>>);
+
+impl Default for HeaderMap {
+  fn default() -> Self {
+    let fields = CODE_FIELDS
+      .iter()
+      .map(|&field| (field, iter::once(field.header().to_owned()).collect()))
+      .collect();
+    HeaderMap(fields)
+  }
+}
+
+impl HeaderMap {
+  pub fn from_env() -> HeaderMap {
+    let mut map = HeaderMap::default();
+    let json = match env::var("PROMOTIONAL_CODES_HEADERS") {
+      Ok(json) => json,
+      Err(_) => return map,
+    };
+
+    match serde_json::from_str::<HashMap<CodeField, HashSet<String>>>(&json) {
+      Ok(overrides) => map.0.extend(overrides),
+      Err(err) => warn!("Ignoring invalid PROMOTIONAL_CODES_HEADERS: {}", err),
+    }
+    map
+  }
+
+  pub fn field(&self, header: &str) -> Option<CodeField> {
+    CODE_FIELDS
+      .iter()
+      .copied()
+      .find(|field| self.0.get(field).map_or(false, |x| x.contains(header)))
+  }
+}
+
+// v2 added the expired codes, older data simply has none. v3 normalizes the codes
+// when they're parsed instead of when they're loaded.
+impl Versioned for PromotionalCodes {
+  const SCHEMA_VERSION: u32 = 3;
+
+  fn migrate(_version: u32, value: Value) -> Result<Self, DataPersistError> {
+    migrate_unnormalized(value)
+  }
+}
+
+// Ex: "- GENSHINGIFT: Primogem ×50 (<redeem link>)", then the changed and the
+// expired or removed codes
+fn summarize(
+  added: &[PromotionalCode],
+  modified: &[PromotionalCode],
+  previous: &[PromotionalCode],
+  removed: &[PromotionalCode],
+) -> String {
+  let added = added.iter().filter_map(|x| {
+    let code = x.code.as_deref()?;
+    Some(format!(
+      "- {}: {} ({}{})",
+      code,
+      x.reward.as_deref().unwrap_or("Unknown reward"),
+      REDEEM_URL,
+      code
+    ))
+  });
+  let modified = modified.iter().filter_map(|x| {
+    let before = previous.iter().find(|before| before.same_code(x))?;
+    let changes: Vec<String> = before
+      .changes_to(x)
+      .into_iter()
+      .map(|x| x.summary)
+      .collect();
+    Some(format!(
+      "- {} changed, {}",
+      x.code.as_deref()?,
+      changes.join(", ")
+    ))
+  });
+  let removed = removed
+    .iter()
+    .filter_map(|x| Some(format!("- {} expired/removed", x.code.as_deref()?)));
+
+  let lines: Vec<String> = added.chain(modified).chain(removed).collect();
+  format!("Promotional codes updated:\n{}", lines.join("\n"))
 }
 
 impl WikiResource for PromotionalCodes {
+  const HTML_FALLBACK: bool = true;
+
+  type Item = PromotionalCode;
+
   fn empty(&self) -> bool {
     self.codes.is_empty()
   }
 
-  fn difference(&self, other: &Self) -> Self {
-    let mut difference: Vec<PromotionalCode> = Vec::new();
+  fn count(&self) -> usize {
+    self.codes.len()
+  }
 
-    for code in &self.codes {
-      if !other.codes.contains(&code) {
-        difference.push(code.to_owned())
-      }
+  fn normalize(self) -> Self {
+    let normalize = |x: Option<String>| x.map(|x| normalize_value(&x));
+    let normalize_codes = |codes: Vec<PromotionalCode>| {
+      codes
+        .into_iter()
+        .map(|code| {
+          let discovered = normalize(code.discovered);
+          PromotionalCode {
+            code: normalize(code.code),
+            server: normalize(code.server),
+            reward: normalize(code.reward),
+            discovered_at: discovered_at(discovered.as_deref()),
+            discovered,
+            expires: normalize(code.expires),
+          }
+        })
+        .collect()
+    };
+
+    PromotionalCodes {
+      codes: normalize_codes(self.codes),
+      expired: normalize_codes(self.expired),
     }
-    PromotionalCodes { codes: difference }
   }
 
-  fn from(nodes: &Vec<Node>) -> Self {
-    let mut after_available = false;
+  // Codes gone from the Available section stay around as expired until their
+  // expiry date is older than the grace window
+  fn prune(self, previous: Option<&Self>, now: DateTime<Utc>) -> Self {
+    let grace = Duration::days(
+      env::var("CODE_EXPIRY_GRACE_DAYS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_EXPIRY_GRACE_DAYS),
+    );
+    let today = now.naive_utc().date();
 
-    for node in nodes {
-      match node {
-        Node::Text { value, .. } => {
-          if value.contains("== Available ==") {
-            after_available = true;
-          }
-          if value.contains("== Expired ==") {
-            after_available = false;
-          }
-        }
-        Node::Table { rows, .. } => {
-          if !after_available {
-            continue;
-          }
+    let expired = previous
+      .into_iter()
+      .flat_map(|x| x.codes.iter().chain(&x.expired))
+      .filter(|x| !self.codes.iter().any(|code| code.key() == x.key()))
+      .filter(|x| match x.expires.as_deref().and_then(parse_date) {
+        Some(expires) => today - expires <= grace,
+        None => false,
+      })
+      .cloned()
+      .collect();
 
-          let mut it = rows.iter();
-
-          let headers: Vec<String> = it
-            .next()
-            .unwrap()
-            .cells
-            .iter()
-            .map(|x| get_cell_content_as_string(&x.content))
-            .collect();
-
-          let codes = it
-            .map(|row| {
-              let mut code = PromotionalCode::new();
-
-              for (idx, cell) in row.cells.iter().enumerate() {
-                let value = get_cell_content_as_string(&cell.content);
-
-                match headers[idx].as_str() {
-                  "Code" => code.code = Some(value),
-                  "Server" => code.server = Some(value),
-                  "Reward" => code.reward = Some(value),
-                  "Discovered" => code.discovered = Some(value),
-                  "Expires" => code.expires = Some(value),
-                  _ => {}
-                }
-              }
-              code
-            })
-            .collect::<Vec<_>>();
-          return PromotionalCodes { codes };
+    PromotionalCodes {
+      codes: self.codes,
+      expired,
+    }
+  }
+
+  // A code listed on several pages is kept as found on the first one
+  fn merge(mut self, other: Self) -> Self {
+    for code in other.codes {
+      if !self.codes.iter().any(|x| x.same_code(&code)) {
+        self.codes.push(code);
+      }
+    }
+    for code in other.expired {
+      if !self.expired.iter().any(|x| x.same_code(&code)) {
+        self.expired.push(code);
+      }
+    }
+    self
+  }
+
+  // The codes are matched like in difference, see PromotionalCode::same_code
+  fn diff(&self, previous: &Self) -> Diff<PromotionalCode> {
+    let mut diff = Diff::default();
+    for code in &self.codes {
+      if previous.codes.iter().any(|x| x.same_as(code)) {
+        continue;
+      }
+      match previous.codes.iter().find(|x| x.same_code(code)) {
+        Some(before) => {
+          diff.modified.push(code.to_owned());
+          diff.previous.push(before.to_owned());
         }
-        _ => {}
+        None => diff.added.push(code.to_owned()),
       }
     }
+    diff.removed = previous
+      .codes
+      .iter()
+      .filter(|code| {
+        !self
+          .codes
+          .iter()
+          .any(|x| x.same_as(code) || x.same_code(code))
+      })
+      .cloned()
+      .collect();
+    diff
+  }
+
+  // A code still listed with different fields is listed as it is now
+  fn difference(&self, other: &Self) -> Self {
+    let codes = self
+      .codes
+      .iter()
+      .filter(|code| !other.codes.iter().any(|x| x.same_as(code)))
+      .cloned()
+      .collect();
+    PromotionalCodes::from_entries(codes)
+  }
+
+  fn from_entries(codes: Vec<PromotionalCode>) -> Self {
+    PromotionalCodes {
+      codes,
+      expired: vec![],
+    }
+  }
+
+  fn expected_headers() -> &'static [&'static str] {
+    &["Code", "Server", "Reward", "Discovered", "Expires"]
+  }
+
+  // Accepted aliases are reported under the expected header
+  fn headers(nodes: &[Node]) -> Vec<String> {
+    let header_map = HeaderMap::from_env();
+    match available_tables(nodes).first() {
+      Some(rows) => get_headers(rows)
+        .into_iter()
+        .map(|x| match header_map.field(&x) {
+          Some(field) => field.header().to_owned(),
+          None => x,
+        })
+        .collect(),
+      None => vec![],
+    }
+  }
+
+  fn from(nodes: &[Node]) -> Self {
+    PromotionalCodes::from_with_headers(nodes, &HeaderMap::from_env())
+  }
+
+  fn from_html(html: &str) -> Option<Self> {
+    let codes = html_available_tables(&Html::parse_fragment(html), &HeaderMap::from_env())?;
+    Some(PromotionalCodes::from_entries(codes))
+  }
+
+  fn summarize_diff(diff: &ResourceDiff<Self>) -> String {
+    summarize(&diff.added.codes, &[], &[], &diff.removed.codes)
+  }
 
-    PromotionalCodes { codes: vec![] }
+  // The modified codes are reported by what changed instead of being listed again
+  fn summarize_entries(entries: &Diff<PromotionalCode>) -> String {
+    summarize(
+      &entries.added,
+      &entries.modified,
+      &entries.previous,
+      &entries.removed,
+    )
   }
 
   fn get_title() -> &'static str {
     "Promotional_Codes"
   }
 }
+
+// The headings sometimes reach us as plain text instead of Heading nodes
+fn text_section_nodes<'a>(nodes: &'a [Node<'a>]) -> &'a [Node<'a>] {
+  let contains = |node: &Node, marker: &str| match node {
+    Node::Text { value, .. } => value.contains(marker),
+    _ => false,
+  };
+
+  let start = match nodes.iter().position(|x| contains(x, "== Available ==")) {
+    Some(idx) => idx + 1,
+    None => return &[],
+  };
+
+  let end = nodes[start..]
+    .iter()
+    .position(|x| contains(x, "== Expired =="))
+    .map_or(nodes.len(), |idx| start + idx);
+
+  &nodes[start..end]
+}
+
+// Editors have used both == and === for the section, the first level found wins. An
+// Expired heading nested in it still ends it.
+fn available_section<'a>(nodes: &'a [Node<'a>]) -> &'a [Node<'a>] {
+  let section = (2..=3)
+    .map(|level| section_nodes(nodes, "Available", level))
+    .find(|x| !x.is_empty())
+    .unwrap_or_else(|| text_section_nodes(nodes));
+
+  let end = section
+    .iter()
+    .position(|node| match node {
+      Node::Heading { nodes, .. } => get_cell_content_as_string(nodes).trim() == "Expired",
+      _ => false,
+    })
+    .unwrap_or(section.len());
+  &section[..end]
+}
+
+fn available_tables<'a>(nodes: &'a [Node<'a>]) -> Vec<&'a [TableRow<'a>]> {
+  available_section(nodes)
+    .iter()
+    .filter_map(|node| match node {
+      Node::Table { rows, .. } if !rows.is_empty() => Some(rows.as_slice()),
+      _ => None,
+    })
+    .collect()
+}
+
+fn get_codes(rows: &[TableRow], header_map: &HeaderMap) -> Vec<PromotionalCode> {
+  let grid = table_grid(rows);
+  let fields: Vec<Option<CodeField>> = get_headers(rows)
+    .iter()
+    .map(|x| header_map.field(x))
+    .collect();
+
+  // A row short of cells keeps the fields it has, one without a code is skipped
+  let mut short = 0;
+  let mut skipped = 0;
+  let mut codes = Vec::with_capacity(grid.len());
+  for cells in grid.iter().skip(1) {
+    let mut code = PromotionalCode::new();
+
+    for (idx, cell) in cells.iter().enumerate() {
+      let value = get_cell_content_as_string(&cell.content);
+
+      match fields.get(idx).copied().flatten() {
+        Some(CodeField::Code) => code.code = Some(value),
+        Some(CodeField::Server) => code.server = Some(value),
+        Some(CodeField::Reward) => code.reward = Some(value),
+        Some(CodeField::Discovered) => code.discovered = Some(value),
+        Some(CodeField::Expires) => code.expires = Some(value),
+        None => {}
+      }
+    }
+    code.discovered_at = discovered_at(code.discovered.as_deref());
+
+    if cells.len() < fields.len() {
+      short += 1;
+    }
+    match code.code {
+      Some(_) => codes.push(code),
+      None => skipped += 1,
+    }
+  }
+
+  if short > 0 || skipped > 0 {
+    warn!(
+      "{} promotional code rows are short of cells, {} rows skipped without a code",
+      short, skipped
+    );
+  }
+  codes
+}
+
+fn get_headers(rows: &[TableRow]) -> Vec<String> {
+  match table_grid(&rows[..1]).pop() {
+    Some(cells) => cells
+      .iter()
+      .map(|x| get_cell_content_as_string(&x.content))
+      .collect(),
+    None => vec![],
+  }
+}
+
+fn element_text(element: ElementRef) -> String {
+  let text: Vec<&str> = element.text().flat_map(str::split_whitespace).collect();
+  text.join(" ")
+}
+
+// Same sections as the wikitext: the tables after an Available heading, up to the
+// next heading of the same level or an Expired one. Rowspans aren't expanded.
+fn html_available_tables(document: &Html, header_map: &HeaderMap) -> Option<Vec<PromotionalCode>> {
+  let elements = Selector::parse("h2, h3, table").ok()?;
+  let row_selector = Selector::parse("tr").ok()?;
+  let cell_selector = Selector::parse("th, td").ok()?;
+
+  let mut available_level: Option<&str> = None;
+  let mut codes: Vec<PromotionalCode> = Vec::new();
+  let mut skipped = 0;
+  for element in document.select(&elements) {
+    let name = element.value().name();
+    if name != "table" {
+      let heading = element_text(element);
+      available_level = match available_level {
+        _ if heading == "Available" => Some(name),
+        // Deeper headings, ex: h3 under an h2, stay in the section
+        Some(level) if heading != "Expired" && name > level => Some(level),
+        _ => None,
+      };
+      continue;
+    }
+    if available_level.is_none() {
+      continue;
+    }
+
+    let mut rows = element.select(&row_selector);
+    let fields: Vec<Option<CodeField>> = match rows.next() {
+      Some(header) => header
+        .select(&cell_selector)
+        .map(|x| header_map.field(&element_text(x)))
+        .collect(),
+      None => continue,
+    };
+
+    for row in rows {
+      let mut code = PromotionalCode::new();
+      for (idx, cell) in row.select(&cell_selector).enumerate() {
+        let value = Some(element_text(cell));
+        match fields.get(idx).copied().flatten() {
+          Some(CodeField::Code) => code.code = value,
+          Some(CodeField::Server) => code.server = value,
+          Some(CodeField::Reward) => code.reward = value,
+          Some(CodeField::Discovered) => code.discovered = value,
+          Some(CodeField::Expires) => code.expires = value,
+          None => {}
+        }
+      }
+      code.discovered_at = discovered_at(code.discovered.as_deref());
+      // Like get_codes, a row without a code is skipped
+      match code.code {
+        Some(_) => codes.push(code),
+        None => skipped += 1,
+      }
+    }
+  }
+
+  if skipped > 0 {
+    warn!(
+      "{} rows of the rendered promotional codes skipped without a code",
+      skipped
+    );
+  }
+  Some(codes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::create_configuration;
+  use super::*;
+  use chrono::TimeZone;
+
+  // An Available section with one table, the cells in the order of expected_headers
+  fn page(rows: &[[&str; 5]]) -> String {
+    let mut wiki_text = "== Available ==\n{| class=\"wikitable\"\n".to_owned();
+    for header in PromotionalCodes::expected_headers() {
+      wiki_text += &format!("! {}\n", header);
+    }
+    for row in rows {
+      wiki_text += "|-\n";
+      for cell in row {
+        wiki_text += &format!("| {}\n", cell);
+      }
+    }
+    wiki_text + "|}\n"
+  }
+
+  fn rewards(codes: &PromotionalCodes) -> Vec<Option<&str>> {
+    codes.codes.iter().map(|x| x.reward.as_deref()).collect()
+  }
+
+  #[test]
+  fn keeps_the_text_of_icon_rewards() {
+    let codes = PromotionalCodes::from_wikitext(include_str!("fixtures/icon_rewards.wikitext"));
+    assert_eq!(
+      rewards(&codes),
+      vec![
+        Some("Primogem ×60, Mora ×10,000"),
+        Some("Hero's Wit ×3"),
+        Some("Primogem ×50"),
+      ]
+    );
+  }
+
+  #[test]
+  fn ignores_dash_and_space_variants() {
+    let before = PromotionalCodes::from_wikitext(&page(&[[
+      "GENSHINGIFT",
+      "All",
+      "Primogem ×50 - Hero's Wit ×3",
+      "June 30, 2021",
+      "Indefinite",
+    ]]));
+    let after = PromotionalCodes::from_wikitext(&page(&[[
+      "GENSHINGIFT",
+      "All",
+      "Primogem\u{00A0}×50 \u{2013} Hero\u{2019}s Wit ×3",
+      "June\u{00A0}30,  2021",
+      "Indefinite",
+    ]]));
+
+    assert!(after.difference(&before).empty());
+    assert!(before.difference(&after).empty());
+    assert!(after.diff(&before).is_empty());
+  }
+
+  fn genshingift(code: &str) -> PromotionalCodes {
+    PromotionalCodes::from_wikitext(&page(&[[
+      code,
+      "All",
+      "Primogem ×50",
+      "June 30, 2021",
+      "Indefinite",
+    ]]))
+  }
+
+  #[test]
+  fn ignores_case_and_zero_width_space_variants() {
+    let before = genshingift("GENSHINGIFT");
+    for variant in &["genshingift", "GenshinGift", "GENSHIN\u{200B}GIFT"] {
+      let after = genshingift(variant);
+      assert!(after.difference(&before).empty(), "{:?}", variant);
+      assert!(before.difference(&after).empty(), "{:?}", variant);
+      assert!(after.diff(&before).is_empty(), "{:?}", variant);
+    }
+    // Only the comparison is normalized
+    assert_eq!(listed(&genshingift("genshingift")), vec!["genshingift"]);
+  }
+
+  #[test]
+  fn ignores_the_order_of_servers() {
+    let servers = |server| {
+      PromotionalCodes::from_wikitext(&page(&[[
+        "GENSHINGIFT",
+        server,
+        "Primogem ×50",
+        "June 30, 2021",
+        "Indefinite",
+      ]]))
+    };
+    let before = servers("Europe, Asia, America");
+    let after = servers("America,Asia, Europe, Asia");
+
+    assert!(after.difference(&before).empty());
+    assert!(before.difference(&after).empty());
+    assert!(after.diff(&before).is_empty());
+    // A server that was added is still a change
+    assert!(!servers("Europe, Asia, America, TW")
+      .diff(&before)
+      .is_empty());
+  }
+
+  fn listed(codes: &PromotionalCodes) -> Vec<&str> {
+    codes
+      .codes
+      .iter()
+      .filter_map(|x| x.code.as_deref())
+      .collect()
+  }
+
+  #[test]
+  fn reads_every_table_of_the_section() {
+    let codes = PromotionalCodes::from_wikitext(include_str!("fixtures/two_tables.wikitext"));
+    assert_eq!(
+      listed(&codes),
+      vec!["FIRSTTABLE1", "SECONDTABLE1", "SECONDTABLE2"]
+    );
+  }
+
+  #[test]
+  fn finds_the_section_by_its_heading_nodes() {
+    let wiki_text = include_str!("fixtures/heading_nodes.wikitext");
+    let nodes = create_configuration().parse(wiki_text).nodes;
+    let headings: Vec<(u8, String)> = nodes
+      .iter()
+      .filter_map(|node| match node {
+        Node::Heading { level, nodes, .. } => Some((*level, get_cell_content_as_string(nodes))),
+        _ => None,
+      })
+      .collect();
+    assert!(headings.contains(&(3, "Available".to_owned())));
+
+    // Only the tables of the section count, the ones of its subsections included
+    let codes = PromotionalCodes::from_wikitext(wiki_text);
+    assert_eq!(
+      listed(&codes),
+      vec!["AVAILABLE1", "AVAILABLE2", "ASIAONLY1"]
+    );
+  }
+
+  #[test]
+  fn repeats_spanning_cells_on_each_column() {
+    let codes = PromotionalCodes::from_wikitext(include_str!("fixtures/rowspan_server.wikitext"));
+    let fields: Vec<[Option<&str>; 4]> = codes
+      .codes
+      .iter()
+      .map(|x| {
+        [
+          x.server.as_deref(),
+          x.reward.as_deref(),
+          x.discovered.as_deref(),
+          x.expires.as_deref(),
+        ]
+      })
+      .collect();
+
+    assert_eq!(listed(&codes), vec!["ROWSPAN1", "ROWSPAN2", "ROWSPAN3"]);
+    assert_eq!(
+      fields,
+      vec![
+        [
+          Some("America, Europe"),
+          Some("Primogem ×60"),
+          Some("June 30, 2021"),
+          Some("Indefinite"),
+        ],
+        [
+          Some("America, Europe"),
+          Some("Mora ×10,000"),
+          Some("July 1, 2021"),
+          Some("Indefinite"),
+        ],
+        [
+          Some("Asia"),
+          Some("Hero's Wit ×3"),
+          Some("Hero's Wit ×3"),
+          Some("Indefinite"),
+        ],
+      ]
+    );
+  }
+
+  #[test]
+  fn keeps_the_rows_around_a_short_one() {
+    let codes = PromotionalCodes::from_wikitext(include_str!("fixtures/short_row.wikitext"));
+    assert_eq!(
+      listed(&codes),
+      vec!["GENSHINGIFT", "SHORTROW1", "NTQ6ELU3ZYA5"]
+    );
+
+    // The short row keeps the cells it has
+    let short = codes.find("SHORTROW1").unwrap().code;
+    assert_eq!(short.server.as_deref(), Some("Europe"));
+    assert_eq!(short.reward, None);
+    assert_eq!(short.expires, None);
+    let last = codes.find("NTQ6ELU3ZYA5").unwrap().code;
+    assert_eq!(last.server.as_deref(), Some("America, Europe"));
+    assert_eq!(last.expires.as_deref(), Some("July 31, 2021"));
+  }
+
+  #[test]
+  fn parses_local_wikitext() {
+    let codes =
+      PromotionalCodes::from_wikitext(include_str!("fixtures/promotional_codes.wikitext"));
+    assert_eq!(
+      listed(&codes),
+      vec![
+        "GENSHINGIFT",
+        "5SM6VJVQL4ZC",
+        "NTQ6ELU3ZYA5",
+        "LS6T4L9ZZ7DN"
+      ]
+    );
+    let code = codes.find("genshingift").unwrap().code;
+    assert_eq!(code.server.as_deref(), Some("All"));
+    assert_eq!(code.reward.as_deref(), Some("Primogem ×50, Hero's Wit ×3"));
+    assert_eq!(code.discovered.as_deref(), Some("June 30, 2021"));
+    assert_eq!(code.expires.as_deref(), Some("Indefinite"));
+  }
+
+  #[test]
+  fn maps_renamed_columns_through_the_header_map() {
+    let wiki_text = page(&[[
+      "GENSHINGIFT",
+      "All",
+      "Primogem ×50",
+      "June 30, 2021",
+      "Indefinite",
+    ]])
+    .replace("! Reward\n", "! Rewards\n")
+    .replace("! Expires\n", "! Valid until\n");
+    let nodes = create_configuration().parse(&wiki_text).nodes;
+
+    let mut header_map = HeaderMap::default();
+    header_map.0.insert(
+      CodeField::Reward,
+      iter::once("Rewards".to_owned()).collect(),
+    );
+    header_map.0.insert(
+      CodeField::Expires,
+      vec!["Expires".to_owned(), "Valid until".to_owned()]
+        .into_iter()
+        .collect(),
+    );
+    let codes = PromotionalCodes::from_with_headers(&nodes, &header_map);
+    assert_eq!(rewards(&codes), vec![Some("Primogem ×50")]);
+    assert_eq!(codes.codes[0].expires.as_deref(), Some("Indefinite"));
+
+    // Without the map the renamed columns are left out
+    let codes = PromotionalCodes::from_with_headers(&nodes, &HeaderMap::default());
+    assert_eq!(rewards(&codes), vec![None]);
+    assert_eq!(codes.codes[0].expires, None);
+  }
+
+  #[test]
+  fn parses_the_discovery_dates() {
+    let dates = [
+      "2021-06-30",
+      "June 30, 2021",
+      "Jun 30, 2021",
+      "30 June 2021",
+      "30 Jun 2021",
+      "Unknown",
+    ];
+    let names: Vec<String> = (0..dates.len())
+      .map(|x| format!("DISCOVERED{}", x))
+      .collect();
+    let rows: Vec<[&str; 5]> = names
+      .iter()
+      .zip(&dates)
+      .map(|(code, date)| [code.as_str(), "All", "Primogem ×60", *date, "Indefinite"])
+      .collect();
+    // Straight from the parse, before normalize
+    let nodes = create_configuration().parse(&page(&rows)).nodes;
+    let codes = <PromotionalCodes as WikiResource>::from(&nodes);
+
+    let june_30 = Utc.ymd(2021, 6, 30).and_hms(0, 0, 0);
+    let parsed: Vec<Option<DateTime<Utc>>> = codes.codes.iter().map(|x| x.discovered_at).collect();
+    assert_eq!(
+      parsed,
+      vec![
+        Some(june_30),
+        Some(june_30),
+        Some(june_30),
+        Some(june_30),
+        Some(june_30),
+        None
+      ]
+    );
+    // The raw text is kept, unreadable or not
+    let raw: Vec<Option<&str>> = codes
+      .codes
+      .iter()
+      .map(|x| x.discovered.as_deref())
+      .collect();
+    let dates: Vec<Option<&str>> = dates.iter().map(|x| Some(*x)).collect();
+    assert_eq!(raw, dates);
+  }
+
+  #[test]
+  fn renders_the_time_left_at_the_boundaries() {
+    let now = Utc.ymd(2021, 6, 30).and_hms(12, 0, 0);
+    let expires_in = |left: Option<Duration>| expires_in(left.map(|x| now + x), now);
+    let cases = [
+      (None, "no known expiry"),
+      (Some(-Duration::days(1)), "expired"),
+      (Some(Duration::zero()), "expired"),
+      (Some(Duration::minutes(59)), "expires in less than an hour"),
+      (Some(Duration::hours(1)), "expires in 1 hour"),
+      (Some(Duration::hours(23)), "expires in 23 hours"),
+      (Some(Duration::days(1)), "expires in 1 day"),
+      (Some(Duration::days(3)), "expires in 3 days"),
+      (Some(Duration::days(30)), "expires in 30 days"),
+      (Some(Duration::days(31)), "expires in over a month"),
+    ];
+    for (left, rendered) in cases.iter() {
+      assert_eq!(expires_in(*left), *rendered, "{:?} left", left);
+    }
+  }
+
+  #[test]
+  fn summarizes_the_reward_items() {
+    assert_eq!(
+      reward_summary(Some("Primogem ×60\n Hero's  Wit ×3")),
+      "Primogem ×60, Hero's Wit ×3"
+    );
+    assert_eq!(reward_summary(None), "Unknown reward");
+    let long = reward_summary(Some(&"Primogem ×60, ".repeat(10)));
+    assert_eq!(long.chars().count(), REWARD_SUMMARY_LENGTH);
+    assert!(long.ends_with('…'));
+  }
+
+  #[test]
+  fn diffs_added_removed_and_modified_codes() {
+    let wiki_text = include_str!("fixtures/promotional_codes.wikitext");
+    // 5SM6VJVQL4ZC replaced OLDCODE12345 and the expiry of NTQ6ELU3ZYA5 was filled in
+    let previous = PromotionalCodes::from_wikitext(
+      &wiki_text
+        .replace("5SM6VJVQL4ZC", "OLDCODE12345")
+        .replace("July 31, 2021", "Unknown"),
+    );
+    let current = PromotionalCodes::from_wikitext(wiki_text);
+
+    let diff = current.diff(&previous);
+    let codes = |codes: &[PromotionalCode]| -> Vec<String> {
+      codes.iter().filter_map(|x| x.code.to_owned()).collect()
+    };
+    assert_eq!(codes(&diff.added), vec!["5SM6VJVQL4ZC"]);
+    assert_eq!(codes(&diff.removed), vec!["OLDCODE12345"]);
+    assert_eq!(codes(&diff.modified), vec!["NTQ6ELU3ZYA5"]);
+    assert_eq!(diff.modified[0].expires.as_deref(), Some("July 31, 2021"));
+    assert_eq!(codes(&diff.previous), vec!["NTQ6ELU3ZYA5"]);
+    assert_eq!(diff.previous[0].expires.as_deref(), Some("Unknown"));
+
+    assert_eq!(
+      PromotionalCodes::summarize_entries(&diff),
+      "Promotional codes updated:\n\
+       - 5SM6VJVQL4ZC: Primogem ×100, Mora ×10,000 (Version 2.0 Special Program) \
+       (https://genshin.hoyoverse.com/en/gift?code=5SM6VJVQL4ZC)\n\
+       - NTQ6ELU3ZYA5 changed, expires: Unknown → July 31, 2021\n\
+       - OLDCODE12345 expired/removed"
+    );
+
+    // Nothing changed between identical snapshots
+    let unchanged = current.diff(&current);
+    assert!(unchanged.added.is_empty() && unchanged.removed.is_empty());
+    assert!(unchanged.modified.is_empty());
+  }
+}
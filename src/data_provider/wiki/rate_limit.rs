@@ -0,0 +1,48 @@
+use async_std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  updated_at: Instant,
+}
+
+// Token bucket shared by every fandom request of a WikiContext, configured by
+// WIKI_REQUESTS_PER_SECOND and WIKI_BURST. The lock is held while waiting for a
+// token so bursts are served one at a time
+#[derive(Debug)]
+pub struct RateLimiter {
+  rate: f64,
+  capacity: f64,
+  bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+  pub fn new(rate: f64, capacity: f64) -> RateLimiter {
+    RateLimiter {
+      rate,
+      capacity,
+      bucket: Mutex::new(Bucket {
+        tokens: capacity,
+        updated_at: Instant::now(),
+      }),
+    }
+  }
+
+  pub async fn acquire(&self) {
+    let mut bucket = self.bucket.lock().await;
+
+    let now = Instant::now();
+    let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.capacity);
+    bucket.updated_at = now;
+
+    if bucket.tokens < 1.0 {
+      let wait = (1.0 - bucket.tokens) / self.rate;
+      actix_rt::time::delay_for(Duration::from_secs_f64(wait)).await;
+      bucket.tokens = 1.0;
+      bucket.updated_at = Instant::now();
+    }
+    bucket.tokens -= 1.0;
+  }
+}
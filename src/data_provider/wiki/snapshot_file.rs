@@ -0,0 +1,131 @@
+use super::super::persist::Stored;
+use super::{ResourceDiff, WikiResource};
+use async_std::fs;
+use derive_more::{Display, Error};
+use serde::de::DeserializeOwned;
+use std::path::Path;
+
+#[derive(Debug, Display, Error)]
+pub enum SnapshotFileError {
+  #[display(fmt = "Failed to read {}: {}", _0, _1)]
+  Read(#[error(not(source))] String, std::io::Error),
+  #[display(fmt = "{} is empty", _0)]
+  Empty(#[error(not(source))] String),
+  #[display(fmt = "{} isn't a snapshot of the resource: {}", _0, _1)]
+  Malformed(#[error(not(source))] String, serde_json::Error),
+  #[display(fmt = "Failed to serialize the diff: {}", _0)]
+  Serialize(serde_json::Error),
+}
+
+// Takes the bare resource, ex: a scrape, as well as the persisted envelope
+async fn load<T: WikiResource + DeserializeOwned>(path: &Path) -> Result<T, SnapshotFileError> {
+  let name = path.display().to_string();
+  let json = fs::read(path)
+    .await
+    .map_err(|err| SnapshotFileError::Read(name.to_owned(), err))?;
+  if json.iter().all(u8::is_ascii_whitespace) {
+    return Err(SnapshotFileError::Empty(name));
+  }
+
+  match serde_json::from_slice::<T>(&json) {
+    Ok(resource) => Ok(resource.normalize()),
+    Err(err) => match serde_json::from_slice::<Stored<T>>(&json) {
+      Ok(stored) => Ok(stored.data.normalize()),
+      Err(_) => Err(SnapshotFileError::Malformed(name, err)),
+    },
+  }
+}
+
+// Diffs two snapshots saved to disk, ex: to check a parser change against old scrapes
+// without running the service
+pub async fn diff_snapshot_files<T: WikiResource + DeserializeOwned>(
+  old: &Path,
+  new: &Path,
+) -> Result<ResourceDiff<T>, SnapshotFileError> {
+  let old = load::<T>(old).await?;
+  let new = load::<T>(new).await?;
+  Ok(ResourceDiff {
+    added: new.difference(&old),
+    removed: old.difference(&new),
+  })
+}
+
+// The summary, then the whole diff as JSON, see `mona_spy diff-snapshots`
+pub async fn render_snapshot_diff<T: WikiResource + DeserializeOwned>(
+  old: &Path,
+  new: &Path,
+) -> Result<String, SnapshotFileError> {
+  let diff = diff_snapshot_files::<T>(old, new).await?;
+  let json = serde_json::to_string_pretty(&diff).map_err(SnapshotFileError::Serialize)?;
+  Ok(format!("{}\n{}", T::summarize_diff(&diff), json))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::promotional_codes::PromotionalCodes;
+  use super::*;
+  use serde_json::{json, Value};
+  use std::path::PathBuf;
+
+  fn write(name: &str, contents: &str) -> PathBuf {
+    let path =
+      std::env::temp_dir().join(format!("mona_spy-snapshot-{}-{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+  }
+
+  fn snapshot(name: &str, codes: &[&str]) -> PathBuf {
+    let codes: Vec<Value> = codes
+      .iter()
+      .map(|code| json!({ "code": code, "server": "All", "reward": "Primogem ×60" }))
+      .collect();
+    write(name, &json!({ "codes": codes }).to_string())
+  }
+
+  #[actix_rt::test]
+  async fn renders_the_diff_of_two_files() {
+    let old = snapshot("old.json", &["GENSHINGIFT", "OLDCODE12345"]);
+    let new = snapshot("new.json", &["GENSHINGIFT", "NEWCODE12345"]);
+
+    let rendered = render_snapshot_diff::<PromotionalCodes>(&old, &new)
+      .await
+      .unwrap();
+    let (summary, diff) = rendered.split_at(rendered.find("\n{").unwrap());
+    assert_eq!(
+      summary,
+      "Promotional codes updated:\n\
+       - NEWCODE12345: Primogem ×60 (https://genshin.hoyoverse.com/en/gift?code=NEWCODE12345)\n\
+       - OLDCODE12345 expired/removed"
+    );
+    let diff: Value = serde_json::from_str(diff).unwrap();
+    assert_eq!(diff["added"]["codes"][0]["code"], "NEWCODE12345");
+    assert_eq!(diff["removed"]["codes"][0]["code"], "OLDCODE12345");
+    assert_eq!(diff["added"]["codes"].as_array().unwrap().len(), 1);
+    assert_eq!(diff["removed"]["codes"].as_array().unwrap().len(), 1);
+  }
+
+  #[actix_rt::test]
+  async fn rejects_unreadable_files() {
+    let valid = snapshot("valid.json", &["GENSHINGIFT"]);
+    let empty = write("empty.json", " \n");
+    let malformed = write("malformed.json", r#"{"codes": "GENSHINGIFT"}"#);
+    let missing = std::env::temp_dir().join("mona_spy-snapshot-missing.json");
+
+    let diff = |old: PathBuf| {
+      let valid = valid.clone();
+      async move { diff_snapshot_files::<PromotionalCodes>(&old, &valid).await }
+    };
+    assert!(matches!(
+      diff(empty).await,
+      Err(SnapshotFileError::Empty(_))
+    ));
+    assert!(matches!(
+      diff(malformed).await,
+      Err(SnapshotFileError::Malformed(_, _))
+    ));
+    assert!(matches!(
+      diff(missing).await,
+      Err(SnapshotFileError::Read(_, _))
+    ));
+  }
+}
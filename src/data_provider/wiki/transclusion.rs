@@ -0,0 +1,134 @@
+use super::{
+  create_configuration, fetch_wiki_text, get_cell_content_as_string, Result, WikiContext,
+};
+use parse_wiki_text::Node;
+use std::collections::HashMap;
+use std::future::Future;
+
+// Pages like {{Transcluded Table}} keep their rows in a Template: page, which may
+// itself transclude another template, so we only follow a couple of levels.
+const MAX_DEPTH: usize = 2;
+
+pub async fn expand(
+  client: &reqwest::Client,
+  context: &WikiContext,
+  wiki_text: String,
+) -> Result<String> {
+  expand_with(wiki_text, |title| async move {
+    fetch_wiki_text(client, context, &title).await
+  })
+  .await
+}
+
+// Same as expand, with the templates read through fetch, ex: from fixtures
+async fn expand_with<F, Fut>(wiki_text: String, mut fetch: F) -> Result<String>
+where
+  F: FnMut(String) -> Fut,
+  Fut: Future<Output = Result<String>>,
+{
+  let mut cache: HashMap<String, String> = HashMap::new();
+  let mut wiki_text = wiki_text;
+
+  for _ in 0..MAX_DEPTH {
+    let templates = get_transcluded_templates(&wiki_text);
+    if templates.is_empty() {
+      break;
+    }
+
+    // Splice from the end so the earlier offsets stay valid
+    for (start, end, title) in templates.into_iter().rev() {
+      if !cache.contains_key(&title) {
+        let content = fetch(title.clone()).await?;
+        cache.insert(title.clone(), strip_noinclude(&content));
+      }
+      wiki_text.replace_range(start..end, cache[&title].as_str());
+    }
+  }
+
+  Ok(wiki_text)
+}
+
+// Returns the position and page title of every template, but only when the page
+// holds nothing other than template calls
+fn get_transcluded_templates(wiki_text: &str) -> Vec<(usize, usize, String)> {
+  let result = create_configuration().parse(wiki_text);
+  let mut templates = Vec::new();
+
+  for node in &result.nodes {
+    match node {
+      Node::Template {
+        name, start, end, ..
+      } => {
+        let name = get_cell_content_as_string(name);
+        let name = name.trim();
+        let title = match name.strip_prefix("Template:") {
+          Some(_) => name.to_owned(),
+          None => "Template:".to_owned() + name,
+        };
+        templates.push((*start, *end, title));
+      }
+      Node::Text { value, .. } if value.trim().is_empty() => {}
+      Node::Comment { .. } | Node::Heading { .. } | Node::ParagraphBreak { .. } => {}
+      _ => return vec![],
+    }
+  }
+
+  templates
+}
+
+fn strip_noinclude(wiki_text: &str) -> String {
+  let mut content = String::new();
+  let mut rest = wiki_text;
+
+  while let Some(start) = rest.find("<noinclude>") {
+    content.push_str(&rest[..start]);
+    rest = match rest[start..].find("</noinclude>") {
+      Some(end) => &rest[start + end + "</noinclude>".len()..],
+      None => "",
+    };
+  }
+  content.push_str(rest);
+
+  content
+    .replace("<includeonly>", "")
+    .replace("</includeonly>", "")
+    .replace("<onlyinclude>", "")
+    .replace("</onlyinclude>", "")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::promotional_codes::PromotionalCodes;
+  use super::super::{WikiError, WikiResource};
+  use super::*;
+
+  const PAGE: &str = "{{Promotional Codes Table}}\n";
+  const TEMPLATE: &str = include_str!("fixtures/transcluded_template.wikitext");
+
+  async fn expand_fixture(wiki_text: &str) -> Result<String> {
+    expand_with(wiki_text.to_owned(), |title| async move {
+      match title.as_str() {
+        "Template:Promotional Codes Table" => Ok(TEMPLATE.to_owned()),
+        _ => Err(WikiError::FetchError),
+      }
+    })
+    .await
+  }
+
+  #[actix_rt::test]
+  async fn reads_the_rows_from_the_template() {
+    let wiki_text = expand_fixture(PAGE).await.unwrap();
+    assert!(!wiki_text.contains("noinclude"));
+
+    let codes = PromotionalCodes::from_wikitext(&wiki_text);
+    assert_eq!(codes.count(), 2);
+    assert!(codes.find("TRANSCLUDED1").is_some());
+    assert!(codes.find("TRANSCLUDED2").is_some());
+  }
+
+  #[actix_rt::test]
+  async fn leaves_pages_with_content_alone() {
+    let page = "Some text\n{{Promotional Codes Table}}\n";
+    assert_eq!(expand_fixture(page).await.unwrap(), page);
+  }
+}
@@ -0,0 +1,164 @@
+use crate::data_provider::wiki::{HistoryPage, WikiResource};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+    .replace('\'', "&apos;")
+}
+
+// Stable across regenerations, it only depends on the resource, the change and when
+// it happened
+fn entry_id<T: WikiResource>(diff: &str, timestamp: DateTime<Utc>) -> String {
+  let hash = Sha256::new()
+    .chain(T::get_title())
+    .chain(diff)
+    .chain(timestamp.to_rfc3339())
+    .finalize();
+  format!("urn:sha256:{:x}", hash)
+}
+
+// An Atom feed with an entry per change of the history, newest first. Snapshots
+// without a change aren't listed.
+pub fn atom_feed<T: WikiResource>(history: &HistoryPage<T>, self_url: &str) -> String {
+  let entries: Vec<String> = history
+    .entries
+    .iter()
+    .filter(|x| !x.diff.added.empty() || !x.diff.removed.empty())
+    .map(|entry| {
+      let diff = serde_json::to_string(&entry.diff).unwrap_or_default();
+      format!(
+        "<entry><id>{}</id><title>{}</title><updated>{}</updated><content type=\"text\">{}</content></entry>",
+        entry_id::<T>(&diff, entry.timestamp),
+        escape(&format!(
+          "{}: {} added, {} removed",
+          T::get_title(),
+          entry.diff.added.count(),
+          entry.diff.removed.count()
+        )),
+        entry.timestamp.to_rfc3339(),
+        escape(&T::summarize_diff(&entry.diff))
+      )
+    })
+    .collect();
+
+  let updated = history
+    .entries
+    .first()
+    .map_or_else(Utc::now, |x| x.timestamp);
+  format!(
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+     <feed xmlns=\"http://www.w3.org/2005/Atom\">\
+     <id>{}</id><title>{}</title><updated>{}</updated>\
+     <link rel=\"self\" href=\"{}\"/><author><name>mona_spy</name></author>{}</feed>",
+    escape(self_url),
+    escape(&format!("{} changes", T::get_title())),
+    updated.to_rfc3339(),
+    escape(self_url),
+    entries.join("")
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data_provider::wiki::promotional_codes::PromotionalCodes;
+  use crate::data_provider::wiki::{HistoryEntry, ResourceDiff};
+  use atom_syndication::Feed;
+  use chrono::TimeZone;
+  use serde_json::json;
+
+  fn codes(codes: &[&str]) -> PromotionalCodes {
+    let codes: Vec<_> = codes
+      .iter()
+      .map(|code| json!({ "code": code, "server": "All", "reward": "Primogem ×60 & <Mora>" }))
+      .collect();
+    serde_json::from_value(json!({ "codes": codes })).unwrap()
+  }
+
+  fn entry(
+    id: u64,
+    timestamp: DateTime<Utc>,
+    added: &[&str],
+    removed: &[&str],
+  ) -> HistoryEntry<PromotionalCodes> {
+    HistoryEntry {
+      id,
+      timestamp,
+      diff: ResourceDiff {
+        added: codes(added),
+        removed: codes(removed),
+      },
+    }
+  }
+
+  // Newest first, the snapshot in the middle changed nothing
+  fn history() -> HistoryPage<PromotionalCodes> {
+    HistoryPage {
+      entries: vec![
+        entry(
+          3,
+          Utc.ymd(2021, 7, 2).and_hms(12, 0, 0),
+          &["NEWCODE12345"],
+          &["GENSHINGIFT"],
+        ),
+        entry(2, Utc.ymd(2021, 7, 1).and_hms(12, 0, 0), &[], &[]),
+        entry(
+          1,
+          Utc.ymd(2021, 6, 30).and_hms(12, 0, 0),
+          &["GENSHINGIFT"],
+          &[],
+        ),
+      ],
+      next_offset: None,
+    }
+  }
+
+  fn parse(xml: &str) -> Feed {
+    xml.parse().expect("the feed isn't valid Atom")
+  }
+
+  #[test]
+  fn generates_a_valid_feed() {
+    let feed = parse(&atom_feed(&history(), "https://mona.test/feed.xml?a=1&b=2"));
+    assert_eq!(feed.id(), "https://mona.test/feed.xml?a=1&b=2");
+    assert_eq!(
+      feed.updated().timestamp(),
+      Utc.ymd(2021, 7, 2).and_hms(12, 0, 0).timestamp()
+    );
+
+    let updated: Vec<i64> = feed
+      .entries()
+      .iter()
+      .map(|x| x.updated().timestamp())
+      .collect();
+    assert_eq!(
+      updated,
+      vec![
+        Utc.ymd(2021, 7, 2).and_hms(12, 0, 0).timestamp(),
+        Utc.ymd(2021, 6, 30).and_hms(12, 0, 0).timestamp(),
+      ]
+    );
+  }
+
+  #[test]
+  fn keeps_the_ids_across_regenerations() {
+    let ids = |xml: &str| -> Vec<String> {
+      parse(xml)
+        .entries()
+        .iter()
+        .map(|x| x.id().to_owned())
+        .collect()
+    };
+    let first = ids(&atom_feed(&history(), "https://mona.test/feed.xml"));
+    let second = ids(&atom_feed(&history(), "https://mona.test/feed.xml"));
+    assert_eq!(first, second);
+    assert_eq!(first.len(), 2);
+    assert_ne!(first[0], first[1]);
+    assert!(first.iter().all(|x| x.starts_with("urn:sha256:")));
+  }
+}
@@ -8,3 +8,59 @@ pub struct SubscribeBody {
   pub token: Option<String>, // Ex: "target=myApp-myCalendarChannelDest". (Optional) Your channel token.
   pub expiration: Option<u64>, // Ex: 1426325213000 // (Optional) Your requested channel expiration time.
 }
+
+#[derive(Deserialize, Debug)]
+pub struct WebhookBody {
+  pub url: String, // Ex: "https://mydomain.com/mona". Receives every change as JSON.
+  pub secret: Option<String>, // Ex: "s3cr3t". (Optional) Signs the payloads, see X-MonaSpy-Signature.
+  pub servers: Option<Vec<String>>, // Ex: ["Europe", "All"]. (Optional) Only the codes of these servers.
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CodesQuery {
+  pub reward: Option<String>, // Ex: "primogems". Case-insensitive match on the reward.
+  pub active: Option<bool>,   // Ex: true. Only codes that can still be redeemed.
+  pub limit: Option<usize>,   // Ex: 20. (Optional) Codes per page, at most 100.
+  pub offset: Option<usize>,  // Ex: 20. (Optional) Codes to skip.
+  pub sort: Option<String>, // Ex: "-discovered". (Optional) A CODE_SORT_KEYS key, "-" for descending.
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResourceQuery {
+  pub max_age: Option<i64>, // Ex: 600. (Optional) Seconds the persisted resource is still fresh for.
+  pub stale: Option<bool>, // Ex: true. (Optional) Serves an older resource while it's refreshed in the background.
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClearCacheQuery {
+  pub history: Option<bool>, // Ex: true. (Optional) Also removes the snapshots of the resource.
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HistoryQuery {
+  pub limit: Option<usize>, // Ex: 20. (Optional) Entries per page, at most 100.
+  pub offset: Option<usize>, // Ex: 20. (Optional) The next_offset of the previous page.
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RecentCodesQuery {
+  pub hours: Option<i64>, // Ex: 24. (Optional) Size of the window, defaults to a day.
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NotificationsQuery {
+  pub notifier: Option<String>, // Ex: "discord". (Optional) Only the attempts of this notifier.
+  pub outcome: Option<String>,  // Ex: "failed". (Optional) "delivered" or "failed".
+  pub limit: Option<usize>,     // Ex: 50. (Optional) Records to return, at most 500.
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RestoreQuery {
+  pub overwrite: Option<bool>, // Ex: true. (Optional) Replaces the keys that already hold a value.
+}
+
+// Sent over /ws to pick the resources to receive, ex: {"subscribe": ["Promotional_Codes"]}
+#[derive(Deserialize, Debug)]
+pub struct WsSubscribe {
+  pub subscribe: Vec<String>,
+}
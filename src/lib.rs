@@ -0,0 +1,3 @@
+pub mod config;
+pub mod data_provider;
+pub mod interface;
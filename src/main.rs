@@ -1,38 +1,532 @@
 mod check_update;
-mod data_provider;
-mod interface;
+mod feed;
+mod pagination;
+mod websocket;
 
-use actix_web::{error, get, post, web, App, HttpResponse, HttpServer};
+use actix_web::http::header;
+use actix_web::{delete, error, get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web_actors::ws;
+use async_std::stream::StreamExt;
+use chrono::{Duration, Utc};
+use config::Config;
+use data_provider::get_or_update;
+use data_provider::notifier;
+use data_provider::notifier::{EventBroadcaster, Notifiers, Outcome};
+use data_provider::persist;
+use data_provider::persist::{BackupArchive, Stored};
 use data_provider::subscription;
-use data_provider::subscription::{PushBody, PushResponse};
-use data_provider::wiki::promotional_codes::PromotionalCodes;
-use data_provider::wiki::update_wiki_resource;
-use interface::SubscribeBody;
+use data_provider::subscription::{PushBody, PushResponse, WebhookSummary};
+use data_provider::wiki::abyss_rotation::AbyssRotation;
+use data_provider::wiki::material_schedule::MaterialSchedule;
+use data_provider::wiki::promotional_codes::{CodeLookup, PromotionalCodes, CODE_SORT_KEYS};
+use data_provider::wiki::{
+  breaker_status, compare_revisions, get_wiki_resource, refresh_wiki_resource,
+  refresh_wiki_resource_diff, render_snapshot_diff, resource_history, update_wiki_resource,
+  BreakerState, BreakerStatus, WikiContext, WikiResource,
+};
+use interface::{
+  ClearCacheQuery, CodesQuery, HistoryQuery, NotificationsQuery, RecentCodesQuery, ResourceQuery,
+  RestoreQuery, SubscribeBody, WebhookBody,
+};
+use log::{debug, error, info, warn};
+use mona_spy::{config, data_provider, interface};
+use serde::Serialize;
 use serde_json::Value;
 use std::env;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+// The ETag is the hash of the body, so If-None-Match also matches weak tags and "*"
+fn not_modified(req: &HttpRequest, etag: &str) -> bool {
+  req
+    .headers()
+    .get(header::IF_NONE_MATCH)
+    .and_then(|x| x.to_str().ok())
+    .map_or(false, |x| {
+      x.split(',')
+        .map(|x| x.trim().trim_start_matches("W/"))
+        .any(|x| x == etag || x == "*")
+    })
+}
+
+// 304 when the client already has this body
+fn json_response<T: Serialize>(req: &HttpRequest, body: &T) -> HttpResponse {
+  let mut response = HttpResponse::Ok();
+  if let Ok(checksum) = persist::checksum(body) {
+    let etag = format!("\"{}\"", checksum);
+    if not_modified(req, &etag) {
+      return HttpResponse::NotModified()
+        .header(header::ETAG, etag)
+        .finish();
+    }
+    response.header(header::ETAG, etag);
+  }
+  response.json(body)
+}
+
+// The fetch time is surfaced through Last-Modified, the body stays the bare resource
+fn resource_response<T: Serialize>(req: &HttpRequest, stored: &Stored<T>) -> HttpResponse {
+  let mut response = json_response(req, &stored.data);
+  if let Ok(last_modified) = stored
+    .fetched_at
+    .format("%a, %d %b %Y %H:%M:%S GMT")
+    .to_string()
+    .parse()
+  {
+    response
+      .headers_mut()
+      .insert(header::LAST_MODIFIED, last_modified);
+  }
+  response
+}
+
+// Without max_age the resource is always fetched again, with stale an older one is
+// returned right away and refreshed in the background
+async fn fetch_resource<T: WikiResource>(
+  context: &WikiContext,
+  notifiers: &Notifiers,
+  query: &ResourceQuery,
+) -> actix_web::Result<Stored<T>> {
+  let resource = match (query.max_age, query.stale.unwrap_or(false)) {
+    (Some(max_age), true) => {
+      let (stored, freshness) =
+        get_or_update::<T>(context, notifiers, Duration::seconds(max_age)).await?;
+      debug!("Serving {} {:?}", T::get_title(), freshness);
+      stored
+    }
+    (Some(max_age), false) => {
+      refresh_wiki_resource::<T>(context, notifiers, Duration::seconds(max_age)).await?
+    }
+    (None, _) => update_wiki_resource::<T>(context, notifiers).await?,
+  };
+  Ok(resource)
+}
 
 #[get("/promotional_codes")]
-async fn promotional_codes() -> actix_web::Result<HttpResponse> {
-  let new_resource = update_wiki_resource::<PromotionalCodes>().await?;
-  Ok(HttpResponse::Ok().json(new_resource))
+async fn promotional_codes(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  notifiers: web::Data<Notifiers>,
+  query: web::Query<ResourceQuery>,
+) -> actix_web::Result<HttpResponse> {
+  let new_resource = fetch_resource::<PromotionalCodes>(&context, &notifiers, &query).await?;
+  Ok(resource_response(&req, &new_resource))
+}
+
+#[post("/refresh/{resource}")]
+async fn refresh(
+  context: web::Data<WikiContext>,
+  notifiers: web::Data<Notifiers>,
+  web::Path(resource): web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+  let response = match resource.as_str() {
+    "promotional_codes" => HttpResponse::Ok()
+      .json(refresh_wiki_resource_diff::<PromotionalCodes>(&context, &notifiers).await?),
+    "material_schedule" => HttpResponse::Ok()
+      .json(refresh_wiki_resource_diff::<MaterialSchedule>(&context, &notifiers).await?),
+    "abyss_rotation" => HttpResponse::Ok()
+      .json(refresh_wiki_resource_diff::<AbyssRotation>(&context, &notifiers).await?),
+    _ => return Err(error::ErrorNotFound("Unknown Resource")),
+  };
+  Ok(response)
+}
+
+const DEFAULT_HISTORY_LIMIT: usize = 20;
+const MAX_HISTORY_LIMIT: usize = 100;
+
+#[get("/history/{resource}")]
+async fn history(
+  context: web::Data<WikiContext>,
+  web::Path(resource): web::Path<String>,
+  query: web::Query<HistoryQuery>,
+) -> actix_web::Result<HttpResponse> {
+  let offset = query.offset.unwrap_or(0);
+  let limit = query
+    .limit
+    .unwrap_or(DEFAULT_HISTORY_LIMIT)
+    .min(MAX_HISTORY_LIMIT);
+
+  let response = match resource.as_str() {
+    "promotional_codes" => {
+      HttpResponse::Ok().json(resource_history::<PromotionalCodes>(&context, offset, limit).await)
+    }
+    "material_schedule" => {
+      HttpResponse::Ok().json(resource_history::<MaterialSchedule>(&context, offset, limit).await)
+    }
+    "abyss_rotation" => {
+      HttpResponse::Ok().json(resource_history::<AbyssRotation>(&context, offset, limit).await)
+    }
+    _ => return Err(error::ErrorNotFound("Unknown Resource")),
+  };
+  Ok(response)
+}
+
+const FEED_LENGTH: usize = 50;
+
+// Built from the snapshots, so the feed outlives restarts
+#[get("/feed.xml")]
+async fn promotional_codes_feed(req: HttpRequest, context: web::Data<WikiContext>) -> HttpResponse {
+  let history = resource_history::<PromotionalCodes>(&context, 0, FEED_LENGTH).await;
+  let connection = req.connection_info();
+  let self_url = format!(
+    "{}://{}{}",
+    connection.scheme(),
+    connection.host(),
+    req.path()
+  );
+  HttpResponse::Ok()
+    .content_type("application/atom+xml")
+    .body(feed::atom_feed(&history, &self_url))
+}
+
+// Live changes as Server-Sent Events, a new client only gets the ones to come and
+// a reconnecting one what it missed through Last-Event-ID
+#[get("/events")]
+async fn events(req: HttpRequest, broadcaster: web::Data<EventBroadcaster>) -> HttpResponse {
+  let last_event_id = req
+    .headers()
+    .get("Last-Event-ID")
+    .and_then(|x| x.to_str().ok())
+    .and_then(|x| x.parse().ok());
+  let events = match last_event_id {
+    Some(last_event_id) => broadcaster.subscribe_after(last_event_id),
+    None => broadcaster.subscribe_live(),
+  };
+  let stream = events.map(|x| Ok::<_, actix_web::Error>(web::Bytes::from(x.to_sse())));
+  HttpResponse::Ok()
+    .content_type("text/event-stream")
+    .header(header::CACHE_CONTROL, "no-cache")
+    .streaming(Box::pin(stream))
+}
+
+// Live changes as JSON text frames, see WsSession for subscribing to some resources
+#[get("/ws")]
+async fn ws_events(
+  req: HttpRequest,
+  stream: web::Payload,
+  broadcaster: web::Data<EventBroadcaster>,
+) -> actix_web::Result<HttpResponse> {
+  let session = websocket::WsSession::new(broadcaster.subscribe_live());
+  ws::start(session, &req, stream)
+}
+
+#[derive(Serialize)]
+struct Health {
+  // "degraded" while the wiki circuit isn't closed
+  status: &'static str,
+  wiki: BreakerStatus,
+  promotional_codes_age_seconds: Option<i64>,
+}
+
+#[get("/health")]
+async fn health(context: web::Data<WikiContext>) -> HttpResponse {
+  let wiki = breaker_status();
+  let key = context.key(PromotionalCodes::get_title());
+  let age = persist::staleness::<PromotionalCodes>(&key).await;
+  HttpResponse::Ok().json(Health {
+    status: if wiki.state == BreakerState::Closed {
+      "ok"
+    } else {
+      "degraded"
+    },
+    wiki,
+    promotional_codes_age_seconds: age.map(|x| x.num_seconds()),
+  })
+}
+
+// Admin endpoints expect the admin token of the config as a bearer token, they don't
+// exist while it's unset
+fn authorize(req: &HttpRequest) -> actix_web::Result<()> {
+  let token = req
+    .app_data::<web::Data<Config>>()
+    .and_then(|config| config.admin_token.to_owned())
+    .ok_or_else(|| error::ErrorNotFound("Not Found"))?;
+  let bearer = req
+    .headers()
+    .get(header::AUTHORIZATION)
+    .and_then(|x| x.to_str().ok())
+    .and_then(|x| x.strip_prefix("Bearer "));
+
+  match bearer {
+    Some(bearer) if !token.is_empty() && bearer == token => Ok(()),
+    _ => Err(error::ErrorUnauthorized("Invalid Token")),
+  }
+}
+
+// Wipes the persisted resource so the next fetch scrapes and notifies from scratch
+#[delete("/cache/{resource}")]
+async fn clear_cache(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  web::Path(resource): web::Path<String>,
+  query: web::Query<ClearCacheQuery>,
+) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  let title = match resource.as_str() {
+    "promotional_codes" => PromotionalCodes::get_title(),
+    "material_schedule" => MaterialSchedule::get_title(),
+    "abyss_rotation" => AbyssRotation::get_title(),
+    _ => return Err(error::ErrorNotFound("Unknown Resource")),
+  };
+
+  persist::delete(&context.key(title), query.history.unwrap_or(false))
+    .await
+    .map_err(|_| error::ErrorInternalServerError("Failed to Clear the Cache"))?;
+  Ok(HttpResponse::NoContent().finish())
+}
+
+// Every persisted key with its size and fetch time, whatever the backend
+#[get("/resources")]
+async fn resources(req: HttpRequest) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  let keys = persist::list_keys()
+    .await
+    .map_err(|_| error::ErrorInternalServerError("Failed to List the Resources"))?;
+  Ok(HttpResponse::Ok().json(keys))
+}
+
+// Drops what PERSIST_CACHE holds for the resource, after it was written by another
+// process or restored by hand
+#[post("/cache/{resource}/reload")]
+async fn reload_cache(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  web::Path(resource): web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  let title = match resource.as_str() {
+    "promotional_codes" => PromotionalCodes::get_title(),
+    "material_schedule" => MaterialSchedule::get_title(),
+    "abyss_rotation" => AbyssRotation::get_title(),
+    _ => return Err(error::ErrorNotFound("Unknown Resource")),
+  };
+
+  persist::refresh(&context.key(title))
+    .await
+    .map_err(|_| error::ErrorInternalServerError("Failed to Reload the Cache"))?;
+  Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/promotional_codes/compare/{old_rev}/{new_rev}")]
+async fn promotional_codes_compare(
+  context: web::Data<WikiContext>,
+  web::Path((old_rev, new_rev)): web::Path<(u64, u64)>,
+) -> actix_web::Result<HttpResponse> {
+  let diff = compare_revisions::<PromotionalCodes>(&context, old_rev, new_rev).await?;
+  Ok(HttpResponse::Ok().json(diff))
+}
+
+#[get("/promotional_codes/snapshots")]
+async fn promotional_codes_snapshots(context: web::Data<WikiContext>) -> HttpResponse {
+  let snapshots = persist::list_snapshots(&context.key(PromotionalCodes::get_title())).await;
+  HttpResponse::Ok().json(snapshots)
+}
+
+#[get("/promotional_codes/snapshots/{id}")]
+async fn promotional_codes_snapshot(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  web::Path(id): web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+  let key = context.key(PromotionalCodes::get_title());
+  let snapshot: Stored<PromotionalCodes> = persist::get_snapshot(&key, id)
+    .await
+    .ok_or_else(|| error::ErrorNotFound("Unknown Snapshot"))?;
+  Ok(resource_response(&req, &snapshot))
+}
+
+#[get("/codes")]
+async fn codes(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  query: web::Query<CodesQuery>,
+) -> actix_web::Result<HttpResponse> {
+  let resource = get_wiki_resource::<PromotionalCodes>(&context)
+    .await
+    .ok_or_else(|| error::ErrorNotFound("No Codes"))?;
+  let codes = resource.data.filter(query.reward.as_deref(), query.active);
+  let page = pagination::paginate(
+    codes,
+    query.limit,
+    query.offset,
+    query.sort.as_deref(),
+    &CODE_SORT_KEYS,
+    CodeLookup::compare,
+  )?;
+  Ok(json_response(&req, &page))
+}
+
+const DEFAULT_RECENT_HOURS: i64 = 24;
+
+#[get("/codes/recent")]
+async fn recent_codes(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  query: web::Query<RecentCodesQuery>,
+) -> actix_web::Result<HttpResponse> {
+  let resource = get_wiki_resource::<PromotionalCodes>(&context)
+    .await
+    .ok_or_else(|| error::ErrorNotFound("No Codes"))?;
+  let hours = query.hours.unwrap_or(DEFAULT_RECENT_HOURS);
+  let codes = resource.data.recent(Utc::now() - Duration::hours(hours));
+  Ok(json_response(&req, &codes))
+}
+
+// Doesn't redeem anything, only compares with the codes seen so far
+#[get("/codes/check/{code}")]
+async fn check_code(
+  context: web::Data<WikiContext>,
+  web::Path(code): web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+  let resource = get_wiki_resource::<PromotionalCodes>(&context)
+    .await
+    .ok_or_else(|| error::ErrorNotFound("No Codes"))?;
+  Ok(HttpResponse::Ok().json(resource.data.check(&code)))
+}
+
+#[get("/codes/{code}")]
+async fn code(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  web::Path(code): web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+  let resource = get_wiki_resource::<PromotionalCodes>(&context)
+    .await
+    .ok_or_else(|| error::ErrorNotFound("Unknown Code"))?;
+  let code = resource
+    .data
+    .find(&code)
+    .ok_or_else(|| error::ErrorNotFound("Unknown Code"))?;
+  Ok(json_response(&req, &code))
+}
+
+#[get("/material_schedule")]
+async fn material_schedule(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  notifiers: web::Data<Notifiers>,
+  query: web::Query<ResourceQuery>,
+) -> actix_web::Result<HttpResponse> {
+  let new_resource = fetch_resource::<MaterialSchedule>(&context, &notifiers, &query).await?;
+  Ok(resource_response(&req, &new_resource))
+}
+
+#[get("/abyss_rotation")]
+async fn abyss_rotation(
+  req: HttpRequest,
+  context: web::Data<WikiContext>,
+  notifiers: web::Data<Notifiers>,
+  query: web::Query<ResourceQuery>,
+) -> actix_web::Result<HttpResponse> {
+  let new_resource = fetch_resource::<AbyssRotation>(&context, &notifiers, &query).await?;
+  Ok(resource_response(&req, &new_resource))
 }
 
 #[post("/subscribe")]
 async fn subscribe(
   body: web::Json<SubscribeBody>,
 ) -> Result<HttpResponse, subscription::SubscritionError> {
-  println!("{:?}", body);
+  info!("Subscribe request {:?}", body);
   match subscription::subscribe(body.into_inner()).await {
     Ok(_) => Ok(HttpResponse::Ok().body("Subscribed!")),
     Err(err) => Err(err),
   }
 }
 
+// Webhooks managed at runtime, admin endpoints like /cache since they make this
+// service call any URL
+#[post("/subscriptions")]
+async fn add_webhook(
+  req: HttpRequest,
+  body: web::Json<WebhookBody>,
+) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  let body = body.into_inner();
+  let webhook =
+    subscription::add_webhook(&body.url, body.secret, body.servers.unwrap_or_default()).await?;
+  info!("Added webhook {} for {}", webhook.id, webhook.url);
+  Ok(HttpResponse::Created().json(WebhookSummary::from(&webhook)))
+}
+
+#[get("/subscriptions")]
+async fn webhooks(req: HttpRequest) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  let webhooks: Vec<WebhookSummary> = subscription::webhooks()
+    .await
+    .iter()
+    .map(WebhookSummary::from)
+    .collect();
+  Ok(HttpResponse::Ok().json(webhooks))
+}
+
+#[delete("/subscriptions/{id}")]
+async fn remove_webhook(
+  req: HttpRequest,
+  web::Path(id): web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  subscription::remove_webhook(&id).await?;
+  info!("Removed webhook {}", id);
+  Ok(HttpResponse::NoContent().finish())
+}
+
+const DEFAULT_NOTIFICATIONS_LIMIT: usize = 100;
+const MAX_NOTIFICATIONS_LIMIT: usize = 500;
+
+// The latest delivery attempts of the notifiers, to find out why a change wasn't received
+#[get("/admin/notifications")]
+async fn notifications(
+  req: HttpRequest,
+  query: web::Query<NotificationsQuery>,
+) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  let outcome = match query.outcome.as_deref() {
+    None => None,
+    Some("delivered") => Some(Outcome::Delivered),
+    Some("failed") => Some(Outcome::Failed),
+    Some(_) => return Err(error::ErrorBadRequest("Unknown Outcome")),
+  };
+  let limit = query
+    .limit
+    .unwrap_or(DEFAULT_NOTIFICATIONS_LIMIT)
+    .min(MAX_NOTIFICATIONS_LIMIT);
+
+  let mut records = notifier::audit_log(query.notifier.as_deref(), outcome).await;
+  records.truncate(limit);
+  Ok(HttpResponse::Ok().json(records))
+}
+
+// Every persisted value with its metadata, to move the state to another environment
+async fn export_backup(req: HttpRequest) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  let archive = persist::export_all()
+    .await
+    .map_err(|_| error::ErrorInternalServerError("Failed to Export the Backup"))?;
+  Ok(HttpResponse::Ok().json(archive))
+}
+
+// Without overwrite the keys already holding a value are skipped and reported
+async fn import_backup(
+  req: HttpRequest,
+  query: web::Query<RestoreQuery>,
+  archive: web::Json<BackupArchive>,
+) -> actix_web::Result<HttpResponse> {
+  authorize(&req)?;
+  let report = persist::import_all(archive.into_inner(), query.overwrite.unwrap_or(false))
+    .await
+    .map_err(|_| error::ErrorInternalServerError("Failed to Import the Backup"))?;
+  Ok(HttpResponse::Ok().json(report))
+}
+
+// An archive holds every resource with its snapshots, well past the default limit
+const MAX_BACKUP_BYTES: usize = 64 * 1024 * 1024;
+
 #[post("/subscribe_test")]
 async fn subscribe_test(body: web::Json<PushBody<Value>>) -> actix_web::Result<HttpResponse> {
   match &body.resource_type {
     None => {
-      println!("Sync {:?}", body);
+      debug!("Sync {:?}", body);
     }
     Some(resource_type) => match resource_type.as_str() {
       "mona_spy::data_provider::wiki::promotional_codes::PromotionalCodes" => {
@@ -42,7 +536,25 @@ async fn subscribe_test(body: web::Json<PushBody<Value>>) -> actix_web::Result<H
           .ok_or(error::ErrorBadRequest("Empty Resource"))?;
         let resource: PromotionalCodes = serde_json::from_value(resource)
           .map_err(|_| error::ErrorBadRequest("Bad Resource Format"))?;
-        println!("Received Update Request for {:?}", resource);
+        debug!("Received Update Request for {:?}", resource);
+      }
+      "mona_spy::data_provider::wiki::material_schedule::MaterialSchedule" => {
+        let resource = body
+          .resource
+          .to_owned()
+          .ok_or(error::ErrorBadRequest("Empty Resource"))?;
+        let resource: MaterialSchedule = serde_json::from_value(resource)
+          .map_err(|_| error::ErrorBadRequest("Bad Resource Format"))?;
+        debug!("Received Update Request for {:?}", resource);
+      }
+      "mona_spy::data_provider::wiki::abyss_rotation::AbyssRotation" => {
+        let resource = body
+          .resource
+          .to_owned()
+          .ok_or(error::ErrorBadRequest("Empty Resource"))?;
+        let resource: AbyssRotation = serde_json::from_value(resource)
+          .map_err(|_| error::ErrorBadRequest("Bad Resource Format"))?;
+        debug!("Received Update Request for {:?}", resource);
       }
       _ => return Err(error::ErrorBadRequest("Invalid Resource Type:")),
     },
@@ -53,6 +565,23 @@ async fn subscribe_test(body: web::Json<PushBody<Value>>) -> actix_web::Result<H
   }))
 }
 
+// `mona_spy diff-snapshots <resource> <old.json> <new.json>` diffs two snapshots
+// saved to disk, ex: to check a parser change against old scrapes, instead of
+// starting the server
+async fn diff_snapshots(args: &[String]) -> Result<String, String> {
+  let (resource, old, new) = match args {
+    [resource, old, new] => (resource, Path::new(old), Path::new(new)),
+    _ => return Err("usage: mona_spy diff-snapshots <resource> <old.json> <new.json>".to_owned()),
+  };
+  let rendered = match resource.as_str() {
+    "promotional_codes" => render_snapshot_diff::<PromotionalCodes>(old, new).await,
+    "material_schedule" => render_snapshot_diff::<MaterialSchedule>(old, new).await,
+    "abyss_rotation" => render_snapshot_diff::<AbyssRotation>(old, new).await,
+    _ => return Err(format!("Unknown resource {}", resource)),
+  };
+  rendered.map_err(|err| err.to_string())
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
   #[cfg(debug_assertions)]
@@ -61,18 +590,298 @@ async fn main() -> std::io::Result<()> {
   #[cfg(not(debug_assertions))]
   let ip = "0.0.0.0";
 
-  let port = env::var("PORT").map_or("8080".to_owned(), |x| x);
-  let addr = ip.to_owned() + ":" + port.as_str();
+  env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+  let args: Vec<String> = env::args().skip(1).collect();
+  if args.first().map(String::as_str) == Some("diff-snapshots") {
+    match diff_snapshots(&args[1..]).await {
+      Ok(rendered) => {
+        writeln!(std::io::stdout(), "{}", rendered)?;
+        return Ok(());
+      }
+      Err(err) => {
+        error!("{}", err);
+        std::process::exit(1);
+      }
+    }
+  }
+
+  let config = match Config::from_env() {
+    Ok(config) => config,
+    Err(err) => {
+      error!("Invalid configuration, {}", err);
+      std::process::exit(1);
+    }
+  };
+  let addr = format!("{}:{}", ip, config.port);
+  let backend = persist::create_backend(
+    config.persist_backend,
+    &config.persist_dir,
+    config.persist_cache,
+  );
+  persist::init(backend.clone());
+  let context = match WikiContext::new(
+    &config.wiki_host,
+    &config.wiki_lang,
+    config.requests_per_second,
+    config.burst,
+  ) {
+    Ok(context) => context,
+    Err(err) => {
+      error!("Failed to create the wiki client, {}", err);
+      std::process::exit(1);
+    }
+  };
+
+  match context.login_from_env().await {
+    Ok(true) => info!("Logged in to {}", context.host),
+    Ok(false) => {}
+    Err(err) => warn!("Continuing anonymously, {}", err),
+  }
+
+  info!("Running Server on {}", addr);
+  info!(
+    "Serving {} ({}), persisting to {:?} under {:?}, {} wiki requests per second with bursts of {}, {} webhooks",
+    config.wiki_host,
+    config.wiki_lang,
+    config.persist_backend,
+    config.persist_dir,
+    config.requests_per_second,
+    config.burst,
+    config.webhook_urls.len()
+  );
 
-  println!("Running Server on {}", addr);
+  actix_rt::spawn(subscription::retry_loop());
+  actix_rt::spawn(persist::prune_loop());
+  if let Some(interval) = config.poll_interval {
+    actix_rt::spawn(check_update::call_every(
+      addr.to_owned(),
+      vec![
+        "/promotional_codes",
+        "/material_schedule",
+        "/abyss_rotation",
+      ],
+      interval,
+    ));
+  }
 
-  HttpServer::new(|| {
-    let app = App::new().service(promotional_codes).service(subscribe);
+  // Shared by the workers, so coalesced notifications are held in one place
+  let mut notifiers = notifier::default_notifiers(&config);
+  let broadcaster = Arc::new(EventBroadcaster::new());
+  notifiers.push(broadcaster.clone());
+  actix_rt::spawn(broadcaster.clone().keep_alive_loop());
+  let server_notifiers = notifiers.clone();
+  actix_rt::spawn(notifier::retry_loop(notifiers.clone()));
+  HttpServer::new(move || {
+    let app = App::new()
+      .data(config.clone())
+      .data(context.clone())
+      .data(server_notifiers.clone())
+      .app_data(web::Data::from(broadcaster.clone()))
+      .service(promotional_codes)
+      .service(promotional_codes_compare)
+      .service(promotional_codes_snapshots)
+      .service(promotional_codes_snapshot)
+      .service(codes)
+      // Registered before /codes/{code} so "recent" isn't taken for a code
+      .service(recent_codes)
+      .service(check_code)
+      .service(code)
+      .service(material_schedule)
+      .service(abyss_rotation)
+      .service(refresh)
+      .service(history)
+      .service(promotional_codes_feed)
+      .service(events)
+      .service(ws_events)
+      .service(health)
+      .service(clear_cache)
+      .service(reload_cache)
+      .service(resources)
+      .service(subscribe)
+      .service(add_webhook)
+      .service(webhooks)
+      .service(remove_webhook)
+      .service(notifications)
+      .service(
+        web::resource("/admin/backup")
+          .app_data(web::JsonConfig::default().limit(MAX_BACKUP_BYTES))
+          .route(web::get().to(export_backup))
+          .route(web::post().to(import_backup)),
+      );
     #[cfg(debug_assertions)] // Debug APIs
     let app = app.service(subscribe_test);
     app
   })
   .bind(addr)?
   .run()
-  .await
+  .await?;
+
+  info!("Server stopped, flushing pending notifications and writes");
+  notifier::flush_all(&notifiers).await;
+  if let Err(err) = persist::shutdown().await {
+    error!("Failed to flush pending writes: {:?}", err);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use actix_web::dev::{Body, ResponseBody};
+  use actix_web::http::StatusCode;
+  use actix_web::test;
+  use notifier::ChangeEvent;
+  use serde_json::json;
+  use std::sync::Once;
+
+  // The library's test backend isn't compiled into the binary, every test of the
+  // binary shares this one
+  fn init_backend() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+      let dir = env::temp_dir().join(format!("mona_spy-main-{}", std::process::id()));
+      persist::init(Arc::new(persist::FileBackend::new(dir)));
+    });
+  }
+
+  // The handlers read the wiki they serve from the app data
+  #[actix_rt::test]
+  async fn serves_the_codes_of_the_injected_context() {
+    init_backend();
+    let context = WikiContext::new("injected.test", "en", 1.0, 1.0).unwrap();
+    let codes = PromotionalCodes::from_wikitext(include_str!(
+      "data_provider/wiki/fixtures/promotional_codes.wikitext"
+    ));
+    persist::set_by_key(&context.key(PromotionalCodes::get_title()), &codes)
+      .await
+      .unwrap();
+    let empty = WikiContext::new("empty.test", "en", 1.0, 1.0).unwrap();
+
+    let mut app = test::init_service(App::new().data(context).service(code)).await;
+    let req = test::TestRequest::get()
+      .uri("/codes/genshingift")
+      .to_request();
+    let body: Value = test::read_response_json(&mut app, req).await;
+    assert_eq!(body["code"], "GENSHINGIFT");
+    assert_eq!(body["server"], "All");
+
+    let mut app = test::init_service(App::new().data(empty).service(code)).await;
+    let req = test::TestRequest::get()
+      .uri("/codes/genshingift")
+      .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[actix_rt::test]
+  async fn guards_the_admin_endpoints_with_the_configured_token() {
+    init_backend();
+    let config = Config {
+      admin_token: Some("secret".to_owned()),
+      ..Config::from_env().unwrap()
+    };
+    let mut app = test::init_service(App::new().data(config).service(resources)).await;
+    for (token, status) in &[
+      ("Bearer secret", StatusCode::OK),
+      ("Bearer wrong", StatusCode::UNAUTHORIZED),
+    ] {
+      let req = test::TestRequest::get()
+        .uri("/resources")
+        .header(header::AUTHORIZATION, *token)
+        .to_request();
+      assert_eq!(test::call_service(&mut app, req).await.status(), *status);
+    }
+
+    // Without a token there are no admin endpoints
+    let config = Config {
+      admin_token: None,
+      ..Config::from_env().unwrap()
+    };
+    let mut app = test::init_service(App::new().data(config).service(resources)).await;
+    let req = test::TestRequest::get()
+      .uri("/resources")
+      .header(header::AUTHORIZATION, "Bearer secret")
+      .to_request();
+    assert_eq!(
+      test::call_service(&mut app, req).await.status(),
+      StatusCode::NOT_FOUND
+    );
+  }
+
+  fn change_event(summary: &str) -> ChangeEvent {
+    serde_json::from_value(json!({
+      "resource_type": "test",
+      "title": "Test",
+      "summary": summary,
+      "added_count": 1,
+      "removed_count": 0,
+      "added": {},
+      "removed": {},
+      "fetched_at": Utc::now(),
+      "revision": null,
+    }))
+    .unwrap()
+  }
+
+  // The frame of the next event, keep-alives aside
+  async fn next_event(body: &mut ResponseBody<Body>) -> (u64, ChangeEvent) {
+    loop {
+      let chunk = body.next().await.unwrap().unwrap();
+      let frame = std::str::from_utf8(&chunk).unwrap().to_owned();
+      if frame.starts_with(':') {
+        continue;
+      }
+      let field = |name: &str| {
+        frame
+          .lines()
+          .find_map(|x| x.strip_prefix(name))
+          .unwrap()
+          .to_owned()
+      };
+      assert_eq!(field("event: "), "change");
+      let event = serde_json::from_str(&field("data: ")).unwrap();
+      return (field("id: ").parse().unwrap(), event);
+    }
+  }
+
+  #[actix_rt::test]
+  async fn streams_the_changes_to_subscribers() {
+    init_backend();
+    let broadcaster = Arc::new(EventBroadcaster::new());
+    let notifiers: Notifiers = vec![broadcaster.clone()];
+    let mut app = test::init_service(
+      App::new()
+        .app_data(web::Data::from(broadcaster.clone()))
+        .service(events),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/events").to_request();
+    let mut res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+      res.headers().get(header::CONTENT_TYPE).unwrap(),
+      "text/event-stream"
+    );
+    let mut body = res.take_body();
+
+    let first = change_event("first");
+    notifier::notify_all(&notifiers, &first).await;
+    let (first_id, event) = next_event(&mut body).await;
+    assert_eq!(event, first);
+    notifier::notify_all(&notifiers, &change_event("second")).await;
+    let (second_id, event) = next_event(&mut body).await;
+    assert_eq!(event.summary, "second");
+    assert!(second_id > first_id);
+
+    // A client reconnecting after the first event gets the second one replayed
+    let req = test::TestRequest::get()
+      .uri("/events")
+      .header("Last-Event-ID", first_id.to_string())
+      .to_request();
+    let mut res = test::call_service(&mut app, req).await;
+    let (id, event) = next_event(&mut res.take_body()).await;
+    assert_eq!((id, event.summary.as_str()), (second_id, "second"));
+  }
 }
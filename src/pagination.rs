@@ -0,0 +1,57 @@
+use actix_web::error;
+use serde::Serialize;
+use std::cmp::Ordering;
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+  pub items: Vec<T>,
+  pub total: usize,
+  pub offset: usize,
+}
+
+// Sorts by one of the whitelisted keys, "-key" sorts descending. The sort is stable,
+// so items with the same key keep their order on the page between requests.
+pub fn paginate<T, F>(
+  mut items: Vec<T>,
+  limit: Option<usize>,
+  offset: Option<usize>,
+  sort: Option<&str>,
+  sort_keys: &[&str],
+  compare: F,
+) -> actix_web::Result<Page<T>>
+where
+  F: Fn(&T, &T, &str) -> Ordering,
+{
+  if let Some(sort) = sort {
+    let (key, descending) = match sort.strip_prefix('-') {
+      Some(key) => (key, true),
+      None => (sort, false),
+    };
+    if !sort_keys.contains(&key) {
+      return Err(error::ErrorBadRequest(format!(
+        "Unknown sort key, expected one of: {}",
+        sort_keys.join(", ")
+      )));
+    }
+
+    items.sort_by(|a, b| {
+      if descending {
+        compare(b, a, key)
+      } else {
+        compare(a, b, key)
+      }
+    });
+  }
+
+  let total = items.len();
+  let offset = offset.unwrap_or(0);
+  let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+  Ok(Page {
+    items: items.into_iter().skip(offset).take(limit).collect(),
+    total,
+    offset,
+  })
+}
@@ -0,0 +1,175 @@
+use crate::data_provider::notifier::Broadcast;
+use crate::interface::WsSubscribe;
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web_actors::ws;
+use async_std::channel::Receiver;
+use log::debug;
+use serde_json::json;
+use std::collections::HashSet;
+
+// A /ws client, every change is sent as a JSON text frame until it subscribes to
+// some resources. The events come from the broadcaster, which ends the stream of
+// a client that falls behind and so closes the connection.
+pub struct WsSession {
+  resources: Option<HashSet<String>>,
+  events: Option<Receiver<Broadcast>>,
+}
+
+impl WsSession {
+  pub fn new(events: Receiver<Broadcast>) -> WsSession {
+    WsSession {
+      resources: None,
+      events: Some(events),
+    }
+  }
+
+  fn wants(&self, title: &str) -> bool {
+    self
+      .resources
+      .as_ref()
+      .map_or(true, |resources| resources.contains(title))
+  }
+
+  fn command(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+    match serde_json::from_str::<WsSubscribe>(text) {
+      Ok(command) => {
+        self.resources = Some(command.subscribe.into_iter().collect());
+        ctx.text(json!({ "subscribed": self.resources }).to_string());
+      }
+      Err(err) => ctx.text(json!({ "error": err.to_string() }).to_string()),
+    }
+  }
+}
+
+impl Actor for WsSession {
+  type Context = ws::WebsocketContext<Self>;
+
+  fn started(&mut self, ctx: &mut Self::Context) {
+    if let Some(events) = self.events.take() {
+      ctx.add_stream(events);
+    }
+  }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
+  fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+    match msg {
+      Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+      Ok(ws::Message::Text(text)) => self.command(&text, ctx),
+      Ok(ws::Message::Close(reason)) => {
+        ctx.close(reason);
+        ctx.stop();
+      }
+      Ok(_) => {}
+      Err(err) => {
+        debug!("Closing a websocket: {}", err);
+        ctx.stop();
+      }
+    }
+  }
+}
+
+// The default `finished` stops the session once the broadcaster drops it
+impl StreamHandler<Broadcast> for WsSession {
+  fn handle(&mut self, msg: Broadcast, ctx: &mut Self::Context) {
+    match msg {
+      Broadcast::Event { event, .. } if self.wants(&event.title) => {
+        ctx.text(serde_json::to_string(event.as_ref()).unwrap_or_default())
+      }
+      Broadcast::Event { .. } => {}
+      Broadcast::KeepAlive => ctx.ping(b""),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::data_provider::notifier::{ChangeEvent, EventBroadcaster, Notifier};
+  use actix_web::{test, web, App};
+  use actix_web_actors::ws::{Frame, Message};
+  use chrono::Utc;
+  use futures::{SinkExt, StreamExt};
+  use serde_json::{json, Value};
+  use std::sync::Arc;
+
+  fn change_event(title: &str) -> ChangeEvent {
+    serde_json::from_value(json!({
+      "resource_type": "test",
+      "title": title,
+      "summary": "1 added",
+      "added_count": 1,
+      "removed_count": 0,
+      "added": {},
+      "removed": {},
+      "fetched_at": Utc::now(),
+      "revision": null,
+    }))
+    .unwrap()
+  }
+
+  fn text(frame: Frame) -> Value {
+    match frame {
+      Frame::Text(text) => serde_json::from_slice(&text).unwrap(),
+      frame => panic!("expected a text frame, got {:?}", frame),
+    }
+  }
+
+  #[actix_rt::test]
+  async fn only_sends_the_subscribed_resources() {
+    let broadcaster = Arc::new(EventBroadcaster::new());
+    let data = broadcaster.clone();
+    let mut srv = test::start(move || {
+      App::new()
+        .app_data(web::Data::from(data.clone()))
+        .service(crate::ws_events)
+    });
+    let mut framed = srv.ws_at("/ws").await.unwrap();
+
+    framed
+      .send(Message::Text(
+        r#"{"subscribe": ["Promotional_Codes"]}"#.into(),
+      ))
+      .await
+      .unwrap();
+    let reply = text(framed.next().await.unwrap().unwrap());
+    assert_eq!(reply, json!({ "subscribed": ["Promotional_Codes"] }));
+
+    // Frames arrive in order, so the first event received shows the other was skipped
+    broadcaster
+      .notify(&change_event("Material_Schedule"))
+      .await
+      .unwrap();
+    broadcaster
+      .notify(&change_event("Promotional_Codes"))
+      .await
+      .unwrap();
+    let event = text(framed.next().await.unwrap().unwrap());
+    assert_eq!(event["title"], "Promotional_Codes");
+
+    framed.send(Message::Ping("ping".into())).await.unwrap();
+    match framed.next().await.unwrap().unwrap() {
+      Frame::Pong(msg) => assert_eq!(&msg[..], b"ping"),
+      frame => panic!("expected a pong, got {:?}", frame),
+    }
+  }
+
+  #[actix_rt::test]
+  async fn rejects_an_invalid_subscription() {
+    let broadcaster = Arc::new(EventBroadcaster::new());
+    let mut srv = test::start(move || {
+      App::new()
+        .app_data(web::Data::from(broadcaster.clone()))
+        .service(crate::ws_events)
+    });
+    let mut framed = srv.ws_at("/ws").await.unwrap();
+
+    framed
+      .send(Message::Text(
+        r#"{"subscribe": "Promotional_Codes"}"#.into(),
+      ))
+      .await
+      .unwrap();
+    let reply = text(framed.next().await.unwrap().unwrap());
+    assert!(reply["error"].is_string());
+  }
+}